@@ -1,6 +1,9 @@
 #ifndef BROTLI_BROCCOLI_H
 #define BROTLI_BROCCOLI_H
 #include <stdint.h>
+#include <brotli/types.h>
+
+/* deprecated: passed by value and too small for any state added later, use BroccoliInstance */
 typedef struct BroccoliState_ {
     void *unused;
     unsigned char data[248];
@@ -10,6 +13,7 @@ typedef enum BroccoliResult_ {
   BroccoliSuccess = 0,
   BroccoliNeedsMoreInput = 1,
   BroccoliNeedsMoreOutput = 2,
+  BroccoliInvalidCheckpoint = 123,
   BroccoliBrotliFileNotCraftedForAppend = 124,
   BroccoliInvalidWindowSize = 125,
   BroccoliWindowSizeLargerThanPreviousFile = 126,
@@ -17,10 +21,20 @@ typedef enum BroccoliResult_ {
 } BroccoliResult;
 
 
+/* the functions on BroccoliState below are deprecated in favour of the
+   BroccoliInstance functions after them, which they match one for one */
 BroccoliState BroccoliCreateInstance();
 
 BroccoliState BroccoliCreateInstanceWithWindowSize(uint8_t window_size);
 
+/* later files may have larger windows than the first, which must be catable:
+   see BroccoliLeadingHeader */
+BroccoliState BroccoliCreateInstanceWithWindowGrowth();
+
+/* call before the first file: drops the magic number headers of the files in favour
+   of a single one for the whole output, see BroccoliLeadingHeader */
+void BroccoliMergeMagicHeaders(BroccoliState *state);
+
 void BroccoliDestroyInstance(BroccoliState state);
 
 void BroccoliNewBrotliFile(BroccoliState *state);
@@ -45,4 +59,87 @@ BroccoliResult BroccoliConcatFinish(BroccoliState * state,
 BroccoliResult BroccoliConcatFinished(BroccoliState * state,
                               size_t *available_out,
                               uint8_t*output_buf);
+
+/* once finished, the first *replaced_len bytes of the output have to be replaced
+   by the *header_len bytes written to header, which needs room for 32 */
+BroccoliResult BroccoliLeadingHeader(const BroccoliState *state,
+                                     uint8_t *header,
+                                     size_t *header_len,
+                                     size_t *replaced_len);
+
+typedef struct BroccoliInstance_ BroccoliInstance;
+
+/* alloc_func and free_func are either both given or both NULL, in which case
+   the instance lives on the heap; returns NULL if they do not match */
+BroccoliInstance* BroccoliInstanceCreate(
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+BroccoliInstance* BroccoliInstanceCreateWithWindowSize(
+    uint8_t window_size,
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+/* later files may have larger windows than the first, which must be catable:
+   see BroccoliInstanceLeadingHeader */
+BroccoliInstance* BroccoliInstanceCreateWithWindowGrowth(
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+/* frees the instance with the allocator it was created with; NULL is ignored */
+void BroccoliInstanceDestroy(BroccoliInstance *instance);
+
+/* call before the first file: drops the magic number headers of the files in favour
+   of a single one for the whole output, see BroccoliInstanceLeadingHeader */
+void BroccoliInstanceMergeMagicHeaders(BroccoliInstance *instance);
+
+/* call before the first file: puts a marker metadata metablock in front of every
+   file after the first, so that the files can be found again in the output; the
+   markers get a random tag unless built without the standard library */
+void BroccoliInstanceMarkMembers(BroccoliInstance *instance);
+
+/* BroccoliInstanceMarkMembers with the given tag, which has to be the one the
+   existing markers carry when appending to a marked concatenation */
+void BroccoliInstanceMarkMembersWithTag(BroccoliInstance *instance, uint64_t tag);
+
+void BroccoliInstanceNewBrotliFile(BroccoliInstance *instance);
+
+BroccoliResult BroccoliInstanceConcatStream(
+    BroccoliInstance *instance,
+    size_t *available_in,
+    const uint8_t **input_buf_ptr,
+    size_t *available_out,
+    uint8_t **output_buf_ptr);
+
+BroccoliResult BroccoliInstanceConcatStreaming(
+    BroccoliInstance *instance,
+    size_t *available_in,
+    const uint8_t *input_buf_ptr,
+    size_t *available_out,
+    uint8_t *output_buf_ptr);
+
+BroccoliResult BroccoliInstanceConcatFinish(BroccoliInstance *instance,
+                                            size_t *available_out,
+                                            uint8_t **output_buf);
+BroccoliResult BroccoliInstanceConcatFinished(BroccoliInstance *instance,
+                                              size_t *available_out,
+                                              uint8_t *output_buf);
+
+/* once finished, the first *replaced_len bytes of the output have to be replaced
+   by the *header_len bytes written to header, which needs room for 32 */
+BroccoliResult BroccoliInstanceLeadingHeader(const BroccoliInstance *instance,
+                                             uint8_t *header,
+                                             size_t *header_len,
+                                             size_t *replaced_len);
+
+/* saves the state to checkpoint, which has room for *checkpoint_len bytes, and
+   sets *checkpoint_len to the bytes written; if there is not enough room this
+   returns BroccoliNeedsMoreOutput with *checkpoint_len set to the room needed */
+BroccoliResult BroccoliInstanceCheckpoint(const BroccoliInstance *instance,
+                                          uint8_t *checkpoint,
+                                          size_t *checkpoint_len);
+
+/* replaces the state with one saved by BroccoliInstanceCheckpoint, keeping the
+   allocator; returns BroccoliInvalidCheckpoint, leaving the state alone, for
+   anything else */
+BroccoliResult BroccoliInstanceRestore(BroccoliInstance *instance,
+                                       const uint8_t *checkpoint,
+                                       size_t checkpoint_len);
 #endif
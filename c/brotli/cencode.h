@@ -0,0 +1,192 @@
+/* Copyright 2013 Google Inc. All Rights Reserved.
+
+   Distributed under MIT license.
+   See file LICENSE for detail or copy at https://opensource.org/licenses/MIT
+*/
+
+/**
+ * @file
+ * The Rust encoder API under CBrotliEncoder names.
+ *
+ * Every function here behaves exactly like its BrotliEncoder counterpart in encode.h and
+ * multiencode.h, but its name does not clash with the ones exported by libbrotlienc, so a
+ * program can link against both. Building the library with the ffi-prefixed-encoder-only
+ * feature leaves the BrotliEncoder names out of it altogether.
+ *
+ * The types are shared with encode.h, which has the same include guard as the encode.h of
+ * libbrotli: include this header before any other brotli encoder header.
+ */
+
+#ifndef BROTLI_ENC_CENCODE_H_
+#define BROTLI_ENC_CENCODE_H_
+
+#include "encode.h"
+#include "multiencode.h"
+
+#if defined(__cplusplus) || defined(c_plusplus)
+extern "C" {
+#endif
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderSetParameter(
+    BrotliEncoderState* state, BrotliEncoderParameter param, uint32_t value);
+
+BROTLI_ENC_API BrotliEncoderState* CBrotliEncoderCreateInstance(
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+BROTLI_ENC_API void CBrotliEncoderDestroyInstance(BrotliEncoderState* state);
+
+BROTLI_ENC_API size_t CBrotliEncoderMaxCompressedSize(size_t input_size);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderCompress(
+    int quality, int lgwin, BrotliEncoderMode mode, size_t input_size,
+    const uint8_t input_buffer[BROTLI_ARRAY_PARAM(input_size)],
+    size_t* encoded_size,
+    uint8_t encoded_buffer[BROTLI_ARRAY_PARAM(*encoded_size)]);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderCompressStream(
+    BrotliEncoderState* state, BrotliEncoderOperation op, size_t* available_in,
+    const uint8_t** next_in, size_t* available_out, uint8_t** next_out,
+    size_t* total_out);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderCompressStreaming(
+    BrotliEncoderState* state, BrotliEncoderOperation op, size_t* available_in,
+    const uint8_t* next_in, size_t* available_out, uint8_t* next_out);
+
+BROTLI_ENC_API void CBrotliEncoderSetMetablockCallback(
+    BrotliEncoderState* state, brotli_metablock_func callback, void* opaque);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderIsFinished(BrotliEncoderState* state);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderHasMoreOutput(
+    BrotliEncoderState* state);
+
+BROTLI_ENC_API const uint8_t* CBrotliEncoderTakeOutput(
+    BrotliEncoderState* state, size_t* size);
+
+BROTLI_ENC_API void CBrotliEncoderSetCustomDictionary(
+  BrotliEncoderState* state,
+  size_t size,
+  const uint8_t *dict);
+
+BROTLI_ENC_API BrotliEncoderErrorCode CBrotliEncoderGetErrorCode(
+    const BrotliEncoderState* state);
+
+BROTLI_ENC_API const char* CBrotliEncoderGetErrorString(
+    const BrotliEncoderState* state);
+
+BROTLI_ENC_API const char* CBrotliEncoderErrorString(BrotliEncoderErrorCode c);
+
+BROTLI_ENC_API BrotliEncoderErrorCode CBrotliEncoderGetLastErrorCode(void);
+
+BROTLI_ENC_API const char* CBrotliEncoderGetLastPanicMessage(void);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderParamsInit(
+    BrotliEncoderParamsC* params, size_t size);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderSetParams(
+    BrotliEncoderState* state, const BrotliEncoderParamsC* params);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderGetParams(
+    const BrotliEncoderState* state, BrotliEncoderParamsC* params);
+
+BROTLI_ENC_API BrotliEncoderWriter* CBrotliEncoderCreateWriter(
+    const BrotliEncoderParamsC* params, size_t buffer_size,
+    brotli_encoder_write_func write_func, void* write_opaque,
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderWriterWrite(
+    BrotliEncoderWriter* writer, size_t input_size, const uint8_t* input);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderWriterFlush(BrotliEncoderWriter* writer);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderWriterFinish(BrotliEncoderWriter* writer);
+
+BROTLI_ENC_API BrotliEncoderErrorCode CBrotliEncoderWriterGetErrorCode(
+    const BrotliEncoderWriter* writer);
+
+BROTLI_ENC_API void CBrotliEncoderDestroyWriter(BrotliEncoderWriter* writer);
+
+BROTLI_ENC_API BrotliEncoderReader* CBrotliEncoderCreateReader(
+    const BrotliEncoderParamsC* params, size_t buffer_size,
+    brotli_encoder_read_func read_func, void* read_opaque,
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+BROTLI_ENC_API BROTLI_BOOL CBrotliEncoderReaderRead(
+    BrotliEncoderReader* reader, size_t* encoded_size, uint8_t* encoded);
+
+BROTLI_ENC_API BrotliEncoderErrorCode CBrotliEncoderReaderGetErrorCode(
+    const BrotliEncoderReader* reader);
+
+BROTLI_ENC_API void CBrotliEncoderDestroyReader(BrotliEncoderReader* reader);
+
+BROTLI_ENC_API uint32_t CBrotliEncoderVersion(void);
+
+/**
+ * Allocates and frees memory the way @p state was told to in ::CBrotliEncoderCreateInstance.
+ */
+BROTLI_ENC_API uint8_t* CBrotliEncoderMallocU8(BrotliEncoderState* state, size_t size);
+
+BROTLI_ENC_API void CBrotliEncoderFreeU8(BrotliEncoderState* state, uint8_t* data, size_t size);
+
+BROTLI_ENC_API size_t* CBrotliEncoderMallocUsize(BrotliEncoderState* state, size_t size);
+
+BROTLI_ENC_API void CBrotliEncoderFreeUsize(BrotliEncoderState* state, size_t* data, size_t size);
+
+BROTLI_ENC_API int32_t CBrotliEncoderCompressMulti(
+    size_t num_params,
+    const BrotliEncoderParameter* param_keys,
+    const uint32_t* param_values,
+    size_t input_size,
+    const uint8_t *input_buffer,
+    size_t *encoded_size,
+    uint8_t *encoded,
+    size_t desired_num_threads,
+    brotli_alloc_func alloc_func, brotli_free_func free_func,
+    void** alloc_opaque_per_thread);
+
+BROTLI_ENC_API size_t CBrotliEncoderMaxCompressedSizeMulti(size_t input_size, size_t num_threads);
+
+BROTLI_ENC_API BrotliEncoderWorkPool* CBrotliEncoderCreateWorkPool(
+    size_t num_threads,
+    brotli_alloc_func alloc_func,
+    brotli_free_func free_func,
+    void** alloc_opaque_per_thread);
+
+BROTLI_ENC_API void CBrotliEncoderDestroyWorkPool(BrotliEncoderWorkPool* work_pool);
+
+BROTLI_ENC_API int32_t CBrotliEncoderCompressWorkPool(
+    BrotliEncoderWorkPool *work_pool,
+    size_t num_params,
+    const BrotliEncoderParameter* param_keys,
+    const uint32_t* param_values,
+    size_t input_size,
+    const uint8_t *input_buffer,
+    size_t *encoded_size,
+    uint8_t *encoded,
+    size_t desired_num_threads,
+    brotli_alloc_func alloc_func, brotli_free_func free_func,
+    void** alloc_opaque_per_thread);
+
+BROTLI_ENC_API BrotliEncoderWorkPoolJob* CBrotliEncoderCompressWorkPoolSubmit(
+    BrotliEncoderWorkPool *work_pool,
+    size_t num_params,
+    const BrotliEncoderParameter* param_keys,
+    const uint32_t* param_values,
+    size_t input_size,
+    const uint8_t *input_buffer,
+    size_t desired_num_threads,
+    brotli_alloc_func alloc_func, brotli_free_func free_func,
+    void** alloc_opaque_per_thread);
+
+BROTLI_ENC_API int32_t CBrotliEncoderWorkPoolJobIsDone(const BrotliEncoderWorkPoolJob* job);
+
+BROTLI_ENC_API int32_t CBrotliEncoderCompressWorkPoolWait(
+    BrotliEncoderWorkPoolJob* job,
+    size_t *encoded_size,
+    uint8_t *encoded);
+
+#if defined(__cplusplus) || defined(c_plusplus)
+}  /* extern "C" */
+#endif
+
+#endif  /* BROTLI_ENC_CENCODE_H_ */
@@ -388,6 +388,59 @@ BROTLI_ENC_API BROTLI_BOOL BrotliEncoderCompressStreaming(
     BrotliEncoderState* state, BrotliEncoderOperation op, size_t* available_in,
     const uint8_t* next_in, size_t* available_out, uint8_t* next_out);
 
+typedef enum BrotliEncoderIRCommandType {
+  BROTLI_IR_COPY = 0,
+  BROTLI_IR_DICT = 1,
+  BROTLI_IR_LITERAL = 2,
+  BROTLI_IR_BLOCK_SWITCH_COMMAND = 3,
+  BROTLI_IR_BLOCK_SWITCH_LITERAL = 4,
+  BROTLI_IR_BLOCK_SWITCH_DISTANCE = 5,
+  BROTLI_IR_PREDICTION_MODE = 6
+} BrotliEncoderIRCommandType;
+
+/**
+ * One command of a metablock. Only the fields belonging to @p command_type
+ * are set; the others are zero.
+ */
+typedef struct BrotliEncoderIRCommand {
+  BrotliEncoderIRCommandType command_type;
+  uint8_t block_type;   /* block switches */
+  uint8_t stride;       /* literal block switches */
+  uint8_t word_size;    /* dictionary words, as stored in the dictionary */
+  uint8_t transform;
+  uint8_t high_entropy; /* literals */
+  uint32_t word_id;
+  uint32_t distance;    /* copies */
+  /* bytes produced: copy length, literal count or transformed word size */
+  uint32_t num_bytes;
+  const uint8_t* data;  /* literals, pointing into the encoder's input */
+} BrotliEncoderIRCommand;
+
+/** The context maps a metablock was encoded with. */
+typedef struct BrotliEncoderPredictionModeContextMap {
+  const uint8_t* literal_context_map;
+  size_t literal_context_map_size;
+  const uint8_t* predmode_speed_and_distance_context_map;
+  size_t predmode_speed_and_distance_context_map_size;
+} BrotliEncoderPredictionModeContextMap;
+
+typedef void (*brotli_metablock_func)(
+    void* opaque, const BrotliEncoderPredictionModeContextMap* context_map,
+    const BrotliEncoderIRCommand* commands, size_t num_commands);
+
+/**
+ * Registers @p callback to be called by ::BrotliEncoderCompressStream with
+ * the intermediate representation of every metablock written from then on.
+ *
+ * The context maps and commands are only valid during the call.
+ *
+ * @param state encoder instance
+ * @param callback called once per metablock; @c NULL stops the calls
+ * @param opaque passed to @p callback
+ */
+BROTLI_ENC_API void BrotliEncoderSetMetablockCallback(
+    BrotliEncoderState* state, brotli_metablock_func callback, void* opaque);
+
 /**
  * Checks if encoder instance reached the final state.
  *
@@ -445,6 +498,257 @@ BROTLI_ENC_API void BrotliEncoderSetCustomDictionary(
   size_t size,
   const uint8_t *dict);
     
+/**
+ * Why an encoder call failed.
+ *
+ * Functions that take a ::BrotliEncoderState record it there for
+ * ::BrotliEncoderGetErrorCode. Every call that can fail, the one-shot and
+ * multithreaded functions included, also records it for the calling thread,
+ * see ::BrotliEncoderGetLastErrorCode. Those functions keep returning
+ * ::BROTLI_TRUE or ::BROTLI_FALSE.
+ */
+typedef enum BrotliEncoderErrorCode {
+  BROTLI_ENCODER_NO_ERROR = 0,
+  /** unknown parameter or value, or a parameter set after compression started */
+  BROTLI_ENCODER_ERROR_INVALID_PARAMETER = -1,
+  /** a NULL state or a thread count of zero */
+  BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS = -2,
+  BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL = -3,
+  /** an operation the stream does not allow in its current state */
+  BROTLI_ENCODER_ERROR_INVALID_OPERATION = -4,
+  /** the output of the threads could not be joined into one stream */
+  BROTLI_ENCODER_ERROR_CONCATENATION = -5,
+  /** the encoder panicked, see ::BrotliEncoderGetLastPanicMessage */
+  BROTLI_ENCODER_ERROR_PANIC = -6,
+  /** the callback of a ::BrotliEncoderReader or ::BrotliEncoderWriter failed */
+  BROTLI_ENCODER_ERROR_CALLBACK = -7
+} BrotliEncoderErrorCode;
+
+/**
+ * Gets the error of the last call on @p state that can fail.
+ */
+BROTLI_ENC_API BrotliEncoderErrorCode BrotliEncoderGetErrorCode(
+    const BrotliEncoderState* state);
+
+/**
+ * Gets a static string naming the error of the last call on @p state.
+ */
+BROTLI_ENC_API const char* BrotliEncoderGetErrorString(
+    const BrotliEncoderState* state);
+
+/**
+ * Gets a static string naming @p c.
+ */
+BROTLI_ENC_API const char* BrotliEncoderErrorString(BrotliEncoderErrorCode c);
+
+/**
+ * Gets the error of the last encoder call on the calling thread that can fail.
+ *
+ * Without the std feature there is one for the whole program instead.
+ */
+BROTLI_ENC_API BrotliEncoderErrorCode BrotliEncoderGetLastErrorCode(void);
+
+/**
+ * Gets the message of the last panic the encoder caught on the calling thread.
+ *
+ * The string stays valid until the next panic on that thread; it is empty if
+ * there was none, and always empty without the std feature.
+ */
+BROTLI_ENC_API const char* BrotliEncoderGetLastPanicMessage(void);
+
+/**
+ * Every encoder parameter at once, see ::BrotliEncoderSetParams.
+ *
+ * @p size holds the size of the struct the caller was built with, so that
+ * fields added to the end later on leave callers built against an older header
+ * working. Set it with ::BrotliEncoderParamsInit. Flags are 0 or 1.
+ */
+typedef struct BrotliEncoderParamsC {
+  size_t size;
+  uint32_t mode;  /* a ::BrotliEncoderMode */
+  int32_t quality;
+  int32_t lgwin;
+  int32_t lgblock;
+  uint64_t size_hint;
+  int32_t disable_literal_context_modeling;
+  int32_t q9_5;
+  int32_t large_window;
+  int32_t avoid_distance_prefix_search;
+  int32_t catable;
+  int32_t use_dictionary;  /* with catable, only honoured together with catable_dictionary */
+  int32_t catable_dictionary;
+  int32_t appendable;
+  int32_t magic_number;
+  int32_t favor_cpu_efficiency;
+  /* the encoder still picks the hasher type from the quality */
+  int32_t hasher_type;
+  int32_t hasher_bucket_bits;
+  int32_t hasher_block_bits;
+  int32_t hasher_hash_len;
+  int32_t hasher_num_last_distances_to_check;
+  int32_t hasher_literal_byte_score;
+  uint8_t stride_detection_quality;
+  uint8_t high_entropy_detection_quality;
+  uint8_t cdf_adaptation_detection;
+  uint8_t prior_bitmask_detection;
+  /* (speed, max) pairs for stride_low, stride_speed, cm_low and cm_speed */
+  uint16_t literal_adaptation[8];
+} BrotliEncoderParamsC;
+
+/**
+ * The smallest @p size a ::BrotliEncoderParamsC may have: the struct up to and
+ * including @c favor_cpu_efficiency. The fields past the end of a smaller
+ * struct keep their defaults.
+ */
+#define BROTLI_ENCODER_PARAMS_C_V1_SIZE \
+    (offsetof(BrotliEncoderParamsC, favor_cpu_efficiency) + sizeof(int32_t))
+
+/**
+ * Fills @p params with the default parameters and sets its size to @p size.
+ *
+ * Only the first @p size bytes of @p params are written.
+ *
+ * @param size @c sizeof(BrotliEncoderParamsC)
+ * @returns ::BROTLI_FALSE if @p size is smaller than
+ *          ::BROTLI_ENCODER_PARAMS_C_V1_SIZE
+ * @returns ::BROTLI_TRUE otherwise
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderParamsInit(
+    BrotliEncoderParamsC* params, size_t size);
+
+/**
+ * Sets every parameter of @p state from @p params at once.
+ *
+ * Like ::BrotliEncoderSetParameter this only works before compression starts.
+ * Fields past the end of the caller's struct keep their defaults, and fields
+ * past the ones this library knows about must be zero.
+ *
+ * @returns ::BROTLI_FALSE if a value is out of range, see
+ *          ::BrotliEncoderGetErrorCode
+ * @returns ::BROTLI_TRUE otherwise
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderSetParams(
+    BrotliEncoderState* state, const BrotliEncoderParamsC* params);
+
+/**
+ * Reads every parameter of @p state into @p params, whose size must be set.
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderGetParams(
+    const BrotliEncoderState* state, BrotliEncoderParamsC* params);
+
+/**
+ * Consumes up to @p size bytes of compressed @p data.
+ *
+ * @returns how many bytes it took, or @c 0 or less on failure
+ */
+typedef ptrdiff_t (*brotli_encoder_write_func)(
+    void* opaque, const uint8_t* data, size_t size);
+
+/**
+ * Fills up to @p size bytes of @p data with input.
+ *
+ * @returns how many bytes it wrote, @c 0 at the end of the input or less than
+ *          @c 0 on failure
+ */
+typedef ptrdiff_t (*brotli_encoder_read_func)(
+    void* opaque, uint8_t* data, size_t size);
+
+/**
+ * Opaque structure that compresses everything it is given and passes the
+ * output on to a ::brotli_encoder_write_func.
+ *
+ * Allocated and initialized with ::BrotliEncoderCreateWriter.
+ * Cleaned up and deallocated with ::BrotliEncoderDestroyWriter.
+ */
+typedef struct BrotliEncoderWriterStruct BrotliEncoderWriter;
+
+/**
+ * Creates a writer that calls @p write_func with @p write_opaque whenever its
+ * buffer of @p buffer_size bytes, or 4096 if that is @c 0, fills up.
+ *
+ * @param params the parameters to compress with, or @c NULL for the defaults
+ * @returns @c 0 if the parameters are invalid or @p write_func is @c NULL, see
+ *          ::BrotliEncoderGetLastErrorCode
+ */
+BROTLI_ENC_API BrotliEncoderWriter* BrotliEncoderCreateWriter(
+    const BrotliEncoderParamsC* params, size_t buffer_size,
+    brotli_encoder_write_func write_func, void* write_opaque,
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+/**
+ * Compresses all of @p input.
+ *
+ * After a failure every further call on @p writer fails with the same error.
+ *
+ * @returns ::BROTLI_FALSE on failure, see ::BrotliEncoderWriterGetErrorCode
+ * @returns ::BROTLI_TRUE otherwise
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderWriterWrite(
+    BrotliEncoderWriter* writer, size_t input_size,
+    const uint8_t input[BROTLI_ARRAY_PARAM(input_size)]);
+
+/**
+ * Passes everything written so far to the callback as complete metablocks.
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderWriterFlush(BrotliEncoderWriter* writer);
+
+/**
+ * Ends the stream and passes its last bytes to the callback.
+ *
+ * Further writes fail with ::BROTLI_ENCODER_ERROR_INVALID_OPERATION.
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderWriterFinish(BrotliEncoderWriter* writer);
+
+BROTLI_ENC_API BrotliEncoderErrorCode BrotliEncoderWriterGetErrorCode(
+    const BrotliEncoderWriter* writer);
+
+/**
+ * Deallocates @p writer, first finishing the stream unless that was done
+ * already or @p writer failed.
+ */
+BROTLI_ENC_API void BrotliEncoderDestroyWriter(BrotliEncoderWriter* writer);
+
+/**
+ * Opaque structure that pulls input from a ::brotli_encoder_read_func and
+ * hands out the compressed stream.
+ *
+ * Allocated and initialized with ::BrotliEncoderCreateReader.
+ * Cleaned up and deallocated with ::BrotliEncoderDestroyReader.
+ */
+typedef struct BrotliEncoderReaderStruct BrotliEncoderReader;
+
+/**
+ * Creates a reader that calls @p read_func with @p read_opaque to fill its
+ * input buffer of @p buffer_size bytes, or 4096 if that is @c 0.
+ *
+ * @param params the parameters to compress with, or @c NULL for the defaults
+ * @returns @c 0 if the parameters are invalid or @p read_func is @c NULL, see
+ *          ::BrotliEncoderGetLastErrorCode
+ */
+BROTLI_ENC_API BrotliEncoderReader* BrotliEncoderCreateReader(
+    const BrotliEncoderParamsC* params, size_t buffer_size,
+    brotli_encoder_read_func read_func, void* read_opaque,
+    brotli_alloc_func alloc_func, brotli_free_func free_func, void* opaque);
+
+/**
+ * Reads the next part of the compressed stream.
+ *
+ * @param[in, out] encoded_size @b in: size of @p encoded, which must not be
+ *                 @c 0; \n
+ *                 @b out: length of compressed data written to @p encoded,
+ *                 @c 0 once the stream has ended
+ * @returns ::BROTLI_FALSE on failure, see ::BrotliEncoderReaderGetErrorCode
+ * @returns ::BROTLI_TRUE otherwise
+ */
+BROTLI_ENC_API BROTLI_BOOL BrotliEncoderReaderRead(
+    BrotliEncoderReader* reader, size_t* encoded_size,
+    uint8_t encoded[BROTLI_ARRAY_PARAM(*encoded_size)]);
+
+BROTLI_ENC_API BrotliEncoderErrorCode BrotliEncoderReaderGetErrorCode(
+    const BrotliEncoderReader* reader);
+
+BROTLI_ENC_API void BrotliEncoderDestroyReader(BrotliEncoderReader* reader);
+
 /**
  * Gets an encoder library version.
  *
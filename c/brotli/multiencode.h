@@ -65,7 +65,9 @@ BROTLI_ENC_API size_t BrotliEncoderMaxCompressedSizeMulti(size_t input_size, siz
  * @param alloc_func custom memory allocation function
  * @param free_func custom memory free function
  * @param opaque custom memory manager handle
- * @returns @c 0 if instance can not be allocated or initialized
+ * @returns @c 0 if instance can not be allocated or initialized, and always
+ *          without the std feature: ::BrotliEncoderCompressWorkPool then
+ *          compresses on the calling thread when given that @c 0
  * @returns pointer to initialized ::BrotliEncoderWorkPool otherwise
  */
 BROTLI_ENC_API BrotliEncoderWorkPool* BrotliEncoderCreateWorkPool(
@@ -126,4 +128,61 @@ BROTLI_ENC_API int32_t BrotliEncoderCompressWorkPool(
     brotli_alloc_func alloc_func, brotli_free_func free_func,
     void** alloc_opaque_per_thread);
 
+/**
+ * Opaque handle to a compression submitted to a ::BrotliEncoderWorkPool without waiting for it.
+ * Returned by ::BrotliEncoderCompressWorkPoolSubmit and freed by ::BrotliEncoderCompressWorkPoolWait.
+ */
+typedef struct BrotliEncoderWorkPoolJobStruct BrotliEncoderWorkPoolJob;
+
+/**
+ * Starts compressing @p input_buffer on the threads of @p work_pool and returns immediately.
+ *
+ * Each call gets its own queue in the pool: while several compressions share the pool, their
+ * jobs are dispatched round robin so a large input cannot starve the others.
+ *
+ * The parameters are the same as for ::BrotliEncoderCompressWorkPool, except that the output
+ * buffer is passed to ::BrotliEncoderCompressWorkPoolWait instead.
+ * @p input_buffer must stay valid and unchanged until that call returns.
+ *
+ * @returns NULL if a parameter is invalid or @p work_pool is NULL
+ * @returns a job handle to pass to ::BrotliEncoderCompressWorkPoolWait otherwise
+ */
+BROTLI_ENC_API BrotliEncoderWorkPoolJob* BrotliEncoderCompressWorkPoolSubmit(
+    BrotliEncoderWorkPool *work_pool,
+    size_t num_params,
+    const BrotliEncoderParameter* param_keys,
+    const uint32_t* param_values,
+    size_t input_size,
+    const uint8_t *input_buffer,
+    size_t desired_num_threads,
+    brotli_alloc_func alloc_func, brotli_free_func free_func,
+    void** alloc_opaque_per_thread);
+
+/**
+ * Checks, without blocking, whether a submitted compression has finished.
+ *
+ * @returns ::BROTLI_TRUE if ::BrotliEncoderCompressWorkPoolWait would return immediately
+ * @returns ::BROTLI_FALSE otherwise
+ */
+BROTLI_ENC_API int32_t BrotliEncoderWorkPoolJobIsDone(const BrotliEncoderWorkPoolJob* job);
+
+/**
+ * Waits for a submitted compression, copies its output to @p encoded and frees @p job.
+ *
+ * If @p encoded is too small, nothing is copied and @p job is @b not freed: @p encoded_size
+ * receives the size needed and the call may be repeated with a large enough buffer.
+ *
+ * @param[in, out] encoded_size @b in: size of @p encoded; \n
+ *                 @b out: length of compressed data written to @p encoded,
+ *                 or the size required if @p encoded is too small
+ * @param encoded compressed data destination buffer
+ * @returns ::BROTLI_FALSE in case of compression error
+ * @returns ::BROTLI_FALSE if output buffer is too small; @p job is still valid
+ * @returns ::BROTLI_TRUE otherwise
+ */
+BROTLI_ENC_API int32_t BrotliEncoderCompressWorkPoolWait(
+    BrotliEncoderWorkPoolJob* job,
+    size_t *encoded_size,
+    uint8_t *encoded);
+
 
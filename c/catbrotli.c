@@ -70,14 +70,22 @@ int main(int argc, char**argv) {
     unsigned char * obuffer = (unsigned char*)malloc(buffer_size);
     unsigned char* obuffer_ptr = obuffer;
     size_t avail_out = buffer_size;
-    BroccoliState state;
+    BroccoliInstance *state;
     if (has_window_size) {
-        state = BroccoliCreateInstanceWithWindowSize(window_size);
+        state = BroccoliInstanceCreateWithWindowSize(window_size, NULL, NULL, NULL);
     } else {
-        state = BroccoliCreateInstance();
+        state = BroccoliInstanceCreate(NULL, NULL, NULL);
+    }
+    if (!state) {
+        if (has_window_size) {
+            fprintf(stderr, "Could not create a concatenator with window size %d\n", window_size);
+        } else {
+            fprintf(stderr, "Could not create a concatenator\n");
+        }
+        return 1;
     }
     for (i = 1; i < argc; ++i) {
-        BroccoliNewBrotliFile(&state);
+        BroccoliInstanceNewBrotliFile(state);
         FILE * input_file = fopen(argv[i], "rb");
         if (!input_file) {
             fprintf(stderr, "Could not open %s\n", argv[i]);
@@ -92,8 +100,8 @@ int main(int argc, char**argv) {
                 break;
             }
             while(1) {
-                BroccoliResult res = BroccoliConcatStream(
-                    &state,
+                BroccoliResult res = BroccoliInstanceConcatStream(
+                    state,
                     &avail_in,
                     &ibuffer_ptr,
                     &avail_out,
@@ -115,8 +123,8 @@ int main(int argc, char**argv) {
         }
     }
     while(1) {
-        BroccoliResult res = BroccoliConcatFinish(
-            &state,
+        BroccoliResult res = BroccoliInstanceConcatFinish(
+            state,
             &avail_out,
             &obuffer_ptr);
         if (res == BroccoliNeedsMoreOutput) {
@@ -134,5 +142,6 @@ int main(int argc, char**argv) {
         }
         abort(); //failure
     }
+    BroccoliInstanceDestroy(state);
     return 0;
 }
@@ -0,0 +1,115 @@
+import threading
+import unittest
+
+import rust_brotli
+
+
+def make_test_data(size):
+    return bytes((i % 251) ^ ((i * 7) % 13) for i in range(size)) + b'the quick brown fox ' * (size // 20)
+
+
+class TestRustBrotli(unittest.TestCase):
+    def setUp(self):
+        self.test_data = make_test_data(256 * 1024)
+        self.params = rust_brotli.EncoderParams(quality=5, lgwin=22)
+
+    def test_params(self):
+        params = rust_brotli.EncoderParams(quality=3, catable=True, mode=rust_brotli.MODE_TEXT)
+        assert params.quality == 3
+        assert params.catable
+        assert not params.appendable
+        assert params.lgwin == rust_brotli.EncoderParams().lgwin
+        params.literal_adaptation = [(1, 2), (3, 4), (5, 6), (7, 8)]
+        assert params.literal_adaptation == [(1, 2), (3, 4), (5, 6), (7, 8)]
+        with self.assertRaises(AttributeError):
+            rust_brotli.EncoderParams(no_such_setting=1)
+        with self.assertRaises(ValueError):
+            rust_brotli.compress(b'x', rust_brotli.EncoderParams(mode=99))
+
+    def test_rt(self):
+        output = rust_brotli.compress(self.test_data, self.params)
+        assert len(output) < len(self.test_data)
+        assert rust_brotli.decompress(output) == self.test_data
+
+    def test_buffer_protocol(self):
+        output = rust_brotli.compress(memoryview(bytearray(self.test_data))[1000:], self.params)
+        assert rust_brotli.decompress(bytearray(output)) == self.test_data[1000:]
+        with self.assertRaises(ValueError):
+            rust_brotli.compress(memoryview(self.test_data)[::2], self.params)
+
+    def test_streaming(self):
+        compressor = rust_brotli.Compressor(self.params)
+        output = b''
+        for offset in range(0, len(self.test_data), 10000):
+            output += compressor.process(self.test_data[offset:offset + 10000])
+        output += compressor.flush()
+        output += compressor.finish()
+        with self.assertRaises(rust_brotli.EncoderError):
+            compressor.process(b'more')
+        decompressor = rust_brotli.Decompressor()
+        rt = b''
+        for offset in range(0, len(output), 777):
+            rt += decompressor.process(output[offset:offset + 777])
+        assert decompressor.is_finished
+        rt += decompressor.finish()
+        assert rt == self.test_data
+
+    def test_decoder_errors(self):
+        output = rust_brotli.compress(self.test_data, self.params)
+        with self.assertRaises(rust_brotli.TruncatedInputError) as cm:
+            rust_brotli.decompress(output[:len(output) // 2])
+        assert cm.exception.name == 'NEEDS_MORE_INPUT'
+        with self.assertRaises(rust_brotli.FormatError) as cm:
+            rust_brotli.decompress(b'\xff' * 64)
+        assert cm.exception.code < 0
+        assert isinstance(cm.exception, rust_brotli.DecoderError)
+        assert isinstance(cm.exception, rust_brotli.BrotliError)
+        decompressor = rust_brotli.Decompressor()
+        decompressor.process(output[:100])
+        with self.assertRaises(rust_brotli.TruncatedInputError):
+            decompressor.finish()
+
+    def test_concatenation(self):
+        first = rust_brotli.EncoderParams(quality=5, lgwin=22, appendable=True, magic_number=True)
+        rest = rust_brotli.EncoderParams(quality=5, lgwin=22, catable=True, magic_number=True)
+        pieces = [self.test_data[:1000], self.test_data[1000:50000], self.test_data[50000:]]
+        files = [rust_brotli.compress(pieces[0], first)] + [rust_brotli.compress(piece, rest) for piece in pieces[1:]]
+        concatenator = rust_brotli.Concatenator(merge_magic_headers=True)
+        output = b''
+        for brotli_file in files:
+            concatenator.new_brotli_file()
+            output += concatenator.process(brotli_file)
+        output += concatenator.finish()
+        header, replaced_length = concatenator.leading_header()
+        output = header + output[replaced_length:]
+        assert rust_brotli.decompress(output) == self.test_data
+        concatenator = rust_brotli.Concatenator()
+        concatenator.new_brotli_file()
+        with self.assertRaises(rust_brotli.ConcatenationError):
+            concatenator.process(rust_brotli.compress(pieces[0], self.params))
+            concatenator.new_brotli_file()
+            concatenator.process(rust_brotli.compress(pieces[1], self.params))
+            concatenator.finish()
+
+    def test_work_pool(self):
+        work_pool = rust_brotli.WorkPool(4)
+        params = rust_brotli.EncoderParams(quality=5, lgwin=22, magic_number=True)
+        results = [None] * 4
+
+        def compress(index):
+            results[index] = work_pool.compress(self.test_data, params)
+        threads = [threading.Thread(target=compress, args=(index,)) for index in range(4)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+        for output in results:
+            assert rust_brotli.decompress(output) == self.test_data
+        output = work_pool.compress(bytearray(self.test_data), params, num_threads=2)
+        assert rust_brotli.decompress(output) == self.test_data
+        with self.assertRaises(ValueError):
+            rust_brotli.WorkPool(0)
+
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,648 @@
+// Native Python bindings: the compressor, decompressor, concatenator and work pool as a PyO3
+// extension module. Input is read in place through the buffer protocol, the GIL is released
+// while brotli runs, and output comes back as bytes.
+extern crate brotli;
+#[macro_use]
+extern crate pyo3;
+
+use std::ffi::CStr;
+use std::slice;
+use brotli::{BrotliDecompressStream, BrotliResult, BrotliState, SliceWrapper};
+use brotli::concat::{BroCatli, BroCatliResult, LEADING_HEADER_BYTES};
+use brotli::enc::{interface, new_work_pool, submit_compress_worker_pool};
+use brotli::enc::{BrotliEncoderParams, BrotliEncoderThreadError, CompressionThreadInput, CompressionThreadResult,
+                  StandardAlloc, UnionHasher, WorkerPool};
+use brotli::enc::encode::{BrotliEncoderCompressStream, BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance,
+                          BrotliEncoderHasMoreOutput, BrotliEncoderIsFinished, BrotliEncoderOperation,
+                          BrotliEncoderStateStruct};
+use brotli::ffi::compressor::{apply_params_c, BrotliEncoderParamsC, BrotliEncoderParamsInit};
+use brotli::ffi::decompressor::ffi::{BrotliDecoderErrorCode, BrotliDecoderErrorString};
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+create_exception!(rust_brotli, BrotliError, PyException, "Base class of the errors raised by this module.");
+create_exception!(rust_brotli, EncoderError, BrotliError, "The encoder failed, or was used after finish().");
+create_exception!(rust_brotli, InsufficientOutputSpaceError, EncoderError,
+                  "The compressed output did not fit the space the work pool set aside for it.");
+create_exception!(rust_brotli, WorkerPanicError, EncoderError, "A compression thread of the work pool panicked.");
+create_exception!(rust_brotli, ConcatenationError, BrotliError,
+                  "Brotli files could not be joined: they were not made catable or appendable, or were cut short.");
+create_exception!(rust_brotli, DecoderError, BrotliError,
+                  "The decoder failed; code holds the BrotliDecoderErrorCode and name its name.");
+create_exception!(rust_brotli, FormatError, DecoderError, "The input is not a valid brotli stream.");
+create_exception!(rust_brotli, AllocationError, DecoderError, "The decoder could not allocate its tables or window.");
+create_exception!(rust_brotli, TruncatedInputError, DecoderError, "The input ended before the brotli stream did.");
+
+const BUFFER_SIZE: usize = 65536;
+
+// the bytes behind a buffer, read in place: the buffer protocol keeps them alive, and a
+// bytearray from being resized, until the PyBuffer is released
+fn buffer_bytes(buffer: &PyBuffer<u8>) -> PyResult<&[u8]> {
+  if !buffer.is_c_contiguous() {
+    return Err(PyValueError::new_err("brotli needs a contiguous buffer"));
+  }
+  if buffer.len_bytes() == 0 {
+    return Ok(&[]);
+  }
+  Ok(unsafe { slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes()) })
+}
+
+/// Every setting of BrotliEncoderParams; keyword arguments to the constructor set them by name,
+/// and anything not given keeps the encoder's default.
+#[pyclass(module="rust_brotli")]
+#[derive(Clone)]
+pub struct EncoderParams {
+  /// MODE_GENERIC, MODE_TEXT, MODE_FONT or one of the MODE_FORCE_*_PRIOR values
+  #[pyo3(get, set)]
+  mode: u32,
+  #[pyo3(get, set)]
+  quality: i32,
+  #[pyo3(get, set)]
+  lgwin: i32,
+  #[pyo3(get, set)]
+  lgblock: i32,
+  #[pyo3(get, set)]
+  size_hint: u64,
+  #[pyo3(get, set)]
+  disable_literal_context_modeling: bool,
+  #[pyo3(get, set)]
+  q9_5: bool,
+  #[pyo3(get, set)]
+  large_window: bool,
+  #[pyo3(get, set)]
+  avoid_distance_prefix_search: bool,
+  #[pyo3(get, set)]
+  catable: bool,
+  #[pyo3(get, set)]
+  use_dictionary: bool,
+  #[pyo3(get, set)]
+  catable_dictionary: bool,
+  #[pyo3(get, set)]
+  appendable: bool,
+  #[pyo3(get, set)]
+  magic_number: bool,
+  #[pyo3(get, set)]
+  favor_cpu_efficiency: bool,
+  #[pyo3(get, set)]
+  hasher_type: i32,
+  #[pyo3(get, set)]
+  hasher_bucket_bits: i32,
+  #[pyo3(get, set)]
+  hasher_block_bits: i32,
+  #[pyo3(get, set)]
+  hasher_hash_len: i32,
+  #[pyo3(get, set)]
+  hasher_num_last_distances_to_check: i32,
+  #[pyo3(get, set)]
+  hasher_literal_byte_score: i32,
+  #[pyo3(get, set)]
+  stride_detection_quality: u8,
+  #[pyo3(get, set)]
+  high_entropy_detection_quality: u8,
+  #[pyo3(get, set)]
+  cdf_adaptation_detection: u8,
+  #[pyo3(get, set)]
+  prior_bitmask_detection: u8,
+  /// (speed, max) pairs for stride_low, stride_speed, cm_low and cm_speed
+  #[pyo3(get, set)]
+  literal_adaptation: [(u16, u16); 4],
+}
+
+impl EncoderParams {
+  fn from_c(c: &BrotliEncoderParamsC) -> EncoderParams {
+    let mut literal_adaptation = [(0u16, 0u16); 4];
+    for (index, item) in literal_adaptation.iter_mut().enumerate() {
+      *item = (c.literal_adaptation[index * 2], c.literal_adaptation[index * 2 + 1]);
+    }
+    EncoderParams {
+      mode: c.mode,
+      quality: c.quality,
+      lgwin: c.lgwin,
+      lgblock: c.lgblock,
+      size_hint: c.size_hint,
+      disable_literal_context_modeling: c.disable_literal_context_modeling != 0,
+      q9_5: c.q9_5 != 0,
+      large_window: c.large_window != 0,
+      avoid_distance_prefix_search: c.avoid_distance_prefix_search != 0,
+      catable: c.catable != 0,
+      use_dictionary: c.use_dictionary != 0,
+      catable_dictionary: c.catable_dictionary != 0,
+      appendable: c.appendable != 0,
+      magic_number: c.magic_number != 0,
+      favor_cpu_efficiency: c.favor_cpu_efficiency != 0,
+      hasher_type: c.hasher_type,
+      hasher_bucket_bits: c.hasher_bucket_bits,
+      hasher_block_bits: c.hasher_block_bits,
+      hasher_hash_len: c.hasher_hash_len,
+      hasher_num_last_distances_to_check: c.hasher_num_last_distances_to_check,
+      hasher_literal_byte_score: c.hasher_literal_byte_score,
+      stride_detection_quality: c.stride_detection_quality,
+      high_entropy_detection_quality: c.high_entropy_detection_quality,
+      cdf_adaptation_detection: c.cdf_adaptation_detection,
+      prior_bitmask_detection: c.prior_bitmask_detection,
+      literal_adaptation: literal_adaptation,
+    }
+  }
+  fn to_c(&self) -> BrotliEncoderParamsC {
+    let mut literal_adaptation = [0u16; 8];
+    for (index, &(speed, max)) in self.literal_adaptation.iter().enumerate() {
+      literal_adaptation[index * 2] = speed;
+      literal_adaptation[index * 2 + 1] = max;
+    }
+    BrotliEncoderParamsC {
+      size: std::mem::size_of::<BrotliEncoderParamsC>(),
+      mode: self.mode,
+      quality: self.quality,
+      lgwin: self.lgwin,
+      lgblock: self.lgblock,
+      size_hint: self.size_hint,
+      disable_literal_context_modeling: self.disable_literal_context_modeling as i32,
+      q9_5: self.q9_5 as i32,
+      large_window: self.large_window as i32,
+      avoid_distance_prefix_search: self.avoid_distance_prefix_search as i32,
+      catable: self.catable as i32,
+      use_dictionary: self.use_dictionary as i32,
+      catable_dictionary: self.catable_dictionary as i32,
+      appendable: self.appendable as i32,
+      magic_number: self.magic_number as i32,
+      favor_cpu_efficiency: self.favor_cpu_efficiency as i32,
+      hasher_type: self.hasher_type,
+      hasher_bucket_bits: self.hasher_bucket_bits,
+      hasher_block_bits: self.hasher_block_bits,
+      hasher_hash_len: self.hasher_hash_len,
+      hasher_num_last_distances_to_check: self.hasher_num_last_distances_to_check,
+      hasher_literal_byte_score: self.hasher_literal_byte_score,
+      stride_detection_quality: self.stride_detection_quality,
+      high_entropy_detection_quality: self.high_entropy_detection_quality,
+      cdf_adaptation_detection: self.cdf_adaptation_detection,
+      prior_bitmask_detection: self.prior_bitmask_detection,
+      literal_adaptation: literal_adaptation,
+    }
+  }
+  fn params(&self) -> PyResult<BrotliEncoderParams> {
+    let mut params = BrotliEncoderParams::default();
+    if !apply_params_c(&self.to_c(), &mut params) {
+      return Err(PyValueError::new_err("invalid encoder mode"));
+    }
+    Ok(params)
+  }
+}
+
+#[pymethods]
+impl EncoderParams {
+  #[new]
+  #[pyo3(signature = (**settings))]
+  fn new(py: Python, settings: Option<&Bound<PyDict>>) -> PyResult<Py<EncoderParams>> {
+    let mut c: BrotliEncoderParamsC = unsafe { std::mem::zeroed() };
+    unsafe { BrotliEncoderParamsInit(&mut c, std::mem::size_of::<BrotliEncoderParamsC>()) };
+    let ret = Bound::new(py, EncoderParams::from_c(&c))?;
+    if let Some(settings) = settings {
+      for (name, value) in settings.iter() {
+        ret.setattr(name.downcast::<pyo3::types::PyString>()?, value)?;
+      }
+    }
+    Ok(ret.unbind())
+  }
+  fn __repr__(&self) -> String {
+    format!("EncoderParams(mode={}, quality={}, lgwin={}, lgblock={}, catable={}, appendable={}, magic_number={})",
+            self.mode, self.quality, self.lgwin, self.lgblock,
+            py_bool(self.catable), py_bool(self.appendable), py_bool(self.magic_number))
+  }
+}
+
+fn py_bool(value: bool) -> &'static str {
+  if value { "True" } else { "False" }
+}
+
+fn encoder_params(params: Option<&EncoderParams>) -> PyResult<BrotliEncoderParams> {
+  match params {
+    Some(params) => params.params(),
+    None => Ok(BrotliEncoderParams::default()),
+  }
+}
+
+// runs op over all of input and until the encoder has nothing more to say for it
+fn encode(state: &mut BrotliEncoderStateStruct<StandardAlloc>,
+          op: BrotliEncoderOperation,
+          input: &[u8]) -> Result<Vec<u8>, &'static str> {
+  let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+                          _cmds: &mut [interface::StaticCommand],
+                          _mb: interface::InputPair, _m: &mut StandardAlloc| ();
+  let mut output = Vec::new();
+  let mut buffer = vec![0u8; BUFFER_SIZE];
+  let mut available_in = input.len();
+  let mut input_offset = 0usize;
+  loop {
+    let mut available_out = buffer.len();
+    let mut output_offset = 0usize;
+    let ret = BrotliEncoderCompressStream(state, op, &mut available_in, input, &mut input_offset,
+                                          &mut available_out, &mut buffer[..], &mut output_offset,
+                                          &mut None, &mut nop_callback);
+    output.extend_from_slice(&buffer[..output_offset]);
+    if ret <= 0 {
+      return Err("the encoder failed");
+    }
+    let done = match op {
+      BrotliEncoderOperation::BROTLI_OPERATION_FINISH => BrotliEncoderIsFinished(state) != 0,
+      _ => available_in == 0 && BrotliEncoderHasMoreOutput(state) == 0,
+    };
+    if done {
+      return Ok(output);
+    }
+  }
+}
+
+/// A streaming compressor: process() and flush() return the output so far and finish() the
+/// rest, after which the compressor can only be dropped.
+#[pyclass(module="rust_brotli")]
+pub struct Compressor {
+  state: BrotliEncoderStateStruct<StandardAlloc>,
+  finished: bool,
+}
+
+impl Compressor {
+  fn run(&mut self, py: Python, op: BrotliEncoderOperation, input: &[u8]) -> PyResult<Py<PyBytes>> {
+    if self.finished {
+      return Err(EncoderError::new_err("compressor already finished"));
+    }
+    if let BrotliEncoderOperation::BROTLI_OPERATION_FINISH = op {
+      self.finished = true;
+    }
+    let state = &mut self.state;
+    match py.allow_threads(|| encode(state, op, input)) {
+      Ok(output) => Ok(PyBytes::new(py, &output[..]).unbind()),
+      Err(message) => {
+        self.finished = true;
+        Err(EncoderError::new_err(message))
+      },
+    }
+  }
+}
+
+#[pymethods]
+impl Compressor {
+  #[new]
+  #[pyo3(signature = (params=None))]
+  fn new(params: Option<PyRef<EncoderParams>>) -> PyResult<Compressor> {
+    let mut state = BrotliEncoderCreateInstance(StandardAlloc::default());
+    state.params = encoder_params(params.as_deref())?;
+    Ok(Compressor {
+      state: state,
+      finished: false,
+    })
+  }
+  fn process(&mut self, py: Python, data: PyBuffer<u8>) -> PyResult<Py<PyBytes>> {
+    let input = buffer_bytes(&data)?;
+    self.run(py, BrotliEncoderOperation::BROTLI_OPERATION_PROCESS, input)
+  }
+  fn flush(&mut self, py: Python) -> PyResult<Py<PyBytes>> {
+    self.run(py, BrotliEncoderOperation::BROTLI_OPERATION_FLUSH, &[])
+  }
+  fn finish(&mut self, py: Python) -> PyResult<Py<PyBytes>> {
+    self.run(py, BrotliEncoderOperation::BROTLI_OPERATION_FINISH, &[])
+  }
+}
+
+impl Drop for Compressor {
+  fn drop(&mut self) {
+    BrotliEncoderDestroyInstance(&mut self.state);
+  }
+}
+
+fn decoder_error(code: BrotliDecoderErrorCode) -> PyErr {
+  let name = unsafe { CStr::from_ptr(BrotliDecoderErrorString(code) as *const std::os::raw::c_char) }
+    .to_string_lossy().into_owned();
+  let code = code as i32;
+  let message = format!("brotli decoder error {} ({})", code, name);
+  let err = match code {
+    -16..=-1 => FormatError::new_err(message),
+    -30..=-21 => AllocationError::new_err(message),
+    _ => DecoderError::new_err(message),
+  };
+  Python::with_gil(|py| {
+    let value = err.value(py);
+    let _ = value.setattr("code", code);
+    let _ = value.setattr("name", name);
+  });
+  err
+}
+
+fn truncated_input() -> PyErr {
+  let err = TruncatedInputError::new_err("brotli stream is incomplete");
+  Python::with_gil(|py| {
+    let value = err.value(py);
+    let _ = value.setattr("code", BrotliDecoderErrorCode::BROTLI_DECODER_NEEDS_MORE_INPUT as i32);
+    let _ = value.setattr("name", "NEEDS_MORE_INPUT");
+  });
+  err
+}
+
+type DecoderState = BrotliState<StandardAlloc, StandardAlloc, StandardAlloc>;
+
+enum Decoded {
+  NeedsMoreInput(Vec<u8>),
+  Finished(Vec<u8>),
+  TrailingData,
+  Failed(BrotliDecoderErrorCode),
+}
+
+fn decode(state: &mut DecoderState, input: &[u8]) -> Decoded {
+  let mut output = Vec::new();
+  let mut buffer = vec![0u8; BUFFER_SIZE];
+  let mut available_in = input.len();
+  let mut input_offset = 0usize;
+  let mut total_out = 0usize;
+  loop {
+    let mut available_out = buffer.len();
+    let mut output_offset = 0usize;
+    let result = BrotliDecompressStream(&mut available_in, &mut input_offset, input,
+                                        &mut available_out, &mut output_offset, &mut buffer[..],
+                                        &mut total_out, state);
+    output.extend_from_slice(&buffer[..output_offset]);
+    match result {
+      BrotliResult::NeedsMoreOutput => {},
+      BrotliResult::NeedsMoreInput => return Decoded::NeedsMoreInput(output),
+      BrotliResult::ResultSuccess => {
+        if available_in != 0 {
+          return Decoded::TrailingData;
+        }
+        return Decoded::Finished(output);
+      },
+      BrotliResult::ResultFailure => return Decoded::Failed(state.error_code),
+    }
+  }
+}
+
+fn new_decoder() -> DecoderState {
+  BrotliState::new(StandardAlloc::default(), StandardAlloc::default(), StandardAlloc::default())
+}
+
+/// A streaming decompressor: process() returns the output so far, and finish() raises
+/// TruncatedInputError unless the stream has ended.
+#[pyclass(module="rust_brotli")]
+pub struct Decompressor {
+  state: DecoderState,
+  finished: bool,
+}
+
+#[pymethods]
+impl Decompressor {
+  #[new]
+  fn new() -> Decompressor {
+    Decompressor {
+      state: new_decoder(),
+      finished: false,
+    }
+  }
+  fn process(&mut self, py: Python, data: PyBuffer<u8>) -> PyResult<Py<PyBytes>> {
+    let input = buffer_bytes(&data)?;
+    if self.finished {
+      if input.is_empty() {
+        return Ok(PyBytes::new(py, &[]).unbind());
+      }
+      return Err(DecoderError::new_err("data after the end of the brotli stream"));
+    }
+    let state = &mut self.state;
+    match py.allow_threads(|| decode(state, input)) {
+      Decoded::NeedsMoreInput(output) => Ok(PyBytes::new(py, &output[..]).unbind()),
+      Decoded::Finished(output) => {
+        self.finished = true;
+        Ok(PyBytes::new(py, &output[..]).unbind())
+      },
+      Decoded::TrailingData => Err(DecoderError::new_err("data after the end of the brotli stream")),
+      Decoded::Failed(code) => Err(decoder_error(code)),
+    }
+  }
+  fn finish(&mut self, py: Python) -> PyResult<Py<PyBytes>> {
+    if !self.finished {
+      return Err(truncated_input());
+    }
+    Ok(PyBytes::new(py, &[]).unbind())
+  }
+  /// whether the whole brotli stream has been decoded
+  #[getter]
+  fn is_finished(&self) -> bool {
+    self.finished
+  }
+}
+
+/// Compresses data in one call.
+#[pyfunction]
+#[pyo3(signature = (data, params=None))]
+fn compress(py: Python, data: PyBuffer<u8>, params: Option<PyRef<EncoderParams>>) -> PyResult<Py<PyBytes>> {
+  let mut compressor = Compressor::new(params)?;
+  let input = buffer_bytes(&data)?;
+  let state = &mut compressor.state;
+  let output = py.allow_threads(|| {
+    let mut output = match encode(state, BrotliEncoderOperation::BROTLI_OPERATION_PROCESS, input) {
+      Ok(output) => output,
+      Err(message) => return Err(message),
+    };
+    match encode(state, BrotliEncoderOperation::BROTLI_OPERATION_FINISH, &[]) {
+      Ok(tail) => output.extend_from_slice(&tail[..]),
+      Err(message) => return Err(message),
+    }
+    Ok(output)
+  });
+  match output {
+    Ok(output) => Ok(PyBytes::new(py, &output[..]).unbind()),
+    Err(message) => Err(EncoderError::new_err(message)),
+  }
+}
+
+/// Decompresses a whole brotli stream in one call.
+#[pyfunction]
+fn decompress(py: Python, data: PyBuffer<u8>) -> PyResult<Py<PyBytes>> {
+  let input = buffer_bytes(&data)?;
+  let mut state = new_decoder();
+  match py.allow_threads(|| decode(&mut state, input)) {
+    Decoded::Finished(output) => Ok(PyBytes::new(py, &output[..]).unbind()),
+    Decoded::NeedsMoreInput(_) => Err(truncated_input()),
+    Decoded::TrailingData => Err(DecoderError::new_err("data after the end of the brotli stream")),
+    Decoded::Failed(code) => Err(decoder_error(code)),
+  }
+}
+
+fn concatenation_error(result: BroCatliResult) -> PyErr {
+  let err = ConcatenationError::new_err(result.to_string());
+  Python::with_gil(|py| {
+    let _ = err.value(py).setattr("code", result as i32);
+  });
+  err
+}
+
+/// Joins brotli files into one: the first must be appendable and the rest catable. Call
+/// new_brotli_file() before the bytes of each file.
+#[pyclass(module="rust_brotli")]
+pub struct Concatenator {
+  state: BroCatli,
+}
+
+#[pymethods]
+impl Concatenator {
+  #[new]
+  #[pyo3(signature = (window_size=None, window_growth=false, merge_magic_headers=false))]
+  fn new(window_size: Option<u8>, window_growth: bool, merge_magic_headers: bool) -> Concatenator {
+    let mut state = if window_growth {
+      BroCatli::new_with_window_growth()
+    } else {
+      match window_size {
+        Some(window_size) => BroCatli::new_with_window_size(window_size),
+        None => BroCatli::new(),
+      }
+    };
+    if merge_magic_headers {
+      state.merge_magic_headers();
+    }
+    Concatenator { state: state }
+  }
+  fn new_brotli_file(&mut self) {
+    self.state.new_brotli_file();
+  }
+  fn process(&mut self, py: Python, data: PyBuffer<u8>) -> PyResult<Py<PyBytes>> {
+    let input = buffer_bytes(&data)?;
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let mut input_offset = 0usize;
+    loop {
+      let mut output_offset = 0usize;
+      let result = self.state.stream(input, &mut input_offset, &mut buffer[..], &mut output_offset);
+      output.extend_from_slice(&buffer[..output_offset]);
+      match result {
+        BroCatliResult::NeedsMoreOutput => {},
+        BroCatliResult::NeedsMoreInput => return Ok(PyBytes::new(py, &output[..]).unbind()),
+        BroCatliResult::Success => return Err(ConcatenationError::new_err("brotli stream finished before the last file")),
+        failure => return Err(concatenation_error(failure)),
+      }
+    }
+  }
+  fn finish(&mut self, py: Python) -> PyResult<Py<PyBytes>> {
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+      let mut output_offset = 0usize;
+      let result = self.state.finish(&mut buffer[..], &mut output_offset);
+      output.extend_from_slice(&buffer[..output_offset]);
+      match result {
+        BroCatliResult::NeedsMoreOutput => {},
+        BroCatliResult::Success => return Ok(PyBytes::new(py, &output[..]).unbind()),
+        BroCatliResult::NeedsMoreInput => return Err(ConcatenationError::new_err("last file ended unexpectedly")),
+        failure => return Err(concatenation_error(failure)),
+      }
+    }
+  }
+  /// Once finished, (header, replaced_length): the first replaced_length bytes of the whole
+  /// output have to be replaced by header for the window to have grown or the headers to merge.
+  fn leading_header(&self, py: Python) -> PyResult<(Py<PyBytes>, usize)> {
+    let mut header = [0u8; LEADING_HEADER_BYTES];
+    match self.state.leading_header(&mut header[..]) {
+      Ok((replaced, written)) => Ok((PyBytes::new(py, &header[..written]).unbind(), replaced)),
+      Err(failure) => Err(concatenation_error(failure)),
+    }
+  }
+}
+
+// an input held by the buffer protocol for as long as the work pool needs it
+struct HeldBuffer(PyBuffer<u8>);
+
+impl SliceWrapper<u8> for HeldBuffer {
+  fn slice(&self) -> &[u8] {
+    match buffer_bytes(&self.0) {
+      Ok(bytes) => bytes,
+      Err(_) => &[],
+    }
+  }
+}
+
+fn thread_error(err: BrotliEncoderThreadError) -> PyErr {
+  match err {
+    BrotliEncoderThreadError::InsufficientOutputSpace =>
+      InsufficientOutputSpaceError::new_err("compressed output did not fit"),
+    BrotliEncoderThreadError::ConcatenationDidNotProcessFullFile =>
+      ConcatenationError::new_err("joining the compressed chunks did not process all of them"),
+    BrotliEncoderThreadError::ConcatenationError(result) |
+    BrotliEncoderThreadError::ConcatenationFinalizationError(result) => concatenation_error(result),
+    BrotliEncoderThreadError::OtherThreadPanic | BrotliEncoderThreadError::ThreadExecError(_) =>
+      WorkerPanicError::new_err("a compression thread panicked"),
+    BrotliEncoderThreadError::MetablockLogError(index) =>
+      WorkerPanicError::new_err(format!("the metablock log of work item {} could not be read back", index)),
+    BrotliEncoderThreadError::WorkItemPanic(index, range, _) =>
+      WorkerPanicError::new_err(format!("work item {} panicked compressing input from {} to {}",
+                                        index, range.start, range.end)),
+  }
+}
+
+/// A pool of compression threads shared by every compress() call made on it.
+#[pyclass(module="rust_brotli")]
+pub struct WorkPool {
+  work_pool: WorkerPool<CompressionThreadResult<StandardAlloc>,
+                        UnionHasher<StandardAlloc>,
+                        StandardAlloc,
+                        CompressionThreadInput<HeldBuffer>>,
+  num_threads: usize,
+}
+
+#[pymethods]
+impl WorkPool {
+  #[new]
+  fn new(num_threads: usize) -> PyResult<WorkPool> {
+    if num_threads == 0 {
+      return Err(PyValueError::new_err("a work pool needs at least one thread"));
+    }
+    Ok(WorkPool {
+      work_pool: new_work_pool(num_threads),
+      num_threads: num_threads,
+    })
+  }
+  /// Compresses data across num_threads threads of the pool (by default all of them); the
+  /// result decompresses like any brotli stream.
+  #[pyo3(signature = (data, params=None, num_threads=None))]
+  fn compress(&self,
+              py: Python,
+              data: PyBuffer<u8>,
+              params: Option<PyRef<EncoderParams>>,
+              num_threads: Option<usize>) -> PyResult<Py<PyBytes>> {
+    let params = encoder_params(params.as_deref())?;
+    buffer_bytes(&data)?;
+    let num_threads = std::cmp::max(num_threads.unwrap_or(self.num_threads), 1);
+    let alloc_per_thread = (0..num_threads).map(|_| StandardAlloc::default()).collect();
+    let pending = submit_compress_worker_pool(&params, HeldBuffer(data), alloc_per_thread,
+                                              self.work_pool.new_tenant(1, 0));
+    let finished = py.allow_threads(|| pending.wait());
+    match finished.compressed {
+      Ok(output) => Ok(PyBytes::new(py, &output[..]).unbind()),
+      Err(err) => Err(thread_error(err)),
+    }
+  }
+}
+
+#[pymodule]
+fn rust_brotli(m: &Bound<PyModule>) -> PyResult<()> {
+  let py = m.py();
+  m.add_function(wrap_pyfunction!(compress, m)?)?;
+  m.add_function(wrap_pyfunction!(decompress, m)?)?;
+  m.add_class::<EncoderParams>()?;
+  m.add_class::<Compressor>()?;
+  m.add_class::<Decompressor>()?;
+  m.add_class::<Concatenator>()?;
+  m.add_class::<WorkPool>()?;
+  m.add("MODE_GENERIC", 0)?;
+  m.add("MODE_TEXT", 1)?;
+  m.add("MODE_FONT", 2)?;
+  m.add("MODE_FORCE_LSB_PRIOR", 3)?;
+  m.add("MODE_FORCE_MSB_PRIOR", 4)?;
+  m.add("MODE_FORCE_UTF8_PRIOR", 5)?;
+  m.add("MODE_FORCE_SIGNED_PRIOR", 6)?;
+  m.add("BrotliError", py.get_type::<BrotliError>())?;
+  m.add("EncoderError", py.get_type::<EncoderError>())?;
+  m.add("InsufficientOutputSpaceError", py.get_type::<InsufficientOutputSpaceError>())?;
+  m.add("WorkerPanicError", py.get_type::<WorkerPanicError>())?;
+  m.add("ConcatenationError", py.get_type::<ConcatenationError>())?;
+  m.add("DecoderError", py.get_type::<DecoderError>())?;
+  m.add("FormatError", py.get_type::<FormatError>())?;
+  m.add("AllocationError", py.get_type::<AllocationError>())?;
+  m.add("TruncatedInputError", py.get_type::<TruncatedInputError>())?;
+  Ok(())
+}
@@ -1,5 +1,4 @@
 #![no_std]
-#![cfg_attr(not(feature="std"), feature(lang_items))]
 #[cfg(feature="std")]
 extern crate std;
 
@@ -7,7 +6,9 @@ pub extern crate brotli;
 pub use brotli::*;
 pub use brotli::ffi::compressor::*;
 pub use brotli::ffi::multicompress::*;
+pub use brotli::ffi::custom_io::*;
 pub use brotli::ffi::decompressor::*;
+pub use brotli::ffi::broccoli::*;
 use core::ptr::null_mut;
 #[cfg(feature="std")]
 unsafe fn std_only_functions() {
@@ -21,6 +22,9 @@ unsafe fn std_only_functions() {
 pub unsafe extern fn instantiate_functions(must_be_null: *const u8) {
     if !must_be_null.is_null() {
         let _ = brotli::ffi::compressor::BrotliEncoderVersion();
+        #[allow(deprecated)]
+        let _ = BroccoliCreateInstance();
+        let _ = BroccoliInstanceCreate(None, None, null_mut());
         let _ = brotli::ffi::decompressor::CBrotliDecoderCreateInstance(None, None, null_mut());
         let _ = brotli::ffi::decompressor::CBrotliDecoderSetParameter(null_mut(), brotli::ffi::decompressor::ffi::interface::BrotliDecoderParameter::BROTLI_DECODER_PARAM_DISABLE_RING_BUFFER_REALLOCATION, 0);
         let _ = brotli::ffi::decompressor::CBrotliDecoderDecompressStream(null_mut(), null_mut(), null_mut(), null_mut(), null_mut(), null_mut());
@@ -40,11 +44,30 @@ pub unsafe extern fn instantiate_functions(must_be_null: *const u8) {
             brotli::ffi::decompressor::ffi::BrotliDecoderErrorCode::BROTLI_DECODER_ERROR_UNREACHABLE);
         let _ = BrotliEncoderCreateInstance(None, None, null_mut());
         let _ = BrotliEncoderSetParameter(null_mut(), brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_MODE, 0);
+        let _ = BrotliEncoderSetMetablockCallback(null_mut(), None, null_mut());
         let _ = BrotliEncoderDestroyInstance(null_mut());
         let _ = BrotliEncoderIsFinished(null_mut());
         let _ = BrotliEncoderHasMoreOutput(null_mut());
         let _ = BrotliEncoderTakeOutput(null_mut(), null_mut());
         let _ = BrotliEncoderMaxCompressedSize(0);
+        let _ = BrotliEncoderGetErrorCode(null_mut());
+        let _ = BrotliEncoderGetErrorString(null_mut());
+        let _ = BrotliEncoderErrorString(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+        let _ = BrotliEncoderGetLastErrorCode();
+        let _ = BrotliEncoderGetLastPanicMessage();
+        let _ = BrotliEncoderParamsInit(null_mut(), 0);
+        let _ = BrotliEncoderSetParams(null_mut(), null_mut());
+        let _ = BrotliEncoderGetParams(null_mut(), null_mut());
+        let _ = BrotliEncoderCreateWriter(null_mut(), 0, None, null_mut(), None, None, null_mut());
+        let _ = BrotliEncoderWriterWrite(null_mut(), 0, null_mut());
+        let _ = BrotliEncoderWriterFlush(null_mut());
+        let _ = BrotliEncoderWriterFinish(null_mut());
+        let _ = BrotliEncoderWriterGetErrorCode(null_mut());
+        let _ = BrotliEncoderDestroyWriter(null_mut());
+        let _ = BrotliEncoderCreateReader(null_mut(), 0, None, null_mut(), None, None, null_mut());
+        let _ = BrotliEncoderReaderRead(null_mut(), null_mut(), null_mut());
+        let _ = BrotliEncoderReaderGetErrorCode(null_mut());
+        let _ = BrotliEncoderDestroyReader(null_mut());
         let _ = BrotliEncoderSetCustomDictionary(null_mut(), 0, null_mut());
         let _ = BrotliEncoderCompress(0,0,BrotliEncoderMode::BROTLI_MODE_GENERIC, 0, null_mut(), null_mut(), null_mut());
         let _ = BrotliEncoderCompressStream(null_mut(), BrotliEncoderOperation::BROTLI_OPERATION_FINISH, null_mut(), null_mut(), null_mut(), null_mut(), null_mut());
@@ -57,17 +80,46 @@ pub unsafe extern fn instantiate_functions(must_be_null: *const u8) {
         let _ = BrotliEncoderCreateWorkPool(0, None, None, null_mut());
         let _ = BrotliEncoderDestroyWorkPool(null_mut());
         let _ = BrotliEncoderCompressWorkPool(null_mut(), 0, null_mut(), null_mut(), 0, null_mut(), null_mut(), null_mut(), 0 ,None, None, null_mut());
+        let _ = CBrotliEncoderCreateInstance(None, None, null_mut());
+        let _ = CBrotliEncoderSetParameter(null_mut(), brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_MODE, 0);
+        let _ = CBrotliEncoderSetMetablockCallback(null_mut(), None, null_mut());
+        let _ = CBrotliEncoderDestroyInstance(null_mut());
+        let _ = CBrotliEncoderIsFinished(null_mut());
+        let _ = CBrotliEncoderHasMoreOutput(null_mut());
+        let _ = CBrotliEncoderTakeOutput(null_mut(), null_mut());
+        let _ = CBrotliEncoderVersion();
+        let _ = CBrotliEncoderGetErrorCode(null_mut());
+        let _ = CBrotliEncoderGetErrorString(null_mut());
+        let _ = CBrotliEncoderErrorString(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+        let _ = CBrotliEncoderGetLastErrorCode();
+        let _ = CBrotliEncoderGetLastPanicMessage();
+        let _ = CBrotliEncoderParamsInit(null_mut(), 0);
+        let _ = CBrotliEncoderSetParams(null_mut(), null_mut());
+        let _ = CBrotliEncoderGetParams(null_mut(), null_mut());
+        let _ = CBrotliEncoderCreateWriter(null_mut(), 0, None, null_mut(), None, None, null_mut());
+        let _ = CBrotliEncoderWriterWrite(null_mut(), 0, null_mut());
+        let _ = CBrotliEncoderWriterFlush(null_mut());
+        let _ = CBrotliEncoderWriterFinish(null_mut());
+        let _ = CBrotliEncoderWriterGetErrorCode(null_mut());
+        let _ = CBrotliEncoderDestroyWriter(null_mut());
+        let _ = CBrotliEncoderCreateReader(null_mut(), 0, None, null_mut(), None, None, null_mut());
+        let _ = CBrotliEncoderReaderRead(null_mut(), null_mut(), null_mut());
+        let _ = CBrotliEncoderReaderGetErrorCode(null_mut());
+        let _ = CBrotliEncoderDestroyReader(null_mut());
+        let _ = CBrotliEncoderMaxCompressedSize(0);
+        let _ = CBrotliEncoderSetCustomDictionary(null_mut(), 0, null_mut());
+        let _ = CBrotliEncoderCompress(0,0,BrotliEncoderMode::BROTLI_MODE_GENERIC, 0, null_mut(), null_mut(), null_mut());
+        let _ = CBrotliEncoderCompressStream(null_mut(), BrotliEncoderOperation::BROTLI_OPERATION_FINISH, null_mut(), null_mut(), null_mut(), null_mut(), null_mut());
+        let _ = CBrotliEncoderCompressStreaming(null_mut(), BrotliEncoderOperation::BROTLI_OPERATION_FINISH, null_mut(), null_mut(), null_mut(), null_mut());
+        let _ = CBrotliEncoderMallocU8(null_mut(), 0);
+        let _ = CBrotliEncoderFreeU8(null_mut(), null_mut(), 0);
+        let _ = CBrotliEncoderMallocUsize(null_mut(), 0);
+        let _ = CBrotliEncoderFreeUsize(null_mut(), null_mut(), 0);
+        let _ = CBrotliEncoderMaxCompressedSizeMulti(0,0);
+        let _ = CBrotliEncoderCompressMulti(0,null_mut(), null_mut(), 0, null_mut(), null_mut(), null_mut(), 0, None, None, null_mut());
+        let _ = CBrotliEncoderCreateWorkPool(0, None, None, null_mut());
+        let _ = CBrotliEncoderDestroyWorkPool(null_mut());
+        let _ = CBrotliEncoderCompressWorkPool(null_mut(), 0, null_mut(), null_mut(), 0, null_mut(), null_mut(), null_mut(), 0 ,None, None, null_mut());
     }
 }
 
-#[cfg(not(feature="std"))]
-#[panic_handler]
-extern fn panic_impl(_: &::core::panic::PanicInfo) -> ! {
-    loop {}
-}
-
-#[cfg(not(feature="std"))]
-#[lang = "eh_personality"]
-extern "C" fn eh_personality() {
-}
-
@@ -0,0 +1,219 @@
+// JavaScript glue over the exports of brotli_wasm.wasm: call init() once, then use the
+// functions and classes below. Every call may grow the WebAssembly memory, which detaches
+// views of the old memory.buffer, so views are made afresh for each copy in or out.
+
+let wasm = null;
+
+export class BrotliError extends Error {}
+
+// source: the .wasm bytes, a WebAssembly.Module, or a Response (or promise of one) from fetch
+export async function init(source) {
+  source = await source;
+  let instance;
+  if (source instanceof WebAssembly.Module) {
+    instance = await WebAssembly.instantiate(source, {});
+  } else if (typeof Response !== 'undefined' && source instanceof Response) {
+    instance = (await WebAssembly.instantiate(await source.arrayBuffer(), {})).instance;
+  } else {
+    instance = (await WebAssembly.instantiate(source, {})).instance;
+  }
+  wasm = instance.exports;
+}
+
+function exports() {
+  if (wasm === null) {
+    throw new BrotliError('brotli.js: init() has not finished');
+  }
+  return wasm;
+}
+
+function memory() {
+  return new Uint8Array(wasm.memory.buffer);
+}
+
+// copies out and destroys a BrotliWasmBuffer
+function takeBuffer(buffer) {
+  const data = wasm.BrotliWasmBufferData(buffer) >>> 0;
+  const len = wasm.BrotliWasmBufferLen(buffer) >>> 0;
+  const ret = memory().slice(data, data + len);
+  wasm.BrotliWasmBufferDestroy(buffer);
+  return ret;
+}
+
+function lastError() {
+  return new BrotliError(new TextDecoder().decode(takeBuffer(wasm.BrotliWasmLastError())));
+}
+
+function checked(ret) {
+  if (ret === 0) {
+    throw lastError();
+  }
+  return ret;
+}
+
+// runs op(pointer, length) on a copy of bytes in linear memory
+function withInput(bytes, op) {
+  const wasm = exports();
+  const buffer = wasm.BrotliWasmBufferCreate(bytes.length);
+  try {
+    memory().set(bytes, wasm.BrotliWasmBufferData(buffer) >>> 0);
+    return op(wasm.BrotliWasmBufferData(buffer), bytes.length);
+  } finally {
+    wasm.BrotliWasmBufferDestroy(buffer);
+  }
+}
+
+// the numbers of BrotliEncoderParameter
+const PARAMETERS = {
+  mode: 0,
+  quality: 1,
+  lgwin: 2,
+  lgblock: 3,
+  disableLiteralContextModeling: 4,
+  sizeHint: 5,
+  largeWindow: 6,
+  q9_5: 150,
+  catable: 167,
+  appendable: 168,
+  magicNumber: 169,
+  noDictionary: 170,
+  favorEfficiency: 171,
+  catableDictionary: 172,
+};
+
+// encoder parameters, set from an object such as {quality: 5, lgwin: 22, catable: true};
+// call free() when done with them
+export class EncoderParams {
+  constructor(options) {
+    this.ptr = exports().BrotliWasmParamsCreate();
+    for (const name of Object.keys(options || {})) {
+      this.set(name, options[name]);
+    }
+  }
+  set(name, value) {
+    if (!(name in PARAMETERS)) {
+      throw new BrotliError('unknown encoder parameter ' + name);
+    }
+    checked(wasm.BrotliWasmParamsSet(this.ptr, PARAMETERS[name], Number(value)));
+    return this;
+  }
+  free() {
+    wasm.BrotliWasmParamsDestroy(this.ptr);
+    this.ptr = 0;
+  }
+}
+
+// params: an EncoderParams, an options object for one, or nothing for the defaults
+function withParams(params, op) {
+  if (params instanceof EncoderParams) {
+    return op(params.ptr);
+  }
+  if (params === undefined || params === null) {
+    return op(0);
+  }
+  const owned = new EncoderParams(params);
+  try {
+    return op(owned.ptr);
+  } finally {
+    owned.free();
+  }
+}
+
+export function compress(bytes, params) {
+  return withParams(params, (paramsPtr) =>
+    takeBuffer(checked(withInput(bytes, (ptr, len) => wasm.BrotliWasmCompress(paramsPtr, ptr, len)))));
+}
+
+export function decompress(bytes) {
+  return takeBuffer(checked(withInput(bytes, (ptr, len) => wasm.BrotliWasmDecompress(ptr, len))));
+}
+
+// write() and flush() return the output so far, finish() the rest; call free() when done
+export class Compressor {
+  constructor(params, bufferSize) {
+    this.ptr = withParams(params, (paramsPtr) => exports().BrotliWasmCompressorCreate(paramsPtr, bufferSize || 0));
+  }
+  write(bytes) {
+    withInput(bytes, (ptr, len) => checked(wasm.BrotliWasmCompressorWrite(this.ptr, ptr, len)));
+    return takeBuffer(wasm.BrotliWasmCompressorTakeOutput(this.ptr));
+  }
+  flush() {
+    checked(wasm.BrotliWasmCompressorFlush(this.ptr));
+    return takeBuffer(wasm.BrotliWasmCompressorTakeOutput(this.ptr));
+  }
+  finish() {
+    checked(wasm.BrotliWasmCompressorFinish(this.ptr));
+    return takeBuffer(wasm.BrotliWasmCompressorTakeOutput(this.ptr));
+  }
+  free() {
+    wasm.BrotliWasmCompressorDestroy(this.ptr);
+    this.ptr = 0;
+  }
+}
+
+// write() returns the output so far, finish() the rest and throws unless the stream was whole
+export class Decompressor {
+  constructor(bufferSize) {
+    this.ptr = exports().BrotliWasmDecompressorCreate(bufferSize || 0);
+  }
+  write(bytes) {
+    withInput(bytes, (ptr, len) => checked(wasm.BrotliWasmDecompressorWrite(this.ptr, ptr, len)));
+    return takeBuffer(wasm.BrotliWasmDecompressorTakeOutput(this.ptr));
+  }
+  finish() {
+    checked(wasm.BrotliWasmDecompressorFinish(this.ptr));
+    return takeBuffer(wasm.BrotliWasmDecompressorTakeOutput(this.ptr));
+  }
+  free() {
+    wasm.BrotliWasmDecompressorDestroy(this.ptr);
+    this.ptr = 0;
+  }
+}
+
+// joins brotli files into one: the first must be appendable and the rest catable.
+// options: {windowSize: n} or {windowGrowth: true}, and {mergeMagicHeaders: true}
+export class Concatenator {
+  constructor(options) {
+    options = options || {};
+    const wasm = exports();
+    if (options.windowGrowth) {
+      this.ptr = wasm.BrotliWasmConcatenatorCreateWithWindowGrowth();
+    } else if (options.windowSize !== undefined) {
+      this.ptr = wasm.BrotliWasmConcatenatorCreateWithWindowSize(options.windowSize);
+    } else {
+      this.ptr = wasm.BrotliWasmConcatenatorCreate();
+    }
+    if (options.mergeMagicHeaders) {
+      wasm.BrotliWasmConcatenatorMergeMagicHeaders(this.ptr);
+    }
+  }
+  newBrotliFile() {
+    wasm.BrotliWasmConcatenatorNewBrotliFile(this.ptr);
+  }
+  write(bytes) {
+    withInput(bytes, (ptr, len) => checked(wasm.BrotliWasmConcatenatorWrite(this.ptr, ptr, len)));
+    return takeBuffer(wasm.BrotliWasmConcatenatorTakeOutput(this.ptr));
+  }
+  finish() {
+    checked(wasm.BrotliWasmConcatenatorFinish(this.ptr));
+    return takeBuffer(wasm.BrotliWasmConcatenatorTakeOutput(this.ptr));
+  }
+  // once finished: {header, replacedLength}, where the first replacedLength bytes of the whole
+  // output have to be replaced by header for the window to have grown or the headers to be merged
+  leadingHeader() {
+    const replacedLen = wasm.BrotliWasmBufferCreate(4);
+    try {
+      const header = takeBuffer(checked(
+        wasm.BrotliWasmConcatenatorLeadingHeader(this.ptr, wasm.BrotliWasmBufferData(replacedLen))));
+      const data = wasm.BrotliWasmBufferData(replacedLen) >>> 0;
+      const replacedLength = new DataView(memory().slice(data, data + 4).buffer).getUint32(0, true);
+      return {header: header, replacedLength: replacedLength};
+    } finally {
+      wasm.BrotliWasmBufferDestroy(replacedLen);
+    }
+  }
+  free() {
+    wasm.BrotliWasmConcatenatorDestroy(this.ptr);
+    this.ptr = 0;
+  }
+}
@@ -0,0 +1,497 @@
+// WebAssembly bindings: plain exports over linear memory, wrapped for JavaScript by brotli.js.
+// Handles are pointers into linear memory; functions that can fail return 0 or NULL and leave
+// the reason in BrotliWasmLastError.
+extern crate brotli;
+
+use std::cell::RefCell;
+use std::ptr;
+use std::slice;
+use brotli::{CustomWrite, DecompressorWriterCustomIo};
+use brotli::concat::{BroCatli, BroCatliResult, LEADING_HEADER_BYTES};
+use brotli::enc::{Allocator, BrotliEncoderParams, StandardAlloc};
+use brotli::enc::encode::{BrotliEncoderParameter, set_parameter};
+use brotli::enc::writer::CompressorWriterCustomIo;
+
+type AllocatedBytes = <StandardAlloc as Allocator<u8>>::AllocatedMemory;
+
+thread_local!(static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) });
+
+fn set_last_error(message: String) {
+  LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+fn fail<T>(message: &str, ret: T) -> T {
+  set_last_error(message.to_string());
+  ret
+}
+
+// the staging buffer of a compressor or decompressor, from the same allocator as its state
+fn staging_buffer(alloc: &mut StandardAlloc, buffer_size: usize) -> AllocatedBytes {
+  <StandardAlloc as Allocator<u8>>::alloc_cell(alloc, if buffer_size == 0 { 4096 } else { buffer_size })
+}
+
+unsafe fn input_slice<'a>(data: *const u8, len: usize) -> &'a [u8] {
+  if len == 0 {
+    &[]
+  } else {
+    slice::from_raw_parts(data, len)
+  }
+}
+
+/// Bytes moving across the boundary: JavaScript fills one for input, or copies one out and
+/// destroys it. Linear memory grows under any call, so the data pointer must be looked up
+/// again, and views of memory.buffer recreated, after every call.
+pub struct BrotliWasmBuffer {
+  data: Vec<u8>,
+}
+
+fn buffer_from_vec(data: Vec<u8>) -> *mut BrotliWasmBuffer {
+  Box::into_raw(Box::new(BrotliWasmBuffer { data: data }))
+}
+
+#[no_mangle]
+pub extern fn BrotliWasmBufferCreate(len: usize) -> *mut BrotliWasmBuffer {
+  buffer_from_vec(vec![0u8; len])
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmBufferData(buffer: *mut BrotliWasmBuffer) -> *mut u8 {
+  (*buffer).data.as_mut_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmBufferLen(buffer: *const BrotliWasmBuffer) -> usize {
+  (*buffer).data.len()
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmBufferDestroy(buffer: *mut BrotliWasmBuffer) {
+  if !buffer.is_null() {
+    let _buffer = Box::from_raw(buffer);
+  }
+}
+
+/// Why the last call that returned 0 or NULL failed, as UTF-8 in a new buffer.
+#[no_mangle]
+pub extern fn BrotliWasmLastError() -> *mut BrotliWasmBuffer {
+  LAST_ERROR.with(|last_error| buffer_from_vec(last_error.borrow().as_bytes().to_vec()))
+}
+
+#[no_mangle]
+pub extern fn BrotliWasmParamsCreate() -> *mut BrotliEncoderParams {
+  Box::into_raw(Box::new(BrotliEncoderParams::default()))
+}
+
+// takes the same parameters and values as BrotliEncoderSetParameter
+#[no_mangle]
+pub unsafe extern fn BrotliWasmParamsSet(params: *mut BrotliEncoderParams,
+                                         param: BrotliEncoderParameter,
+                                         value: u32) -> i32 {
+  if set_parameter(&mut *params, param, value) == 0 {
+    return fail("unsupported encoder parameter", 0);
+  }
+  1
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmParamsDestroy(params: *mut BrotliEncoderParams) {
+  if !params.is_null() {
+    let _params = Box::from_raw(params);
+  }
+}
+
+unsafe fn params_or_default(params: *const BrotliEncoderParams) -> BrotliEncoderParams {
+  if params.is_null() {
+    BrotliEncoderParams::default()
+  } else {
+    (*params).clone()
+  }
+}
+
+/// Compresses input_len bytes at input in one go; params may be NULL for the defaults.
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompress(params: *const BrotliEncoderParams,
+                                        input: *const u8,
+                                        input_len: usize) -> *mut BrotliWasmBuffer {
+  let mut output = Vec::new();
+  match brotli::BrotliCompress(&mut input_slice(input, input_len), &mut output, &params_or_default(params)) {
+    Ok(_) => buffer_from_vec(output),
+    Err(e) => fail(&e.to_string(), ptr::null_mut()),
+  }
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmDecompress(input: *const u8, input_len: usize) -> *mut BrotliWasmBuffer {
+  let mut output = Vec::new();
+  match brotli::BrotliDecompress(&mut input_slice(input, input_len), &mut output) {
+    Ok(_) => buffer_from_vec(output),
+    Err(e) => fail(&e.to_string(), ptr::null_mut()),
+  }
+}
+
+/// Collects whatever a compressor or decompressor writes until JavaScript takes it.
+#[derive(Default)]
+pub struct VecSink(Vec<u8>);
+
+impl CustomWrite<&'static str> for VecSink {
+  fn write(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+    self.0.extend_from_slice(data);
+    Ok(data.len())
+  }
+  fn flush(&mut self) -> Result<(), &'static str> {
+    Ok(())
+  }
+}
+
+// hands over what the sink has collected so far, leaving it empty
+fn take_sink(sink: &mut VecSink) -> *mut BrotliWasmBuffer {
+  buffer_from_vec(std::mem::replace(&mut sink.0, Vec::new()))
+}
+
+// writer errors are not repeatable, so the first one is kept and returned from then on
+fn sticky_result(failure: &mut Option<&'static str>, result: Result<(), &'static str>) -> i32 {
+  if let Err(message) = result {
+    *failure = Some(message);
+  }
+  match *failure {
+    Some(message) => fail(message, 0),
+    None => 1,
+  }
+}
+
+pub struct BrotliWasmCompressor {
+  writer: CompressorWriterCustomIo<&'static str, VecSink, AllocatedBytes, StandardAlloc>,
+  failure: Option<&'static str>,
+}
+
+/// params may be NULL for the defaults; buffer_size of 0 picks 4096 bytes.
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompressorCreate(params: *const BrotliEncoderParams,
+                                                buffer_size: usize) -> *mut BrotliWasmCompressor {
+  let mut alloc = StandardAlloc::default();
+  let buffer = staging_buffer(&mut alloc, buffer_size);
+  let writer = CompressorWriterCustomIo::with_params(VecSink::default(), buffer, alloc,
+                                                     "compression failed", &params_or_default(params));
+  Box::into_raw(Box::new(BrotliWasmCompressor {
+    writer: writer,
+    failure: None,
+  }))
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompressorWrite(compressor: *mut BrotliWasmCompressor,
+                                               input: *const u8,
+                                               input_len: usize) -> i32 {
+  let compressor = &mut *compressor;
+  if let Some(message) = compressor.failure {
+    return fail(message, 0);
+  }
+  let result = brotli::enc::writer::write_all(&mut compressor.writer, input_slice(input, input_len));
+  sticky_result(&mut compressor.failure, result)
+}
+
+// compresses everything written so far into output that can be decompressed up to that point
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompressorFlush(compressor: *mut BrotliWasmCompressor) -> i32 {
+  let compressor = &mut *compressor;
+  if let Some(message) = compressor.failure {
+    return fail(message, 0);
+  }
+  let result = compressor.writer.flush();
+  sticky_result(&mut compressor.failure, result)
+}
+
+// writes the end of the stream; the compressor takes no more input afterwards
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompressorFinish(compressor: *mut BrotliWasmCompressor) -> i32 {
+  let compressor = &mut *compressor;
+  if let Some(message) = compressor.failure {
+    return fail(message, 0);
+  }
+  let result = compressor.writer.finish();
+  let ret = sticky_result(&mut compressor.failure, result);
+  if ret != 0 {
+    compressor.failure = Some("compressor already finished");
+  }
+  ret
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompressorTakeOutput(compressor: *mut BrotliWasmCompressor) -> *mut BrotliWasmBuffer {
+  take_sink((*compressor).writer.get_mut())
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmCompressorDestroy(compressor: *mut BrotliWasmCompressor) {
+  if !compressor.is_null() {
+    let _compressor = Box::from_raw(compressor);
+  }
+}
+
+pub struct BrotliWasmDecompressor {
+  writer: DecompressorWriterCustomIo<&'static str, VecSink, AllocatedBytes,
+                                     StandardAlloc, StandardAlloc, StandardAlloc>,
+  failure: Option<&'static str>,
+}
+
+/// buffer_size of 0 picks 4096 bytes.
+#[no_mangle]
+pub extern fn BrotliWasmDecompressorCreate(buffer_size: usize) -> *mut BrotliWasmDecompressor {
+  let mut alloc = StandardAlloc::default();
+  let buffer = staging_buffer(&mut alloc, buffer_size);
+  let writer = DecompressorWriterCustomIo::new(VecSink::default(), buffer,
+                                               alloc, StandardAlloc::default(), StandardAlloc::default(),
+                                               "invalid brotli data");
+  Box::into_raw(Box::new(BrotliWasmDecompressor {
+    writer: writer,
+    failure: None,
+  }))
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmDecompressorWrite(decompressor: *mut BrotliWasmDecompressor,
+                                                 input: *const u8,
+                                                 input_len: usize) -> i32 {
+  let decompressor = &mut *decompressor;
+  if let Some(message) = decompressor.failure {
+    return fail(message, 0);
+  }
+  let result = brotli::enc::writer::write_all(&mut decompressor.writer, input_slice(input, input_len));
+  sticky_result(&mut decompressor.failure, result)
+}
+
+// fails unless the input written made up a whole brotli stream
+#[no_mangle]
+pub unsafe extern fn BrotliWasmDecompressorFinish(decompressor: *mut BrotliWasmDecompressor) -> i32 {
+  let decompressor = &mut *decompressor;
+  if let Some(message) = decompressor.failure {
+    return fail(message, 0);
+  }
+  let result = decompressor.writer.close();
+  let ret = sticky_result(&mut decompressor.failure, result);
+  if ret != 0 {
+    decompressor.failure = Some("decompressor already finished");
+  }
+  ret
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmDecompressorTakeOutput(decompressor: *mut BrotliWasmDecompressor) -> *mut BrotliWasmBuffer {
+  take_sink((*decompressor).writer.get_mut())
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmDecompressorDestroy(decompressor: *mut BrotliWasmDecompressor) {
+  if !decompressor.is_null() {
+    let _decompressor = Box::from_raw(decompressor);
+  }
+}
+
+pub struct BrotliWasmConcatenator {
+  state: BroCatli,
+  output: Vec<u8>,
+}
+
+fn concatenator(state: BroCatli) -> *mut BrotliWasmConcatenator {
+  Box::into_raw(Box::new(BrotliWasmConcatenator {
+    state: state,
+    output: Vec::new(),
+  }))
+}
+
+#[no_mangle]
+pub extern fn BrotliWasmConcatenatorCreate() -> *mut BrotliWasmConcatenator {
+  concatenator(BroCatli::new())
+}
+
+#[no_mangle]
+pub extern fn BrotliWasmConcatenatorCreateWithWindowSize(window_size: u8) -> *mut BrotliWasmConcatenator {
+  concatenator(BroCatli::new_with_window_size(window_size))
+}
+
+// later files may have larger windows than the first, which must be catable: see
+// BrotliWasmConcatenatorLeadingHeader
+#[no_mangle]
+pub extern fn BrotliWasmConcatenatorCreateWithWindowGrowth() -> *mut BrotliWasmConcatenator {
+  concatenator(BroCatli::new_with_window_growth())
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorMergeMagicHeaders(concatenator: *mut BrotliWasmConcatenator) {
+  (*concatenator).state.merge_magic_headers();
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorNewBrotliFile(concatenator: *mut BrotliWasmConcatenator) {
+  (*concatenator).state.new_brotli_file();
+}
+
+fn concat_result(result: BroCatliResult) -> i32 {
+  match result {
+    BroCatliResult::Success | BroCatliResult::NeedsMoreInput => 1,
+    failure => {
+      set_last_error(failure.to_string());
+      0
+    },
+  }
+}
+
+// passes on the part of the current file given, growing the output as needed
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorWrite(concatenator: *mut BrotliWasmConcatenator,
+                                                 input: *const u8,
+                                                 input_len: usize) -> i32 {
+  let concatenator = &mut *concatenator;
+  let input = input_slice(input, input_len);
+  let mut input_offset = 0usize;
+  let mut buffer = [0u8; 4096];
+  loop {
+    let mut output_offset = 0usize;
+    let result = concatenator.state.stream(input, &mut input_offset, &mut buffer[..], &mut output_offset);
+    concatenator.output.extend_from_slice(&buffer[..output_offset]);
+    match result {
+      BroCatliResult::NeedsMoreOutput => {},
+      BroCatliResult::Success => return fail("brotli stream finished before the last file", 0),
+      other => return concat_result(other),
+    }
+  }
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorFinish(concatenator: *mut BrotliWasmConcatenator) -> i32 {
+  let concatenator = &mut *concatenator;
+  let mut buffer = [0u8; 4096];
+  loop {
+    let mut output_offset = 0usize;
+    let result = concatenator.state.finish(&mut buffer[..], &mut output_offset);
+    concatenator.output.extend_from_slice(&buffer[..output_offset]);
+    match result {
+      BroCatliResult::NeedsMoreOutput => {},
+      BroCatliResult::NeedsMoreInput => return fail("last file ended unexpectedly", 0),
+      other => return concat_result(other),
+    }
+  }
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorTakeOutput(concatenator: *mut BrotliWasmConcatenator) -> *mut BrotliWasmBuffer {
+  buffer_from_vec(std::mem::replace(&mut (*concatenator).output, Vec::new()))
+}
+
+// once finished, the first *replaced_len bytes of the whole output have to be replaced by the
+// returned header for the window to have grown or the magic number headers to be merged
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorLeadingHeader(concatenator: *const BrotliWasmConcatenator,
+                                                         replaced_len: *mut usize) -> *mut BrotliWasmBuffer {
+  let mut header = vec![0u8; LEADING_HEADER_BYTES];
+  match (*concatenator).state.leading_header(&mut header[..]) {
+    Ok((replaced, written)) => {
+      *replaced_len = replaced;
+      header.truncate(written);
+      buffer_from_vec(header)
+    },
+    Err(failure) => fail(&failure.to_string(), ptr::null_mut()),
+  }
+}
+
+#[no_mangle]
+pub unsafe extern fn BrotliWasmConcatenatorDestroy(concatenator: *mut BrotliWasmConcatenator) {
+  if !concatenator.is_null() {
+    let _concatenator = Box::from_raw(concatenator);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn test_input() -> Vec<u8> {
+    let mut input = Vec::new();
+    for index in 0..20000u32 {
+      input.extend_from_slice(&[(index % 251) as u8, (index * 7 % 13) as u8, b'w']);
+    }
+    input
+  }
+
+  unsafe fn take(buffer: *mut BrotliWasmBuffer) -> Vec<u8> {
+    assert!(!buffer.is_null());
+    let ret = (*buffer).data.clone();
+    BrotliWasmBufferDestroy(buffer);
+    ret
+  }
+
+  unsafe fn test_params(catable: bool) -> *mut BrotliEncoderParams {
+    let params = BrotliWasmParamsCreate();
+    assert_eq!(BrotliWasmParamsSet(params, BrotliEncoderParameter::BROTLI_PARAM_QUALITY, 5), 1);
+    assert_eq!(BrotliWasmParamsSet(params, BrotliEncoderParameter::BROTLI_PARAM_CATABLE, catable as u32), 1);
+    assert_eq!(BrotliWasmParamsSet(params, BrotliEncoderParameter::BROTLI_PARAM_APPENDABLE, 1), 1);
+    params
+  }
+
+  #[test]
+  fn test_streaming_round_trip() {
+    let input = test_input();
+    unsafe {
+      let params = test_params(false);
+      let compressor = BrotliWasmCompressorCreate(params, 0);
+      BrotliWasmParamsDestroy(params);
+      let mut compressed = Vec::new();
+      for chunk in input.chunks(5000) {
+        assert_eq!(BrotliWasmCompressorWrite(compressor, chunk.as_ptr(), chunk.len()), 1);
+        compressed.extend(take(BrotliWasmCompressorTakeOutput(compressor)));
+      }
+      assert_eq!(BrotliWasmCompressorFinish(compressor), 1);
+      compressed.extend(take(BrotliWasmCompressorTakeOutput(compressor)));
+      assert_eq!(BrotliWasmCompressorWrite(compressor, input.as_ptr(), 1), 0);
+      assert_eq!(take(BrotliWasmLastError()), b"compressor already finished".to_vec());
+      BrotliWasmCompressorDestroy(compressor);
+
+      let decompressor = BrotliWasmDecompressorCreate(0);
+      let mut decompressed = Vec::new();
+      for chunk in compressed.chunks(333) {
+        assert_eq!(BrotliWasmDecompressorWrite(decompressor, chunk.as_ptr(), chunk.len()), 1);
+        decompressed.extend(take(BrotliWasmDecompressorTakeOutput(decompressor)));
+      }
+      assert_eq!(BrotliWasmDecompressorFinish(decompressor), 1);
+      decompressed.extend(take(BrotliWasmDecompressorTakeOutput(decompressor)));
+      BrotliWasmDecompressorDestroy(decompressor);
+      assert_eq!(decompressed, input);
+    }
+  }
+
+  #[test]
+  fn test_decompress_reports_invalid_data() {
+    let input = test_input();
+    unsafe {
+      let decompressor = BrotliWasmDecompressorCreate(0);
+      assert_eq!(BrotliWasmDecompressorWrite(decompressor, input.as_ptr(), input.len()), 0);
+      assert_eq!(take(BrotliWasmLastError()), b"invalid brotli data".to_vec());
+      assert_eq!(BrotliWasmDecompressorFinish(decompressor), 0);
+      BrotliWasmDecompressorDestroy(decompressor);
+      assert!(BrotliWasmDecompress(input.as_ptr(), input.len()).is_null());
+    }
+  }
+
+  #[test]
+  fn test_concatenates_one_shot_outputs() {
+    let input = test_input();
+    let (first, second) = input.split_at(25000);
+    unsafe {
+      let first_params = test_params(false);
+      let second_params = test_params(true);
+      let first_brotli = take(BrotliWasmCompress(first_params, first.as_ptr(), first.len()));
+      let second_brotli = take(BrotliWasmCompress(second_params, second.as_ptr(), second.len()));
+      BrotliWasmParamsDestroy(first_params);
+      BrotliWasmParamsDestroy(second_params);
+      let concatenator = BrotliWasmConcatenatorCreate();
+      for brotli_file in [&first_brotli, &second_brotli].iter() {
+        BrotliWasmConcatenatorNewBrotliFile(concatenator);
+        assert_eq!(BrotliWasmConcatenatorWrite(concatenator, brotli_file.as_ptr(), brotli_file.len()), 1);
+      }
+      assert_eq!(BrotliWasmConcatenatorFinish(concatenator), 1);
+      let output = take(BrotliWasmConcatenatorTakeOutput(concatenator));
+      BrotliWasmConcatenatorDestroy(concatenator);
+      assert_eq!(take(BrotliWasmDecompress(output.as_ptr(), output.len())), input);
+    }
+  }
+}
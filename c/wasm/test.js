@@ -0,0 +1,105 @@
+// Runs the bindings headless under node: cargo build --release --target wasm32-unknown-unknown
+// first, or pass the path of another build of brotli_wasm.wasm.
+import assert from 'assert';
+import {readFileSync} from 'fs';
+import * as brotli from './brotli.js';
+
+const wasmPath = process.argv[2] ||
+  new URL('target/wasm32-unknown-unknown/release/brotli_wasm.wasm', import.meta.url);
+await brotli.init(readFileSync(wasmPath));
+
+function testInput(length) {
+  const ret = new Uint8Array(length);
+  for (let index = 0; index < length; ++index) {
+    ret[index] = index % 3 == 2 ? 0x77 : (index % 251) ^ (index * 7 % 13);
+  }
+  return ret;
+}
+
+function concat(chunks) {
+  const ret = new Uint8Array(chunks.reduce((total, chunk) => total + chunk.length, 0));
+  let offset = 0;
+  for (const chunk of chunks) {
+    ret.set(chunk, offset);
+    offset += chunk.length;
+  }
+  return ret;
+}
+
+const tests = {
+  oneShotRoundTrip() {
+    const input = testInput(100000);
+    const compressed = brotli.compress(input, {quality: 5});
+    assert(compressed.length < input.length);
+    assert.deepStrictEqual(brotli.decompress(compressed), input);
+  },
+
+  streamingRoundTrip() {
+    const input = testInput(300000);
+    const params = new brotli.EncoderParams({quality: 4, lgwin: 20});
+    const compressor = new brotli.Compressor(params, 1024);
+    params.free();
+    const compressed = [];
+    for (let offset = 0; offset < input.length; offset += 7000) {
+      compressed.push(compressor.write(input.subarray(offset, offset + 7000)));
+    }
+    compressed.push(compressor.flush());
+    compressed.push(compressor.finish());
+    assert.throws(() => compressor.write(input), /compressor already finished/);
+    compressor.free();
+
+    const decompressor = new brotli.Decompressor();
+    const decompressed = [];
+    const whole = concat(compressed);
+    for (let offset = 0; offset < whole.length; offset += 999) {
+      decompressed.push(decompressor.write(whole.subarray(offset, offset + 999)));
+    }
+    decompressed.push(decompressor.finish());
+    decompressor.free();
+    assert.deepStrictEqual(concat(decompressed), input);
+  },
+
+  errorsAreThrown() {
+    assert.throws(() => brotli.decompress(testInput(1000)), brotli.BrotliError);
+    assert.throws(() => new brotli.EncoderParams({windowBits: 22}), /unknown encoder parameter/);
+    const decompressor = new brotli.Decompressor();
+    decompressor.write(brotli.compress(testInput(1000), {quality: 5}).subarray(0, 10));
+    assert.throws(() => decompressor.finish(), /invalid brotli data/);
+    decompressor.free();
+  },
+
+  concatenation() {
+    const first = testInput(50000);
+    const second = testInput(70000).reverse();
+    const concatenator = new brotli.Concatenator();
+    const output = [];
+    for (const brotliFile of [brotli.compress(first, {quality: 5, appendable: true}),
+                              brotli.compress(second, {quality: 5, catable: true})]) {
+      concatenator.newBrotliFile();
+      output.push(concatenator.write(brotliFile));
+    }
+    output.push(concatenator.finish());
+    const leading = concatenator.leadingHeader();
+    assert.strictEqual(leading.replacedLength, 0);
+    concatenator.free();
+    assert.deepStrictEqual(brotli.decompress(concat(output)), concat([first, second]));
+  },
+
+  memoryGrowth() {
+    // large enough to grow linear memory during the call, detaching earlier views
+    const input = testInput(8 << 20);
+    assert.deepStrictEqual(brotli.decompress(brotli.compress(input, {quality: 1})), input);
+  },
+};
+
+let failures = 0;
+for (const name of Object.keys(tests)) {
+  try {
+    tests[name]();
+    console.log('ok ' + name);
+  } catch (e) {
+    ++failures;
+    console.log('FAILED ' + name + ': ' + (e.stack || e));
+  }
+}
+process.exit(failures == 0 ? 0 : 1);
@@ -0,0 +1,359 @@
+use std;
+#[cfg(feature="bench-memory")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::io::{self, Read, Write};
+#[cfg(feature="bench-memory")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use brotli::enc::BrotliEncoderParams;
+use super::{compress, compress_multi, decompress, new_work_pool, Rebox};
+use super::cli;
+
+// brotli --bench file...: compresses and decompresses every file at every combination of the
+// --bench-quality, --bench-window and --bench-threads lists, reporting the fastest of --bench-repeat runs.
+
+/// the system allocator, keeping count of the bytes in use and their high water mark. It is only
+/// installed with the bench-memory feature, since the counting slows down every other use of the binary
+#[cfg(feature="bench-memory")]
+pub struct PeakAlloc;
+
+#[cfg(feature="bench-memory")]
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature="bench-memory")]
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature="bench-memory")]
+unsafe impl GlobalAlloc for PeakAlloc {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let ret = System.alloc(layout);
+    if !ret.is_null() {
+      let in_use = IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+      PEAK.fetch_max(in_use, Ordering::Relaxed);
+    }
+    ret
+  }
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout);
+    IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+  }
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let ret = System.realloc(ptr, layout, new_size);
+    if !ret.is_null() {
+      if new_size > layout.size() {
+        let in_use = IN_USE.fetch_add(new_size - layout.size(), Ordering::Relaxed) + new_size - layout.size();
+        PEAK.fetch_max(in_use, Ordering::Relaxed);
+      } else {
+        IN_USE.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+      }
+    }
+    ret
+  }
+}
+
+// the heap in use now, after forgetting any earlier peak
+#[cfg(feature="bench-memory")]
+fn reset_peak() -> usize {
+  let in_use = IN_USE.load(Ordering::Relaxed);
+  PEAK.store(in_use, Ordering::Relaxed);
+  in_use
+}
+
+#[cfg(feature="bench-memory")]
+fn peak_since(baseline: usize) -> Option<usize> {
+  Some(PEAK.load(Ordering::Relaxed).saturating_sub(baseline))
+}
+
+#[cfg(not(feature="bench-memory"))]
+fn reset_peak() -> usize {
+  0
+}
+
+// nothing is counted without the bench-memory allocator
+#[cfg(not(feature="bench-memory"))]
+fn peak_since(_baseline: usize) -> Option<usize> {
+  None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quality {
+  pub quality: i32,
+  pub q9_5: bool,
+}
+
+impl Quality {
+  /// the quality names -q accepts, without the -q: 0 to 11, 9.5, 9.5x or 9.5y
+  pub fn parse(name: &str) -> Option<Quality> {
+    match name {
+      "9.5" => Some(Quality{quality: 10, q9_5: true}),
+      "9.5x" => Some(Quality{quality: 11, q9_5: true}),
+      "9.5y" => Some(Quality{quality: 12, q9_5: true}),
+      _ => match name.parse::<i32>() {
+        Ok(quality) if quality >= 0 && quality <= 11 => Some(Quality{quality: quality, q9_5: false}),
+        _ => None,
+      },
+    }
+  }
+  pub fn name(&self) -> String {
+    match (self.q9_5, self.quality) {
+      (true, 10) => "9.5".to_string(),
+      (true, 11) => "9.5x".to_string(),
+      (true, _) => "9.5y".to_string(),
+      (false, quality) => quality.to_string(),
+    }
+  }
+}
+
+pub struct Options {
+  pub qualities: Vec<Quality>,
+  pub windows: Vec<i32>,
+  pub threads: Vec<usize>,
+  pub repeat: usize,
+  pub csv: bool,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      qualities: ["1", "5", "9", "9.5"].iter().map(|name| Quality::parse(name).unwrap()).collect(),
+      windows: vec![22],
+      threads: vec![1],
+      repeat: 3,
+      csv: false,
+    }
+  }
+}
+
+fn parse_list<T, F: Fn(&str) -> Option<T>>(list: &str, parse: F) -> Option<Vec<T>> {
+  let mut ret = Vec::new();
+  for item in list.split(',') {
+    match parse(item.trim()) {
+      Some(value) => ret.push(value),
+      None => return None,
+    }
+  }
+  Some(ret)
+}
+
+/// applies argument to options if it is one of the --bench matrix flags; returns false otherwise,
+/// or if its value does not parse
+pub fn parse_flag(options: &mut Options, argument: &str) -> bool {
+  if argument.starts_with("--bench-quality=") {
+    match parse_list(&argument["--bench-quality=".len()..], Quality::parse) {
+      Some(qualities) => options.qualities = qualities,
+      None => return false,
+    }
+  } else if argument.starts_with("--bench-window=") {
+    match parse_list(&argument["--bench-window=".len()..], |item| item.parse::<i32>().ok().and_then(
+      |lgwin| if lgwin >= 10 && lgwin <= 24 { Some(lgwin) } else { None })) {
+      Some(windows) => options.windows = windows,
+      None => return false,
+    }
+  } else if argument.starts_with("--bench-threads=") {
+    match parse_list(&argument["--bench-threads=".len()..], |item| item.parse::<usize>().ok().and_then(
+      |threads| if threads > 0 { Some(threads) } else { None })) {
+      Some(threads) => options.threads = threads,
+      None => return false,
+    }
+  } else if argument.starts_with("--bench-repeat=") {
+    match argument["--bench-repeat=".len()..].parse::<usize>() {
+      Ok(repeat) if repeat > 0 => options.repeat = repeat,
+      _ => return false,
+    }
+  } else if argument == "--bench-format=csv" {
+    options.csv = true;
+  } else if argument == "--bench-format=table" {
+    options.csv = false;
+  } else {
+    return false;
+  }
+  true
+}
+
+pub struct Measurement {
+  pub file: String,
+  pub quality: Quality,
+  pub lgwin: i32,
+  pub threads: usize,
+  pub original_size: usize,
+  pub compressed_size: usize,
+  pub compress_time: Duration, // the fastest run
+  pub decompress_time: Duration,
+  pub compress_peak: Option<usize>, // the most heap any run had in use above what was in use before it, if counted
+  pub decompress_peak: Option<usize>,
+}
+
+impl Measurement {
+  pub fn ratio(&self) -> f64 {
+    if self.compressed_size == 0 { 0.0 } else { self.original_size as f64 / self.compressed_size as f64 }
+  }
+  fn megabytes_per_second(&self, time: Duration) -> f64 {
+    let seconds = time.as_secs() as f64 + time.subsec_nanos() as f64 * 1e-9;
+    if seconds == 0.0 { 0.0 } else { self.original_size as f64 / seconds / 1e6 }
+  }
+  pub fn compress_speed(&self) -> f64 {
+    self.megabytes_per_second(self.compress_time)
+  }
+  pub fn decompress_speed(&self) -> f64 {
+    self.megabytes_per_second(self.decompress_time)
+  }
+}
+
+/// times one cell of the matrix, checking that every run decompresses back to input
+pub fn measure(file: &str,
+               input: &[u8],
+               base_params: &BrotliEncoderParams,
+               quality: Quality,
+               lgwin: i32,
+               threads: usize,
+               repeat: usize,
+               buffer_size: usize) -> io::Result<Measurement> {
+  let mut params = base_params.clone();
+  params.quality = quality.quality;
+  params.q9_5 = quality.q9_5;
+  params.lgwin = lgwin;
+  params.size_hint = input.len();
+  let mut work_pool = if threads > 1 { Some(new_work_pool(threads - 1)) } else { None };
+  let mut ret = Measurement {
+    file: file.to_string(),
+    quality: quality,
+    lgwin: lgwin,
+    threads: threads,
+    original_size: input.len(),
+    compressed_size: 0,
+    compress_time: Duration::new(u64::max_value(), 0),
+    decompress_time: Duration::new(u64::max_value(), 0),
+    compress_peak: None,
+    decompress_peak: None,
+  };
+  for _ in 0..repeat {
+    let mut compressed = Vec::new();
+    let baseline = reset_peak();
+    let start = Instant::now();
+    if threads > 1 {
+      compress_multi(&mut &input[..], &mut compressed, &params, threads, &[], work_pool.as_mut())?;
+    } else {
+      compress(&mut &input[..], &mut compressed, buffer_size, &params, &[], 1)?;
+    }
+    let elapsed = start.elapsed();
+    ret.compress_peak = std::cmp::max(ret.compress_peak, peak_since(baseline)); // None sorts first
+    ret.compress_time = std::cmp::min(ret.compress_time, elapsed);
+    ret.compressed_size = compressed.len();
+
+    let mut round_tripped = Vec::with_capacity(input.len());
+    let baseline = reset_peak();
+    let start = Instant::now();
+    decompress(&mut &compressed[..], &mut round_tripped, buffer_size, Rebox::default())?;
+    let elapsed = start.elapsed();
+    // the output buffer was allocated up front, so it does not count towards the decompressor's memory
+    ret.decompress_peak = std::cmp::max(ret.decompress_peak, peak_since(baseline));
+    ret.decompress_time = std::cmp::min(ret.decompress_time, elapsed);
+    if &round_tripped[..] != input {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed data differs from the input"));
+    }
+  }
+  Ok(ret)
+}
+
+static COLUMNS: &'static [&'static str] = &[
+  "file", "quality", "lgwin", "threads", "original", "compressed", "ratio",
+  "compress_MB/s", "decompress_MB/s", "compress_peak_KiB", "decompress_peak_KiB",
+];
+
+fn kibibytes(bytes: Option<usize>) -> String {
+  match bytes {
+    Some(bytes) => ((bytes + 1023) / 1024).to_string(),
+    None => "-".to_string(),
+  }
+}
+
+fn row(measurement: &Measurement) -> Vec<String> {
+  vec![
+    measurement.file.clone(),
+    measurement.quality.name(),
+    measurement.lgwin.to_string(),
+    measurement.threads.to_string(),
+    measurement.original_size.to_string(),
+    measurement.compressed_size.to_string(),
+    format!("{:.3}", measurement.ratio()),
+    format!("{:.2}", measurement.compress_speed()),
+    format!("{:.2}", measurement.decompress_speed()),
+    kibibytes(measurement.compress_peak),
+    kibibytes(measurement.decompress_peak),
+  ]
+}
+
+fn csv_field(field: &str) -> String {
+  if field.contains(',') || field.contains('"') || field.contains('\n') {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+pub fn write_csv<W: Write>(w: &mut W, measurements: &[Measurement]) -> io::Result<()> {
+  writeln!(w, "{}", COLUMNS.join(","))?;
+  for measurement in measurements.iter() {
+    let fields: Vec<String> = row(measurement).iter().map(|field| csv_field(field)).collect();
+    writeln!(w, "{}", fields.join(","))?;
+  }
+  Ok(())
+}
+
+pub fn write_table<W: Write>(w: &mut W, measurements: &[Measurement]) -> io::Result<()> {
+  let rows: Vec<Vec<String>> = measurements.iter().map(row).collect();
+  let widths: Vec<usize> = COLUMNS.iter().enumerate().map(
+    |(index, name)| rows.iter().map(|row| row[index].len()).fold(name.len(), std::cmp::max)).collect();
+  let header: Vec<String> = COLUMNS.iter().zip(widths.iter()).map(|(name, width)| format!("{:>1$}", name, width)).collect();
+  writeln!(w, "{}", header.join("  "))?;
+  for row in rows.iter() {
+    let cells: Vec<String> = row.iter().zip(widths.iter()).enumerate().map(
+      // the file name reads best left aligned, the numbers right aligned
+      |(index, (cell, width))| if index == 0 { format!("{:<1$}", cell, width) } else { format!("{:>1$}", cell, width) }).collect();
+    writeln!(w, "{}", cells.join("  "))?;
+  }
+  Ok(())
+}
+
+/// benchmarks every file across the matrix in options, writes the report to stdout and returns the exit status
+pub fn run(files: &[String], options: &Options, params: &BrotliEncoderParams, buffer_size: usize) -> i32 {
+  if files.len() == 0 {
+    eprintln!("brotli: --bench needs at least one file");
+    return cli::EXIT_ERROR;
+  }
+  let mut measurements = Vec::new();
+  let mut status = cli::EXIT_OK;
+  for file in files.iter() {
+    let mut input = Vec::new();
+    if let Err(e) = File::open(file).and_then(|mut f| f.read_to_end(&mut input)) {
+      eprintln!("brotli: {}: {}", file, e);
+      status = cli::EXIT_ERROR;
+      continue;
+    }
+    for quality in options.qualities.iter() {
+      for lgwin in options.windows.iter() {
+        for threads in options.threads.iter() {
+          match measure(file, &input[..], params, *quality, *lgwin, *threads, options.repeat, buffer_size) {
+            Ok(measurement) => measurements.push(measurement),
+            Err(e) => {
+              eprintln!("brotli: {} at -q{} -w{} -j{}: {}", file, quality.name(), lgwin, threads, e);
+              status = cli::EXIT_ERROR;
+            },
+          }
+        }
+      }
+    }
+  }
+  let stdout = io::stdout();
+  let mut output = stdout.lock();
+  let written = if options.csv {
+    write_csv(&mut output, &measurements[..])
+  } else {
+    write_table(&mut output, &measurements[..])
+  };
+  if let Err(e) = written {
+    eprintln!("brotli: {}", e);
+    return cli::EXIT_ERROR;
+  }
+  status
+}
@@ -3,9 +3,13 @@
 mod test_broccoli;
 mod test_custom_dict;
 mod test_threading;
+mod test_cli;
 pub mod integration_tests;
 mod tests;
 mod util;
+mod cli;
+mod precompress;
+mod bench;
 
 extern crate brotli;
 extern crate brotli_decompressor;
@@ -16,7 +20,7 @@ extern crate sha2;
 #[macro_use]
 extern crate alloc_no_stdlib;
 use brotli::enc::{UnionHasher, BrotliEncoderParams, BrotliEncoderMaxCompressedSizeMulti, WorkerPool, compress_worker_pool, new_work_pool};
-use brotli::enc::threading::{SendAlloc,Owned, CompressionThreadResult, CompressMulti, BrotliEncoderThreadError};
+use brotli::enc::threading::{SendAlloc,Owned, CompressionThreadInput, CompressionThreadResult, CompressMulti, BrotliEncoderThreadError};
 #[allow(unused_imports)]
 use brotli::{HuffmanCode};
 use brotli::CustomRead;
@@ -28,10 +32,14 @@ use alloc_no_stdlib::{SliceWrapper, SliceWrapperMut, StackAllocator, AllocatedSt
 mod validate;
 use std::env;
 
+// lets brotli --bench report how much memory each setting takes, at the cost of counting every allocation
+#[cfg(feature="bench-memory")]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: bench::PeakAlloc = bench::PeakAlloc;
+
 use std::fs::File;
 use std::io::{self, Error, ErrorKind, Read, Write, Seek, SeekFrom};
 
-const MAX_THREADS: usize = 16;
 
 pub struct Rebox<T> {
   b: Box<[T]>,
@@ -282,82 +290,72 @@ pub fn new_brotli_heap_alloc() -> HeapAllocator {
 }
 impl brotli::enc::BrotliAlloc for HeapAllocator {
 }
+fn log_metablock(pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                 data:&mut [brotli::interface::Command<brotli::SliceOffset>],
+                 mb:brotli::InputPair,
+                 _mfv: &mut HeapAllocator) {
+    let tmp = brotli::interface::Command::PredictionMode(
+        brotli::interface::PredictionModeContextMap::<brotli::InputReference>{
+            literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
+            predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
+        });
+    util::write_one(&tmp);
+    for cmd in data.iter() {
+        util::write_one(&brotli::thaw_pair(cmd, &mb));
+    }
+}
+fn nop_metablock(_pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                 _data:&mut [brotli::interface::Command<brotli::SliceOffset>],
+                 _mb:brotli::InputPair,
+                 _mfv: &mut HeapAllocator) {
+}
+pub type CompressionWorkPool = WorkerPool<CompressionThreadResult<HeapAllocator>,
+                                         UnionHasher<HeapAllocator>,
+                                         HeapAllocator,
+                                         CompressionThreadInput<Rebox<u8>>>;
+
+// the custom dictionary must already be the first custom_dictionary_len bytes of input
 pub fn compress_multi_nostd(
   input: Vec<u8>,
   output: &mut [u8],
   params:&BrotliEncoderParams,
-  mut num_threads: usize,
+  num_threads: usize,
+  custom_dictionary_len: usize,
 ) -> Result<usize, BrotliEncoderThreadError> {
-      let mut alloc_array = [
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-      ];
-      if num_threads > alloc_array.len() {
-        num_threads = alloc_array.len();
-      }
-      CompressMulti(params, &mut Owned::new(Rebox::from(input)), output, &mut alloc_array[..num_threads], &mut util::MTSpawner::default())
+      let mut alloc_array: Vec<_> = (0..num_threads).map(|_| SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit)).collect();
+      let mut log = if params.log_meta_block { log_metablock } else { nop_metablock };
+      CompressMulti(params, &mut Owned::new(Rebox::from(input)), output, &mut alloc_array[..num_threads], &mut util::MTSpawner::default(),
+                    custom_dictionary_len, &mut log)
 }
 pub fn compress_multi<InputType:Read,
                       OutputType:Write>(
   r: &mut InputType,
   w: &mut OutputType,
   params:&BrotliEncoderParams,
-  mut num_threads: usize,
-  work_pool: Option<&mut WorkerPool<CompressionThreadResult<HeapAllocator>,
-                                    UnionHasher<HeapAllocator>,
-                                    HeapAllocator,
-                                    (Rebox<u8>, BrotliEncoderParams)>>,
+  num_threads: usize,
+  custom_dictionary: &[u8],
+  work_pool: Option<&mut CompressionWorkPool>,
 ) -> Result<usize, io::Error> {
-  let mut input: Vec<u8> = Vec::<u8>::new();
+  // the workers share one buffer, so the dictionary is placed in front of the data to compress
+  let mut input: Vec<u8> = custom_dictionary.to_vec();
   if let Err(err) = r.read_to_end(&mut input) {
     return Err(err);
   }
-  let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input.len(), num_threads)]);
+  let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input.len() - custom_dictionary.len(), &params.multi_chunking, num_threads)]);
   let res = if let Some(worker_pool) = work_pool {
-      let mut alloc_array = [
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-        SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit),
-      ];
-      if num_threads > alloc_array.len() {
-        num_threads = alloc_array.len();
-      }
+      let mut alloc_array: Vec<_> = (0..num_threads).map(|_| SendAlloc::new(HeapAllocator::default(), UnionHasher::Uninit)).collect();
+      let mut log = if params.log_meta_block { log_metablock } else { nop_metablock };
       compress_worker_pool(
         params,
         &mut Owned::new(Rebox::from(input)),
         output.slice_mut(),
         &mut alloc_array[..num_threads],
         worker_pool,
+        custom_dictionary.len(),
+        &mut log,
       )
   } else {
-      compress_multi_nostd(input, output.slice_mut(), params, num_threads)  
+      compress_multi_nostd(input, output.slice_mut(), params, num_threads, custom_dictionary.len())
   };
   
   match res {
@@ -381,33 +379,20 @@ pub fn compress<InputType, OutputType>(r: &mut InputType,
                                        num_threads: usize) -> Result<usize, io::Error>
     where InputType: Read,
           OutputType: Write {
-    if num_threads > 1 && custom_dictionary.len() ==0 && !params.log_meta_block {
+    if params.log_meta_block {
+        println_stderr!("window {} 0 0 0", params.lgwin);
+    }
+    if num_threads > 1 {
       if has_stdlib() {
-        return compress_multi(r, w, params, num_threads, Some(&mut new_work_pool(num_threads - 1)));
+        return compress_multi(r, w, params, num_threads, custom_dictionary, Some(&mut new_work_pool(num_threads - 1)));
       } else {
-        return compress_multi(r, w, params, num_threads, None);
+        return compress_multi(r, w, params, num_threads, custom_dictionary, None);
       }
     }
     let mut alloc_u8 = HeapAllocator::default();
     let mut input_buffer = alloc_u8.alloc_cell(buffer_size);
     let mut output_buffer = alloc_u8.alloc_cell(buffer_size);
-    let mut log = |pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
-                   data:&mut [brotli::interface::Command<brotli::SliceOffset>],
-                   mb:brotli::InputPair,
-                  _mfv: &mut HeapAllocator| {
-        let tmp = brotli::interface::Command::PredictionMode(
-            brotli::interface::PredictionModeContextMap::<brotli::InputReference>{
-                literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
-                predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
-            });
-        util::write_one(&tmp);
-        for cmd in data.iter() {
-            util::write_one(&brotli::thaw_pair(cmd, &mb));
-        }
-    };
-    if params.log_meta_block {
-        println_stderr!("window {} 0 0 0", params.lgwin);
-    }
+    let mut log = log_metablock;
     brotli::BrotliCompressCustomIoCustomDict(&mut IoReaderWrapper::<InputType>(r),
                                    &mut IoWriterWrapper::<OutputType>(w),
                                    &mut input_buffer.slice_mut(),
@@ -491,6 +476,17 @@ fn has_stdlib() -> bool {
     false
 }
 
+// -jN: 0 means 1, and more threads than there are cores would only compete for them
+fn parse_num_threads(value: &str) -> Option<usize> {
+  match value.parse::<usize>() {
+    Ok(n) => {
+      let num_cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+      Some(core::cmp::max(1, core::cmp::min(n, num_cores)))
+    },
+    Err(_) => None,
+  }
+}
+
 fn main() {
   let mut buffer_size = 65536;
   let mut do_compress = false;
@@ -499,27 +495,84 @@ fn main() {
   let mut custom_dictionary = Vec::<u8>::new();
   let mut use_work_pool = has_stdlib();
   params.quality = 11; // default
-  let mut filenames = [std::string::String::new(), std::string::String::new()];
+  let mut filenames = Vec::<std::string::String>::new();
   let mut num_benchmarks = 1;
   let mut double_dash = false;
   let mut num_threads = 1;
-  if env::args_os().len() > 1 {
-    let mut first = true;
-    for argument in env::args() {
-      if first {
-        first = false;
-        continue;
-      }
+  let mut num_threads_given = false;
+  // the original interface stays the default: decompress unless given -c, brotli [input] [output].
+  // --gzip selects the gzip-style one that works on files in place, as do --precompress and --bench
+  let legacy = !env::args().skip(1).take_while(|argument| argument != "--").any(|argument| {
+    argument == "--gzip" || argument == "--precompress" || argument.starts_with("--precompress=") || argument == "--bench"
+  });
+  let mut cli_options = cli::Options::default();
+  let mut precompress_options = precompress::Options::default();
+  let mut precompress_dir = None;
+  let mut precompress_flag = None; // the first --precompress tuning flag, which needs --precompress itself
+  let mut bench_options = bench::Options::default();
+  let mut do_bench = false;
+  if env::args_os().len() > 1 || !legacy {
+    let mut args = env::args().skip(1);
+    while let Some(argument) = args.next() {
       if argument == "--" {
         double_dash = true;
         continue;
       }
+      if argument == "--gzip" && !double_dash {
+        continue;
+      }
+      if !legacy && !double_dash {
+        if argument == "--precompress" || argument.starts_with("--precompress=") {
+          precompress_dir = if argument == "--precompress" { args.next() } else { Some(argument["--precompress=".len()..].to_string()) };
+          if precompress_dir.is_none() {
+            println_stderr!("brotli: option {} requires an argument", argument);
+            std::process::exit(cli::EXIT_ERROR);
+          }
+          continue;
+        }
+        if precompress::parse_flag(&mut precompress_options, &argument) {
+          if precompress_flag.is_none() {
+            precompress_flag = Some(argument);
+          }
+          continue;
+        }
+        if argument == "--bench" {
+          do_bench = true;
+          continue;
+        }
+        if argument.starts_with("--bench-") {
+          if !bench::parse_flag(&mut bench_options, &argument) {
+            println_stderr!("brotli: invalid option {}", argument);
+            std::process::exit(cli::EXIT_ERROR);
+          }
+          continue;
+        }
+        if cli::parse_flag(&mut cli_options, &argument) {
+          continue;
+        }
+        if cli::takes_value(&argument) {
+          match args.next() {
+            Some(value) => cli::set_value(&mut cli_options, &argument, value),
+            None => {
+              println_stderr!("brotli: option {} requires an argument", argument);
+              std::process::exit(cli::EXIT_ERROR);
+            },
+          }
+          continue;
+        }
+      }
       if (argument == "-catable" || argument == "--catable") && !double_dash {
           params.catable = true;
           params.use_dictionary = false;
           params.appendable = true;
           continue;
       }
+      if (argument == "-catable-dictionary" || argument == "--catable-dictionary") && !double_dash {
+          params.catable = true;
+          params.catable_dictionary = true;
+          params.appendable = true;
+          continue;
+      }
       if (argument == "-nothreadpool" || argument == "--nothreadpool") && !double_dash {
           use_work_pool = false;
           continue;
@@ -640,11 +693,14 @@ fn main() {
         continue;
       }
       if argument.starts_with("-j") && !double_dash {
-        num_threads = core::cmp::min(
-          core::cmp::max(
-            1,
-            argument.trim_matches('-').trim_matches('j').parse::<i32>().unwrap() as usize),
-          MAX_THREADS);
+        num_threads_given = true;
+        num_threads = match parse_num_threads(&argument[2..]) {
+          Some(n) => n,
+          None => {
+            println_stderr!("brotli: invalid thread count in {}", argument);
+            std::process::exit(cli::EXIT_ERROR);
+          },
+        };
         continue;
       }
       if argument.starts_with("-bytescore=") && !double_dash {
@@ -733,19 +789,53 @@ fn main() {
         continue;
       }
       if argument == "-h" || argument == "-help" || argument == "--help" && !double_dash {
-        println_stderr!("Decompression:\nbrotli [input_file] [output_file]\nCompression:brotli -c -q9.5 -w22 [input_file] [output_file]\nQuality may be one of -q9.5 -q9.5x -q9.5y or -q[0-11] for standard brotli settings.\nOptional size hint -s<size> to direct better compression\n\nThe -i parameter produces a cross human readdable IR representation of the file.\nThis can be ingested by other compressors.\nIR-specific options include:\n-findprior\n-speed=<inc,max,inc,max,inc,max,inc,max>");
+        if !legacy {
+          println_stderr!("Usage: brotli --gzip [OPTION]... [FILE]...\nCompress (by default) or decompress each FILE in place, replacing it with FILE.br or the reverse.\nWith no FILE, or when FILE is -, read standard input and write standard output.\n\n-d --decompress\tdecompress\n-c --stdout\twrite to standard output and keep the input files\n-k --keep\tkeep the input files\n-f --force\toverwrite existing output files\n-o FILE --output=FILE\twrite to FILE (single input only)\n-S SUF --suffix=SUF\tuse suffix SUF instead of .br\n-t --test\ttest the integrity of compressed files\n-v --verbose\treport the name and compression ratio of each file\n-jN\tcompress with N threads\n\nbrotli --precompress DIR [--include=GLOB]... [--exclude=GLOB]... [--min-saving=PERCENT] [--report=FILE]\nwrites FILE.br next to each matching file under DIR, in parallel on -jN threads (all cores by default).\nA .br newer than its file is left alone, and none is kept unless it saves PERCENT (5 by default).\n--report writes one JSON object per file, to standard output if FILE is -.\n\nbrotli --bench [--bench-quality=1,5,9,9.5] [--bench-window=22] [--bench-threads=1] [--bench-repeat=3] [--bench-format=table|csv] FILE...\ncompresses and decompresses each FILE at every combination of the listed settings, reporting the ratio,\nthe speed of the fastest run and, if built with the bench-memory feature, the peak heap use.\n\nExit status is 0 on success, 1 on error and 2 if a file was skipped with a warning.\nTuning options are described by brotli --help.\nWithout --gzip, --precompress or --bench, brotli keeps its original interface: brotli [-c] [input_file] [output_file].");
+          return;
+        }
+        println_stderr!("Decompression:\nbrotli [input_file] [output_file]\nCompression:brotli -c -q9.5 -w22 [input_file] [output_file]\nQuality may be one of -q9.5 -q9.5x -q9.5y or -q[0-11] for standard brotli settings.\nOptional size hint -s<size> to direct better compression\n\nThe -i parameter produces a cross human readdable IR representation of the file.\nThis can be ingested by other compressors.\nIR-specific options include:\n-findprior\n-speed=<inc,max,inc,max,inc,max,inc,max>\n\nbrotli --gzip --help describes the gzip-style interface for compressing files in place.");
         return;
       }
-      if filenames[0] == "" {
-         filenames[0] = argument.clone();
-         continue;
-      }
-      if filenames[1] == "" {
-         filenames[1] = argument.clone();
+      if !legacy || filenames.len() < 2 {
+         filenames.push(argument.clone());
          continue;
       }
       panic!("Unknown Argument {:}", argument);
    }
+   if !legacy {
+     if do_validate || num_benchmarks != 1 {
+       println_stderr!("brotli: -validate and -b<count> are not available with --gzip, --precompress or --bench");
+       std::process::exit(cli::EXIT_ERROR);
+     }
+     if do_bench {
+       std::process::exit(bench::run(&filenames[..], &bench_options, &params, buffer_size));
+     }
+     if let Some(ref flag) = precompress_flag {
+       if precompress_dir.is_none() {
+         println_stderr!("brotli: {} requires --precompress", flag);
+         std::process::exit(cli::EXIT_ERROR);
+       }
+     }
+     if let Some(dir) = precompress_dir {
+       if filenames.len() != 0 {
+         println_stderr!("brotli: --precompress takes no other files");
+         std::process::exit(cli::EXIT_ERROR);
+       }
+       precompress_options.verbose = cli_options.verbose;
+       if !num_threads_given {
+         num_threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+       }
+       std::process::exit(precompress::run(&dir, &precompress_options, &params, buffer_size, num_threads));
+     }
+     std::process::exit(cli::run(&cli_options, &filenames[..], &cli::Settings{
+       params: &params,
+       custom_dictionary: &custom_dictionary[..],
+       buffer_size: buffer_size,
+       num_threads: num_threads,
+       use_work_pool: use_work_pool,
+     }));
+   }
+   filenames.resize(2, std::string::String::new());
    if filenames[0] != "" {
       let mut input = match File::open(&Path::new(&filenames[0])) {
         Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
@@ -773,13 +863,13 @@ fn main() {
             }
           } else if do_compress {
             if let Some(ref mut work_pool) = worker_pool {
-              match compress_multi(&mut input, &mut output, &params, num_threads, Some(work_pool)) {
+              match compress_multi(&mut input, &mut output, &params, num_threads, &custom_dictionary[..], Some(work_pool)) {
                 Ok(_) => {}
                 Err(e) => panic!("Error {:?}", e),
               }
             } else {
               if num_threads != 1 {
-                match compress_multi(&mut input, &mut output, &params, num_threads, None) {
+                match compress_multi(&mut input, &mut output, &params, num_threads, &custom_dictionary[..], None) {
                   Ok(_) => {}
                   Err(e) => panic!("Error {:?}", e),
                 }
@@ -6,9 +6,13 @@ use std::io::{Read, Write};
 use std::path::Path;
 use std::fs::File;
 
-use brotli::concat::{BroCatli, BroCatliResult};
+use brotli::{CustomWrite, SliceWrapper, SliceWrapperMut};
+use brotli::concat::{BroCatli, BroCatliResult, BroCatliWriterCustomIo, LEADING_HEADER_BYTES, stream_window_size};
+#[cfg(feature="std")]
+use brotli::concat::{ConcatRewriter, uncat_members};
+use brotli::enc::BrotliEncoderParams;
 fn usage() {
-    writeln!(&mut ::std::io::stderr(), "Usage: [-w<window_size>] filename0 filename1 filename2...").unwrap();
+    writeln!(&mut ::std::io::stderr(), "Usage: [-w<window_size>|-grow] [-magic] [-mark] [-bs<buffer_size>] [-reencode [-q<quality>]] filename0 filename1 filename2...\n       --split|--list filename...\n-grow: give the output the largest window of any file (reads the file headers first)\n-magic: replace the magic number headers of the files with one for the whole output\n-mark: mark where each file starts, so that --split can take the output apart again\n--split: write each file of a concatenation made with -mark to filename.<index> (--list just lists them)\n-reencode: recompress the metablocks of files not made to be concatenated that cannot be copied as they are").unwrap();
 }
 fn read_no_interrupt<R:Read>(r: &mut R, buf: &mut [u8]) -> Result<usize, io::Error> {
     loop {
@@ -45,13 +49,135 @@ fn write_no_interrupt<W:Write>(w: &mut W, mut buf: &[u8]) -> Result<usize, io::E
     }
 }
 
+struct Buffer(Vec<u8>);
+impl SliceWrapper<u8> for Buffer {
+    fn slice(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+impl SliceWrapperMut<u8> for Buffer {
+    fn slice_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..]
+    }
+}
+
+// with -magic the output is held until the end, when its leading header is rewritten
+struct Output<W: Write>(W, Option<Vec<u8>>);
+impl<W: Write> CustomWrite<io::Error> for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self.1 {
+            Some(ref mut held) => {
+                held.extend_from_slice(buf);
+                Ok(buf.len())
+            },
+            None => write_no_interrupt(&mut self.0, buf),
+        }
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self.1 {
+            Some(_) => Ok(()),
+            None => self.0.flush(),
+        }
+    }
+}
+
+fn concat_error(result: BroCatliResult) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}", result))
+}
+
+// with -reencode each file goes through a ConcatRewriter, which compresses again only the
+// metablocks that won't go in bitwise
+#[cfg(feature="std")]
+fn reencode_file<W: CustomWrite<io::Error>>(input_file: &mut File,
+                                            ibuffer: &mut [u8],
+                                            window_size: u8,
+                                            params: &BrotliEncoderParams,
+                                            output: &mut W) -> Result<(), io::Error> {
+    let mut rewriter = ConcatRewriter::new(window_size, params);
+    let mut rewritten = Vec::new();
+    loop {
+        let cur_read = read_no_interrupt(input_file, ibuffer)?;
+        if cur_read == 0 {
+            break;
+        }
+        rewriter.write(&ibuffer[..cur_read], &mut rewritten)?;
+        output.write(&rewritten[..])?;
+        rewritten.clear();
+    }
+    rewriter.finish(&mut rewritten)?;
+    output.write(&rewritten[..]).map(|_| ())
+}
+
+#[cfg(not(feature="std"))]
+fn reencode_file<W: CustomWrite<io::Error>>(_input_file: &mut File,
+                                            _ibuffer: &mut [u8],
+                                            _window_size: u8,
+                                            _params: &BrotliEncoderParams,
+                                            _output: &mut W) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "-reencode needs brotli built with the std feature"))
+}
+
+// the first of the two passes of -grow: the window of the output is the largest of them all
+fn window_growth_state(filenames: &[String]) -> Result<BroCatli, io::Error> {
+    let mut window_sizes = Vec::new();
+    for filename in filenames {
+        let mut input_file = File::open(&Path::new(filename))?;
+        let mut header = [0u8; 2];
+        let mut header_len = 0usize;
+        while header_len < header.len() {
+            match read_no_interrupt(&mut input_file, &mut header[header_len..])? {
+                0 => break,
+                cur_read => header_len += cur_read,
+            }
+        }
+        match stream_window_size(&header[..header_len]) {
+            Ok(ws) => window_sizes.push(ws),
+            Err(result) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                     format!("{:} {:}", filename, result))),
+        }
+    }
+    let first = window_sizes.first().cloned().unwrap_or(0);
+    let largest = window_sizes.iter().cloned().max().unwrap_or(0);
+    Ok(BroCatli::new_for_window_sizes(first, largest))
+}
+
+// --split writes each member of a file concatenated with -mark to <filename>.<index>; --list prints them
+#[cfg(feature="std")]
+fn split_file(filename: &str, write_members: bool) -> Result<(), io::Error> {
+    let mut concatenated = Vec::new();
+    File::open(&Path::new(filename))?.read_to_end(&mut concatenated)?;
+    let members = uncat_members(&concatenated[..]).map_err(concat_error)?;
+    for member in members {
+        let mut standalone = vec![0u8; member.standalone_len(&concatenated[..]).map_err(concat_error)?];
+        member.write_standalone(&concatenated[..], &mut standalone[..]).map_err(concat_error)?;
+        if write_members {
+            let mut output = File::create(&Path::new(&format!("{}.{}", filename, member.index)))?;
+            write_no_interrupt(&mut output, &standalone[..])?;
+        } else {
+            println!("{}\t{}", member.index, standalone.len());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature="std"))]
+fn split_file(_filename: &str, _write_members: bool) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "--split needs brotli built with the std feature"))
+}
 
 fn main() {
     let mut window_size: Option<u8> = None;
     let mut double_dash = false;
     let mut buffer_size = 4096usize;
+    let mut reencode = false;
+    let mut grow = false;
+    let mut magic = false;
+    let mut mark = false;
+    let mut split: Option<bool> = None; // Some(false) only lists the members
+    // re-encoding is only a fallback for files that cannot be copied, so favour speed
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 9;
     let mut filenames = Vec::<String>::new();
-    let mut ostream = io::stdout();
     if env::args_os().len() > 1 {
         for argument in env::args().skip(1) {
             if argument.starts_with("-w") && !double_dash {
@@ -62,6 +188,30 @@ fn main() {
                 buffer_size = argument.trim_matches('-').trim_matches('b').trim_matches('s').parse::<usize>().unwrap();
                 continue;
             }
+            if argument == "-grow" && !double_dash {
+                grow = true;
+                continue;
+            }
+            if argument == "-mark" && !double_dash {
+                mark = true;
+                continue;
+            }
+            if (argument == "--split" || argument == "--list") && !double_dash {
+                split = Some(argument == "--split");
+                continue;
+            }
+            if argument == "-magic" && !double_dash {
+                magic = true;
+                continue;
+            }
+            if argument == "-reencode" && !double_dash {
+                reencode = true;
+                continue;
+            }
+            if argument.starts_with("-q") && !double_dash {
+                params.quality = argument.trim_matches('-').trim_matches('q').parse::<i32>().unwrap();
+                continue;
+            }
             if argument == "--" {
                 double_dash = true;
                 continue;
@@ -72,78 +222,75 @@ fn main() {
         usage();
         return;
     }
-    let mut ibuffer = vec![0u8; buffer_size];
-    let mut obuffer = vec![0u8; buffer_size];
-    let mut ooffset = 0;
-    let mut ioffset;
-    let mut bro_cat_li = match window_size {
+    if let Some(write_members) = split {
+        for filename in filenames {
+            if let Err(why) = split_file(&filename, write_members) {
+                panic!("Failed to split {:} {:}", filename, why);
+            }
+        }
+        return;
+    }
+    let ostream = io::stdout();
+    let output = Output(ostream.lock(), if magic { Some(Vec::new()) } else { None });
+    let buffer = Buffer(vec![0u8; buffer_size]);
+    let mut state = match window_size {
         Some(ws) => BroCatli::new_with_window_size(ws),
+        // the output is held anyway, so the window can grow in a single pass
+        None if magic && grow => BroCatli::new_with_window_growth(),
+        None if grow => match window_growth_state(&filenames[..]) {
+            Ok(state) => state,
+            Err(why) => panic!("Failed to read the window sizes {:}", why),
+        },
         None => BroCatli::new(),
     };
+    if magic {
+        state.merge_magic_headers();
+    }
+    if mark {
+        state.mark_members();
+    }
+    let mut bro_cat_li = BroCatliWriterCustomIo::with_state(output, buffer, state, concat_error);
+    let mut ibuffer = vec![0u8; buffer_size];
     for filename in filenames {
-        bro_cat_li.new_brotli_file();
+        bro_cat_li.start_new_file();
         let mut input_file = match File::open(&Path::new(&filename)) {
             Err(why) => panic!("couldn't open {:}\n{:}", filename, why),
             Ok(file) => file,
         };
+        if reencode {
+            let window_size = bro_cat_li.window_size();
+            match reencode_file(&mut input_file, &mut ibuffer[..], window_size, &params, &mut bro_cat_li) {
+                Err(why) => panic!("Failed to concatenate files on {:} {:}", filename, why),
+                Ok(_) => continue,
+            }
+        }
         loop {
-            ioffset = 0;
             match read_no_interrupt(&mut input_file, &mut ibuffer[..]) {
                 Err(e) => panic!("{}", e),
                 Ok(cur_read) => {
                     if cur_read == 0 {
                         break;
                     }
-                    loop {
-                        match bro_cat_li.stream(&ibuffer[..cur_read], &mut ioffset,
-                                                &mut obuffer[..], &mut ooffset) {
-                            BroCatliResult::NeedsMoreOutput => {
-                                match write_no_interrupt(&mut ostream, &obuffer[..ooffset]) {
-                                    Err(why) => panic!("couldn't write: {:}", why),
-                                    Ok(count) => {assert_eq!(count, ooffset);},
-                                }
-                                ooffset = 0;
-                            },
-                            BroCatliResult::NeedsMoreInput => {
-                                break;
-                            },
-                            BroCatliResult::Success => {
-                                panic!("Unexpected state: Success when streaming before finish");
-                            }
-                            failure => {
-                                panic!("Failed to concatenate files on {:} {:?}", filename, failure);
-                            },
-                        }
-                        
+                    if let Err(why) = bro_cat_li.write(&ibuffer[..cur_read]) {
+                        panic!("Failed to concatenate files on {:} {:}", filename, why);
                     }
                 }
             }
         }
     }
-    loop {
-        match bro_cat_li.finish(&mut obuffer[..], &mut ooffset) {
-            BroCatliResult::NeedsMoreOutput => {
-                match write_no_interrupt(&mut ostream, &obuffer[..ooffset]) {
-                    Err(why) => panic!("couldn't write: {:}", why),
-                    Ok(count) => {assert_eq!(count, ooffset);},
-                }
-                ooffset = 0;
-            },
-            BroCatliResult::NeedsMoreInput => {
-                panic!("Unexpected EOF");
-            },
-            BroCatliResult::Success => {
-                if ooffset != 0 {
-                    match write_no_interrupt(&mut ostream, &obuffer[..ooffset]) {
-                        Err(why) => panic!("couldn't write: {:}", why),
-                        Ok(count) => {assert_eq!(count, ooffset);},
-                    }
-                }
-                break;
-            }
-            failure => {
-                panic!("{:?}", failure)
-            }
+    if let Err(why) = bro_cat_li.finish() {
+        panic!("{:}", why);
+    }
+    if let Some(held) = bro_cat_li.get_mut().1.take() {
+        let mut header = [0u8; LEADING_HEADER_BYTES];
+        let (replaced_len, header_len) = match bro_cat_li.leading_header(&mut header[..]) {
+            Ok(lengths) => lengths,
+            Err(why) => panic!("{:}", why),
+        };
+        let output = &mut bro_cat_li.get_mut().0;
+        if let Err(why) = write_no_interrupt(output, &header[..header_len]).and_then(
+            |_| write_no_interrupt(output, &held[replaced_len..])) {
+            panic!("{:}", why);
         }
     }
 }
@@ -0,0 +1,341 @@
+use std;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, IsTerminal};
+use std::panic;
+use std::path::{Path, PathBuf};
+use brotli::enc::BrotliEncoderParams;
+use super::{compress, compress_multi, decompress, new_work_pool, CompressionWorkPool};
+
+// gzip-style command line: brotli --gzip [-d] [-c] [-k] [-f] [-o FILE] [-S SUF] [-t] [-v] [file...]
+// Each file is compressed to file.br (or decompressed from it) next to the original, which is removed
+// afterwards unless -k is given.
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_WARNING: i32 = 2;
+
+pub struct Options {
+  pub decompress: bool,
+  pub to_stdout: bool,
+  pub keep: bool,
+  pub force: bool,
+  pub output: Option<String>,
+  pub suffix: String,
+  pub test: bool,
+  pub verbose: bool,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      decompress: false,
+      to_stdout: false,
+      keep: false,
+      force: false,
+      output: None,
+      suffix: ".br".to_string(),
+      test: false,
+      verbose: false,
+    }
+  }
+}
+
+// the options that take no value, as single letters so they may be grouped like -dc or -kv
+fn parse_short_flag(options: &mut Options, flag: char) -> bool {
+  match flag {
+    'd' => options.decompress = true,
+    'z' => options.decompress = false,
+    'c' => options.to_stdout = true,
+    'k' => options.keep = true,
+    'f' => options.force = true,
+    't' => options.test = true,
+    'v' => options.verbose = true,
+    _ => return false,
+  }
+  true
+}
+
+/// applies argument to options if it is one of the gzip-style flags that take no separate value,
+/// returning false (and leaving options alone) otherwise
+pub fn parse_flag(options: &mut Options, argument: &str) -> bool {
+  match argument {
+    "--decompress" | "--uncompress" => options.decompress = true,
+    "--compress" => options.decompress = false,
+    "--stdout" | "--to-stdout" => options.to_stdout = true,
+    "--keep" => options.keep = true,
+    "--force" => options.force = true,
+    "--test" => options.test = true,
+    "--verbose" => options.verbose = true,
+    _ => {
+      if argument.starts_with("--output=") {
+        options.output = Some(argument["--output=".len()..].to_string());
+        return true;
+      }
+      if argument.starts_with("--suffix=") {
+        options.suffix = argument["--suffix=".len()..].to_string();
+        return true;
+      }
+      if argument.len() < 2 || !argument.starts_with('-') || argument.starts_with("--") {
+        return false;
+      }
+      let mut grouped = Options::default();
+      if !argument[1..].chars().all(|flag| parse_short_flag(&mut grouped, flag)) {
+        return false;
+      }
+      for flag in argument[1..].chars() {
+        parse_short_flag(options, flag);
+      }
+    },
+  }
+  true
+}
+
+/// whether argument is a flag whose value is the following argument
+pub fn takes_value(argument: &str) -> bool {
+  argument == "-o" || argument == "-S" || argument == "--output" || argument == "--suffix"
+}
+
+pub fn set_value(options: &mut Options, argument: &str, value: String) {
+  if argument == "-o" || argument == "--output" {
+    options.output = Some(value);
+  } else {
+    options.suffix = value;
+  }
+}
+
+pub struct Settings<'a> {
+  pub params: &'a BrotliEncoderParams,
+  pub custom_dictionary: &'a [u8],
+  pub buffer_size: usize,
+  pub num_threads: usize,
+  pub use_work_pool: bool,
+}
+
+enum Failure {
+  Warning(String), // the file was skipped but the others go on
+  Error(String),
+}
+
+struct Counted<T> {
+  inner: T,
+  count: u64,
+}
+
+impl<T: Read> Read for Counted<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let size = self.inner.read(buf)?;
+    self.count += size as u64;
+    Ok(size)
+  }
+}
+
+impl<T: Write> Write for Counted<T> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let size = self.inner.write(buf)?;
+    self.count += size as u64;
+    Ok(size)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+fn run_codec<R: Read, W: Write>(options: &Options,
+                                settings: &Settings,
+                                work_pool: Option<&mut CompressionWorkPool>,
+                                r: &mut R,
+                                w: &mut W) -> io::Result<()> {
+  if options.decompress || options.test {
+    return decompress(r, w, settings.buffer_size, settings.custom_dictionary.to_vec().into());
+  }
+  if settings.num_threads > 1 {
+    compress_multi(r, w, settings.params, settings.num_threads, settings.custom_dictionary, work_pool).map(|_| ())
+  } else {
+    compress(r, w, settings.buffer_size, settings.params, settings.custom_dictionary, 1).map(|_| ())
+  }
+}
+
+fn ratio(before: u64, after: u64) -> f64 {
+  if before == 0 {
+    0.0
+  } else {
+    100.0 * (1.0 - after as f64 / before as f64)
+  }
+}
+
+fn report(options: &Options, name: &str, input_size: u64, output_size: u64, output_name: &str) {
+  if !options.verbose {
+    return;
+  }
+  if options.test {
+    eprintln!("{}:\tOK", name);
+  } else if options.decompress {
+    eprintln!("{}:\t{:5.1}% -- {} {}", name, ratio(output_size, input_size),
+              if output_name == "-" { "written to" } else { "created" }, output_name);
+  } else {
+    eprintln!("{}:\t{:5.1}% -- {} {}", name, ratio(input_size, output_size),
+              if output_name == "-" { "written to" } else { "created" }, output_name);
+  }
+}
+
+fn process_stdin(options: &Options, settings: &Settings, work_pool: Option<&mut CompressionWorkPool>) -> Result<(), Failure> {
+  let mut input = Counted{inner: io::stdin(), count: 0};
+  if options.test {
+    let mut output = Counted{inner: io::sink(), count: 0};
+    run_codec(options, settings, work_pool, &mut input, &mut output).map_err(|e| Failure::Error(format!("stdin: {}", e)))?;
+    report(options, "stdin", input.count, output.count, "-");
+    return Ok(());
+  }
+  if let Some(ref output_name) = options.output {
+    let output_path = Path::new(output_name);
+    check_overwrite(options, output_path)?;
+    let mut output = Counted{inner: File::create(output_path).map_err(|e| Failure::Error(format!("{}: {}", output_name, e)))?, count: 0};
+    if let Err(e) = run_codec(options, settings, work_pool, &mut input, &mut output) {
+      let _ = fs::remove_file(output_path);
+      return Err(Failure::Error(format!("stdin: {}", e)));
+    }
+    report(options, "stdin", input.count, output.count, output_name);
+    return Ok(());
+  }
+  if !options.decompress && !options.force && io::stdout().is_terminal() {
+    return Err(Failure::Error("compressed data not written to a terminal. Use -f to force compression.".to_string()));
+  }
+  let stdout = io::stdout();
+  let mut output = Counted{inner: stdout.lock(), count: 0};
+  run_codec(options, settings, work_pool, &mut input, &mut output).map_err(|e| Failure::Error(format!("stdin: {}", e)))?;
+  report(options, "stdin", input.count, output.count, "-");
+  Ok(())
+}
+
+/// creates the file output_path is written to before being renamed into place. Its name is unique to
+/// this process, and if a file of that name is already there it is left alone and an error naming it returned
+pub fn create_temporary(output_path: &Path) -> io::Result<(PathBuf, File)> {
+  let mut temporary = output_path.as_os_str().to_os_string();
+  temporary.push(format!(".{}.tmp", std::process::id()));
+  let temporary = PathBuf::from(temporary);
+  match OpenOptions::new().write(true).create_new(true).open(&temporary) {
+    Ok(file) => Ok((temporary, file)),
+    Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", temporary.display(), e))),
+  }
+}
+
+fn check_overwrite(options: &Options, output_path: &Path) -> Result<(), Failure> {
+  if !options.force && fs::symlink_metadata(output_path).is_ok() {
+    return Err(Failure::Error(format!("{} already exists; use -f to overwrite", output_path.display())));
+  }
+  Ok(())
+}
+
+fn output_path_for(options: &Options, name: &str) -> Result<PathBuf, Failure> {
+  if let Some(ref output_name) = options.output {
+    return Ok(PathBuf::from(output_name));
+  }
+  if !options.decompress {
+    if options.suffix.len() != 0 && name.ends_with(&options.suffix[..]) {
+      return Err(Failure::Warning(format!("{} already has {} suffix -- unchanged", name, options.suffix)));
+    }
+    return Ok(PathBuf::from(format!("{}{}", name, options.suffix)));
+  }
+  if options.suffix.len() == 0 || !name.ends_with(&options.suffix[..]) || name.len() == options.suffix.len() {
+    return Err(Failure::Warning(format!("{}: unknown suffix -- ignored", name)));
+  }
+  Ok(PathBuf::from(&name[..name.len() - options.suffix.len()]))
+}
+
+fn process_file(options: &Options, settings: &Settings, work_pool: Option<&mut CompressionWorkPool>, name: &str) -> Result<(), Failure> {
+  let input_path = Path::new(name);
+  let metadata = fs::metadata(input_path).map_err(|e| Failure::Error(format!("{}: {}", name, e)))?;
+  if metadata.is_dir() {
+    return Err(Failure::Warning(format!("{} is a directory -- ignored", name)));
+  }
+  if !metadata.is_file() && !options.force {
+    return Err(Failure::Warning(format!("{} is not a regular file -- ignored", name)));
+  }
+  let mut input = Counted{
+    inner: File::open(input_path).map_err(|e| Failure::Error(format!("{}: {}", name, e)))?,
+    count: 0,
+  };
+  if options.test {
+    let mut output = Counted{inner: io::sink(), count: 0};
+    run_codec(options, settings, work_pool, &mut input, &mut output).map_err(|e| Failure::Error(format!("{}: {}", name, e)))?;
+    report(options, name, input.count, output.count, "-");
+    return Ok(());
+  }
+  if options.to_stdout {
+    if !options.decompress && !options.force && io::stdout().is_terminal() {
+      return Err(Failure::Error("compressed data not written to a terminal. Use -f to force compression.".to_string()));
+    }
+    let stdout = io::stdout();
+    let mut output = Counted{inner: stdout.lock(), count: 0};
+    run_codec(options, settings, work_pool, &mut input, &mut output).map_err(|e| Failure::Error(format!("{}: {}", name, e)))?;
+    report(options, name, input.count, output.count, "-");
+    return Ok(());
+  }
+  let output_path = output_path_for(options, name)?;
+  if output_path == input_path {
+    return Err(Failure::Error(format!("{}: input and output are the same file", name)));
+  }
+  check_overwrite(options, &output_path)?;
+  // written under a temporary name and renamed, so a failure never leaves a truncated output behind
+  let (temporary, output_file) = create_temporary(&output_path).map_err(|e| Failure::Error(e.to_string()))?;
+  let mut output = Counted{inner: output_file, count: 0};
+  let mut result = match panic::catch_unwind(panic::AssertUnwindSafe(|| run_codec(options, settings, work_pool, &mut input, &mut output))) {
+    Ok(result) => result,
+    Err(_) => Err(io::Error::new(io::ErrorKind::Other, "internal error")),
+  };
+  if result.is_ok() {
+    // the output takes on the input's permissions and modification time, like gzip
+    result = output.inner.set_permissions(metadata.permissions());
+    if let (true, Ok(modified)) = (result.is_ok(), metadata.modified()) {
+      result = output.inner.set_modified(modified);
+    }
+  }
+  let result = result.and_then(|_| fs::rename(&temporary, &output_path));
+  if let Err(e) = result {
+    let _ = fs::remove_file(&temporary);
+    return Err(Failure::Error(format!("{}: {}", name, e)));
+  }
+  report(options, name, input.count, output.count, &output_path.display().to_string());
+  if !options.keep {
+    fs::remove_file(input_path).map_err(|e| Failure::Error(format!("{}: {}", name, e)))?;
+  }
+  Ok(())
+}
+
+/// processes every input ("-" or none at all meaning stdin) and returns the process exit status:
+/// 0 on success, 1 if any file failed, and 2 if some were only skipped with a warning
+pub fn run(options: &Options, inputs: &[String], settings: &Settings) -> i32 {
+  if options.output.is_some() && inputs.len() > 1 {
+    eprintln!("brotli: -o can only be used with a single input file");
+    return EXIT_ERROR;
+  }
+  let mut work_pool = if settings.num_threads > 1 && settings.use_work_pool && !options.decompress && !options.test {
+    Some(new_work_pool(settings.num_threads - 1))
+  } else {
+    None
+  };
+  let stdin_only = [std::string::String::from("-")];
+  let inputs = if inputs.len() == 0 { &stdin_only[..] } else { inputs };
+  let mut status = EXIT_OK;
+  for name in inputs.iter() {
+    let result = if name == "-" {
+      process_stdin(options, settings, work_pool.as_mut())
+    } else {
+      process_file(options, settings, work_pool.as_mut(), name)
+    };
+    match result {
+      Ok(()) => {},
+      Err(Failure::Warning(message)) => {
+        eprintln!("brotli: {}", message);
+        if status == EXIT_OK {
+          status = EXIT_WARNING;
+        }
+      },
+      Err(Failure::Error(message)) => {
+        eprintln!("brotli: {}", message);
+        status = EXIT_ERROR;
+      },
+    }
+  }
+  status
+}
@@ -0,0 +1,331 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use brotli::enc::{BrotliEncoderParams, WorkerPool, BatchSpawnableLite};
+use brotli::enc::backward_references::BrotliEncoderMode;
+use brotli::enc::threading::{SendAlloc, Owned, InternalSendAlloc, Joinable};
+use super::{compress, HeapAllocator};
+use super::cli;
+
+// brotli --precompress <dir>: writes a .br sibling next to every matching asset under dir, for servers
+// that hand out precompressed files for Content-Encoding: br. Each file is one job on a WorkerPool.
+
+pub struct Options {
+  pub include: Vec<String>,
+  pub exclude: Vec<String>,
+  pub min_saving_percent: f64, // a .br that saves less than this is not worth serving
+  pub report: Option<String>, // where to write one JSON object per file, - meaning stdout
+  pub verbose: bool,
+}
+
+// what gets precompressed when no --include is given: text and uncompressed fonts. Icons often just wrap
+// PNGs, and wasm modules can be large enough to hold up a run at high quality, so those take an --include
+static DEFAULT_INCLUDE: &'static [&'static str] = &[
+  "*.html", "*.htm", "*.css", "*.js", "*.mjs", "*.json", "*.map", "*.svg", "*.xml", "*.txt", "*.md",
+  "*.csv", "*.ttf", "*.otf", "*.eot",
+];
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      include: Vec::new(),
+      exclude: Vec::new(),
+      min_saving_percent: 5.0,
+      report: None,
+      verbose: false,
+    }
+  }
+}
+
+/// applies argument to options if it is one of the --precompress tuning flags
+pub fn parse_flag(options: &mut Options, argument: &str) -> bool {
+  if argument.starts_with("--include=") {
+    options.include.push(argument["--include=".len()..].to_string());
+  } else if argument.starts_with("--exclude=") {
+    options.exclude.push(argument["--exclude=".len()..].to_string());
+  } else if argument.starts_with("--min-saving=") {
+    match argument["--min-saving=".len()..].trim_end_matches('%').parse::<f64>() {
+      Ok(percent) => options.min_saving_percent = percent,
+      Err(_) => return false,
+    }
+  } else if argument.starts_with("--report=") {
+    options.report = Some(argument["--report=".len()..].to_string());
+  } else {
+    return false;
+  }
+  true
+}
+
+/// shell-style match of a /-separated path: * and ? stay within one path component, ** spans any number of them
+pub fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+  if pattern.len() == 0 {
+    return path.len() == 0;
+  }
+  if pattern.starts_with(b"**") {
+    let mut rest = &pattern[2..];
+    if rest.starts_with(b"/") {
+      // "**/" also matches no directories at all
+      rest = &rest[1..];
+    }
+    return (0..path.len() + 1).any(|skip| glob_match(rest, &path[skip..]));
+  }
+  match pattern[0] {
+    b'*' => (0..path.len() + 1).take_while(|skip| *skip == 0 || path[skip - 1] != b'/').any(
+      |skip| glob_match(&pattern[1..], &path[skip..])),
+    b'?' => path.len() != 0 && path[0] != b'/' && glob_match(&pattern[1..], &path[1..]),
+    c => path.len() != 0 && path[0] == c && glob_match(&pattern[1..], &path[1..]),
+  }
+}
+
+// patterns without a / apply to the file name wherever it is, like .gitignore
+fn matches_any(patterns: &[String], relative_path: &str) -> bool {
+  let file_name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+  patterns.iter().any(|pattern| if pattern.contains('/') {
+    glob_match(pattern.as_bytes(), relative_path.as_bytes())
+  } else {
+    glob_match(pattern.as_bytes(), file_name.as_bytes())
+  })
+}
+
+pub fn mode_for(path: &Path) -> BrotliEncoderMode {
+  let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+  match &extension[..] {
+    "ttf" | "otf" | "eot" => BrotliEncoderMode::BROTLI_MODE_FONT,
+    "html" | "htm" | "css" | "js" | "mjs" | "json" | "map" | "svg" | "xml" | "txt" | "md" | "csv" => BrotliEncoderMode::BROTLI_MODE_TEXT,
+    _ => BrotliEncoderMode::BROTLI_MODE_GENERIC,
+  }
+}
+
+fn compressed_path(path: &Path) -> PathBuf {
+  let mut name = path.as_os_str().to_os_string();
+  name.push(".br");
+  PathBuf::from(name)
+}
+
+// every regular file under dir, sorted so the report comes out in a stable order
+fn walk(root: &Path, dir: &Path, options: &Options, found: &mut Vec<(PathBuf, String)>) -> io::Result<()> {
+  let mut entries = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    entries.push(entry?.path());
+  }
+  entries.sort();
+  for path in entries {
+    let file_type = fs::symlink_metadata(&path)?.file_type();
+    let relative = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+    if file_type.is_dir() {
+      walk(root, &path, options, found)?;
+      continue;
+    }
+    // symlinked files are compressed through the link, but symlinked directories are not followed
+    if file_type.is_symlink() && !path.is_file() {
+      continue;
+    }
+    if relative.ends_with(".br") {
+      continue;
+    }
+    let included = if options.include.len() == 0 {
+      let defaults: Vec<String> = DEFAULT_INCLUDE.iter().map(|pattern| pattern.to_string()).collect();
+      matches_any(&defaults[..], &relative)
+    } else {
+      matches_any(&options.include[..], &relative)
+    };
+    if included && !matches_any(&options.exclude[..], &relative) {
+      found.push((path, relative));
+    }
+  }
+  Ok(())
+}
+
+pub enum Outcome {
+  Written,
+  UpToDate, // the existing .br is newer than the file
+  NotWorthIt, // the saving was under the threshold, so no .br is kept
+  Failed(String),
+}
+
+pub struct AssetReport {
+  pub relative_path: String,
+  pub original_size: u64,
+  pub compressed_size: Option<u64>,
+  pub outcome: Outcome,
+}
+
+pub struct Batch {
+  files: Vec<(PathBuf, String)>,
+  params: BrotliEncoderParams,
+  buffer_size: usize,
+  min_saving_percent: f64,
+}
+
+type PrecompressPool = WorkerPool<AssetReport, (), HeapAllocator, Batch>;
+
+fn precompress_one(path: &Path, relative_path: &str, batch: &Batch) -> Result<AssetReport, String> {
+  let metadata = fs::metadata(path).map_err(|e| e.to_string())?;
+  let mut report = AssetReport {
+    relative_path: relative_path.to_string(),
+    original_size: metadata.len(),
+    compressed_size: None,
+    outcome: Outcome::UpToDate,
+  };
+  let output_path = compressed_path(path);
+  let existing = fs::metadata(&output_path).ok();
+  if let Some(ref existing) = existing {
+    if let (Ok(compressed_time), Ok(source_time)) = (existing.modified(), metadata.modified()) {
+      if compressed_time >= source_time {
+        report.compressed_size = Some(existing.len());
+        return Ok(report);
+      }
+    }
+  }
+  let mut input = Vec::new();
+  File::open(path).and_then(|mut file| file.read_to_end(&mut input)).map_err(|e| e.to_string())?;
+  let mut params = batch.params.clone();
+  params.mode = mode_for(path);
+  params.size_hint = input.len();
+  let mut output = Vec::new();
+  compress(&mut &input[..], &mut output, batch.buffer_size, &params, &[], 1).map_err(|e| e.to_string())?;
+  report.original_size = input.len() as u64;
+  report.compressed_size = Some(output.len() as u64);
+  let saving = if input.len() == 0 { 0.0 } else { 100.0 * (1.0 - output.len() as f64 / input.len() as f64) };
+  if saving < batch.min_saving_percent {
+    if existing.is_some() {
+      // an out of date .br would otherwise be served in place of the new file
+      fs::remove_file(&output_path).map_err(|e| e.to_string())?;
+    }
+    report.outcome = Outcome::NotWorthIt;
+    return Ok(report);
+  }
+  // written under a temporary name and renamed, so a server never sees half a file
+  let (temporary, mut file) = cli::create_temporary(&output_path).map_err(|e| e.to_string())?;
+  let written = file.write_all(&output[..]).and_then(|_| {
+    file.set_permissions(metadata.permissions())
+  }).and_then(|_| fs::rename(&temporary, &output_path));
+  if let Err(e) = written {
+    let _ = fs::remove_file(&temporary);
+    return Err(e.to_string());
+  }
+  report.outcome = Outcome::Written;
+  Ok(report)
+}
+
+fn precompress_job(_extra: (), index: usize, _num_jobs: usize, batch: &Batch, _alloc: HeapAllocator) -> AssetReport {
+  let (ref path, ref relative_path) = batch.files[index];
+  match precompress_one(path, relative_path, batch) {
+    Ok(report) => report,
+    Err(message) => AssetReport {
+      relative_path: relative_path.clone(),
+      original_size: 0,
+      compressed_size: None,
+      outcome: Outcome::Failed(message),
+    },
+  }
+}
+
+fn json_string(value: &str) -> String {
+  let mut ret = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' => ret.push_str("\\\""),
+      '\\' => ret.push_str("\\\\"),
+      c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+      c => ret.push(c),
+    }
+  }
+  ret.push('"');
+  ret
+}
+
+pub fn json_line(report: &AssetReport) -> String {
+  let (outcome, error) = match report.outcome {
+    Outcome::Written => ("written", None),
+    Outcome::UpToDate => ("up-to-date", None),
+    Outcome::NotWorthIt => ("below-threshold", None),
+    Outcome::Failed(ref message) => ("failed", Some(message)),
+  };
+  let mut ret = format!("{{\"path\":{},\"original_size\":{},\"compressed_size\":{},\"outcome\":\"{}\"",
+                        json_string(&report.relative_path),
+                        report.original_size,
+                        match report.compressed_size { Some(size) => size.to_string(), None => "null".to_string() },
+                        outcome);
+  if let Some(message) = error {
+    ret.push_str(&format!(",\"error\":{}", json_string(message)));
+  }
+  ret.push('}');
+  ret
+}
+
+/// precompresses every matching file under root with num_threads workers and returns the exit status
+pub fn run(root: &str,
+           options: &Options,
+           params: &BrotliEncoderParams,
+           buffer_size: usize,
+           num_threads: usize) -> i32 {
+  let root_path = Path::new(root);
+  let mut files = Vec::new();
+  if let Err(e) = walk(root_path, root_path, options, &mut files) {
+    eprintln!("brotli: {}: {}", root, e);
+    return cli::EXIT_ERROR;
+  }
+  let num_jobs = files.len();
+  let mut pool = PrecompressPool::new(num_threads);
+  let mut batch = Owned::new(Batch {
+    files: files,
+    params: params.clone(),
+    buffer_size: buffer_size,
+    min_saving_percent: options.min_saving_percent,
+  });
+  let mut locked_batch = pool.make_spawner(&mut batch);
+  let mut work: Vec<_> = (0..num_jobs).map(|_| SendAlloc::new(HeapAllocator::default(), ())).collect();
+  for (index, item) in work.iter_mut().enumerate() {
+    pool.spawn(&mut locked_batch, item, index, num_jobs, precompress_job);
+  }
+  let mut report_output: Option<Box<dyn Write>> = match options.report {
+    None => None,
+    Some(ref name) if name == "-" => Some(Box::new(io::stdout())),
+    Some(ref name) => match File::create(name) {
+      Ok(file) => Some(Box::new(io::BufWriter::new(file))),
+      Err(e) => {
+        eprintln!("brotli: {}: {}", name, e);
+        return cli::EXIT_ERROR;
+      },
+    },
+  };
+  let mut status = cli::EXIT_OK;
+  let (mut num_written, mut total_original, mut total_compressed) = (0usize, 0u64, 0u64);
+  for item in work.into_iter() {
+    let report = match item.0 {
+      InternalSendAlloc::Join(join) => match join.join() {
+        Ok(report) => report,
+        Err(e) => {
+          eprintln!("brotli: precompression job failed: {:?}", e);
+          status = cli::EXIT_ERROR;
+          continue;
+        },
+      },
+      _ => panic!("job was not spawned"),
+    };
+    if let Outcome::Failed(ref message) = report.outcome {
+      eprintln!("brotli: {}: {}", report.relative_path, message);
+      status = cli::EXIT_ERROR;
+    }
+    if let (&Outcome::Written, Some(size)) = (&report.outcome, report.compressed_size) {
+      num_written += 1;
+      total_original += report.original_size;
+      total_compressed += size;
+      if options.verbose {
+        eprintln!("{}:\t{} -> {} bytes", report.relative_path, report.original_size, size);
+      }
+    }
+    if let Some(ref mut output) = report_output {
+      if let Err(e) = writeln!(output, "{}", json_line(&report)) {
+        eprintln!("brotli: report: {}", e);
+        status = cli::EXIT_ERROR;
+      }
+    }
+  }
+  if options.verbose {
+    eprintln!("precompressed {} of {} files: {} -> {} bytes", num_written, num_jobs, total_original, total_compressed);
+  }
+  pool.shutdown();
+  status
+}
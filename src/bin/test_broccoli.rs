@@ -6,6 +6,8 @@ extern crate brotli_decompressor;
 use brotli_decompressor::{CustomRead, CustomWrite};
 use super::brotli::enc::BrotliEncoderParams;
 use super::brotli::concat::{BroCatli, BroCatliResult};
+#[cfg(feature="std")]
+use super::brotli::concat::{AppendableLogWriter, BroCatliReader, BroCatliWriter, magic_header, reencode_for_concat, scan_window_sizes, splices_cleanly, stream_window_size, uncat, uncat_members};
 use super::integration_tests::UnlimitedBuffer;
 static RANDOM_THEN_UNICODE : &'static [u8] = include_bytes!("../../testdata/random_then_unicode");
 static ALICE: &'static[u8]  = include_bytes!("../../testdata/alice29.txt");
@@ -422,3 +424,474 @@ fn test_concat() {
     concat_many_subsets(&mut files[..], &mut ufiles[..], None);
     concat_many_subsets(&mut files[..], &mut ufiles[..], Some(28)); // FIXME: make this 28
 }
+
+fn compress_catable(files: &[&[u8]]) -> Vec<Vec<u8>> {
+  let mut first = true;
+  files.iter().map(|data| {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.appendable = first;
+    params.catable = !first;
+    params.use_dictionary = first;
+    first = false;
+    let mut src = UnlimitedBuffer::new(data);
+    let mut dst = UnlimitedBuffer::new(&[]);
+    super::compress(&mut src, &mut dst, 4096, &params, &[], 1).unwrap();
+    dst.data().to_vec()
+  }).collect()
+}
+
+fn check_concatenation(files: &[&[u8]], concatenated: &[u8]) {
+  let mut input = UnlimitedBuffer::new(concatenated);
+  let mut rt = UnlimitedBuffer::new(&[]);
+  super::decompress(&mut input, &mut rt, 65536, Rebox::default()).unwrap();
+  let expected: Vec<u8> = files.iter().flat_map(|data| data.iter().cloned()).collect();
+  assert_eq!(rt.data(), &expected[..]);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_writer_concatenates_files() {
+  use std::io::Write;
+  let files = [ALICE, EMPTY, &[8][..], QUICKFOX, UKKONOOA];
+  let brotli_files = compress_catable(&files[..]);
+  let mut writer = BroCatliWriter::new(Vec::new(), 3);
+  for brotli in brotli_files.iter() {
+    writer.start_new_file();
+    for chunk in brotli.chunks(7) {
+      writer.write_all(chunk).unwrap();
+    }
+    writer.flush().unwrap();
+  }
+  check_concatenation(&files[..], &writer.into_inner()[..]);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_reader_concatenates_sources() {
+  use std::io::Read;
+  let files = [UKKONOOA, &[8, 9][..], EMPTY, ASYOULIKE];
+  let brotli_files = compress_catable(&files[..]);
+  for buffer_size in [1usize, 5, 4096].iter() {
+    let mut reader = BroCatliReader::new(brotli_files.iter().map(|brotli| &brotli[..]), *buffer_size);
+    let mut concatenated = Vec::new();
+    let mut chunk = [0u8; 11];
+    loop {
+      let size = reader.read(&mut chunk[..]).unwrap();
+      if size == 0 {
+        break;
+      }
+      concatenated.extend_from_slice(&chunk[..size]);
+    }
+    check_concatenation(&files[..], &concatenated[..]);
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_adapters_report_uncatable_files() {
+  use std::io::{ErrorKind, Read, Write};
+  let mut brotli_files = compress_catable(&[UKKONOOA, QUICKFOX][..]);
+  let mut params = BrotliEncoderParams::default();
+  params.quality = 5;
+  let mut dst = UnlimitedBuffer::new(&[]);
+  super::compress(&mut UnlimitedBuffer::new(ALICE), &mut dst, 4096, &params, &[], 1).unwrap();
+  brotli_files[1] = dst.data().to_vec();
+
+  let mut writer = BroCatliWriter::new(Vec::new(), 4096);
+  let mut result = Ok(());
+  for brotli in brotli_files.iter() {
+    writer.start_new_file();
+    result = result.and_then(|_| writer.write_all(brotli));
+  }
+  let err = result.unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::InvalidData);
+  assert_eq!(err.get_ref().unwrap().downcast_ref::<BroCatliResult>(),
+             Some(&BroCatliResult::BrotliFileNotCraftedForConcatenation));
+
+  let mut reader = BroCatliReader::new(brotli_files.iter().map(|brotli| &brotli[..]), 4096);
+  let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+  assert_eq!(err.get_ref().unwrap().downcast_ref::<BroCatliResult>(),
+             Some(&BroCatliResult::BrotliFileNotCraftedForConcatenation));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_reencoding_writer_accepts_ordinary_streams() {
+  use std::io::Write;
+  let files = [UKKONOOA, QUICKFOX, ALICE, &[8][..], ASYOULIKE];
+  let mut brotli_files = compress_catable(&files[..]);
+  let mut params = BrotliEncoderParams::default();
+  params.quality = 5;
+  params.lgwin = 18;
+  for index in [0usize, 2, 3].iter() {
+    // a plain stream with neither the empty last metablock nor the catable header; the third one
+    // also has a larger window than the first
+    let mut dst = UnlimitedBuffer::new(&[]);
+    params.lgwin = if *index == 2 { 24 } else { 18 };
+    super::compress(&mut UnlimitedBuffer::new(files[*index]), &mut dst, 4096, &params, &[], 1).unwrap();
+    brotli_files[*index] = dst.data().to_vec();
+  }
+  for buffer_size in [1usize, 4096].iter() {
+    let mut writer = BroCatliWriter::with_reencode(Vec::new(), *buffer_size, &params);
+    for brotli in brotli_files.iter() {
+      writer.start_new_file();
+      for chunk in brotli.chunks(1000) {
+        writer.write_all(chunk).unwrap();
+      }
+    }
+    let concatenated = writer.into_inner();
+    check_concatenation(&files[..], &concatenated[..]);
+    // the catable files went in bitwise, apart from their realigned headers and final bits
+    let tail = &brotli_files[4][8..brotli_files[4].len() - 2];
+    assert!(concatenated.windows(tail.len()).any(|window| window == tail));
+  }
+  assert!(splices_cleanly(&brotli_files[1][..], 22));
+  assert!(!splices_cleanly(&brotli_files[2][..], 22));
+  assert!(!splices_cleanly(&brotli_files[2][..], 18));
+  // a first stream keeps its metablocks: only the header of the last one and the final bits change
+  let appendable = reencode_for_concat(&brotli_files[2][..], 0, &params).unwrap();
+  assert!(appendable.len() <= brotli_files[2].len() + 1);
+  assert!(appendable.iter().zip(brotli_files[2].iter()).filter(|&(a, b)| a != b).count() <= 6);
+  assert!(splices_cleanly(&appendable[..], 0));
+  let catable = reencode_for_concat(&brotli_files[2][..], 24, &params).unwrap();
+  assert!(splices_cleanly(&catable[..], 24));
+  // and a stream that was catable already only gets a new header
+  let recatable = reencode_for_concat(&brotli_files[4][..], 22, &params).unwrap();
+  assert!(recatable.len() <= brotli_files[4].len() + 1);
+  assert!(splices_cleanly(&recatable[..], 22));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_window_grows_to_largest_file() {
+  use std::io::Write;
+  let files = [QUICKFOX, ALICE, &[8][..], UKKONOOA, ASYOULIKE, RANDOM10K];
+  for window_sizes in [[10u8, 15, 17, 22, 16, 24], [17, 16, 18, 10, 24, 20], [16, 16, 12, 16, 15, 16]].iter() {
+    let brotli_files: Vec<Vec<u8>> = files.iter().zip(window_sizes.iter()).map(|(data, lgwin)| {
+      let mut params = BrotliEncoderParams::default();
+      params.quality = 5;
+      params.catable = true;
+      params.use_dictionary = false;
+      params.lgwin = i32::from(*lgwin);
+      let mut dst = UnlimitedBuffer::new(&[]);
+      super::compress(&mut UnlimitedBuffer::new(data), &mut dst, 4096, &params, &[], 1).unwrap();
+      dst.data().to_vec()
+    }).collect();
+    let mut writer = BroCatliWriter::with_window_growth(Vec::new(), 7);
+    for brotli in brotli_files.iter() {
+      writer.start_new_file();
+      writer.write_all(&brotli[..]).unwrap();
+    }
+    let concatenated = writer.into_inner();
+    check_concatenation(&files[..], &concatenated[..]);
+    let largest = window_sizes.iter().cloned().max().unwrap();
+    assert_eq!(stream_window_size(&concatenated[..]), Ok(largest));
+    // the two pass version fixes the window up front instead
+    let mut inputs: Vec<std::io::Cursor<&[u8]>> = brotli_files.iter().map(|brotli| std::io::Cursor::new(&brotli[..])).collect();
+    let mut writer = BroCatliWriter::with_state(Vec::new(), 4096, scan_window_sizes(&mut inputs[..]).unwrap());
+    for input in inputs.iter_mut() {
+      writer.start_new_file();
+      std::io::copy(input, &mut writer).unwrap();
+    }
+    let concatenated = writer.into_inner();
+    check_concatenation(&files[..], &concatenated[..]);
+    assert_eq!(stream_window_size(&concatenated[..]), Ok(largest));
+  }
+  // without growth a larger window is refused, and growth needs the first file to be catable
+  let mut brotli_files = compress_catable(&[ALICE, QUICKFOX]);
+  let mut params = BrotliEncoderParams::default();
+  params.quality = 5;
+  params.catable = true;
+  params.use_dictionary = false;
+  params.lgwin = 24;
+  let mut dst = UnlimitedBuffer::new(&[]);
+  super::compress(&mut UnlimitedBuffer::new(QUICKFOX), &mut dst, 4096, &params, &[], 1).unwrap();
+  brotli_files[1] = dst.data().to_vec();
+  for grow in [false, true].iter() {
+    let mut writer = if *grow {
+      BroCatliWriter::with_window_growth(Vec::new(), 4096)
+    } else {
+      BroCatliWriter::new(Vec::new(), 4096)
+    };
+    writer.start_new_file();
+    writer.write_all(&brotli_files[0][..]).unwrap();
+    writer.start_new_file();
+    let err = writer.write_all(&brotli_files[1][..]).unwrap_err();
+    assert_eq!(err.into_inner().unwrap().downcast_ref::<BroCatliResult>(),
+               Some(&BroCatliResult::WindowSizeLargerThanPreviousFile));
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_magic_headers_merge() {
+  use std::io::Write;
+  let files = [ALICE, QUICKFOX, &[8][..], UKKONOOA, ASYOULIKE];
+  let total = files.iter().map(|data| data.len() as u64).sum::<u64>();
+  // which files have a magic number header, whether the first one is only appendable,
+  // whether the window grows, and the header expected on the output
+  let cases = [([true; 5], false, false, Some((0x81, total))),
+               ([true; 5], true, false, Some((0x82, total))),
+               ([true, true, false, true, true], false, true, Some((0x81, 0))),
+               ([false, true, true, true, true], false, false, Some((0x82, 0))),
+               ([false, true, true, true, true], true, false, None)];
+  for &(magic, appendable, grow, expected) in cases.iter() {
+    let brotli_files: Vec<Vec<u8>> = files.iter().enumerate().map(|(index, data)| {
+      let mut params = BrotliEncoderParams::default();
+      params.quality = 5;
+      params.appendable = appendable && index == 0;
+      params.catable = !params.appendable;
+      params.use_dictionary = params.appendable;
+      params.magic_number = magic[index];
+      params.size_hint = data.len();
+      params.lgwin = if grow { 16 + index as i32 } else { 20 };
+      let mut dst = UnlimitedBuffer::new(&[]);
+      super::compress(&mut UnlimitedBuffer::new(data), &mut dst, 4096, &params, &[], 1).unwrap();
+      assert_eq!(magic_header(dst.data()).is_some(), magic[index]);
+      dst.data().to_vec()
+    }).collect();
+    for buffer_size in [1usize, 4096].iter() {
+      let mut state = if grow { BroCatli::new_with_window_growth() } else { BroCatli::new() };
+      state.merge_magic_headers();
+      let mut writer = BroCatliWriter::with_state(Vec::new(), *buffer_size, state);
+      for brotli in brotli_files.iter() {
+        writer.start_new_file();
+        for chunk in brotli.chunks(7) {
+          writer.write_all(chunk).unwrap();
+        }
+      }
+      let concatenated = writer.into_inner();
+      check_concatenation(&files[..], &concatenated[..]);
+      assert_eq!(magic_header(&concatenated[..]), expected);
+      let magic_numbers = concatenated.windows(3).filter(
+        |window| window[..2] == [0xe1, 0x97] && window[2] >= 0x80 && window[2] <= 0x82).count();
+      assert_eq!(magic_numbers, expected.is_some() as usize);
+    }
+  }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_uncat_splits_marked_concatenation() {
+  use std::io::Write;
+  let files = [ALICE, &[8][..], QUICKFOX, UKKONOOA, RANDOM10K, ASYOULIKE];
+  for window_size in [None, Some(22u8)].iter() {
+    // with the window fixed up front, the first file has to be catable as well
+    let brotli_files = match *window_size {
+      Some(_) => compress_catable(&[&[][..]].iter().chain(files.iter()).cloned().collect::<Vec<&[u8]>>()[..])[1..].to_vec(),
+      None => compress_catable(&files[..]),
+    };
+    for buffer_size in [1usize, 4096].iter() {
+      let mut state = match *window_size {
+        Some(window_size) => BroCatli::new_with_window_size(window_size),
+        None => BroCatli::new(),
+      };
+      state.mark_members();
+      let mut writer = BroCatliWriter::with_state(Vec::new(), *buffer_size, state);
+      for brotli in brotli_files.iter() {
+        writer.start_new_file();
+        writer.write_all(&brotli[..]).unwrap();
+      }
+      let concatenated = writer.into_inner();
+      check_concatenation(&files[..], &concatenated[..]);
+      let members = uncat(&concatenated[..]).unwrap();
+      assert_eq!(members.len(), files.len());
+      for (index, (member, data)) in members.iter().zip(files.iter()).enumerate() {
+        check_concatenation(&[data], &member[..]);
+        if index != 0 && brotli_files[index].len() > 8 {
+          // apart from the realigned header, a member comes back bit for bit
+          assert!(member.ends_with(&brotli_files[index][8..]));
+        }
+      }
+      let indices: Vec<u32> = uncat_members(&concatenated[..]).unwrap().map(|member| member.index).collect();
+      assert_eq!(indices, (0..files.len() as u32).collect::<Vec<u32>>());
+    }
+  }
+  // without markers the whole stream is one member
+  let brotli_files = compress_catable(&files[..1]);
+  let members = uncat(&brotli_files[0][..]).unwrap();
+  assert_eq!(members, vec![brotli_files[0].clone()]);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_uncat_skips_markers_stored_in_a_member() {
+  use std::io::Write;
+  let mark_concatenation = |brotli_files: &[Vec<u8>]| {
+    let mut state = BroCatli::new();
+    state.mark_members();
+    let mut writer = BroCatliWriter::with_state(Vec::new(), 4096, state);
+    for brotli in brotli_files.iter() {
+      writer.start_new_file();
+      writer.write_all(&brotli[..]).unwrap();
+    }
+    writer.into_inner()
+  };
+  // random data compresses to stored metablocks, which keep the markers of inner as they are
+  let inner = mark_concatenation(&compress_catable(&[RANDOM10K, RANDOM_THEN_UNICODE])[..]);
+  let stored = compress_catable(&[&inner[..]]).remove(0);
+  assert!(stored.windows(3).any(|window| window == [0xe1, 0x97, 0x8b]));
+  assert_eq!(uncat(&stored[..]).unwrap(), vec![stored.clone()]);
+  let outer = mark_concatenation(&[stored, compress_catable(&[EMPTY, QUICKFOX]).remove(1)]);
+  let members = uncat(&outer[..]).unwrap();
+  assert_eq!(members.len(), 2);
+  check_concatenation(&[&inner[..]], &members[0][..]);
+  check_concatenation(&[QUICKFOX], &members[1][..]);
+  let mut outer_members = uncat_members(&outer[..]).unwrap();
+  let mut inner_members = uncat_members(&inner[..]).unwrap();
+  assert_eq!(outer_members.by_ref().count(), 2);
+  assert_eq!(inner_members.by_ref().count(), 2);
+  assert!(outer_members.member_tag().is_some());
+  assert!(outer_members.member_tag() != inner_members.member_tag());
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_catable_streams_use_dictionary_a_window_in() {
+  use std::io::{ErrorKind, Write};
+  let compress_at = |data: &[u8], lgwin: i32, catable_dictionary: bool| {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.catable = true;
+    params.use_dictionary = false;
+    params.catable_dictionary = catable_dictionary;
+    params.magic_number = !catable_dictionary; // implied by catable_dictionary
+    params.size_hint = data.len();
+    params.lgwin = lgwin;
+    let mut dst = UnlimitedBuffer::new(&[]);
+    super::compress(&mut UnlimitedBuffer::new(data), &mut dst, 4096, &params, &[], 1).unwrap();
+    dst.data().to_vec()
+  };
+  let files = [ASYOULIKE, QUICKFOX, ALICE, &[8][..]];
+  let brotli_files: Vec<Vec<u8>> = files.iter().map(|data| compress_at(data, 16, true)).collect();
+  assert_eq!(magic_header(&brotli_files[2][..]), Some((0x83, ALICE.len() as u64)));
+  // past the first window, dictionary words make up for the references into the data before
+  assert!(brotli_files[2].len() < compress_at(ALICE, 16, false).len());
+  for merge in [false, true].iter() {
+    let mut state = BroCatli::new();
+    if *merge {
+      state.merge_magic_headers();
+    }
+    let mut writer = BroCatliWriter::with_state(Vec::new(), 4096, state);
+    for brotli in brotli_files.iter() {
+      writer.start_new_file();
+      for chunk in brotli.chunks(7) {
+        writer.write_all(chunk).unwrap();
+      }
+    }
+    let concatenated = writer.into_inner();
+    check_concatenation(&files[..], &concatenated[..]);
+    if *merge {
+      assert_eq!(magic_header(&concatenated[..]).map(|header| header.0), Some(0x83));
+    }
+  }
+  // in a larger window the dictionary words would be addressed differently
+  let mut writer = BroCatliWriter::new(Vec::new(), 4096);
+  writer.start_new_file();
+  writer.write_all(&compress_at(QUICKFOX, 18, false)[..]).unwrap();
+  writer.start_new_file();
+  let err = writer.write_all(&brotli_files[2][..]).unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::InvalidData);
+  assert_eq!(err.get_ref().unwrap().downcast_ref::<BroCatliResult>(),
+             Some(&BroCatliResult::BrotliFileNotCraftedForConcatenation));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_appendable_log_survives_reopening_and_torn_writes() {
+  use std::fs;
+  use std::io::Write;
+  let path = std::env::temp_dir().join(format!("brotli-log-{}", std::process::id()));
+  let mut params = BrotliEncoderParams::default();
+  params.quality = 5;
+  params.lgwin = 18;
+  let batches = [ASYOULIKE, &[8][..], QUICKFOX, ALICE, UKKONOOA];
+  {
+    let mut log = AppendableLogWriter::create(&path, &params).unwrap();
+    log.flush().unwrap();
+    for batch in batches[..2].iter() {
+      log.write_all(batch).unwrap();
+      log.flush().unwrap();
+      assert!(fs::metadata(&path).unwrap().len() > 0);
+    }
+    assert_eq!(log.batches(), 2);
+    log.write_all(batches[2]).unwrap();
+  } // the last batch goes in on drop
+  check_concatenation(&batches[..3], &fs::read(&path).unwrap()[..]);
+  let complete = fs::read(&path).unwrap();
+  {
+    let mut log = AppendableLogWriter::open(&path, &params).unwrap();
+    assert_eq!(log.batches(), 3);
+    log.write_all(batches[3]).unwrap();
+    log.flush().unwrap();
+  }
+  let appended = fs::read(&path).unwrap();
+  check_concatenation(&batches[..4], &appended[..]);
+  assert_eq!(uncat(&appended[..]).unwrap().len(), 4);
+  // a write torn before the end of the previous batch got rewritten
+  for torn_len in [1usize, 7, appended.len() - complete.len()].iter() {
+    let mut torn = complete.clone();
+    torn.extend_from_slice(&appended[complete.len()..complete.len() + torn_len]);
+    fs::write(&path, &torn[..]).unwrap();
+    {
+      let mut log = AppendableLogWriter::open(&path, &params).unwrap();
+      assert_eq!(log.batches(), 3);
+      assert_eq!(fs::read(&path).unwrap(), complete);
+      log.write_all(batches[4]).unwrap();
+    }
+    check_concatenation(&[batches[0], batches[1], batches[2], batches[4]], &fs::read(&path).unwrap()[..]);
+  }
+  // and a first batch that never made it all the way
+  fs::write(&path, &complete[..100]).unwrap();
+  {
+    let mut log = AppendableLogWriter::open(&path, &params).unwrap();
+    assert_eq!(log.batches(), 0);
+    log.write_all(batches[4]).unwrap();
+  }
+  check_concatenation(&batches[4..], &fs::read(&path).unwrap()[..]);
+  fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature="std")]
+#[test]
+fn test_appendable_log_reopens_past_stored_markers() {
+  use std::fs;
+  use std::io::Write;
+  let path = std::env::temp_dir().join(format!("brotli-log-stored-{}", std::process::id()));
+  let mut params = BrotliEncoderParams::default();
+  params.quality = 5;
+  params.lgwin = 18;
+  // a first batch holding a marked concatenation as it is, and enough random batches after it
+  // that the file spans several of the chunks it is read in
+  let mut state = BroCatli::new();
+  state.mark_members();
+  let mut writer = BroCatliWriter::with_state(Vec::new(), 4096, state);
+  for brotli in compress_catable(&[RANDOM10K, QUICKFOX]).iter() {
+    writer.start_new_file();
+    writer.write_all(&brotli[..]).unwrap();
+  }
+  let inner = writer.into_inner();
+  let mut batches: Vec<&[u8]> = vec![&inner[..]];
+  batches.extend((0..16).map(|_| RANDOM10K));
+  {
+    let mut log = AppendableLogWriter::create(&path, &params).unwrap();
+    for batch in batches.iter() {
+      log.write_all(batch).unwrap();
+      log.flush().unwrap();
+    }
+  }
+  assert!(fs::metadata(&path).unwrap().len() > 2 * 65536);
+  {
+    let mut log = AppendableLogWriter::open(&path, &params).unwrap();
+    assert_eq!(log.batches(), batches.len() as u32);
+    log.write_all(ALICE).unwrap();
+  }
+  batches.push(ALICE);
+  let appended = fs::read(&path).unwrap();
+  check_concatenation(&batches[..], &appended[..]);
+  let members = uncat(&appended[..]).unwrap();
+  assert_eq!(members.len(), batches.len());
+  check_concatenation(&[&inner[..]], &members[0][..]);
+  fs::remove_file(&path).unwrap();
+}
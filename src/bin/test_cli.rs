@@ -0,0 +1,224 @@
+#![cfg(test)]
+use std;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use super::cli;
+use super::precompress;
+use super::bench;
+use super::brotli::enc::BrotliEncoderParams;
+use super::brotli::enc::backward_references::BrotliEncoderMode;
+static ALICE: &'static[u8]  = include_bytes!("../../testdata/alice29.txt");
+
+// a fresh directory per test, since the tests run concurrently
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("brotli-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(options: &cli::Options, inputs: &[&PathBuf]) -> i32 {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    let inputs: Vec<String> = inputs.iter().map(|path| path.to_str().unwrap().to_string()).collect();
+    cli::run(options, &inputs[..], &cli::Settings{
+        params: &params,
+        custom_dictionary: &[],
+        buffer_size: 65536,
+        num_threads: 1,
+        use_work_pool: false,
+    })
+}
+
+#[test]
+fn cli_flags_group_like_gzip() {
+    let mut options = cli::Options::default();
+    assert!(cli::parse_flag(&mut options, "-dkv"));
+    assert!(options.decompress && options.keep && options.verbose && !options.force);
+    assert!(cli::parse_flag(&mut options, "--suffix=.brotli"));
+    assert_eq!(options.suffix, ".brotli");
+    // tuning flags that merely start with a gzip letter are left alone
+    assert!(!cli::parse_flag(&mut options, "-findprior"));
+    assert!(!cli::parse_flag(&mut options, "-customdictionary=x"));
+    assert!(!options.force && !options.to_stdout);
+    assert!(cli::takes_value("-o") && cli::takes_value("-S") && !cli::takes_value("-c"));
+}
+
+#[test]
+fn cli_thread_counts_are_checked() {
+    assert_eq!(super::parse_num_threads("0"), Some(1));
+    assert_eq!(super::parse_num_threads("1"), Some(1));
+    assert!(super::parse_num_threads("100000").unwrap() <= std::thread::available_parallelism().unwrap().get());
+    assert_eq!(super::parse_num_threads(""), None);
+    assert_eq!(super::parse_num_threads("-3"), None);
+    assert_eq!(super::parse_num_threads("99999999999999999999999"), None);
+}
+
+#[test]
+fn cli_round_trips_files_in_place() {
+    let dir = scratch_dir("round-trip");
+    let original = dir.join("alice.txt");
+    let compressed = dir.join("alice.txt.br");
+    fs::write(&original, ALICE).unwrap();
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1234567890);
+    fs::File::options().write(true).open(&original).unwrap().set_modified(mtime).unwrap();
+
+    assert_eq!(run(&cli::Options::default(), &[&original]), cli::EXIT_OK);
+    assert!(!original.exists());
+    assert_eq!(fs::metadata(&compressed).unwrap().modified().unwrap(), mtime);
+    assert!(fs::metadata(&compressed).unwrap().len() < ALICE.len() as u64);
+
+    let mut decompress = cli::Options::default();
+    decompress.decompress = true;
+    decompress.keep = true;
+    assert_eq!(run(&decompress, &[&compressed]), cli::EXIT_OK);
+    let mut round_tripped = Vec::new();
+    fs::File::open(&original).unwrap().read_to_end(&mut round_tripped).unwrap();
+    assert_eq!(&round_tripped[..], ALICE);
+    assert_eq!(fs::metadata(&original).unwrap().modified().unwrap(), mtime);
+    assert!(compressed.exists());
+
+    // the output exists now, so without -f nothing is touched
+    assert_eq!(run(&decompress, &[&compressed]), cli::EXIT_ERROR);
+    decompress.force = true;
+    assert_eq!(run(&decompress, &[&compressed]), cli::EXIT_OK);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cli_skips_files_without_the_suffix() {
+    let dir = scratch_dir("suffix");
+    let plain = dir.join("plain.txt");
+    fs::write(&plain, b"not compressed").unwrap();
+    let mut options = cli::Options::default();
+    options.decompress = true;
+    assert_eq!(run(&options, &[&plain]), cli::EXIT_WARNING);
+    assert!(plain.exists());
+    // a hard error outranks a warning
+    assert_eq!(run(&options, &[&plain, &dir.join("missing.br")]), cli::EXIT_ERROR);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cli_leaves_no_output_when_a_file_fails() {
+    let dir = scratch_dir("failure");
+    let corrupt = dir.join("corrupt.txt.br");
+    fs::write(&corrupt, &ALICE[..4096]).unwrap();
+    let mut options = cli::Options::default();
+    options.decompress = true;
+    assert_eq!(run(&options, &[&corrupt]), cli::EXIT_ERROR);
+    let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(names, vec![std::ffi::OsString::from("corrupt.txt.br")]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn cli_leaves_an_existing_temporary_file_alone() {
+    let dir = scratch_dir("temporary");
+    let input = dir.join("alice.txt");
+    fs::write(&input, ALICE).unwrap();
+    let temporary = dir.join(format!("alice.txt.br.{}.tmp", std::process::id()));
+    fs::write(&temporary, b"not ours").unwrap();
+    let mut options = cli::Options::default();
+    options.keep = true;
+    assert_eq!(run(&options, &[&input]), cli::EXIT_ERROR);
+    assert_eq!(fs::read(&temporary).unwrap(), b"not ours".to_vec());
+    assert!(!dir.join("alice.txt.br").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn precompress_globs_match_like_a_shell() {
+    assert!(precompress::glob_match(b"*.css", b"site.css"));
+    assert!(!precompress::glob_match(b"*.css", b"css/site.css"));
+    assert!(precompress::glob_match(b"css/*.css", b"css/site.css"));
+    assert!(precompress::glob_match(b"**/*.css", b"site.css"));
+    assert!(precompress::glob_match(b"**/*.css", b"a/b/site.css"));
+    assert!(precompress::glob_match(b"fonts/**", b"fonts/a/b.ttf"));
+    assert!(precompress::glob_match(b"?.js", b"a.js"));
+    assert!(!precompress::glob_match(b"?.js", b"ab.js"));
+    assert_eq!(precompress::mode_for(Path::new("a/b.TTF")) as i32, BrotliEncoderMode::BROTLI_MODE_FONT as i32);
+    assert_eq!(precompress::mode_for(Path::new("index.html")) as i32, BrotliEncoderMode::BROTLI_MODE_TEXT as i32);
+    assert_eq!(precompress::mode_for(Path::new("app.wasm")) as i32, BrotliEncoderMode::BROTLI_MODE_GENERIC as i32);
+    // woff is compressed already, so it is no font to the encoder
+    assert_eq!(precompress::mode_for(Path::new("a.woff")) as i32, BrotliEncoderMode::BROTLI_MODE_GENERIC as i32);
+}
+
+#[test]
+fn precompress_writes_worthwhile_siblings_once() {
+    let dir = scratch_dir("precompress");
+    fs::create_dir_all(dir.join("css")).unwrap();
+    fs::write(dir.join("index.html"), ALICE).unwrap();
+    fs::write(dir.join("css/site.css"), ALICE).unwrap();
+    fs::write(dir.join("tiny.txt"), b"x").unwrap();
+    fs::write(dir.join("photo.png"), ALICE).unwrap();
+    let report = dir.join("report.json");
+    let mut options = precompress::Options::default();
+    options.report = Some(report.to_str().unwrap().to_string());
+    options.exclude.push("css/**".to_string());
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    let run = |options: &precompress::Options| precompress::run(dir.to_str().unwrap(), options, &params, 65536, 3);
+
+    assert_eq!(run(&options), cli::EXIT_OK);
+    let mut compressed = Vec::new();
+    fs::File::open(dir.join("index.html.br")).unwrap().read_to_end(&mut compressed).unwrap();
+    let mut round_tripped = Vec::new();
+    super::decompress(&mut &compressed[..], &mut round_tripped, 65536, super::Rebox::default()).unwrap();
+    assert_eq!(&round_tripped[..], ALICE);
+    // excluded, not worth it, and not a text asset respectively
+    assert!(!dir.join("css/site.css.br").exists());
+    assert!(!dir.join("tiny.txt.br").exists());
+    assert!(!dir.join("photo.png.br").exists());
+    let mut lines = String::new();
+    fs::File::open(&report).unwrap().read_to_string(&mut lines).unwrap();
+    assert_eq!(lines.lines().count(), 2);
+    assert!(lines.contains("{\"path\":\"index.html\",\"original_size\":152089,\"compressed_size\":"));
+    assert!(lines.contains("\"outcome\":\"written\"}"));
+    assert!(lines.contains("{\"path\":\"tiny.txt\",\"original_size\":1,\"compressed_size\":5,\"outcome\":\"below-threshold\"}"));
+
+    // the .br is newer than its source now, so a second run leaves it be
+    assert_eq!(run(&options), cli::EXIT_OK);
+    lines.clear();
+    fs::File::open(&report).unwrap().read_to_string(&mut lines).unwrap();
+    assert!(lines.contains("\"outcome\":\"up-to-date\"}"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn bench_flags_build_the_matrix() {
+    let mut options = bench::Options::default();
+    assert!(bench::parse_flag(&mut options, "--bench-quality=2,9.5x"));
+    assert_eq!(options.qualities, vec![bench::Quality{quality: 2, q9_5: false}, bench::Quality{quality: 11, q9_5: true}]);
+    assert_eq!(options.qualities[1].name(), "9.5x");
+    assert!(bench::parse_flag(&mut options, "--bench-threads=1,4"));
+    assert_eq!(options.threads, vec![1, 4]);
+    assert!(!bench::parse_flag(&mut options, "--bench-quality=12"));
+    assert!(!bench::parse_flag(&mut options, "--bench-window=9"));
+    assert!(!bench::parse_flag(&mut options, "--bench-threads=0"));
+}
+
+#[test]
+fn bench_measures_a_round_trip() {
+    let params = BrotliEncoderParams::default();
+    let mut measurements = Vec::new();
+    for threads in [1, 2].iter() {
+        let measurement = bench::measure("alice29.txt", ALICE, &params, bench::Quality::parse("5").unwrap(), 20, *threads, 2, 65536).unwrap();
+        assert_eq!(measurement.original_size, ALICE.len());
+        assert!(measurement.ratio() > 2.0);
+        // peak memory is only counted when the bench-memory feature installs the counting allocator
+        assert_eq!(measurement.compress_peak.is_some(), cfg!(feature="bench-memory"));
+        assert!(measurement.compress_peak.unwrap_or(1) > 0 && measurement.decompress_peak.unwrap_or(1) > 0);
+        measurements.push(measurement);
+    }
+    let mut csv = Vec::new();
+    bench::write_csv(&mut csv, &measurements[..]).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("file,quality,lgwin,threads,original,compressed,ratio,"));
+    assert!(lines[1].starts_with("alice29.txt,5,20,1,152089,"));
+    assert!(lines[2].starts_with("alice29.txt,5,20,2,152089,"));
+}
@@ -184,3 +184,27 @@ fn test_custom_dict_for_multithreading() {
 }
 
 
+
+#[test]
+fn test_custom_dict_multithreaded() {
+    let mut raw = UnlimitedBuffer::new(ALICE);
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    let mut br = UnlimitedBuffer::new(&[]);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    let mut bad_rt = UnlimitedBuffer::new(&[]);
+    let dict = &ALICE[12515..23411];
+    super::compress(&mut raw, &mut br, 4096, &params, dict, 4).unwrap();
+    raw.reset_read();
+    let mut vec = Vec::<u8>::new();
+    vec.extend(dict);
+    super::decompress(&mut br, &mut rt, 4096, Rebox::from(vec)).unwrap();
+    assert_eq!(rt.data(), raw.data());
+    br.reset_read();
+    let mut wrong = Vec::<u8>::new();
+    wrong.extend(&dict[1..]);
+    match super::decompress(&mut br, &mut bad_rt, 4096, Rebox::from(wrong)) {
+        Ok(_) => assert!(bad_rt.data() != raw.data()),
+        Err(_) => {},
+    }
+}
@@ -5,8 +5,24 @@ extern crate core;
 extern crate brotli_decompressor;
 use super::new_brotli_heap_alloc;
 use brotli_decompressor::{SliceWrapperMut, SliceWrapper};
-use super::brotli::enc::{UnionHasher, BrotliEncoderParams, BrotliEncoderMaxCompressedSizeMulti, compress_multi, compress_multi_no_threadpool};
-use brotli::enc::threading::{SendAlloc,Owned};
+use super::brotli::enc::{UnionHasher, BrotliEncoderParams, BrotliEncoderMaxCompressedSizeMulti, compress_multi, compress_multi_no_threadpool,
+                         compress_worker_pool, new_work_pool,
+                         UnionChunkingPolicy, FixedSizeChunks, ContentDefinedChunks};
+#[cfg(feature="std")]
+use super::brotli::enc::submit_compress_worker_pool;
+#[cfg(feature="scoped-threads")]
+use super::brotli::enc::compress_multi_scoped;
+#[cfg(all(feature="rayon", feature="std"))]
+use super::brotli::enc::{RayonSpawner, compress_multi_rayon};
+#[cfg(all(feature="rayon", feature="std"))]
+extern crate rayon;
+use brotli::enc::threading::{SendAlloc,Owned, BatchSpawnableLite, Joinable, InternalSendAlloc, BrotliEncoderThreadError};
+use brotli::enc::WorkerPool;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use super::HeapAllocator;
+use brotli::enc::interface;
+use brotli::{InputPair, InputReferenceMut};
 
 use super::integration_tests::UnlimitedBuffer;
 static RANDOM_THEN_UNICODE : &'static [u8] = include_bytes!("../../testdata/random_then_unicode");
@@ -26,7 +42,7 @@ fn multi_threaded_split_compression_test(input_data: &'static[u8], num_threads:
         params.catable = true;
         params.use_dictionary = false;
     }
-    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), num_threads)]);
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &UnionChunkingPolicy::default(), num_threads)]);
     let mut alloc_per_thread = [
         SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
         SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
@@ -116,7 +132,7 @@ fn thread_spawn_per_job_split_compression_test(input_data: &'static[u8], num_thr
         params.use_dictionary = false;
     }
     params.favor_cpu_efficiency = true; // this should test both paths
-    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), num_threads)]);
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &UnionChunkingPolicy::default(), num_threads)]);
     let mut alloc_per_thread = [
         SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
         SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
@@ -179,3 +195,482 @@ fn thread_spawn_per_job_split_compression_test_0b1() {
 fn thread_spawn_per_job_split_compression_test_0b3() {
     thread_spawn_per_job_split_compression_test(&[], 3, 5, false, 144325)
 }
+
+#[cfg(feature="scoped-threads")]
+fn scoped_split_compression_test(input_data: &[u8], num_threads: usize, quality: i32) {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = quality;
+    params.magic_number = true;
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &UnionChunkingPolicy::default(), num_threads)]);
+    let mut alloc_per_thread = [
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+    ];
+    // the input is a local copy, so it can only be borrowed, never handed off as 'static
+    let local_input = input_data.to_vec();
+    let res = compress_multi_scoped(
+        &params,
+        &local_input[..],
+        output.slice_mut(),
+        &mut alloc_per_thread[..num_threads],
+    );
+    let observed_size = res.unwrap();
+    assert!(observed_size != 0);
+    let mut compressed_version = UnlimitedBuffer::new(&output.slice()[..observed_size]);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::default()) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+    }
+    assert_eq!(rt.data(), input_data);
+}
+#[cfg(feature="scoped-threads")]
+#[test]
+fn scoped_split_compression_test_1() {
+    scoped_split_compression_test(&RANDOM_THEN_UNICODE[..], 1, 3)
+}
+#[cfg(feature="scoped-threads")]
+#[test]
+fn scoped_split_compression_test_4() {
+    scoped_split_compression_test(&ALICE[..], 4, 5)
+}
+#[cfg(feature="scoped-threads")]
+#[test]
+fn scoped_split_compression_test_0b3() {
+    scoped_split_compression_test(&[], 3, 5)
+}
+
+#[cfg(all(feature="rayon", feature="std"))]
+fn rayon_split_compression_test(input_data: &'static[u8], num_threads: usize, quality: i32, spawner: &mut RayonSpawner) {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = quality;
+    params.magic_number = true;
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &UnionChunkingPolicy::default(), num_threads)]);
+    let mut alloc_per_thread = [
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+    ];
+    let res = compress_multi_rayon(
+        &params,
+        &mut Owned::new(SliceRef(input_data)),
+        output.slice_mut(),
+        &mut alloc_per_thread[..num_threads],
+        spawner,
+    );
+    let observed_size = res.unwrap();
+    assert!(observed_size != 0);
+    let mut compressed_version = UnlimitedBuffer::new(&output.slice()[..observed_size]);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::default()) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+    }
+    assert_eq!(rt.data(), input_data);
+}
+#[cfg(all(feature="rayon", feature="std"))]
+#[test]
+fn rayon_global_pool_split_compression_test() {
+    rayon_split_compression_test(&ALICE[..], 4, 5, &mut RayonSpawner::default())
+}
+#[cfg(all(feature="rayon", feature="std"))]
+#[test]
+fn rayon_owned_pool_split_compression_test() {
+    let pool = std::sync::Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+    let mut spawner = RayonSpawner::new(pool.clone());
+    // run from inside the single-threaded pool itself: join must not deadlock waiting on its own worker
+    pool.install(|| rayon_split_compression_test(&RANDOM_THEN_UNICODE[..], 3, 5, &mut spawner))
+}
+
+#[test]
+fn multi_threaded_metablock_log_covers_input_in_order() {
+    let dict = &ALICE[..4096];
+    let input_data = &ALICE[4096..];
+    let num_threads = 4;
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.log_meta_block = true;
+    let mut input = dict.to_vec();
+    input.extend(input_data);
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &UnionChunkingPolicy::default(), num_threads)]);
+    let mut alloc_per_thread = [
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+    ];
+    let mut pos = 0usize;
+    let mut num_metablocks = 0usize;
+    let observed_size = {
+        let mut log = |_pm:&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                       cmds:&mut [interface::StaticCommand],
+                       mb:InputPair,
+                       _m:&mut super::HeapAllocator| {
+            num_metablocks += 1;
+            for cmd in cmds.iter() {
+                match interface::thaw_pair(cmd, &mb) {
+                    interface::Command::Literal(ref lit) => {
+                        assert_eq!(lit.data.slice(), &input_data[pos..pos + lit.data.slice().len()]);
+                        pos += lit.data.slice().len();
+                    },
+                    interface::Command::Copy(ref copy) => pos += copy.num_bytes as usize,
+                    interface::Command::Dict(ref dict_cmd) => pos += dict_cmd.final_size as usize,
+                    _ => {},
+                }
+            }
+        };
+        compress_worker_pool(
+            &params,
+            &mut Owned::new(Rebox::from(input)),
+            output.slice_mut(),
+            &mut alloc_per_thread[..num_threads],
+            &mut new_work_pool(num_threads - 1),
+            dict.len(),
+            &mut log,
+        ).unwrap()
+    };
+    assert_eq!(pos, input_data.len());
+    assert!(num_metablocks >= num_threads);
+    let mut compressed_version = UnlimitedBuffer::new(&output.slice()[..observed_size]);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::from(dict.to_vec())).unwrap();
+    assert_eq!(rt.data(), input_data);
+}
+
+fn chunked_compression(input_data: &'static[u8], num_threads: usize, policy: UnionChunkingPolicy) -> Vec<u8> {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.multi_chunking = policy;
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &policy, num_threads)]);
+    let mut alloc_per_thread = [
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+    ];
+    let observed_size = compress_multi(
+        &params,
+        &mut Owned::new(SliceRef(input_data)),
+        output.slice_mut(),
+        &mut alloc_per_thread[..num_threads],
+    ).unwrap();
+    let mut compressed_version = UnlimitedBuffer::new(&output.slice()[..observed_size]);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::default()) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+    }
+    assert_eq!(rt.data(), input_data);
+    output.slice()[..observed_size].to_vec()
+}
+
+#[test]
+fn fixed_size_chunking_independent_of_thread_count() {
+    let policy = UnionChunkingPolicy::FixedSize(FixedSizeChunks::new(40000));
+    let expected = chunked_compression(&ALICE[..], 1, policy);
+    for num_threads in 2..6 {
+        assert_eq!(chunked_compression(&ALICE[..], num_threads, policy), expected);
+    }
+}
+
+#[test]
+fn max_compressed_size_multi_covers_small_fixed_chunks() {
+    // incompressible data is stored, so each chunk costs the full overhead of a stream of its own
+    let mut state = 0x2545f4914f6cdd1du64;
+    let input_data: Vec<u8> = (0..1 << 20).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+    }).collect();
+    let num_threads = 4;
+    for &chunk_size in [16384, 4096, 1024].iter() {
+        let mut params = BrotliEncoderParams::default();
+        params.quality = 5;
+        params.multi_chunking = UnionChunkingPolicy::FixedSize(FixedSizeChunks::new(chunk_size));
+        let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(input_data.len(), &params.multi_chunking, num_threads)]);
+        let mut alloc_per_thread = [
+            SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+            SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+            SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+            SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        ];
+        let observed_size = compress_multi(
+            &params,
+            &mut Owned::new(Rebox::from(input_data.clone())),
+            output.slice_mut(),
+            &mut alloc_per_thread[..num_threads],
+        ).unwrap();
+        let mut compressed_version = UnlimitedBuffer::new(&output.slice()[..observed_size]);
+        let mut rt = UnlimitedBuffer::new(&[]);
+        super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::default()).unwrap();
+        assert_eq!(rt.data(), &input_data[..]);
+    }
+}
+
+#[test]
+fn content_defined_chunking_independent_of_thread_count() {
+    let policy = UnionChunkingPolicy::ContentDefined(ContentDefinedChunks::new(14));
+    let expected = chunked_compression(&RANDOM_THEN_UNICODE[..], 1, policy);
+    for num_threads in 2..6 {
+        assert_eq!(chunked_compression(&RANDOM_THEN_UNICODE[..], num_threads, policy), expected);
+    }
+}
+
+#[test]
+fn content_defined_chunking_boundaries_follow_content() {
+    let policy = ContentDefinedChunks::new(12);
+    let boundaries = |data: &[u8]| {
+        let mut ret = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            start = policy.chunk_end(data, start, 4);
+            assert!(start - ret.last().cloned().unwrap_or(0) <= policy.max_size);
+            ret.push(start);
+        }
+        ret
+    };
+    let original = boundaries(ALICE);
+    let mut shifted_data = vec![b'x'; 3];
+    shifted_data.extend(ALICE);
+    let shifted: Vec<usize> = boundaries(&shifted_data).iter().map(|b| b - 3).collect();
+    assert!(original.len() > 8);
+    // past the first couple of chunks, inserting bytes at the front moves no cut points
+    assert_eq!(&original[2..], &shifted[2..]);
+}
+
+type TestPool = WorkerPool<u32, (), HeapAllocator, Vec<u8>>;
+type TestWork = SendAlloc<u32, (), HeapAllocator, <TestPool as BatchSpawnableLite<u32, (), HeapAllocator, Vec<u8>>>::JoinHandle>;
+
+fn byte_at_index_unless_one(_extra: (), index: usize, _num_threads: usize, data: &Vec<u8>, _alloc: HeapAllocator) -> u32 {
+    if index == 1 {
+        panic!("work item 1 always fails");
+    }
+    data[index] as u32
+}
+
+fn run_jobs(pool: &mut TestPool, num_jobs: usize) -> Vec<Result<u32, BrotliEncoderThreadError>> {
+    let mut input = Owned::new((0..num_jobs as u8).map(|x| x * 2).collect::<Vec<u8>>());
+    let mut locked_input = pool.make_spawner(&mut input);
+    let mut work: Vec<TestWork> = (0..num_jobs).map(|_| SendAlloc::new(new_brotli_heap_alloc(), ())).collect();
+    // every job is queued before any is joined
+    for (index, item) in work.iter_mut().enumerate() {
+        pool.spawn(&mut locked_input, item, index, num_jobs, byte_at_index_unless_one);
+    }
+    work.into_iter().map(|item| match item.0 {
+        InternalSendAlloc::Join(join) => join.join(),
+        _ => panic!("job was not spawned"),
+    }).collect()
+}
+
+fn check_jobs(results: &[Result<u32, BrotliEncoderThreadError>]) {
+    for (index, result) in results.iter().enumerate() {
+        match *result {
+            Ok(val) => assert_eq!(val, index as u32 * 2),
+            Err(BrotliEncoderThreadError::ThreadExecError(_)) => assert_eq!(index, 1),
+            Err(ref e) => panic!("unexpected error {:?}", e),
+        }
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn worker_pool_recovers_from_panicking_job() {
+    let mut pool = TestPool::new(2);
+    for _ in 0..3 {
+        let results = run_jobs(&mut pool, 6);
+        assert!(results[1].is_err());
+        check_jobs(&results);
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn worker_pool_resizes_beyond_sixteen_threads() {
+    let mut pool = TestPool::new(24);
+    assert_eq!(pool.num_threads(), 24);
+    check_jobs(&run_jobs(&mut pool, 40));
+    pool.resize(3);
+    assert_eq!(pool.num_threads(), 3);
+    check_jobs(&run_jobs(&mut pool, 20));
+    pool.resize(0);
+    assert_eq!(pool.num_threads(), 1);
+    check_jobs(&run_jobs(&mut pool, 5));
+}
+
+#[cfg(feature="std")]
+#[test]
+fn worker_pool_shutdown_drains_queued_jobs() {
+    let mut pool = TestPool::new(1);
+    let mut input = Owned::new((0..16u8).map(|x| x * 2).collect::<Vec<u8>>());
+    let mut locked_input = pool.make_spawner(&mut input);
+    let mut work: Vec<TestWork> = (0..16).map(|_| SendAlloc::new(new_brotli_heap_alloc(), ())).collect();
+    for (index, item) in work.iter_mut().enumerate() {
+        pool.spawn(&mut locked_input, item, index, 16, byte_at_index_unless_one);
+    }
+    pool.shutdown();
+    check_jobs(&work.into_iter().map(|item| match item.0 {
+        InternalSendAlloc::Join(join) => join.join(),
+        _ => panic!("job was not spawned"),
+    }).collect::<Vec<_>>());
+}
+
+// panics if read from any thread other than the one that made it
+struct OwnerThreadOnly(&'static [u8], std::thread::ThreadId);
+impl SliceWrapper<u8> for OwnerThreadOnly {
+    fn slice(&self) -> &[u8] {
+        assert_eq!(std::thread::current().id(), self.1);
+        self.0
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn multi_threaded_error_names_failed_work_item() {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    let mut output = Rebox::from(vec![0u8;BrotliEncoderMaxCompressedSizeMulti(ALICE.len(), &UnionChunkingPolicy::default(), 2)]);
+    let mut alloc_per_thread = [
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+        SendAlloc::new(new_brotli_heap_alloc(), UnionHasher::Uninit),
+    ];
+    let mut input = Owned::new(OwnerThreadOnly(ALICE, std::thread::current().id()));
+    match compress_multi(&params, &mut input, output.slice_mut(), &mut alloc_per_thread[..]) {
+        Err(BrotliEncoderThreadError::WorkItemPanic(index, range, _)) => {
+            assert_eq!(index, 0);
+            assert_eq!(range, 0..ALICE.len() / 2);
+        },
+        other => panic!("expected work item 0 to fail, got {:?}", other),
+    }
+    // the input is handed back even on failure
+    assert_eq!(input.view().0.len(), ALICE.len());
+}
+
+// holds every job back until opened, and records the order the jobs ran in by the first byte of their input
+#[derive(Default)]
+struct Gate {
+    open: AtomicBool,
+    started: AtomicUsize,
+    order: Mutex<Vec<u8>>,
+}
+
+type GatedPool = WorkerPool<u8, Arc<Gate>, HeapAllocator, Vec<u8>>;
+type GatedWork = SendAlloc<u8, Arc<Gate>, HeapAllocator, <GatedPool as BatchSpawnableLite<u8, Arc<Gate>, HeapAllocator, Vec<u8>>>::JoinHandle>;
+
+fn wait_for_gate(gate: Arc<Gate>, _index: usize, _num_threads: usize, data: &Vec<u8>, _alloc: HeapAllocator) -> u8 {
+    gate.started.fetch_add(1, Ordering::SeqCst);
+    while !gate.open.load(Ordering::SeqCst) {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    gate.order.lock().unwrap().push(data[0]);
+    data[0]
+}
+
+fn spawn_gated<Spawner: BatchSpawnableLite<u8, Arc<Gate>, HeapAllocator, Vec<u8>,
+                                            JoinHandle=<GatedPool as BatchSpawnableLite<u8, Arc<Gate>, HeapAllocator, Vec<u8>>>::JoinHandle>>(
+    spawner: &mut Spawner, gate: &Arc<Gate>, tag: u8, num_jobs: usize) -> (Spawner::FinalJoinHandle, Vec<GatedWork>) {
+    let mut input = Owned::new(vec![tag; num_jobs]);
+    let mut locked_input = spawner.make_spawner(&mut input);
+    let mut work: Vec<GatedWork> = (0..num_jobs).map(|_| SendAlloc::new(new_brotli_heap_alloc(), gate.clone())).collect();
+    for (index, item) in work.iter_mut().enumerate() {
+        spawner.spawn(&mut locked_input, item, index, num_jobs, wait_for_gate);
+    }
+    (locked_input, work)
+}
+
+fn join_gated(work: Vec<GatedWork>) {
+    for item in work {
+        match item.0 {
+            InternalSendAlloc::Join(join) => assert!(join.join().is_ok()),
+            _ => panic!("job was not spawned"),
+        }
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn worker_pool_tenants_share_workers_by_weight_and_priority() {
+    let gate = Arc::new(Gate::default());
+    let pool = GatedPool::new(1);
+    // occupy the only worker until every tenant has its jobs queued
+    let (_blocker_input, blocker) = spawn_gated(&mut pool.new_tenant(1, 0), &gate, 0, 1);
+    while gate.started.load(Ordering::SeqCst) == 0 {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    let mut light = pool.new_tenant(1, 0);
+    let mut heavy = pool.new_tenant(3, 0);
+    let mut urgent = pool.new_tenant(1, 1);
+    let (_light_input, light_work) = spawn_gated(&mut light, &gate, 1, 4);
+    let (_heavy_input, heavy_work) = spawn_gated(&mut heavy, &gate, 2, 12);
+    let (_urgent_input, urgent_work) = spawn_gated(&mut urgent, &gate, 3, 2);
+    gate.open.store(true, Ordering::SeqCst);
+    join_gated(blocker);
+    join_gated(light_work);
+    join_gated(heavy_work);
+    join_gated(urgent_work);
+    let order = gate.order.lock().unwrap();
+    // the higher priority tenant runs first even though it was queued last
+    assert_eq!(&order[..3], &[0, 3, 3][..]);
+    // then the weight 3 tenant gets three workers' turns for every one of the weight 1 tenant
+    for turns in order[3..].chunks(4) {
+        assert_eq!(turns.iter().filter(|tag| **tag == 1).count(), 1);
+    }
+}
+
+#[cfg(feature="std")]
+#[test]
+fn worker_pool_submit_then_wait_round_trips() {
+    let pool = new_work_pool(2);
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.magic_number = true;
+    let mut pending = submit_compress_worker_pool(&params,
+                                                  SliceRef(ALICE),
+                                                  vec![new_brotli_heap_alloc(), new_brotli_heap_alloc()],
+                                                  pool.new_tenant(1, 0));
+    let finished = loop {
+        match pending.try_wait() {
+            Ok(finished) => break finished,
+            Err(still_running) => pending = still_running,
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    assert_eq!(finished.input.unwrap().slice(), ALICE);
+    let compressed = finished.compressed.unwrap();
+    let mut compressed_version = UnlimitedBuffer::new(&compressed[..]);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::default()) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+    }
+    assert_eq!(rt.data(), ALICE);
+}
+
+#[cfg(feature="std")]
+#[test]
+fn worker_pool_submissions_beyond_its_size_all_finish() {
+    let pool = new_work_pool(1);
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 3;
+    params.magic_number = true;
+    let pending: Vec<_> = (0..6).map(|_| submit_compress_worker_pool(&params,
+                                                                      SliceRef(ALICE),
+                                                                      vec![new_brotli_heap_alloc(), new_brotli_heap_alloc()],
+                                                                      pool.new_tenant(1, 0))).collect();
+    // queued submissions are still collected when the pool shuts down gracefully
+    pool.shutdown();
+    for pending in pending {
+        let compressed = pending.wait().compressed.unwrap();
+        let mut compressed_version = UnlimitedBuffer::new(&compressed[..]);
+        let mut rt = UnlimitedBuffer::new(&[]);
+        match super::decompress(&mut compressed_version, &mut rt, 65536, Rebox::default()) {
+            Ok(_) => {}
+            Err(e) => panic!("Error {:?}", e),
+        }
+        assert_eq!(rt.data(), ALICE);
+    }
+}
@@ -0,0 +1,384 @@
+#![cfg(feature="std")]
+use core;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::vec::Vec;
+use alloc_stdlib::StandardAlloc;
+use brotli_decompressor::{BrotliDecompressStream, BrotliResult, BrotliState};
+use enc::{BrotliCompress, BrotliEncoderParams};
+use super::{BroCatli, BroCatliWriter, MARKER_CONTENT_BYTES, random_member_tag, stream_window_size};
+use super::metablock::{MetablockError, StreamParser};
+use super::uncat::{marker_at, standalone_header};
+
+/// Writes a brotli file that is a complete brotli stream after every flush, and that can be
+/// reopened later to carry on appending to.
+///
+/// Everything written between two flushes is a batch, compressed on flush as a stream of its own:
+/// the first one appendable and the rest catable, spliced in with BroCatli behind a member marker.
+/// A flush first writes the new batch past the end of the file and only then rewrites the (at
+/// most two) bytes ending the previous batch, syncing after each step. Reopening parses the first
+/// batch for the tag of the member markers, looks for the last of them back from the end of the
+/// file, decodes the batch behind it and cuts off anything after that a torn write left there.
+pub struct AppendableLogWriter {
+  file: File,
+  params: BrotliEncoderParams,
+  batch: Vec<u8>,
+  len: u64, // bytes of the file holding complete batches
+  window_size: u8, // 0 while there are no batches
+  members: u32,
+  member_tag: u64, // the tag of the member markers, random until there are any
+  tail: [u8; 2], // the last two bytes of the file, right aligned
+}
+
+fn damaged() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, "brotli log ends in a damaged batch")
+}
+
+const READ_CHUNK_BYTES: usize = 65536;
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<usize> {
+  match file.seek(SeekFrom::Start(offset)) {
+    Ok(_) => {},
+    Err(e) => return Err(e),
+  }
+  let mut read = 0usize;
+  while read < buffer.len() {
+    match file.read(&mut buffer[read..]) {
+      Ok(0) => break,
+      Ok(cur_read) => read += cur_read,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(read)
+}
+
+// how many bytes of a brotli stream its decoder takes up, or None if the stream is cut short
+fn decoded_len<R: Read>(mut stream: R) -> io::Result<Option<u64>> {
+  let mut state = BrotliState::new(StandardAlloc::default(),
+                                   StandardAlloc::default(),
+                                   StandardAlloc::default());
+  let mut input = vec![0u8; READ_CHUNK_BYTES];
+  let mut output = [0u8; 4096];
+  let mut consumed = 0u64;
+  let mut total_out = 0usize;
+  loop {
+    let read = match stream.read(&mut input[..]) {
+      Ok(0) => return Ok(None),
+      Ok(read) => read,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e),
+    };
+    let mut available_in = read;
+    let mut input_offset = 0usize;
+    loop {
+      let mut available_out = output.len();
+      let mut output_offset = 0usize;
+      match BrotliDecompressStream(&mut available_in, &mut input_offset, &input[..read],
+                                   &mut available_out, &mut output_offset, &mut output[..],
+                                   &mut total_out, &mut state) {
+        BrotliResult::NeedsMoreOutput => {},
+        BrotliResult::ResultSuccess => return Ok(Some(consumed + input_offset as u64)),
+        BrotliResult::NeedsMoreInput => break,
+        BrotliResult::ResultFailure => return Err(damaged()),
+      }
+    }
+    consumed += read as u64;
+  }
+}
+
+// The tag of the markers of the log, from the one ending its first batch if there is one. The
+// batch gets parsed metablock by metablock, so that markers inside its data are passed over.
+fn first_member_tag(file: &mut File) -> io::Result<Option<u64>> {
+  let mut chunk = vec![0u8; READ_CHUNK_BYTES];
+  let mut buffer = Vec::new();
+  let mut offset = 0u64; // of the end of buffer in the file
+  let mut eof = false;
+  let mut parser = None;
+  let mut bit_offset = 0usize;
+  loop {
+    let content_start = (bit_offset + 14 + 7) / 8;
+    let needs_more_input = match parser {
+      None => match StreamParser::new(&buffer[..]) {
+        Ok(new_parser) => {
+          bit_offset = new_parser.header_bits;
+          parser = Some(new_parser);
+          false
+        },
+        Err(MetablockError::NeedsMoreInput) => true,
+        Err(MetablockError::Invalid) => return Ok(None),
+      },
+      Some(_) if buffer.len() < content_start + MARKER_CONTENT_BYTES && !eof => true,
+      Some(ref mut parser) => {
+        if buffer.len() >= content_start + MARKER_CONTENT_BYTES {
+          if let Some(marker) = marker_at(&buffer[..], content_start) {
+            if marker.start_bit == bit_offset && marker.index == 1 {
+              return Ok(Some(marker.tag));
+            }
+          }
+        }
+        match parser.parse(&buffer[..], bit_offset) {
+          Ok(_) if parser.ended() => return Ok(None),
+          Ok(metablock) => {
+            let whole_bytes = metablock.end_bit / 8;
+            buffer.drain(..whole_bytes);
+            bit_offset = metablock.end_bit - whole_bytes * 8;
+            false
+          },
+          Err(MetablockError::NeedsMoreInput) => true,
+          Err(MetablockError::Invalid) => return Ok(None),
+        }
+      },
+    };
+    if needs_more_input {
+      if eof {
+        return Ok(None);
+      }
+      let read = match read_at(file, offset, &mut chunk[..]) {
+        Ok(read) => read,
+        Err(e) => return Err(e),
+      };
+      eof = read < chunk.len();
+      buffer.extend_from_slice(&chunk[..read]);
+      offset += read as u64;
+    }
+  }
+}
+
+// The file offset at which the contents of the last marker with the given tag start, and its
+// index, looking back from the end of the file a chunk at a time.
+fn last_marker(file: &mut File, file_len: u64, tag: u64) -> io::Result<Option<(u64, u32)>> {
+  let mut chunk = vec![0u8; READ_CHUNK_BYTES + MARKER_CONTENT_BYTES];
+  let mut end = file_len; // contents starting from here on have been looked at
+  loop {
+    let start = end.saturating_sub(READ_CHUNK_BYTES as u64);
+    let read = match read_at(file, start, &mut chunk[..(end - start) as usize + MARKER_CONTENT_BYTES]) {
+      Ok(read) => read,
+      Err(e) => return Err(e),
+    };
+    if read >= MARKER_CONTENT_BYTES {
+      // a marker needs a few bytes in front of its contents, so the next chunk overlaps this one
+      for content_start in (0..read - MARKER_CONTENT_BYTES + 1).rev() {
+        if let Some(marker) = marker_at(&chunk[..read], content_start) {
+          if marker.tag == tag {
+            return Ok(Some((start + content_start as u64, marker.index)));
+          }
+        }
+      }
+    }
+    if start == 0 {
+      return Ok(None);
+    }
+    end = start + 3;
+  }
+}
+
+// the length of the log made up of complete batches, its window size, the number of batches and
+// the tag of their markers
+fn recover(file: &mut File, file_len: u64) -> io::Result<(u64, u8, u32, Option<u64>)> {
+  if file_len == 0 {
+    return Ok((0, 0, 0, None));
+  }
+  let mut header = [0u8; 16];
+  let header_len = match read_at(file, 0, &mut header[..]) {
+    Ok(header_len) => header_len,
+    Err(e) => return Err(e),
+  };
+  let window_size = match stream_window_size(&header[..header_len]) {
+    Ok(window_size) => window_size,
+    Err(_) => return Err(damaged()),
+  };
+  let member_tag = match first_member_tag(file) {
+    Ok(member_tag) => member_tag,
+    Err(e) => return Err(e),
+  };
+  let (count, used) = match member_tag {
+    None => match file.seek(SeekFrom::Start(0)).and_then(|_| decoded_len(&mut *file)) {
+      Ok(used) => (1, used),
+      Err(e) => return Err(e),
+    },
+    Some(tag) => {
+      let (content_start, index) = match last_marker(file, file_len, tag) {
+        Ok(Some(marker)) => marker,
+        Ok(None) => return Err(damaged()),
+        Err(e) => return Err(e),
+      };
+      // the last batch on its own: a fresh stream header, then the rest of it as it is
+      let member_start = content_start + MARKER_CONTENT_BYTES as u64;
+      let head_len = match read_at(file, member_start, &mut header[..]) {
+        Ok(head_len) => head_len,
+        Err(e) => return Err(e),
+      };
+      let mut standalone = [0u8; 16];
+      let (standalone_len, replaced_len) = match standalone_header(window_size, &header[..head_len], Some(&mut standalone[..])) {
+        Ok(lengths) => lengths,
+        Err(_) => return Err(damaged()),
+      };
+      let body_start = member_start + replaced_len as u64;
+      match file.seek(SeekFrom::Start(body_start)) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+      match decoded_len(io::Cursor::new(&standalone[..standalone_len]).chain(&mut *file)) {
+        Ok(used) => (index + 1, used.map(|used| body_start + used - standalone_len as u64)),
+        Err(e) => return Err(e),
+      }
+    },
+  };
+  match used {
+    // whatever the decoder left over follows the last batch
+    Some(used) => Ok((used, window_size, count, member_tag)),
+    // the first batch never made it to the file
+    None if count == 1 => Ok((0, 0, 0, None)),
+    None => Err(damaged()),
+  }
+}
+
+impl AppendableLogWriter {
+  /// starts a new, empty log at path
+  pub fn create<P: AsRef<Path>>(path: P, params: &BrotliEncoderParams) -> io::Result<Self> {
+    match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path) {
+      Ok(file) => AppendableLogWriter::from_file(file, params),
+      Err(e) => Err(e),
+    }
+  }
+  /// opens the log at path to append to it, creating it if it does not exist
+  pub fn open<P: AsRef<Path>>(path: P, params: &BrotliEncoderParams) -> io::Result<Self> {
+    match OpenOptions::new().read(true).write(true).create(true).open(path) {
+      Ok(file) => AppendableLogWriter::from_file(file, params),
+      Err(e) => Err(e),
+    }
+  }
+  /// Appends to the log in file, which must be open for reading and writing. Anything behind its
+  /// last complete batch gets truncated away; later batches keep the window of the first one.
+  pub fn from_file(mut file: File, params: &BrotliEncoderParams) -> io::Result<Self> {
+    let file_len = match file.seek(SeekFrom::End(0)) {
+      Ok(file_len) => file_len,
+      Err(e) => return Err(e),
+    };
+    let (len, window_size, members, member_tag) = match recover(&mut file, file_len) {
+      Ok(recovered) => recovered,
+      Err(e) => return Err(e),
+    };
+    if len != file_len {
+      match file.set_len(len).and_then(|_| file.sync_data()) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+    }
+    let mut tail = [0u8; 2];
+    let tail_len = core::cmp::min(len, tail.len() as u64) as usize;
+    match read_at(&mut file, len - tail_len as u64, &mut tail[2 - tail_len..]) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    Ok(AppendableLogWriter {
+      file: file,
+      params: params.clone(),
+      batch: Vec::new(),
+      len: len,
+      window_size: window_size,
+      members: members,
+      member_tag: member_tag.unwrap_or_else(random_member_tag),
+      tail: tail,
+    })
+  }
+  /// the number of complete batches in the log
+  pub fn batches(&self) -> u32 {
+    self.members
+  }
+  pub fn get_ref(&self) -> &File {
+    &self.file
+  }
+  fn compress_batch(&self) -> io::Result<Vec<u8>> {
+    let mut params = self.params.clone();
+    params.appendable = true;
+    if self.window_size != 0 {
+      params.catable = true;
+      params.use_dictionary = false;
+      params.lgwin = i32::from(self.window_size);
+      params.large_window = self.window_size > 24;
+    }
+    let mut compressed = Vec::new();
+    match BrotliCompress(&mut io::Cursor::new(&self.batch[..]), &mut compressed, &params) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    if self.window_size == 0 {
+      return Ok(compressed);
+    }
+    // the output replaces the end of the log, starting with its last bytes
+    let tail_len = core::cmp::min(self.len, 2) as usize;
+    let mut state = BroCatli::new_appending_to(self.window_size, &self.tail[2 - tail_len..], self.members);
+    state.mark_members_with_tag(self.member_tag);
+    let mut writer = BroCatliWriter::with_state(Vec::new(), 4096, state);
+    writer.start_new_file();
+    match writer.write_all(&compressed[..]).and_then(|_| writer.finish()) {
+      Ok(_) => Ok(writer.into_inner()),
+      Err(e) => Err(e),
+    }
+  }
+  fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+    if data.len() == 0 {
+      return Ok(());
+    }
+    match self.file.seek(SeekFrom::Start(offset)).and_then(|_| self.file.write_all(data)) {
+      Ok(_) => self.file.sync_data(),
+      Err(e) => Err(e),
+    }
+  }
+  fn append_batch(&mut self) -> io::Result<()> {
+    if self.batch.len() == 0 {
+      return Ok(());
+    }
+    let replacement = match self.compress_batch() {
+      Ok(replacement) => replacement,
+      Err(e) => return Err(e),
+    };
+    let tail_len = if self.window_size == 0 { 0 } else { core::cmp::min(self.len, 2) as usize };
+    // until the bytes ending the previous batch are rewritten, the file still ends where it did
+    let (replaced, appended) = replacement.split_at(tail_len);
+    let len = self.len;
+    match self.write_at(len, appended).and_then(|_| self.write_at(len - tail_len as u64, replaced)) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    self.len = len - tail_len as u64 + replacement.len() as u64;
+    if replacement.len() >= 2 {
+      self.tail.clone_from_slice(&replacement[replacement.len() - 2..]);
+    } else if replacement.len() == 1 {
+      self.tail = [self.tail[1], replacement[0]];
+    }
+    if self.window_size == 0 {
+      self.window_size = match stream_window_size(&replacement[..]) {
+        Ok(window_size) => window_size,
+        Err(_) => return Err(damaged()),
+      };
+    }
+    self.members += 1;
+    self.batch.clear();
+    Ok(())
+  }
+}
+
+impl Write for AppendableLogWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.batch.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+  /// compresses everything written since the last flush and appends it to the log
+  fn flush(&mut self) -> io::Result<()> {
+    self.append_batch()
+  }
+}
+
+impl Drop for AppendableLogWriter {
+  fn drop(&mut self) {
+    match self.append_batch() {
+      Ok(_) => {},
+      Err(_) => {},
+    }
+  }
+}
@@ -0,0 +1,977 @@
+#![cfg(feature="std")]
+// A parser for the metablocks of a brotli stream. It decodes every metablock, but only to find
+// where it ends and whether its bits would still mean the same with other data in front of them:
+// whether it uses the static dictionary, how far back its copies reach, which of the distances
+// cached before it it takes and whether the literals at the very start of the stream depend on
+// the two bytes before it.
+// It decodes the prefix codes, context maps and block switches itself: brotli_decompressor keeps its
+// Huffman table building and metablock state machine private, and BrotliDecompressStream only stops
+// when it runs out of input or output, never between commands. The tables the encoder shares are used
+// from enc::constants, and the rest should move onto the decompressor once it exports those parts.
+use core;
+use std::vec::Vec;
+use dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength, kBrotliDictionarySizeBitsByLength,
+                 kBrotliMinDictionaryWordLength, kBrotliMaxDictionaryWordLength};
+use transform::{TransformDictionaryWord, kNumTransforms};
+use enc::histogram::ContextType;
+use enc::ir_interpret::Context;
+use enc::constants::{kInsBase, kInsExtra, kCopyBase, kCopyExtra};
+use super::parse_window_size;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetablockError {
+  // the metablock goes on past the bytes seen so far
+  NeedsMoreInput,
+  Invalid,
+}
+
+type ParseResult<T> = Result<T, MetablockError>;
+
+const WINDOW_GAP: i64 = 16;
+const MAX_ALLOWED_DISTANCE: i64 = 0x7ffffffc;
+const NUM_DISTANCE_SHORT_CODES: u32 = 16;
+const MAX_DISTANCE_BITS: u32 = 24;
+const LARGE_MAX_DISTANCE_BITS: u32 = 62;
+// the distance cache a stream starts with
+const INITIAL_DISTANCES: [i64; 4] = [16, 15, 11, 4];
+
+const CODE_LENGTH_CODE_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const CODE_LENGTH_PREFIX_LENGTH: [u8; 16] = [2, 2, 2, 3, 2, 2, 2, 4, 2, 2, 2, 3, 2, 2, 2, 4];
+const CODE_LENGTH_PREFIX_VALUE: [u8; 16] = [0, 4, 3, 2, 0, 4, 3, 1, 0, 4, 3, 2, 0, 4, 3, 5];
+const BLOCK_LENGTH_OFFSET: [u32; 26] = [1, 5, 9, 13, 17, 25, 33, 41, 49, 65, 81, 97, 113, 145, 177, 209,
+                                        241, 305, 369, 497, 753, 1265, 2289, 4337, 8433, 16625];
+const BLOCK_LENGTH_BITS: [u32; 26] = [2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5,
+                                      6, 6, 7, 8, 9, 10, 11, 12, 13, 24];
+// the insert and copy length code ranges of each block of 64 insert-and-copy codes
+const INSERT_RANGE: [u32; 11] = [0, 0, 0, 0, 8, 8, 0, 16, 8, 16, 16];
+const COPY_RANGE: [u32; 11] = [0, 8, 0, 8, 0, 8, 16, 0, 16, 8, 16];
+
+struct BitReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+  fn available(&self) -> usize {
+    self.data.len() * 8 - self.pos
+  }
+  fn peek(&self, num_bits: u32) -> u64 {
+    let mut bits = 0u64;
+    for (index, item) in self.data.iter().skip(self.pos / 8).take(8).enumerate() {
+      bits |= u64::from(*item) << (index * 8);
+    }
+    (bits >> (self.pos % 8)) & ((1u64 << num_bits) - 1)
+  }
+  fn read(&mut self, num_bits: u32) -> ParseResult<u64> {
+    if num_bits > 32 {
+      let low = match self.read(32) {
+        Ok(low) => low,
+        Err(e) => return Err(e),
+      };
+      return self.read(num_bits - 32).map(|high| low | (high << 32));
+    }
+    if self.available() < num_bits as usize {
+      return Err(MetablockError::NeedsMoreInput);
+    }
+    let bits = self.peek(num_bits);
+    self.pos += num_bits as usize;
+    Ok(bits)
+  }
+  fn read_bit(&mut self) -> ParseResult<u32> {
+    if self.pos >= self.data.len() * 8 {
+      return Err(MetablockError::NeedsMoreInput);
+    }
+    let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+    self.pos += 1;
+    Ok(u32::from(bit))
+  }
+  // the padding up to a byte boundary has to be zero
+  fn skip_padding(&mut self) -> ParseResult<()> {
+    let pad = (8 - self.pos % 8) % 8;
+    match self.read(pad as u32) {
+      Ok(0) => Ok(()),
+      Ok(_) => Err(MetablockError::Invalid),
+      Err(e) => Err(e),
+    }
+  }
+  fn skip_bytes(&mut self, num_bytes: usize) -> ParseResult<()> {
+    if self.available() < num_bytes * 8 {
+      return Err(MetablockError::NeedsMoreInput);
+    }
+    self.pos += num_bytes * 8;
+    Ok(())
+  }
+  fn read_var_len_u8(&mut self) -> ParseResult<u32> {
+    match self.read_bit() {
+      Ok(0) => return Ok(0),
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    let num_bits = match self.read(3) {
+      Ok(num_bits) => num_bits as u32,
+      Err(e) => return Err(e),
+    };
+    if num_bits == 0 {
+      return Ok(1);
+    }
+    self.read(num_bits).map(|bits| (1 << num_bits) + bits as u32)
+  }
+}
+
+// a canonical prefix code, decoded a bit at a time
+struct PrefixCode {
+  counts: [u16; 16],
+  symbols: Vec<u16>, // ordered by code length, then by value
+}
+
+impl PrefixCode {
+  fn from_lengths(lengths: &[u8]) -> PrefixCode {
+    let mut counts = [0u16; 16];
+    for length in lengths.iter() {
+      counts[usize::from(*length)] += 1;
+    }
+    counts[0] = 0;
+    let mut symbols = Vec::new();
+    for length in 1..16u8 {
+      for (symbol, item) in lengths.iter().enumerate() {
+        if *item == length {
+          symbols.push(symbol as u16);
+        }
+      }
+    }
+    PrefixCode {
+      counts: counts,
+      symbols: symbols,
+    }
+  }
+  // a code with a single symbol, which takes no bits at all
+  fn single(symbol: u16) -> PrefixCode {
+    PrefixCode {
+      counts: [0u16; 16],
+      symbols: vec![symbol],
+    }
+  }
+  fn read_symbol(&self, br: &mut BitReader) -> ParseResult<u32> {
+    if self.symbols.len() == 1 && self.counts[1..].iter().all(|count| *count == 0) {
+      return Ok(u32::from(self.symbols[0]));
+    }
+    let mut code = 0i32;
+    let mut first = 0i32;
+    let mut index = 0i32;
+    for length in 1..16 {
+      code |= match br.read_bit() {
+        Ok(bit) => bit as i32,
+        Err(e) => return Err(e),
+      };
+      let count = i32::from(self.counts[length]);
+      if code - first < count {
+        return Ok(u32::from(self.symbols[(index + code - first) as usize]));
+      }
+      index += count;
+      first += count;
+      first <<= 1;
+      code <<= 1;
+    }
+    Err(MetablockError::Invalid)
+  }
+  // reads a prefix code over alphabet_size symbols of which only those below max_symbol may be used
+  fn read(br: &mut BitReader, alphabet_size: u32, max_symbol: u32) -> ParseResult<PrefixCode> {
+    let skip = match br.read(2) {
+      Ok(skip) => skip as usize,
+      Err(e) => return Err(e),
+    };
+    if skip == 1 {
+      return PrefixCode::read_simple(br, alphabet_size, max_symbol);
+    }
+    let mut code_length_lengths = [0u8; 18];
+    let mut space = 32u32;
+    let mut num_codes = 0;
+    for order in CODE_LENGTH_CODE_ORDER[skip..].iter() {
+      let available = core::cmp::min(br.available(), 4);
+      let index = br.peek(available as u32) as usize;
+      let length = usize::from(CODE_LENGTH_PREFIX_LENGTH[index]);
+      if length > available {
+        return Err(MetablockError::NeedsMoreInput);
+      }
+      br.pos += length;
+      let value = CODE_LENGTH_PREFIX_VALUE[index];
+      code_length_lengths[*order] = value;
+      if value != 0 {
+        space = space.wrapping_sub(32 >> value);
+        num_codes += 1;
+        if space.wrapping_sub(1) >= 32 {
+          break;
+        }
+      }
+    }
+    if !(num_codes == 1 || space == 0) {
+      return Err(MetablockError::Invalid);
+    }
+    let length_code = if num_codes == 1 {
+      let symbol = code_length_lengths.iter().position(|length| *length != 0).unwrap();
+      PrefixCode::single(symbol as u16)
+    } else {
+      PrefixCode::from_lengths(&code_length_lengths[..])
+    };
+    let mut lengths = vec![0u8; max_symbol as usize];
+    let mut symbol = 0u32;
+    let mut prev_length = 8u32;
+    let mut repeat = 0u32;
+    let mut repeat_length = 0u32;
+    let mut space = 32768u32;
+    while symbol < max_symbol && space > 0 {
+      let length = match length_code.read_symbol(br) {
+        Ok(length) => length,
+        Err(e) => return Err(e),
+      };
+      if length < 16 {
+        repeat = 0;
+        if length != 0 {
+          lengths[symbol as usize] = length as u8;
+          prev_length = length;
+          space = space.wrapping_sub(32768 >> length);
+        }
+        symbol += 1;
+        continue;
+      }
+      let extra_bits = length - 14;
+      let delta = match br.read(extra_bits) {
+        Ok(delta) => delta as u32,
+        Err(e) => return Err(e),
+      };
+      let new_length = if length == 16 { prev_length } else { 0 };
+      if repeat_length != new_length {
+        repeat = 0;
+        repeat_length = new_length;
+      }
+      let old_repeat = repeat;
+      if repeat > 0 {
+        repeat -= 2;
+        repeat <<= extra_bits;
+      }
+      repeat += delta + 3;
+      let repeat_delta = repeat - old_repeat;
+      if symbol + repeat_delta > max_symbol {
+        return Err(MetablockError::Invalid);
+      }
+      if repeat_length != 0 {
+        for item in lengths[symbol as usize..(symbol + repeat_delta) as usize].iter_mut() {
+          *item = repeat_length as u8;
+        }
+        space = space.wrapping_sub(repeat_delta << (15 - repeat_length));
+      }
+      symbol += repeat_delta;
+    }
+    if space != 0 {
+      return Err(MetablockError::Invalid);
+    }
+    Ok(PrefixCode::from_lengths(&lengths[..]))
+  }
+  fn read_simple(br: &mut BitReader, alphabet_size: u32, max_symbol: u32) -> ParseResult<PrefixCode> {
+    let num_symbols = match br.read(2) {
+      Ok(num_symbols) => num_symbols as usize + 1,
+      Err(e) => return Err(e),
+    };
+    let symbol_bits = 32 - (alphabet_size - 1).leading_zeros();
+    let mut symbols = [0u16; 4];
+    for index in 0..num_symbols {
+      symbols[index] = match br.read(symbol_bits) {
+        Ok(symbol) if (symbol as u32) < max_symbol => symbol as u16,
+        Ok(_) => return Err(MetablockError::Invalid),
+        Err(e) => return Err(e),
+      };
+      if symbols[..index].contains(&symbols[index]) {
+        return Err(MetablockError::Invalid);
+      }
+    }
+    let code_lengths: &[u8] = match num_symbols {
+      1 => return Ok(PrefixCode::single(symbols[0])),
+      2 => &[1, 1],
+      3 => &[1, 2, 2],
+      _ => match br.read_bit() {
+        Ok(0) => &[2, 2, 2, 2],
+        Ok(_) => &[1, 2, 3, 3],
+        Err(e) => return Err(e),
+      },
+    };
+    let mut lengths = vec![0u8; max_symbol as usize];
+    for (symbol, length) in symbols.iter().zip(code_lengths.iter()) {
+      lengths[usize::from(*symbol)] = *length;
+    }
+    Ok(PrefixCode::from_lengths(&lengths[..]))
+  }
+}
+
+// block types and counts of one of the literal, command and distance categories
+struct BlockSwitch {
+  num_types: u32,
+  type_code: Option<PrefixCode>,
+  length_code: Option<PrefixCode>,
+  block_type: u32,
+  remaining: u32,
+  type_ring: [u32; 2],
+}
+
+impl BlockSwitch {
+  fn read(br: &mut BitReader) -> ParseResult<BlockSwitch> {
+    let num_types = match br.read_var_len_u8() {
+      Ok(num_types) => num_types + 1,
+      Err(e) => return Err(e),
+    };
+    let mut switch = BlockSwitch {
+      num_types: num_types,
+      type_code: None,
+      length_code: None,
+      block_type: 0,
+      remaining: 1 << 28,
+      type_ring: [1, 0],
+    };
+    if num_types >= 2 {
+      switch.type_code = match PrefixCode::read(br, num_types + 2, num_types + 2) {
+        Ok(code) => Some(code),
+        Err(e) => return Err(e),
+      };
+      switch.length_code = match PrefixCode::read(br, 26, 26) {
+        Ok(code) => Some(code),
+        Err(e) => return Err(e),
+      };
+      switch.remaining = match switch.read_length(br) {
+        Ok(length) => length,
+        Err(e) => return Err(e),
+      };
+    }
+    Ok(switch)
+  }
+  fn read_length(&self, br: &mut BitReader) -> ParseResult<u32> {
+    let code = match self.length_code.as_ref().unwrap().read_symbol(br) {
+      Ok(code) => code as usize,
+      Err(e) => return Err(e),
+    };
+    br.read(BLOCK_LENGTH_BITS[code]).map(|extra| BLOCK_LENGTH_OFFSET[code] + extra as u32)
+  }
+  // counts off one symbol of the category, reading a block switch first if the block is used up
+  fn next(&mut self, br: &mut BitReader) -> ParseResult<()> {
+    if self.remaining == 0 {
+      let code = match self.type_code.as_ref().unwrap().read_symbol(br) {
+        Ok(code) => code,
+        Err(e) => return Err(e),
+      };
+      self.remaining = match self.read_length(br) {
+        Ok(length) => length,
+        Err(e) => return Err(e),
+      };
+      let mut block_type = match code {
+        0 => self.type_ring[0],
+        1 => self.type_ring[1] + 1,
+        _ => code - 2,
+      };
+      if block_type >= self.num_types {
+        block_type -= self.num_types;
+      }
+      self.type_ring = [self.type_ring[1], block_type];
+      self.block_type = block_type;
+    }
+    self.remaining -= 1;
+    Ok(())
+  }
+}
+
+fn read_context_map(br: &mut BitReader, size: usize, num_trees: u32) -> ParseResult<Vec<u8>> {
+  let mut map = vec![0u8; size];
+  if num_trees < 2 {
+    return Ok(map);
+  }
+  let max_run_length_prefix = match br.read_bit() {
+    Ok(0) => 0,
+    Ok(_) => match br.read(4) {
+      Ok(prefix) => prefix as u32 + 1,
+      Err(e) => return Err(e),
+    },
+    Err(e) => return Err(e),
+  };
+  let alphabet_size = num_trees + max_run_length_prefix;
+  let code = match PrefixCode::read(br, alphabet_size, alphabet_size) {
+    Ok(code) => code,
+    Err(e) => return Err(e),
+  };
+  let mut index = 0;
+  while index < size {
+    let symbol = match code.read_symbol(br) {
+      Ok(symbol) => symbol,
+      Err(e) => return Err(e),
+    };
+    if symbol == 0 {
+      index += 1;
+    } else if symbol <= max_run_length_prefix {
+      let run = match br.read(symbol) {
+        Ok(extra) => (1usize << symbol) + extra as usize,
+        Err(e) => return Err(e),
+      };
+      if index + run > size {
+        return Err(MetablockError::Invalid);
+      }
+      index += run;
+    } else {
+      map[index] = (symbol - max_run_length_prefix) as u8;
+      index += 1;
+    }
+  }
+  match br.read_bit() {
+    Ok(0) => {},
+    Ok(_) => { // inverse move to front
+      let mut mtf = [0u8; 256];
+      for (index, item) in mtf.iter_mut().enumerate() {
+        *item = index as u8;
+      }
+      for item in map.iter_mut() {
+        let index = usize::from(*item);
+        let value = mtf[index];
+        *item = value;
+        for position in (0..index).rev() {
+          mtf[position + 1] = mtf[position];
+        }
+        mtf[0] = value;
+      }
+    },
+    Err(e) => return Err(e),
+  }
+  Ok(map)
+}
+
+fn max_distance_symbol(num_direct: u32, postfix_bits: u32) -> u32 {
+  let bound = [0u32, 4, 12, 28];
+  let diff = [73u32, 126, 228, 424];
+  let postfix = 1 << postfix_bits;
+  let index = postfix_bits as usize;
+  if num_direct < bound[index] {
+    num_direct + diff[index] + postfix
+  } else if num_direct > bound[index] + postfix {
+    num_direct + diff[index]
+  } else {
+    bound[index] + diff[index] + postfix
+  }
+}
+
+fn context_type(mode: u8) -> ContextType {
+  match mode {
+    0 => ContextType::CONTEXT_LSB6,
+    1 => ContextType::CONTEXT_MSB6,
+    2 => ContextType::CONTEXT_UTF8,
+    _ => ContextType::CONTEXT_SIGNED,
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetablockKind {
+  Compressed,
+  // the data is at data_start, a byte offset into the bytes parsed
+  Uncompressed {
+    data_start: usize,
+  },
+  Metadata {
+    data_start: usize,
+  },
+  // ISLAST and ISLASTEMPTY
+  LastEmpty,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metablock {
+  pub kind: MetablockKind,
+  pub is_last: bool,
+  // bit offsets of the metablock, of the end of its MLEN field (for a LastEmpty, its end) and of
+  // its end, not counting the padding that follows the last metablock
+  pub start_bit: usize,
+  pub length_end_bit: usize,
+  pub end_bit: usize,
+  // MLEN: the bytes of output, or of metadata
+  pub len: usize,
+  // whether it refers to the static dictionary before the stream has a full window behind it,
+  // where the distance of a word depends on how much data there is, and from then on
+  pub dictionary_before_full_window: bool,
+  pub dictionary_after_full_window: bool,
+  // the longest copy distance, the static dictionary aside
+  pub max_distance: i64,
+  // bit n is set if it takes the distance that was n + 1 back in the distance cache before it
+  pub cached_distances_read: u8,
+  // how many distances it pushed onto the distance cache
+  pub distances_pushed: usize,
+  // whether a literal at the start of the stream is coded differently depending on the bytes
+  // before the stream
+  pub uses_context_before_stream: bool,
+}
+
+/// The state of a stream between metablocks: the window it has decoded so far and its distance cache.
+pub struct StreamParser {
+  pub window_size: u8,
+  pub large_window: bool,
+  pub header_bits: usize,
+  max_backward: i64,
+  // the output that copies may still reach, and the stream position of its first byte
+  history: Vec<u8>,
+  history_start: u64,
+  distances: [i64; 4],
+  // the number of distances pushed onto the cache before those it holds, counting the ones every
+  // stream starts with as the four before it starts
+  pushed_before: [i64; 4],
+  distance_index: i64,
+  ended: bool,
+}
+
+impl StreamParser {
+  /// Starts on the stream header at the beginning of bytes, which has to hold at least two bytes
+  /// unless it is all there is.
+  pub fn new(bytes: &[u8]) -> ParseResult<StreamParser> {
+    if bytes.len() == 0 || (bytes.len() == 1 && bytes[0] & 0x7f == 0x11) {
+      return Err(MetablockError::NeedsMoreInput);
+    }
+    let (window_size, header_bits) = match parse_window_size(bytes) {
+      Ok(header) => header,
+      Err(_) => return Err(MetablockError::Invalid),
+    };
+    Ok(StreamParser {
+      window_size: window_size,
+      large_window: header_bits == 14,
+      header_bits: header_bits,
+      max_backward: (1i64 << window_size) - WINDOW_GAP,
+      history: Vec::new(),
+      history_start: 0,
+      distances: INITIAL_DISTANCES,
+      pushed_before: [-4, -3, -2, -1],
+      distance_index: 0,
+      ended: false,
+    })
+  }
+  pub fn ended(&self) -> bool {
+    self.ended
+  }
+  /// The index the next metablock will have.
+  /// The distance cache, last distance first.
+  pub fn last_distances(&self) -> [i64; 4] {
+    let mut distances = [0i64; 4];
+    for (back, item) in distances.iter_mut().enumerate() {
+      *item = self.distances[((self.distance_index - 1 - back as i64) & 3) as usize];
+    }
+    distances
+  }
+  /// The last len bytes of output.
+  pub fn output_tail(&self, len: usize) -> &[u8] {
+    &self.history[self.history.len() - len..]
+  }
+  fn position(&self) -> u64 {
+    self.history_start + self.history.len() as u64
+  }
+  /// Parses the metablock starting at bit offset start_bit of bytes. Nothing changes if it fails.
+  pub fn parse(&mut self, bytes: &[u8], start_bit: usize) -> ParseResult<Metablock> {
+    let history_len = self.history.len();
+    let distances = (self.distances, self.pushed_before, self.distance_index);
+    let mut result = self.parse_metablock(bytes, start_bit);
+    if let Ok(ref metablock) = result {
+      if metablock.is_last {
+        // the stream ends with zero bits up to a byte boundary
+        let mut br = BitReader {
+          data: bytes,
+          pos: metablock.end_bit,
+        };
+        if let Err(e) = br.skip_padding() {
+          result = Err(e);
+        }
+      }
+    }
+    match result {
+      Ok(ref metablock) => {
+        self.ended = metablock.is_last;
+        // keep a window of output, or all of the last metablock, and as much again, so that it is
+        // not moved every metablock
+        let keep = core::cmp::max(1usize << self.window_size, metablock.len);
+        if self.history.len() > 2 * keep {
+          let excess = self.history.len() - keep;
+          self.history.drain(..excess);
+          self.history_start += excess as u64;
+        }
+      },
+      Err(_) => {
+        self.history.truncate(history_len);
+        self.distances = distances.0;
+        self.pushed_before = distances.1;
+        self.distance_index = distances.2;
+      },
+    }
+    result
+  }
+  fn parse_metablock(&mut self, bytes: &[u8], start_bit: usize) -> ParseResult<Metablock> {
+    let mut br = BitReader {
+      data: bytes,
+      pos: start_bit,
+    };
+    let mut metablock = Metablock {
+      kind: MetablockKind::Compressed,
+      is_last: false,
+      start_bit: start_bit,
+      length_end_bit: start_bit,
+      end_bit: start_bit,
+      len: 0,
+      dictionary_before_full_window: false,
+      dictionary_after_full_window: false,
+      max_distance: 0,
+      cached_distances_read: 0,
+      distances_pushed: 0,
+      uses_context_before_stream: false,
+    };
+    metablock.is_last = match br.read_bit() {
+      Ok(bit) => bit != 0,
+      Err(e) => return Err(e),
+    };
+    if metablock.is_last {
+      match br.read_bit() {
+        Ok(0) => {},
+        Ok(_) => {
+          metablock.kind = MetablockKind::LastEmpty;
+          metablock.length_end_bit = br.pos;
+          metablock.end_bit = br.pos;
+          return Ok(metablock);
+        },
+        Err(e) => return Err(e),
+      }
+    }
+    let nibbles = match br.read(2) {
+      Ok(nibbles) => nibbles as u32,
+      Err(e) => return Err(e),
+    };
+    if nibbles == 3 {
+      match br.read_bit() {
+        Ok(0) => {},
+        Ok(_) => return Err(MetablockError::Invalid),
+        Err(e) => return Err(e),
+      }
+      let skip_bytes = match br.read(2) {
+        Ok(skip_bytes) => skip_bytes as u32,
+        Err(e) => return Err(e),
+      };
+      for index in 0..skip_bytes {
+        let byte = match br.read(8) {
+          Ok(byte) => byte as usize,
+          Err(e) => return Err(e),
+        };
+        if index + 1 == skip_bytes && skip_bytes > 1 && byte == 0 {
+          return Err(MetablockError::Invalid);
+        }
+        metablock.len |= byte << (8 * index);
+      }
+      if skip_bytes != 0 {
+        metablock.len += 1;
+      }
+      metablock.length_end_bit = br.pos;
+      match br.skip_padding() {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+      metablock.kind = MetablockKind::Metadata {
+        data_start: br.pos / 8,
+      };
+      match br.skip_bytes(metablock.len) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+      metablock.end_bit = br.pos;
+      return Ok(metablock);
+    }
+    let nibbles = nibbles + 4;
+    for index in 0..nibbles {
+      let nibble = match br.read(4) {
+        Ok(nibble) => nibble as usize,
+        Err(e) => return Err(e),
+      };
+      if index + 1 == nibbles && nibbles > 4 && nibble == 0 {
+        return Err(MetablockError::Invalid);
+      }
+      metablock.len |= nibble << (4 * index);
+    }
+    metablock.len += 1;
+    metablock.length_end_bit = br.pos;
+    if !metablock.is_last {
+      match br.read_bit() {
+        Ok(0) => {},
+        Ok(_) => {
+          match br.skip_padding() {
+            Ok(_) => {},
+            Err(e) => return Err(e),
+          }
+          let data_start = br.pos / 8;
+          match br.skip_bytes(metablock.len) {
+            Ok(_) => {},
+            Err(e) => return Err(e),
+          }
+          metablock.kind = MetablockKind::Uncompressed {
+            data_start: data_start,
+          };
+          metablock.end_bit = br.pos;
+          self.history.extend_from_slice(&bytes[data_start..data_start + metablock.len]);
+          return Ok(metablock);
+        },
+        Err(e) => return Err(e),
+      }
+    }
+    match self.parse_compressed(&mut br, &mut metablock) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    metablock.end_bit = br.pos;
+    Ok(metablock)
+  }
+  fn parse_compressed(&mut self, br: &mut BitReader, metablock: &mut Metablock) -> ParseResult<()> {
+    let mut literal_switch = match BlockSwitch::read(br) {
+      Ok(switch) => switch,
+      Err(e) => return Err(e),
+    };
+    let mut command_switch = match BlockSwitch::read(br) {
+      Ok(switch) => switch,
+      Err(e) => return Err(e),
+    };
+    let mut distance_switch = match BlockSwitch::read(br) {
+      Ok(switch) => switch,
+      Err(e) => return Err(e),
+    };
+    let postfix_bits = match br.read(2) {
+      Ok(postfix_bits) => postfix_bits as u32,
+      Err(e) => return Err(e),
+    };
+    let num_direct = match br.read(4) {
+      Ok(num_direct) => (num_direct as u32) << postfix_bits,
+      Err(e) => return Err(e),
+    };
+    let mut context_modes = Vec::new();
+    for _ in 0..literal_switch.num_types {
+      match br.read(2) {
+        Ok(mode) => context_modes.push(context_type(mode as u8)),
+        Err(e) => return Err(e),
+      }
+    }
+    let num_literal_trees = match br.read_var_len_u8() {
+      Ok(num_trees) => num_trees + 1,
+      Err(e) => return Err(e),
+    };
+    let literal_context_map = match read_context_map(br, 64 * literal_switch.num_types as usize, num_literal_trees) {
+      Ok(map) => map,
+      Err(e) => return Err(e),
+    };
+    let num_distance_trees = match br.read_var_len_u8() {
+      Ok(num_trees) => num_trees + 1,
+      Err(e) => return Err(e),
+    };
+    let distance_context_map = match read_context_map(br, 4 * distance_switch.num_types as usize, num_distance_trees) {
+      Ok(map) => map,
+      Err(e) => return Err(e),
+    };
+    let mut literal_codes = Vec::new();
+    for _ in 0..num_literal_trees {
+      match PrefixCode::read(br, 256, 256) {
+        Ok(code) => literal_codes.push(code),
+        Err(e) => return Err(e),
+      }
+    }
+    let mut command_codes = Vec::new();
+    for _ in 0..command_switch.num_types {
+      match PrefixCode::read(br, 704, 704) {
+        Ok(code) => command_codes.push(code),
+        Err(e) => return Err(e),
+      }
+    }
+    let max_bits = if self.large_window { LARGE_MAX_DISTANCE_BITS } else { MAX_DISTANCE_BITS };
+    let distance_alphabet_size = NUM_DISTANCE_SHORT_CODES + num_direct + (max_bits << (postfix_bits + 1));
+    let distance_max_symbol = if self.large_window {
+      max_distance_symbol(num_direct, postfix_bits)
+    } else {
+      distance_alphabet_size
+    };
+    let mut distance_codes = Vec::new();
+    for _ in 0..num_distance_trees {
+      match PrefixCode::read(br, distance_alphabet_size, distance_max_symbol) {
+        Ok(code) => distance_codes.push(code),
+        Err(e) => return Err(e),
+      }
+    }
+    for (context_map, trees) in [(&literal_context_map, num_literal_trees), (&distance_context_map, num_distance_trees)].iter() {
+      if context_map.iter().any(|tree| u32::from(*tree) >= *trees) {
+        return Err(MetablockError::Invalid);
+      }
+    }
+    // whether each literal block type picks its prefix code regardless of the bytes before
+    let context_free: Vec<bool> = literal_context_map.chunks(64).map(
+      |row| row.iter().all(|tree| *tree == row[0])).collect();
+    let start_index = self.distance_index;
+    let mut remaining = metablock.len as i64;
+    while remaining > 0 {
+      match command_switch.next(br) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+      let command = match command_codes[command_switch.block_type as usize].read_symbol(br) {
+        Ok(command) => command,
+        Err(e) => return Err(e),
+      };
+      let cell = (command >> 6) as usize;
+      let insert_code = (INSERT_RANGE[cell] + ((command >> 3) & 7)) as usize;
+      let copy_code = (COPY_RANGE[cell] + (command & 7)) as usize;
+      let insert_len = match br.read(kInsExtra[insert_code]) {
+        Ok(extra) => (kInsBase[insert_code] + extra as u32) as i64,
+        Err(e) => return Err(e),
+      };
+      let copy_len = match br.read(kCopyExtra[copy_code]) {
+        Ok(extra) => (kCopyBase[copy_code] + extra as u32) as i64,
+        Err(e) => return Err(e),
+      };
+      if insert_len > remaining {
+        return Err(MetablockError::Invalid);
+      }
+      for _ in 0..insert_len {
+        match literal_switch.next(br) {
+          Ok(_) => {},
+          Err(e) => return Err(e),
+        }
+        let len = self.history.len();
+        let p1 = if len >= 1 { self.history[len - 1] } else { 0 };
+        let p2 = if len >= 2 { self.history[len - 2] } else { 0 };
+        let block_type = literal_switch.block_type as usize;
+        if self.position() < 2 && !context_free[block_type] {
+          metablock.uses_context_before_stream = true;
+        }
+        let context = usize::from(Context(p1, p2, context_modes[block_type]));
+        let tree = usize::from(literal_context_map[block_type * 64 + context]);
+        match literal_codes[tree].read_symbol(br) {
+          Ok(literal) => self.history.push(literal as u8),
+          Err(e) => return Err(e),
+        }
+      }
+      remaining -= insert_len;
+      if remaining <= 0 {
+        break;
+      }
+      // distance code 0 and the commands that imply it take the last distance and leave the cache
+      // as it was; other distances are pushed onto it unless they refer to the dictionary
+      let (distance, cache_slot, pushes) = if cell < 2 {
+        let slot = ((self.distance_index - 1) & 3) as usize;
+        (self.distances[slot], Some(slot), false)
+      } else {
+        match distance_switch.next(br) {
+          Ok(_) => {},
+          Err(e) => return Err(e),
+        }
+        let context = if copy_len > 4 { 3 } else { (copy_len - 2) as usize };
+        let tree = usize::from(distance_context_map[distance_switch.block_type as usize * 4 + context]);
+        let code = match distance_codes[tree].read_symbol(br) {
+          Ok(code) => code,
+          Err(e) => return Err(e),
+        };
+        match self.decode_distance(br, code, num_direct, postfix_bits) {
+          Ok(decoded) => decoded,
+          Err(e) => return Err(e),
+        }
+      };
+      if let Some(slot) = cache_slot {
+        if self.pushed_before[slot] < start_index {
+          metablock.cached_distances_read |= 1 << (start_index - 1 - self.pushed_before[slot]);
+        }
+      }
+      let position = self.position() as i64;
+      let max_distance = core::cmp::min(position, self.max_backward);
+      if distance > max_distance {
+        if distance > MAX_ALLOWED_DISTANCE {
+          return Err(MetablockError::Invalid);
+        }
+        let len = match self.dictionary_word(distance - max_distance - 1, copy_len) {
+          Some(len) => len as i64,
+          None => return Err(MetablockError::Invalid),
+        };
+        if position < self.max_backward {
+          metablock.dictionary_before_full_window = true;
+        } else {
+          metablock.dictionary_after_full_window = true;
+        }
+        remaining -= len;
+      } else {
+        if pushes {
+          let slot = (self.distance_index & 3) as usize;
+          self.distances[slot] = distance;
+          self.pushed_before[slot] = self.distance_index;
+          self.distance_index += 1;
+        }
+        metablock.max_distance = core::cmp::max(metablock.max_distance, distance);
+        if copy_len > remaining {
+          return Err(MetablockError::Invalid);
+        }
+        let start = self.history.len() - distance as usize;
+        for index in 0..copy_len as usize {
+          let byte = self.history[start + index];
+          self.history.push(byte);
+        }
+        remaining -= copy_len;
+      }
+    }
+    if remaining < 0 {
+      return Err(MetablockError::Invalid);
+    }
+    metablock.distances_pushed = (self.distance_index - start_index) as usize;
+    Ok(())
+  }
+  // the distance a distance code stands for, the cache slot it reads if any, and whether the
+  // distance gets pushed onto the cache if it turns out to be a copy
+  fn decode_distance(&self, br: &mut BitReader, code: u32, num_direct: u32, postfix_bits: u32)
+                     -> ParseResult<(i64, Option<usize>, bool)> {
+    if code < NUM_DISTANCE_SHORT_CODES {
+      // which of the last four distances, counting back from the last, and what to add to it
+      let (back, delta) = match code {
+        0 => (0, 0),
+        1 => (1, 0),
+        2 => (2, 0),
+        3 => (3, 0),
+        _ if code < 10 => (0, [-1i64, 1, -2, 2, -3, 3][code as usize - 4]),
+        _ => (1, [-1i64, 1, -2, 2, -3, 3][code as usize - 10]),
+      };
+      let slot = ((self.distance_index - 1 - back) & 3) as usize;
+      let distance = self.distances[slot] + delta;
+      if distance <= 0 {
+        return Err(MetablockError::Invalid);
+      }
+      return Ok((distance, Some(slot), code != 0));
+    }
+    if code < NUM_DISTANCE_SHORT_CODES + num_direct {
+      return Ok((i64::from(code - NUM_DISTANCE_SHORT_CODES) + 1, None, true));
+    }
+    let value = code - NUM_DISTANCE_SHORT_CODES - num_direct;
+    let postfix = value & ((1 << postfix_bits) - 1);
+    let value = value >> postfix_bits;
+    let num_bits = (value >> 1) + 1;
+    if num_bits > 30 {
+      return Err(MetablockError::Invalid);
+    }
+    let extra = match br.read(num_bits) {
+      Ok(extra) => extra as i64,
+      Err(e) => return Err(e),
+    };
+    let offset = ((2 + i64::from(value & 1)) << num_bits) - 4;
+    let distance = i64::from(num_direct) + ((offset + extra) << postfix_bits) + i64::from(postfix) + 1;
+    Ok((distance, None, true))
+  }
+  // appends the static dictionary word a reference past the window stands for, returning its length
+  fn dictionary_word(&mut self, word_id: i64, copy_len: i64) -> Option<usize> {
+    if copy_len < i64::from(kBrotliMinDictionaryWordLength) || copy_len > i64::from(kBrotliMaxDictionaryWordLength) {
+      return None;
+    }
+    let copy_len = copy_len as usize;
+    let shift = kBrotliDictionarySizeBitsByLength[copy_len];
+    let word_index = (word_id & ((1i64 << shift) - 1)) as usize;
+    let transform = word_id >> shift;
+    if transform >= i64::from(kNumTransforms) {
+      return None;
+    }
+    let offset = kBrotliDictionaryOffsetsByLength[copy_len] as usize + word_index * copy_len;
+    let mut word = [0u8; 64];
+    let len = TransformDictionaryWord(&mut word[..],
+                                      &kBrotliDictionary[offset..offset + copy_len],
+                                      copy_len as i32,
+                                      transform as i32) as usize;
+    self.history.extend_from_slice(&word[..len]);
+    Some(len)
+  }
+}
@@ -1,4 +1,26 @@
 use core;
+use core::fmt;
+#[cfg(feature="std")]
+use std::{error, io};
+
+mod log;
+mod metablock;
+mod reader;
+mod reencode;
+mod uncat;
+mod writer;
+pub use self::reader::BroCatliReaderCustomIo;
+pub use self::writer::BroCatliWriterCustomIo;
+#[cfg(feature="std")]
+pub use self::log::AppendableLogWriter;
+#[cfg(feature="std")]
+pub use self::reader::BroCatliReader;
+#[cfg(feature="std")]
+pub use self::writer::BroCatliWriter;
+#[cfg(feature="std")]
+pub use self::reencode::{ConcatRewriter, reencode_for_concat, splices_cleanly};
+#[cfg(feature="std")]
+pub use self::uncat::{UncatMember, UncatMembers, uncat, uncat_members};
 
 #[repr(C)]
 #[derive(Debug,Clone,Copy, PartialEq)]
@@ -6,13 +28,100 @@ pub enum BroCatliResult {
   Success = 0,
   NeedsMoreInput = 1,
   NeedsMoreOutput = 2,
+  InvalidCheckpoint = 123,
   BrotliFileNotCraftedForAppend = 124,
   InvalidWindowSize = 125,
   WindowSizeLargerThanPreviousFile = 126,
   BrotliFileNotCraftedForConcatenation = 127,
 }
 
+impl fmt::Display for BroCatliResult {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let message = match *self {
+      BroCatliResult::Success => "success",
+      BroCatliResult::NeedsMoreInput => "brotli stream ended unexpectedly",
+      BroCatliResult::NeedsMoreOutput => "output buffer full",
+      BroCatliResult::InvalidCheckpoint => "invalid checkpoint",
+      BroCatliResult::BrotliFileNotCraftedForAppend => "brotli file not crafted for append",
+      BroCatliResult::InvalidWindowSize => "invalid window size",
+      BroCatliResult::WindowSizeLargerThanPreviousFile => "window size larger than previous file",
+      BroCatliResult::BrotliFileNotCraftedForConcatenation => "brotli file not crafted for concatenation",
+    };
+    f.write_str(message)
+  }
+}
+
+#[cfg(feature="std")]
+impl error::Error for BroCatliResult {}
+
+// lets the std adapters hand back the failure as the source of an io::Error,
+// so callers can downcast to BroCatliResult to see exactly what went wrong
+#[cfg(feature="std")]
+impl From<BroCatliResult> for io::Error {
+  fn from(result: BroCatliResult) -> io::Error {
+    let kind = match result {
+      BroCatliResult::NeedsMoreInput => io::ErrorKind::UnexpectedEof,
+      _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, result)
+  }
+}
+
+/// The first pass of a concatenation over seekable inputs: reads the window size of each input,
+/// leaving them where they started, and returns the BroCatli for the second pass. Its output has
+/// the largest window of all the inputs, which only works if the first input is catable
+/// whenever a later one has a larger window than it.
+#[cfg(feature="std")]
+pub fn scan_window_sizes<R: io::Read + io::Seek>(inputs: &mut [R]) -> io::Result<BroCatli> {
+  let mut first_window_size = 0u8;
+  let mut largest_window_size = 0u8;
+  for input in inputs.iter_mut() {
+    let start = match input.seek(io::SeekFrom::Current(0)) {
+      Ok(start) => start,
+      Err(e) => return Err(e),
+    };
+    let mut header = [0u8; 2];
+    let mut header_len = 0usize;
+    while header_len < header.len() {
+      match input.read(&mut header[header_len..]) {
+        Ok(0) => break,
+        Ok(size) => header_len += size,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => return Err(e),
+      }
+    }
+    match input.seek(io::SeekFrom::Start(start)) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    let window_size = match stream_window_size(&header[..header_len]) {
+      Ok(window_size) => window_size,
+      Err(failure) => return Err(io::Error::from(failure)),
+    };
+    if first_window_size == 0 {
+      first_window_size = window_size;
+    }
+    largest_window_size = core::cmp::max(largest_window_size, window_size);
+  }
+  Ok(BroCatli::new_for_window_sizes(first_window_size, largest_window_size))
+}
+
 const NUM_STREAM_HEADER_BYTES: usize = 5;
+// enough for the stream header and contents of a magic number metadata metablock
+const MAGIC_PREFIX_BYTES: usize = 18;
+// a member marker metablock: up to 7 bits of the previous stream, its 14 bit header and padding, then the contents
+const MARKER_BYTES: usize = 3 + MARKER_CONTENT_BYTES;
+// the magic number, the gap back to the start of the marker, the index of the stream and the tag
+const MARKER_CONTENT_BYTES: usize = 16;
+const MAGIC_MEMBER: u8 = 0x8b;
+/// room needed by BroCatli::serialize_to_buffer
+pub const SERIALIZED_BYTES: usize = 16 + 2 * NUM_STREAM_HEADER_BYTES + MAGIC_PREFIX_BYTES + 12 + MARKER_BYTES + 15;
+/// room needed by BroCatli::leading_header
+pub const LEADING_HEADER_BYTES: usize = 32;
+const MAGIC_NUMBER: [u8; 2] = [0xe1, 0x97];
+const MAGIC_CATABLE: u8 = 0x81;
+const MAGIC_APPENDABLE: u8 = 0x82;
+const MAGIC_CATABLE_DICTIONARY: u8 = 0x83;
 
 #[derive(Clone,Copy)]
 struct NewStreamData {
@@ -69,16 +178,149 @@ fn parse_window_size(bytes_so_far:&[u8]) -> Result<(u8, usize), ()> {  // return
   Ok((ret, 14))
 }
 
+/// the log2 window size a brotli stream declares in its first (up to) two bytes
+pub fn stream_window_size(header: &[u8]) -> Result<u8, BroCatliResult> {
+  if header.len() == 0 || (header.len() == 1 && header[0] == 0x11) {
+    return Err(BroCatliResult::NeedsMoreInput);
+  }
+  match parse_window_size(header) {
+    Ok((window_size, _)) => Ok(window_size),
+    Err(_) => Err(BroCatliResult::InvalidWindowSize),
+  }
+}
+
+// what the start of a stream says about the magic number header written by BrotliWriteMetadataMetaBlock
+#[derive(Debug, PartialEq)]
+enum MagicPrefix {
+  Undecided,
+  Absent,
+  Present {
+    window_offset: usize,
+    end: usize, // bytes up to the end of the metadata
+    flags: u8,
+    size_hint: u64,
+  },
+}
+
+fn parse_magic_prefix(bytes: &[u8]) -> MagicPrefix {
+  if bytes.len() == 0 || (bytes.len() == 1 && bytes[0] & 0x7f == 0x11) {
+    return MagicPrefix::Undecided;
+  }
+  let window_offset = match parse_window_size(bytes) {
+    Ok((_, window_offset)) => window_offset,
+    Err(_) => return MagicPrefix::Absent,
+  };
+  let mut bits = 0u64;
+  for (index, item) in bytes.iter().take(4).enumerate() {
+    bits |= u64::from(*item) << (index * 8);
+  }
+  let bits_available = core::cmp::min(bytes.len(), 4) * 8 - window_offset;
+  bits >>= window_offset;
+  // not ISLAST, MNIBBLES = 0 (pattern 1,1), reserved, MSKIPBYTES = 1
+  let expected = 0x16u64;
+  let mask = (1u64 << core::cmp::min(bits_available, 6)) - 1;
+  if (bits ^ expected) & mask != 0 {
+    return MagicPrefix::Absent;
+  }
+  if bits_available < 14 {
+    return MagicPrefix::Undecided;
+  }
+  let metadata_len = ((bits >> 6) & 0xff) as usize + 1;
+  if metadata_len < 5 || metadata_len > 14 {
+    return MagicPrefix::Absent;
+  }
+  let start = (window_offset + 14 + 7) / 8;
+  let metadata = &bytes[core::cmp::min(start, bytes.len())..];
+  for (index, item) in metadata.iter().take(3).enumerate() {
+    if index < 2 && *item != MAGIC_NUMBER[index] {
+      return MagicPrefix::Absent;
+    }
+    if index == 2 && (*item < 0x80 || *item > MAGIC_CATABLE_DICTIONARY) {
+      return MagicPrefix::Absent;
+    }
+  }
+  if metadata.len() < metadata_len {
+    return MagicPrefix::Undecided;
+  }
+  let mut size_hint = 0u64;
+  for (index, item) in metadata[4..metadata_len].iter().enumerate() {
+    size_hint |= u64::from(*item & 0x7f) << (7 * index);
+  }
+  MagicPrefix::Present {
+    window_offset: window_offset,
+    end: start + metadata_len,
+    flags: metadata[2],
+    size_hint: size_hint,
+  }
+}
+
+/// The flags byte (0x81 for catable, 0x83 for catable with the dictionary used from a full window
+/// in, 0x82 for appendable, 0x80 otherwise) and the size hint of
+/// the magic number header a brotli stream starts with, if it has one.
+pub fn magic_header(stream: &[u8]) -> Option<(u8, u64)> {
+  match parse_magic_prefix(&stream[..core::cmp::min(stream.len(), MAGIC_PREFIX_BYTES)]) {
+    MagicPrefix::Present{flags, size_hint, ..} => Some((flags, size_hint)),
+    _ => None,
+  }
+}
+
+// every RandomState is keyed afresh, from the system's randomness for the first on each thread
+#[cfg(feature="std")]
+fn random_member_tag() -> u64 {
+  use std::collections::hash_map::RandomState;
+  use std::hash::{BuildHasher, Hasher};
+  let mut hasher = RandomState::new().build_hasher();
+  hasher.write_u8(MAGIC_MEMBER);
+  hasher.finish()
+}
+
+#[cfg(not(feature="std"))]
+fn random_member_tag() -> u64 {
+  0
+}
+
+fn write_bits(num_bits: usize, bits: u64, bit_offset: &mut usize, out: &mut [u8]) {
+  for index in 0..num_bits {
+    let pos = *bit_offset + index;
+    if pos % 8 == 0 {
+      out[pos / 8] = 0;
+    }
+    out[pos / 8] |= (((bits >> index) & 1) as u8) << (pos % 8);
+  }
+  *bit_offset += num_bits;
+}
+
+// the bits that announce log_window_size at the start of a stream, and how many there are
+fn window_size_bits(log_window_size: u8) -> (u64, usize) {
+  if log_window_size > 24 {
+    (0x11 | (u64::from(log_window_size) << 8), 14)
+  } else if log_window_size == 16 {
+    (0, 1)
+  } else if log_window_size > 17 {
+    (u64::from(3 + (log_window_size - 18) * 2), 4)
+  } else if log_window_size == 17 {
+    (1, 7)
+  } else {
+    (u64::from(0x21 + (log_window_size - 10) * 0x10), 7)
+  }
+}
+
 fn detect_varlen_offset(bytes_so_far:&[u8]) -> Result<(usize), ()> {  // returns offfset in bits
-  let (_, mut offset) = match parse_window_size(bytes_so_far) {
+  let (_, offset) = match parse_window_size(bytes_so_far) {
     Ok(x) => x,
     Err(_) => return Err(()),
   };
+  metablock_header_end(bytes_so_far, offset)
+}
+
+// the bit offset at which the contents of the metablock starting at bit offset start
+// are byte aligned, provided it is an uncompressed or metadata metablock
+fn metablock_header_end(bytes_so_far:&[u8], mut offset: usize) -> Result<usize, ()> {
   let mut bytes = 0u64;
-  for (index, item) in bytes_so_far.iter().enumerate() {
+  for (index, item) in bytes_so_far.iter().skip(offset / 8).take(8).enumerate() {
     bytes |= u64::from(*item) << (index * 8);
   }
-  bytes >>= offset;
+  bytes >>= offset % 8;
   offset += 1;
   if (bytes & 1) != 0 { // ISLAST
     bytes >>= 1;
@@ -114,6 +356,7 @@ fn detect_varlen_offset(bytes_so_far:&[u8]) -> Result<(usize), ()> {  // returns
 }
 
 // eat your vegetables
+#[derive(Clone)]
 pub struct BroCatli {
   last_bytes: [u8; 2],
   last_bytes_len: u8,
@@ -123,6 +366,52 @@ pub struct BroCatli {
   // need to make sure that window sizes stay similar or get smaller
   window_size: u8,
   new_stream_pending: Option<NewStreamData>,
+  // with window growth, later streams may have larger windows and the header of the first
+  // stream, kept here, gets rewritten for the largest one
+  grow_window: bool,
+  first_header: [u8; NUM_STREAM_HEADER_BYTES],
+  first_header_len: u8, // 0 unless the first header can be rewritten
+  // when merging magic number headers, the start of each stream is held here until it is known
+  // whether it has one; it then gets streamed on from here, with the header made an empty metablock
+  merge_magic: bool,
+  magic_stage: MagicStage,
+  magic_prefix: [u8; MAGIC_PREFIX_BYTES],
+  magic_prefix_len: u8,
+  magic_prefix_offset: u8,
+  first_magic_flags: u8, // 0 if the first stream had no magic number header
+  all_magic: bool, // whether all streams so far had one, so that their size hints add up
+  magic_size_hint: u64,
+  // with member markers, a metadata metablock recording where each stream after the first starts
+  mark_members: bool,
+  marker: [u8; MARKER_BYTES],
+  marker_len: u8,
+  marker_written: u8,
+  members: u32,
+  // random for each concatenation, so that the markers of a marked concatenation stored inside one
+  // of the streams are not taken for its own
+  member_tag: u64,
+  // the window that streams using the dictionary were compressed with, 0 if there were none: their
+  // dictionary references only decode the same with the concatenation keeping exactly that window
+  dictionary_window: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MagicStage {
+  PassThrough = 0,
+  Collecting = 1,
+  Replaying = 2,
+  Truncated = 3,
+}
+
+impl MagicStage {
+  fn from_u8(stage: u8) -> MagicStage {
+    match stage {
+      1 => MagicStage::Collecting,
+      2 => MagicStage::Replaying,
+      3 => MagicStage::Truncated,
+      _ => MagicStage::PassThrough,
+    }
+  }
 }
 impl Default for BroCatli {
     fn default() -> BroCatli {
@@ -139,10 +428,79 @@ impl BroCatli {
       any_bytes_emitted: false,
       new_stream_pending: None,
       window_size:0,
+      grow_window: false,
+      first_header: [0; NUM_STREAM_HEADER_BYTES],
+      first_header_len: 0,
+      merge_magic: false,
+      magic_stage: MagicStage::PassThrough,
+      magic_prefix: [0; MAGIC_PREFIX_BYTES],
+      magic_prefix_len: 0,
+      magic_prefix_offset: 0,
+      first_magic_flags: 0,
+      all_magic: true,
+      magic_size_hint: 0,
+      mark_members: false,
+      marker: [0; MARKER_BYTES],
+      marker_len: 0,
+      marker_written: 0,
+      members: 0,
+      member_tag: 0,
+      dictionary_window: 0,
+    }
+  }
+  /// Lets later streams have larger windows than the first. The first stream must then be catable:
+  /// once finished, leading_header gives the bytes that the output has to start with instead.
+  pub fn new_with_window_growth() -> BroCatli {
+    let mut ret = BroCatli::new();
+    ret.grow_window = true;
+    ret
+  }
+  /// For inputs whose window sizes are all known up front, as they are with a first pass over
+  /// seekable inputs: the output gets the largest window, which needs the first input to be
+  /// catable only if some later one has a larger window than it.
+  pub fn new_for_window_sizes(first_window_size: u8, largest_window_size: u8) -> BroCatli {
+    if largest_window_size > first_window_size {
+      BroCatli::new_with_window_size(largest_window_size)
+    } else {
+      BroCatli::new()
     }
   }
+  /// For appending to the end of an existing concatenation with the given window that holds
+  /// the given number of streams and ends in tail, its last (up to two) bytes: the output
+  /// starts with the replacement for those bytes.
+  pub fn new_appending_to(log_window_size: u8, tail: &[u8], members: u32) -> BroCatli {
+    let mut ret = BroCatli::new();
+    let tail_len = core::cmp::min(tail.len(), ret.last_bytes.len());
+    ret.last_bytes[..tail_len].clone_from_slice(&tail[tail.len() - tail_len..]);
+    ret.last_bytes_len = tail_len as u8;
+    ret.window_size = log_window_size;
+    ret.any_bytes_emitted = tail_len != 0;
+    ret.members = members;
+    ret
+  }
+  /// Call before the first stream to merge the magic number headers of the streams: the inner ones
+  /// become empty metadata metablocks and leading_header gives a single one for the whole output,
+  /// with the sizes summed if every stream declared one. That needs the first stream to either have
+  /// a magic number header or be catable, and the window size not to be fixed up front.
+  pub fn merge_magic_headers(&mut self) {
+    self.merge_magic = true;
+  }
+  /// Call before the first stream to put a marker metadata metablock in front of every stream after
+  /// the first, so that uncat_members can find the streams again without decompressing anything
+  /// but the first. The markers carry a random tag that tells them apart from those of other
+  /// concatenations; without the std feature there is nothing to draw it from, so use
+  /// mark_members_with_tag there.
+  pub fn mark_members(&mut self) {
+    self.mark_members_with_tag(random_member_tag());
+  }
+  /// mark_members with the given tag, which has to be that of the existing markers when appending
+  /// to a marked concatenation
+  pub fn mark_members_with_tag(&mut self, tag: u64) {
+    self.mark_members = true;
+    self.member_tag = tag;
+  }
   pub fn deserialize_from_buffer(buffer: &[u8]) -> Result<BroCatli, ()> {
-    if 16+NUM_STREAM_HEADER_BYTES > buffer.len() {
+    if SERIALIZED_BYTES > buffer.len() {
         return Err(());
     }
     let mut possible_new_stream_pending = NewStreamData{
@@ -166,7 +524,35 @@ impl BroCatli {
         any_bytes_emitted: (buffer[9] & (1 << 5)) != 0,
         window_size: buffer[11],
         new_stream_pending:new_stream_pending,
+        grow_window: (buffer[14] & 1) != 0,
+        first_header: [0; NUM_STREAM_HEADER_BYTES],
+        first_header_len: core::cmp::min(buffer[15], NUM_STREAM_HEADER_BYTES as u8),
+        merge_magic: (buffer[14] & 2) != 0,
+        magic_stage: MagicStage::from_u8(buffer[46]),
+        magic_prefix: [0; MAGIC_PREFIX_BYTES],
+        magic_prefix_len: core::cmp::min(buffer[44], MAGIC_PREFIX_BYTES as u8),
+        magic_prefix_offset: core::cmp::min(buffer[45], core::cmp::min(buffer[44], MAGIC_PREFIX_BYTES as u8)),
+        first_magic_flags: buffer[47],
+        all_magic: (buffer[14] & 4) != 0,
+        magic_size_hint: 0,
+        mark_members: (buffer[14] & 8) != 0,
+        marker: [0; MARKER_BYTES],
+        marker_len: core::cmp::min(buffer[75], MARKER_BYTES as u8),
+        marker_written: core::cmp::min(buffer[76], core::cmp::min(buffer[75], MARKER_BYTES as u8)),
+        members: u32::from(buffer[77]) | (u32::from(buffer[78]) << 8) | (u32::from(buffer[79]) << 16) | (u32::from(buffer[80]) << 24),
+        member_tag: 0,
+        dictionary_window: buffer[81],
     };
+    ret.marker.clone_from_slice(&buffer[56..56 + MARKER_BYTES]);
+    let xlen = ret.first_header.len();
+    ret.first_header.clone_from_slice(&buffer[16+NUM_STREAM_HEADER_BYTES..][..xlen]);
+    ret.magic_prefix.clone_from_slice(&buffer[26..26 + MAGIC_PREFIX_BYTES]);
+    for (index, item) in buffer[48..56].iter().enumerate() {
+      ret.magic_size_hint |= u64::from(*item) << (index * 8);
+    }
+    for (index, item) in buffer[82..90].iter().enumerate() {
+      ret.member_tag |= u64::from(*item) << (index * 8);
+    }
     if ret.last_bytes.len() > 8 {
         return Err(());
     }
@@ -176,7 +562,7 @@ impl BroCatli {
   }
   #[inline(always)]        
   pub fn serialize_to_buffer(&self, buffer: &mut [u8]) -> Result<(), ()> {
-    if 16+NUM_STREAM_HEADER_BYTES > buffer.len() {
+    if SERIALIZED_BYTES > buffer.len() {
         return Err(());
     }
     buffer[..self.last_bytes.len()].clone_from_slice(
@@ -185,13 +571,33 @@ impl BroCatli {
     buffer[9] = (self.last_byte_sanitized as u8) | ((self.new_stream_pending.is_some() as u8) << 6) | ((self.any_bytes_emitted as u8) << 5);
       buffer[10] = self.last_byte_bit_offset;
       buffer[11] = self.window_size;
+      buffer[14] = self.grow_window as u8 | ((self.merge_magic as u8) << 1) | ((self.all_magic as u8) << 2) | ((self.mark_members as u8) << 3);
+      buffer[15] = self.first_header_len;
+      buffer[16+NUM_STREAM_HEADER_BYTES..26].clone_from_slice(&self.first_header[..]);
+      buffer[26..26 + MAGIC_PREFIX_BYTES].clone_from_slice(&self.magic_prefix[..]);
+      buffer[44] = self.magic_prefix_len;
+      buffer[45] = self.magic_prefix_offset;
+      buffer[46] = self.magic_stage as u8;
+      buffer[47] = self.first_magic_flags;
+      for (index, item) in buffer[48..56].iter_mut().enumerate() {
+        *item = (self.magic_size_hint >> (index * 8)) as u8;
+      }
+      buffer[56..56 + MARKER_BYTES].clone_from_slice(&self.marker[..]);
+      buffer[75] = self.marker_len;
+      buffer[76] = self.marker_written;
+      for (index, item) in buffer[77..81].iter_mut().enumerate() {
+        *item = (self.members >> (index * 8)) as u8;
+      }
+      buffer[81] = self.dictionary_window;
+      for (index, item) in buffer[82..90].iter_mut().enumerate() {
+        *item = (self.member_tag >> (index * 8)) as u8;
+      }
       if let Some(new_stream_pending) = self.new_stream_pending {
           if new_stream_pending.num_bytes_written.is_some() {
               buffer[9] |= (1<<7);
           }
           buffer[12] = new_stream_pending.num_bytes_read;
           buffer[13] = new_stream_pending.num_bytes_written.unwrap_or(0);
-          // 14, 15 reserved
           buffer[16..16+new_stream_pending.bytes_so_far.len()].clone_from_slice(
               &new_stream_pending.bytes_so_far[..]);
       }
@@ -236,11 +642,205 @@ impl BroCatli {
       any_bytes_emitted: false,
       new_stream_pending: None,
       window_size:log_window_size,
+      grow_window: false,
+      first_header: [0; NUM_STREAM_HEADER_BYTES],
+      first_header_len: 0,
+      merge_magic: false,
+      magic_stage: MagicStage::PassThrough,
+      magic_prefix: [0; MAGIC_PREFIX_BYTES],
+      magic_prefix_len: 0,
+      magic_prefix_offset: 0,
+      first_magic_flags: 0,
+      all_magic: true,
+      magic_size_hint: 0,
+      mark_members: false,
+      marker: [0; MARKER_BYTES],
+      marker_len: 0,
+      marker_written: 0,
+      members: 0,
+      member_tag: 0,
+      dictionary_window: 0,
     }
   }
     
+  /// log2 of the window of the concatenated stream, or 0 before the first stream header has been seen
+  pub fn window_size(&self) -> u8 {
+    self.window_size
+  }
+  fn window_can_grow_to(&self, log_window_size: u8) -> bool {
+    // past 24 bits the distance codes change for every stream, so a large window can't be grown into
+    self.grow_window && self.first_header_len != 0 && (log_window_size <= 24 || self.window_size > 24)
+      && (self.dictionary_window == 0 || self.dictionary_window == log_window_size)
+  }
+  // the first stream's header can be rewritten if it opens with a metablock whose
+  // contents start on a byte boundary, ie if it is catable
+  fn record_first_header(&mut self, new_stream_pending: &NewStreamData) {
+    let header = &new_stream_pending.bytes_so_far[..usize::from(new_stream_pending.num_bytes_read)];
+    if let (Ok((_, window_offset)), Ok(_)) = (parse_window_size(header), detect_varlen_offset(header)) {
+      if (header[window_offset / 8] >> (window_offset % 8)) & 1 == 0 { // not ISLAST
+        self.first_header[..header.len()].clone_from_slice(header);
+        self.first_header_len = header.len() as u8;
+      }
+    }
+  }
+  /// With window growth or merged magic number headers, writes the header the finished output has
+  /// to start with into header (which needs LEADING_HEADER_BYTES) and returns how many leading bytes
+  /// of the output it replaces and how many bytes long it is. Both are 0 if there is nothing to rewrite.
+  pub fn leading_header(&self, header: &mut [u8]) -> Result<(usize, usize), BroCatliResult> {
+    if self.first_header_len == 0 {
+      return Ok((0, 0));
+    }
+    if header.len() < LEADING_HEADER_BYTES {
+      return Err(BroCatliResult::NeedsMoreOutput);
+    }
+    let first_header = &self.first_header[..usize::from(self.first_header_len)];
+    let (window_offset, varlen_offset) = match (parse_window_size(first_header), detect_varlen_offset(first_header)) {
+      (Ok((_, window_offset)), Ok(varlen_offset)) => (window_offset, varlen_offset),
+      _ => return Err(BroCatliResult::BrotliFileNotCraftedForConcatenation),
+    };
+    let mut varlen_bits = 0u64;
+    for (index, item) in first_header.iter().enumerate() {
+      varlen_bits |= u64::from(*item) << (index * 8);
+    }
+    varlen_bits >>= window_offset;
+    let (window_bits, window_bits_len) = window_size_bits(self.window_size);
+    let mut bit_offset = 0usize;
+    write_bits(window_bits_len, window_bits, &mut bit_offset, header);
+    if self.merge_magic {
+      let flags = match self.first_magic_flags {
+        MAGIC_CATABLE | MAGIC_CATABLE_DICTIONARY if self.dictionary_window != 0 => MAGIC_CATABLE_DICTIONARY,
+        MAGIC_CATABLE => MAGIC_CATABLE,
+        _ => MAGIC_APPENDABLE,
+      };
+      let mut size_hint = if self.all_magic { self.magic_size_hint } else { 0 };
+      let mut size_hint_b128 = [0u8; 10];
+      let mut size_hint_len = 0usize;
+      loop {
+        size_hint_b128[size_hint_len] = (size_hint & 0x7f) as u8;
+        size_hint >>= 7;
+        size_hint_len += 1;
+        if size_hint == 0 {
+          break;
+        }
+        size_hint_b128[size_hint_len - 1] |= 0x80;
+      }
+      write_bits(6, 0x16, &mut bit_offset, header); // not last, metadata, 1 byte of length
+      write_bits(8, 3 + size_hint_len as u64, &mut bit_offset, header);
+      bit_offset = (bit_offset + 7) / 8 * 8;
+      for item in [MAGIC_NUMBER[0], MAGIC_NUMBER[1], flags, ::VERSION].iter().chain(size_hint_b128[..size_hint_len].iter()) {
+        write_bits(8, u64::from(*item), &mut bit_offset, header);
+      }
+    }
+    if self.first_magic_flags == 0 {
+      write_bits(varlen_offset - window_offset, varlen_bits, &mut bit_offset, header);
+    } // otherwise the first metablock is the emptied header of the first stream, which can go
+    Ok(((varlen_offset + 7) / 8, (bit_offset + 7) / 8))
+  }
+  fn rewrites_leading_header(&self) -> bool {
+    self.grow_window || self.merge_magic
+  }
+  // holds back the start of each stream until it is known whether it has a magic number header,
+  // then streams it on, with any such header emptied if merging them; Success once the rest can go
+  // straight through
+  fn check_magic_header(&mut self, in_bytes: &[u8], in_offset: &mut usize, out_bytes: &mut [u8], out_offset: &mut usize) -> BroCatliResult {
+    if let MagicStage::Collecting = self.magic_stage {
+      let prefix_len = usize::from(self.magic_prefix_len);
+      let to_copy = core::cmp::min(MAGIC_PREFIX_BYTES - prefix_len, in_bytes.len() - *in_offset);
+      self.magic_prefix[prefix_len..prefix_len + to_copy].clone_from_slice(&in_bytes[*in_offset..*in_offset + to_copy]);
+      *in_offset += to_copy;
+      let prefix_len = prefix_len + to_copy;
+      self.magic_prefix_len = prefix_len as u8;
+      let first = self.window_size == 0;
+      match parse_magic_prefix(&self.magic_prefix[..prefix_len]) {
+        MagicPrefix::Undecided => return BroCatliResult::NeedsMoreInput,
+        MagicPrefix::Absent => self.all_magic = false,
+        MagicPrefix::Present{window_offset, end, flags, size_hint} => {
+          if flags == MAGIC_CATABLE_DICTIONARY {
+            let stream_window = match parse_window_size(&self.magic_prefix[..prefix_len]) {
+              Ok((stream_window, _)) => stream_window,
+              Err(_) => return BroCatliResult::InvalidWindowSize,
+            };
+            if (self.dictionary_window != 0 && stream_window != self.dictionary_window)
+              || (!first && stream_window < self.window_size)
+              || (!first && stream_window > self.window_size && !self.window_can_grow_to(stream_window)) {
+              return BroCatliResult::BrotliFileNotCraftedForConcatenation;
+            }
+            self.dictionary_window = stream_window;
+          }
+          if first {
+            self.first_magic_flags = flags;
+          }
+          self.magic_size_hint = self.magic_size_hint.wrapping_add(size_hint);
+          if self.merge_magic {
+            // keep the window bits and make the metadata metablock an empty one
+            let mut stripped = [0u8; MAGIC_PREFIX_BYTES];
+            let mut bit_offset = 0usize;
+            write_bits(window_offset, u64::from(self.magic_prefix[0]) | (u64::from(self.magic_prefix[1]) << 8), &mut bit_offset, &mut stripped[..]);
+            write_bits(6, 0x06, &mut bit_offset, &mut stripped[..]); // not last, metadata, no length
+            let stripped_len = (bit_offset + 7) / 8;
+            let rest = prefix_len - end;
+            stripped[stripped_len..stripped_len + rest].clone_from_slice(&self.magic_prefix[end..prefix_len]);
+            self.magic_prefix = stripped;
+            self.magic_prefix_len = (stripped_len + rest) as u8;
+          }
+        },
+      }
+      self.magic_stage = MagicStage::Replaying;
+      self.magic_prefix_offset = 0;
+    }
+    if let MagicStage::Replaying = self.magic_stage {
+      let prefix = self.magic_prefix;
+      let prefix_len = usize::from(self.magic_prefix_len);
+      let mut prefix_offset = usize::from(self.magic_prefix_offset);
+      let result = self.stream_through(&prefix[..prefix_len], &mut prefix_offset, out_bytes, out_offset);
+      self.magic_prefix_offset = prefix_offset as u8;
+      match result {
+        BroCatliResult::Success | BroCatliResult::NeedsMoreInput | BroCatliResult::NeedsMoreOutput => {},
+        failure => return failure,
+      }
+      if prefix_offset != prefix_len {
+        return result;
+      }
+      self.magic_stage = MagicStage::PassThrough;
+    }
+    if let MagicStage::Truncated = self.magic_stage {
+      return BroCatliResult::BrotliFileNotCraftedForConcatenation;
+    }
+    BroCatliResult::Success
+  }
+  // the marker takes over the bits left in the last byte of the previous stream, and ends byte aligned
+  // so that the header of the next stream gets realigned onto a fresh byte
+  fn build_marker(&mut self) {
+    let mut bit_offset = 0usize;
+    write_bits(usize::from(self.last_byte_bit_offset), u64::from(self.last_bytes[0]), &mut bit_offset, &mut self.marker[..]);
+    write_bits(6, 0x16, &mut bit_offset, &mut self.marker[..]); // not last, metadata, 1 byte of length
+    write_bits(8, MARKER_CONTENT_BYTES as u64 - 1, &mut bit_offset, &mut self.marker[..]);
+    let content_start = (bit_offset + 7) / 8;
+    let gap = content_start * 8 - usize::from(self.last_byte_bit_offset);
+    let member = self.members;
+    let content = &mut self.marker[content_start..content_start + MARKER_CONTENT_BYTES];
+    content[..8].clone_from_slice(
+      &[MAGIC_NUMBER[0], MAGIC_NUMBER[1], MAGIC_MEMBER, gap as u8,
+        member as u8, (member >> 8) as u8, (member >> 16) as u8, (member >> 24) as u8]);
+    for (index, item) in content[8..].iter_mut().enumerate() {
+      *item = (self.member_tag >> (index * 8)) as u8;
+    }
+    self.marker_len = (content_start + MARKER_CONTENT_BYTES) as u8;
+    self.marker_written = 0;
+    self.last_bytes[0] = 0;
+    self.last_byte_bit_offset = 0;
+  }
   pub fn new_brotli_file(&mut self) {
     self.new_stream_pending = Some(NewStreamData::new());
+    // a stream that ended before it was clear whether it had a magic number header is cut short
+    self.magic_stage = match self.magic_stage {
+      MagicStage::Collecting if self.magic_prefix_len != 0 => MagicStage::Truncated,
+      MagicStage::Replaying if self.magic_prefix_offset != self.magic_prefix_len => MagicStage::Truncated,
+      MagicStage::Truncated => MagicStage::Truncated,
+      _ => MagicStage::Collecting,
+    };
+    self.magic_prefix_len = 0;
+    self.magic_prefix_offset = 0;
   }
   fn flush_previous_stream(&mut self, out_bytes: &mut [u8], out_offset: &mut usize) -> BroCatliResult {
     if !self.last_byte_sanitized { // if the previous stream hasn't had the last metablock (bit 1,1) sanitized
@@ -298,6 +898,9 @@ impl BroCatli {
       };
       if self.window_size == 0 { // parse window size and just copy everything
         self.window_size = window_size;
+        if self.rewrites_leading_header() {
+          self.record_first_header(&new_stream_pending);
+        }
         assert_eq!(self.last_byte_bit_offset, 0); // we are first stream
         out_bytes[*out_offset] = new_stream_pending.bytes_so_far[0];
         new_stream_pending.num_bytes_written = Some(1);
@@ -305,7 +908,10 @@ impl BroCatli {
         *out_offset += 1;
       } else {
         if window_size > self.window_size {
-          return BroCatliResult::WindowSizeLargerThanPreviousFile;
+          if !self.window_can_grow_to(window_size) {
+            return BroCatliResult::WindowSizeLargerThanPreviousFile;
+          }
+          self.window_size = window_size;
         }
         let mut realigned_header:[u8;NUM_STREAM_HEADER_BYTES + 1] = [self.last_bytes[0],
                                                                     0,0,0,0,0,
@@ -360,6 +966,9 @@ impl BroCatli {
       return BroCatliResult::NeedsMoreOutput;
     }
     self.new_stream_pending = None;
+    self.marker_len = 0;
+    self.marker_written = 0;
+    self.members = self.members.wrapping_add(1);
     self.last_byte_sanitized = false;
     self.last_byte_bit_offset = 0;
     self.last_bytes_len = 0;
@@ -371,6 +980,13 @@ impl BroCatli {
     BroCatliResult::Success
   }
   pub fn stream(&mut self, in_bytes: &[u8], in_offset: &mut usize, out_bytes: &mut [u8], out_offset: &mut usize) -> BroCatliResult {
+    match self.check_magic_header(in_bytes, in_offset, out_bytes, out_offset) {
+      BroCatliResult::Success => {},
+      result => return result,
+    }
+    self.stream_through(in_bytes, in_offset, out_bytes, out_offset)
+  }
+  fn stream_through(&mut self, in_bytes: &[u8], in_offset: &mut usize, out_bytes: &mut [u8], out_offset: &mut usize) -> BroCatliResult {
     if let Some(mut new_stream_pending) = self.new_stream_pending.clone() {
       let flush_result = self.flush_previous_stream(out_bytes, out_offset);
       if let BroCatliResult::Success = flush_result {
@@ -390,6 +1006,22 @@ impl BroCatli {
         if out_bytes.len() == *out_offset {
           return BroCatliResult::NeedsMoreOutput;
         }
+        if self.mark_members && self.window_size != 0 && self.any_bytes_emitted && new_stream_pending.num_bytes_written.is_none() {
+          if self.marker_len == 0 {
+            self.build_marker();
+          }
+          while self.marker_written < self.marker_len {
+            if out_bytes.len() == *out_offset {
+              return BroCatliResult::NeedsMoreOutput;
+            }
+            out_bytes[*out_offset] = self.marker[usize::from(self.marker_written)];
+            *out_offset += 1;
+            self.marker_written += 1;
+          }
+          if out_bytes.len() == *out_offset {
+            return BroCatliResult::NeedsMoreOutput;
+          }
+        }
         let shift_result = self.shift_and_check_new_stream_header(new_stream_pending, out_bytes, out_offset);
         if let BroCatliResult::Success = shift_result {
         } else {
@@ -475,6 +1107,11 @@ impl BroCatli {
     }
   }
   pub fn finish(&mut self, out_bytes: &mut [u8], out_offset: &mut usize) -> BroCatliResult {
+       match self.magic_stage {
+           MagicStage::Collecting if self.magic_prefix_len != 0 => return BroCatliResult::BrotliFileNotCraftedForConcatenation,
+           MagicStage::Replaying | MagicStage::Truncated => return BroCatliResult::BrotliFileNotCraftedForConcatenation,
+           _ => {},
+       }
        if self.last_byte_sanitized && self.last_bytes_len != 0 {
            self.append_eof_metablock_to_last_bytes();
        }
@@ -518,6 +1155,24 @@ mod test {
             any_bytes_emitted: false,
             last_byte_bit_offset: 7,
             window_size:22,
+            grow_window: true,
+            first_header: [0x21, 0x22, 0x23, 0x24, 0x25],
+            first_header_len: 4,
+            merge_magic: true,
+            magic_stage: super::MagicStage::Replaying,
+            magic_prefix: [0x5a; super::MAGIC_PREFIX_BYTES],
+            magic_prefix_len: 9,
+            magic_prefix_offset: 2,
+            first_magic_flags: 0x81,
+            all_magic: false,
+            magic_size_hint: 0x123456789a,
+            mark_members: true,
+            marker: [0x6b; super::MARKER_BYTES],
+            marker_len: 18,
+            marker_written: 3,
+            members: 0x1234567,
+            member_tag: 0xfedcba9876543210,
+            dictionary_window: 18,
         };
         let mut buffer = [0u8;248];
         broccoli.serialize_to_buffer(&mut buffer[..]).unwrap();
@@ -527,6 +1182,24 @@ mod test {
         assert_eq!(broccoli.last_byte_sanitized, bc.last_byte_sanitized);
         assert_eq!(broccoli.last_byte_bit_offset, bc.last_byte_bit_offset);
         assert_eq!(broccoli.window_size, bc.window_size);
+        assert_eq!(broccoli.grow_window, bc.grow_window);
+        assert_eq!(broccoli.first_header, bc.first_header);
+        assert_eq!(broccoli.first_header_len, bc.first_header_len);
+        assert_eq!(broccoli.merge_magic, bc.merge_magic);
+        assert_eq!(broccoli.magic_stage, bc.magic_stage);
+        assert_eq!(broccoli.magic_prefix, bc.magic_prefix);
+        assert_eq!(broccoli.magic_prefix_len, bc.magic_prefix_len);
+        assert_eq!(broccoli.magic_prefix_offset, bc.magic_prefix_offset);
+        assert_eq!(broccoli.first_magic_flags, bc.first_magic_flags);
+        assert_eq!(broccoli.all_magic, bc.all_magic);
+        assert_eq!(broccoli.magic_size_hint, bc.magic_size_hint);
+        assert_eq!(broccoli.mark_members, bc.mark_members);
+        assert_eq!(broccoli.marker, bc.marker);
+        assert_eq!(broccoli.marker_len, bc.marker_len);
+        assert_eq!(broccoli.marker_written, bc.marker_written);
+        assert_eq!(broccoli.members, bc.members);
+        assert_eq!(broccoli.member_tag, bc.member_tag);
+        assert_eq!(broccoli.dictionary_window, bc.dictionary_window);
         assert_eq!(broccoli.new_stream_pending.unwrap().bytes_so_far,
                    bc.new_stream_pending.unwrap().bytes_so_far);
         assert_eq!(broccoli.new_stream_pending.unwrap().num_bytes_read,
@@ -548,6 +1221,24 @@ mod test {
             any_bytes_emitted: true,
             last_byte_bit_offset: 7,
             window_size:22,
+            grow_window: true,
+            first_header: [0x21, 0x22, 0x23, 0x24, 0x25],
+            first_header_len: 4,
+            merge_magic: false,
+            magic_stage: super::MagicStage::PassThrough,
+            magic_prefix: [0; super::MAGIC_PREFIX_BYTES],
+            magic_prefix_len: 0,
+            magic_prefix_offset: 0,
+            first_magic_flags: 0,
+            all_magic: true,
+            magic_size_hint: 0,
+            mark_members: false,
+            marker: [0; super::MARKER_BYTES],
+            marker_len: 0,
+            marker_written: 0,
+            members: 0,
+            member_tag: 0,
+            dictionary_window: 0,
         };
         let mut buffer = [0u8;248];
         broccoli.serialize_to_buffer(&mut buffer[..]).unwrap();
@@ -557,6 +1248,9 @@ mod test {
         assert_eq!(broccoli.last_byte_sanitized, bc.last_byte_sanitized);
         assert_eq!(broccoli.last_byte_bit_offset, bc.last_byte_bit_offset);
         assert_eq!(broccoli.window_size, bc.window_size);
+        assert_eq!(broccoli.grow_window, bc.grow_window);
+        assert_eq!(broccoli.first_header, bc.first_header);
+        assert_eq!(broccoli.first_header_len, bc.first_header_len);
         assert_eq!(broccoli.new_stream_pending.unwrap().bytes_so_far,
                    bc.new_stream_pending.unwrap().bytes_so_far);
         assert_eq!(broccoli.new_stream_pending.unwrap().num_bytes_read,
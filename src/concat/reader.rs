@@ -0,0 +1,148 @@
+#![cfg_attr(not(feature="std"), allow(unused_imports))]
+use core;
+use super::{BroCatli, BroCatliResult};
+use brotli_decompressor::CustomRead;
+pub use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+#[cfg(feature="std")]
+pub use brotli_decompressor::IntoIoReader;
+#[cfg(feature="std")]
+pub use alloc_stdlib::StandardAlloc;
+#[cfg(feature="std")]
+use std::io;
+#[cfg(feature="std")]
+use std::io::{Read, Error};
+
+/// Reads the concatenation of a sequence of catable brotli files as one brotli stream.
+#[cfg(feature="std")]
+pub struct BroCatliReader<R: Read, I: Iterator<Item=R>>(
+  BroCatliReaderCustomIo<io::Error,
+                         IntoIoReader<R>,
+                         core::iter::Map<I, fn(R) -> IntoIoReader<R>>,
+                         <StandardAlloc as Allocator<u8>>::AllocatedMemory>);
+
+#[cfg(feature="std")]
+impl<R: Read, I: Iterator<Item=R>> BroCatliReader<R, I> {
+  pub fn new(sources: I, buffer_size: usize) -> Self {
+    Self::with_state(sources, buffer_size, BroCatli::new())
+  }
+  /// the window size of the output is fixed up front, so every file must use a window no larger than log_window_size
+  pub fn new_with_window_size(sources: I, buffer_size: usize, log_window_size: u8) -> Self {
+    Self::with_state(sources, buffer_size, BroCatli::new_with_window_size(log_window_size))
+  }
+  fn with_state(sources: I, buffer_size: usize, state: BroCatli) -> Self {
+    let mut alloc = StandardAlloc::default();
+    let buffer = <StandardAlloc as Allocator<u8>>::alloc_cell(&mut alloc, if buffer_size == 0 { 4096 } else { buffer_size });
+    let into_reader: fn(R) -> IntoIoReader<R> = IntoIoReader::<R>;
+    BroCatliReader::<R, I>(BroCatliReaderCustomIo::with_state(sources.map(into_reader),
+                                                              buffer,
+                                                              state,
+                                                              io::Error::from))
+  }
+}
+
+#[cfg(feature="std")]
+impl<R: Read, I: Iterator<Item=R>> Read for BroCatliReader<R, I> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+    self.0.read(buf)
+  }
+}
+
+pub struct BroCatliReaderCustomIo<ErrType,
+                                  R: CustomRead<ErrType>,
+                                  I: Iterator<Item=R>,
+                                  BufferType: SliceWrapperMut<u8>> {
+  input_buffer: BufferType,
+  input_offset: usize,
+  input_len: usize,
+  sources: I,
+  input: Option<R>,
+  state: BroCatli,
+  needs_input: bool,
+  done: bool,
+  error_from_result: fn(BroCatliResult) -> ErrType,
+}
+
+impl<ErrType,
+     R: CustomRead<ErrType>,
+     I: Iterator<Item=R>,
+     BufferType: SliceWrapperMut<u8>>
+BroCatliReaderCustomIo<ErrType, R, I, BufferType> {
+  pub fn new(sources: I,
+             buffer: BufferType,
+             error_from_result: fn(BroCatliResult) -> ErrType) -> Self {
+    Self::with_state(sources, buffer, BroCatli::new(), error_from_result)
+  }
+  pub fn new_with_window_size(sources: I,
+                              buffer: BufferType,
+                              log_window_size: u8,
+                              error_from_result: fn(BroCatliResult) -> ErrType) -> Self {
+    Self::with_state(sources, buffer, BroCatli::new_with_window_size(log_window_size), error_from_result)
+  }
+  pub fn with_state(sources: I,
+                    buffer: BufferType,
+                    state: BroCatli,
+                    error_from_result: fn(BroCatliResult) -> ErrType) -> Self {
+    BroCatliReaderCustomIo {
+      input_buffer: buffer,
+      input_offset: 0,
+      input_len: 0,
+      sources: sources,
+      input: None,
+      state: state,
+      needs_input: true,
+      done: false,
+      error_from_result: error_from_result,
+    }
+  }
+}
+
+impl<ErrType,
+     R: CustomRead<ErrType>,
+     I: Iterator<Item=R>,
+     BufferType: SliceWrapperMut<u8>> CustomRead<ErrType> for
+BroCatliReaderCustomIo<ErrType, R, I, BufferType> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrType> {
+    let mut output_offset = 0usize;
+    while output_offset != buf.len() && !self.done {
+      if self.input.is_none() {
+        match self.sources.next() {
+          Some(source) => {
+            self.input = Some(source);
+            self.state.new_brotli_file();
+          },
+          None => {
+            match self.state.finish(buf, &mut output_offset) {
+              BroCatliResult::NeedsMoreOutput => {},
+              BroCatliResult::Success => self.done = true,
+              failure => return Err((self.error_from_result)(failure)),
+            }
+          },
+        }
+        continue;
+      }
+      if self.input_offset == self.input_len && self.needs_input {
+        if output_offset != 0 {
+          break; // hand back what we have rather than block on the source
+        }
+        self.input_offset = 0;
+        self.input_len = match self.input.as_mut().unwrap().read(self.input_buffer.slice_mut()) {
+          Ok(size) => size,
+          Err(e) => return Err(e),
+        };
+        if self.input_len == 0 {
+          self.input = None;
+          continue;
+        }
+      }
+      // the BroCatli may have output left over even once the input is used up, so only move on
+      // to more input (or the next file) after it asks for it
+      match self.state.stream(&self.input_buffer.slice()[..self.input_len], &mut self.input_offset,
+                              buf, &mut output_offset) {
+        BroCatliResult::NeedsMoreOutput => self.needs_input = false,
+        BroCatliResult::NeedsMoreInput | BroCatliResult::Success => self.needs_input = true,
+        failure => return Err((self.error_from_result)(failure)),
+      }
+    }
+    Ok(output_offset)
+  }
+}
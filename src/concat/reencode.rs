@@ -0,0 +1,438 @@
+#![cfg(feature="std")]
+use core;
+use std::io;
+use std::vec::Vec;
+use enc::{BrotliCompress, BrotliEncoderParams};
+use super::{MAGIC_CATABLE_DICTIONARY, magic_header, window_size_bits};
+use super::metablock::{Metablock, MetablockError, MetablockKind, StreamParser};
+
+// metablocks to compress again are collected up to this much data before it is compressed
+const MAX_PENDING_BYTES: usize = 1 << 22;
+
+fn invalid_stream(error: MetablockError) -> io::Error {
+  match error {
+    MetablockError::NeedsMoreInput => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream"),
+    MetablockError::Invalid => io::Error::new(io::ErrorKind::InvalidData, "invalid brotli stream"),
+  }
+}
+
+fn compress(data: &[u8], params: &BrotliEncoderParams) -> io::Result<Vec<u8>> {
+  let mut encoded = Vec::new();
+  match BrotliCompress(&mut io::Cursor::new(data), &mut encoded, params) {
+    Ok(_) => Ok(encoded),
+    Err(e) => Err(e),
+  }
+}
+
+fn peek_bits(bytes: &[u8], bit_offset: usize, num_bits: usize) -> u64 {
+  let mut bits = 0u64;
+  for (index, item) in bytes.iter().skip(bit_offset / 8).take(5).enumerate() {
+    bits |= u64::from(*item) << (index * 8);
+  }
+  (bits >> (bit_offset % 8)) & ((1u64 << num_bits) - 1)
+}
+
+// output bits, the last byte of which may be partly written
+struct BitWriter {
+  bytes: Vec<u8>,
+  bit_len: usize,
+}
+
+impl BitWriter {
+  fn write_bits(&mut self, mut num_bits: usize, mut bits: u64) {
+    while num_bits != 0 {
+      let offset = self.bit_len % 8;
+      if offset == 0 {
+        self.bytes.push(0);
+      }
+      let count = core::cmp::min(8 - offset, num_bits);
+      let last = self.bytes.len() - 1;
+      self.bytes[last] |= ((bits & ((1 << count) - 1)) << offset) as u8;
+      bits >>= count;
+      num_bits -= count;
+      self.bit_len += count;
+    }
+  }
+  fn copy_bits(&mut self, bytes: &[u8], mut start: usize, end: usize) {
+    if start % 8 == self.bit_len % 8 {
+      // lined up, so the whole bytes in between can go in as they are
+      let lead = core::cmp::min((8 - start % 8) % 8, end - start);
+      self.write_bits(lead, peek_bits(bytes, start, lead));
+      start += lead;
+      let whole_bytes = (end - start) / 8;
+      self.bytes.extend_from_slice(&bytes[start / 8..start / 8 + whole_bytes]);
+      self.bit_len += whole_bytes * 8;
+      start += whole_bytes * 8;
+    }
+    while start < end {
+      let count = core::cmp::min(32, end - start);
+      self.write_bits(count, peek_bits(bytes, start, count));
+      start += count;
+    }
+  }
+  fn pad(&mut self) {
+    self.bit_len = (self.bit_len + 7) / 8 * 8;
+  }
+  // moves the whole bytes written so far to output
+  fn drain_to(&mut self, output: &mut Vec<u8>) {
+    let whole_bytes = self.bit_len / 8;
+    output.extend(self.bytes.drain(..whole_bytes));
+    self.bit_len -= whole_bytes * 8;
+  }
+}
+
+// copies a metablock of bytes to out, turned into one that is not the last if need be;
+// only compressed metablocks keep their bit alignment, so the others get their headers again
+fn emit_metablock(out: &mut BitWriter, bytes: &[u8], metablock: &Metablock) {
+  if metablock.kind == MetablockKind::LastEmpty {
+    return;
+  }
+  let header_start = if metablock.is_last {
+    out.write_bits(1, 0); // ISLAST, in place of ISLAST and ISLASTEMPTY
+    metablock.start_bit + 2
+  } else {
+    metablock.start_bit
+  };
+  match metablock.kind {
+    MetablockKind::LastEmpty => {},
+    MetablockKind::Compressed => if metablock.is_last {
+      out.copy_bits(bytes, header_start, metablock.length_end_bit);
+      out.write_bits(1, 0); // ISUNCOMPRESSED
+      out.copy_bits(bytes, metablock.length_end_bit, metablock.end_bit);
+    } else {
+      out.copy_bits(bytes, metablock.start_bit, metablock.end_bit);
+    },
+    MetablockKind::Uncompressed{data_start} => {
+      out.copy_bits(bytes, header_start, metablock.length_end_bit);
+      out.write_bits(1, 1); // ISUNCOMPRESSED
+      out.pad();
+      out.copy_bits(bytes, data_start * 8, (data_start + metablock.len) * 8);
+    },
+    MetablockKind::Metadata{data_start} => {
+      out.copy_bits(bytes, header_start, metablock.length_end_bit);
+      out.pad();
+      out.copy_bits(bytes, data_start * 8, (data_start + metablock.len) * 8);
+    },
+  }
+}
+
+// whether a metablock takes any of the distances a stream starts with, given how many were pushed before it
+fn reads_initial_distances(metablock: &Metablock, pushed_before: usize) -> bool {
+  pushed_before < 4 && (metablock.cached_distances_read >> pushed_before) != 0
+}
+
+/// Rewrites a standalone brotli stream, fed in piece by piece, so that BroCatli can splice it in after
+/// streams whose window is 2^window_size (0 when it is to be the first stream of the output).
+/// A first stream keeps all its metablocks and only has its last one turned into one that is not the
+/// last, so that further streams can follow. In a later stream only the compressed metablocks
+/// that would decode differently after other data are compressed again, without the static
+/// dictionary: those that refer to the dictionary (unless the stream is flagged as using it only
+/// from a full window in and has the window of the output), to distances cached before them that
+/// the output does not have or to the bytes before the stream, or that reach further back than the
+/// window allows. Every other metablock is copied bitwise. Only the metablock being read and the
+/// window it may refer to are held in memory.
+pub struct ConcatRewriter {
+  window_size: u8,
+  params: BrotliEncoderParams,
+  // the stream from the byte holding the start of the next metablock on
+  input: Vec<u8>,
+  bit_offset: usize,
+  // how much input to wait for before trying to parse a metablock that did not fit again
+  retry_len: usize,
+  parser: Option<StreamParser>,
+  out: BitWriter,
+  out_window_size: u8,
+  large_window: bool,
+  // whether references to the dictionary from a full window in can stay as they are: the stream is
+  // flagged as using the dictionary only from there on, and has the window of the output
+  dictionary_window: bool,
+  any_metablock: bool,
+  // the distances cached in the output, last first, as far as they are known
+  distances: [Option<i64>; 4],
+  // the data of metablocks that have to be compressed again
+  pending: Vec<u8>,
+}
+
+impl ConcatRewriter {
+  pub fn new(window_size: u8, params: &BrotliEncoderParams) -> Self {
+    ConcatRewriter {
+      window_size: window_size,
+      params: params.clone(),
+      input: Vec::new(),
+      bit_offset: 0,
+      retry_len: 0,
+      parser: None,
+      out: BitWriter {
+        bytes: Vec::new(),
+        bit_len: 0,
+      },
+      out_window_size: 0,
+      large_window: false,
+      dictionary_window: false,
+      any_metablock: false,
+      distances: [None; 4],
+      pending: Vec::new(),
+    }
+  }
+  /// Takes the next piece of the stream, appending what it can of the rewritten stream to output.
+  pub fn write(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+    self.input.extend_from_slice(input);
+    match self.parse_available(false) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    self.out.drain_to(output);
+    Ok(())
+  }
+  /// Ends the rewritten stream once the whole input stream has been written, with the bits
+  /// BroCatli expects at the end of an appendable stream.
+  pub fn finish(mut self, output: &mut Vec<u8>) -> io::Result<()> {
+    match self.parse_available(true) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    match self.parser {
+      Some(ref parser) if parser.ended() => {},
+      _ => return Err(invalid_stream(MetablockError::NeedsMoreInput)),
+    }
+    match self.compress_pending() {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    self.out.write_bits(2, 3); // ISLAST, ISLASTEMPTY
+    self.out.pad();
+    self.out.drain_to(output);
+    Ok(())
+  }
+  fn first(&self) -> bool {
+    self.window_size == 0
+  }
+  // parses and passes on metablocks while there is input for them; at the end of the input
+  // whatever is there has to do
+  fn parse_available(&mut self, at_end: bool) -> io::Result<()> {
+    loop {
+      let mut parser = match self.parser.take() {
+        Some(parser) => parser,
+        None => match StreamParser::new(&self.input[..]) {
+          Ok(parser) => {
+            self.start_stream(&parser);
+            parser
+          },
+          Err(MetablockError::NeedsMoreInput) if !at_end => return Ok(()),
+          Err(e) => return Err(invalid_stream(e)),
+        },
+      };
+      let result = self.parse_metablock(&mut parser, at_end);
+      self.parser = Some(parser);
+      match result {
+        Ok(true) => {},
+        Ok(false) => return Ok(()),
+        Err(e) => return Err(e),
+      }
+    }
+  }
+  // passes on the next metablock if there is one; false if there is none yet
+  fn parse_metablock(&mut self, parser: &mut StreamParser, at_end: bool) -> io::Result<bool> {
+    if parser.ended() {
+      if self.input.len() > (self.bit_offset + 7) / 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "data after the end of the brotli stream"));
+      }
+      return Ok(false);
+    }
+    if !at_end && self.input.len() < self.retry_len {
+      return Ok(false);
+    }
+    let last_distances = parser.last_distances();
+    let metablock = match parser.parse(&self.input[..], self.bit_offset) {
+      Ok(metablock) => metablock,
+      Err(MetablockError::NeedsMoreInput) if !at_end => {
+        // wait for twice the input, so that a long metablock is not parsed over and over
+        self.retry_len = self.input.len() * 2;
+        return Ok(false);
+      },
+      Err(e) => return Err(invalid_stream(e)),
+    };
+    let input = core::mem::replace(&mut self.input, Vec::new());
+    let result = if self.first() {
+      emit_metablock(&mut self.out, &input[..], &metablock);
+      Ok(())
+    } else {
+      self.rewrite_metablock(parser, &input[..], &metablock, &last_distances)
+    };
+    self.input = input;
+    let whole_bytes = metablock.end_bit / 8;
+    self.input.drain(..whole_bytes);
+    self.bit_offset = metablock.end_bit - whole_bytes * 8;
+    self.retry_len = 0;
+    self.any_metablock = true;
+    result.map(|_| true)
+  }
+  fn start_stream(&mut self, parser: &StreamParser) {
+    if self.first() {
+      let header_bits = parser.header_bits;
+      self.out.copy_bits(&self.input[..], 0, header_bits);
+    } else {
+      self.out_window_size = core::cmp::min(parser.window_size, self.window_size);
+      self.large_window = self.window_size > 24;
+      let (bits, num_bits) = if self.large_window {
+        (0x11 | (u64::from(self.out_window_size) << 8), 14)
+      } else {
+        window_size_bits(self.out_window_size)
+      };
+      self.out.write_bits(num_bits, bits);
+      self.dictionary_window = match magic_header(&self.input[..]) {
+        Some((flags, _)) => flags == MAGIC_CATABLE_DICTIONARY && parser.window_size == self.window_size,
+        None => false,
+      };
+    }
+    self.bit_offset = parser.header_bits;
+  }
+  fn rewrite_metablock(&mut self,
+                       parser: &StreamParser,
+                       bytes: &[u8],
+                       metablock: &Metablock,
+                       last_distances: &[i64; 4]) -> io::Result<()> {
+    if !self.any_metablock && metablock.kind == MetablockKind::Compressed {
+      // BroCatli splices later streams in at the first byte aligned metablock, so start with an empty one
+      self.out.write_bits(6, 0x06); // not last, metadata, no length
+      self.out.pad();
+    }
+    if metablock.kind != MetablockKind::Compressed {
+      if metablock.kind != MetablockKind::LastEmpty {
+        match self.compress_pending() {
+          Ok(_) => {},
+          Err(e) => return Err(e),
+        }
+        emit_metablock(&mut self.out, bytes, metablock);
+      }
+      return Ok(());
+    }
+    let copyable = parser.large_window == self.large_window
+      && !metablock.dictionary_before_full_window
+      && (self.dictionary_window || !metablock.dictionary_after_full_window)
+      && metablock.max_distance <= (1i64 << self.window_size) - 16
+      && !metablock.uses_context_before_stream;
+    if copyable {
+      // whether the distances it takes from the cache are there depends on what went before
+      match self.compress_pending() {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+      let distances_match = (0..4).all(|back| metablock.cached_distances_read & (1 << back) == 0
+                                       || self.distances[back] == Some(last_distances[back]));
+      if distances_match {
+        emit_metablock(&mut self.out, bytes, metablock);
+        self.push_distances(metablock.distances_pushed, &parser.last_distances());
+        return Ok(());
+      }
+    }
+    self.pending.extend_from_slice(parser.output_tail(metablock.len));
+    if self.pending.len() >= MAX_PENDING_BYTES {
+      return self.compress_pending();
+    }
+    Ok(())
+  }
+  fn push_distances(&mut self, pushed: usize, last_distances: &[i64; 4]) {
+    let previous = self.distances;
+    for (back, item) in self.distances.iter_mut().enumerate() {
+      *item = if back < pushed { Some(last_distances[back]) } else { previous[back - pushed] };
+    }
+  }
+  // compresses the data of the metablocks that could not be copied as a catable stream without
+  // the dictionary and splices its metablocks in
+  fn compress_pending(&mut self) -> io::Result<()> {
+    if self.pending.len() == 0 {
+      return Ok(());
+    }
+    let mut params = self.params.clone();
+    params.magic_number = false;
+    params.catable = true;
+    params.appendable = true;
+    params.use_dictionary = false;
+    params.catable_dictionary = false;
+    params.lgwin = i32::from(self.out_window_size);
+    params.large_window = self.large_window;
+    let encoded = match compress(&self.pending[..], &params) {
+      Ok(encoded) => encoded,
+      Err(e) => return Err(e),
+    };
+    self.pending.clear();
+    let mut parser = match StreamParser::new(&encoded[..]) {
+      Ok(parser) => parser,
+      Err(e) => return Err(invalid_stream(e)),
+    };
+    let mut bit_offset = parser.header_bits;
+    while !parser.ended() {
+      let metablock = match parser.parse(&encoded[..], bit_offset) {
+        Ok(metablock) => metablock,
+        Err(e) => return Err(invalid_stream(e)),
+      };
+      emit_metablock(&mut self.out, &encoded[..], &metablock);
+      self.push_distances(metablock.distances_pushed, &parser.last_distances());
+      bit_offset = metablock.end_bit;
+    }
+    Ok(())
+  }
+}
+
+/// Whether BroCatli can copy a brotli stream bitwise into a concatenation whose first stream has a
+/// 2^window_size window (0 when this is to be the first stream). BroCatli itself only checks the
+/// stream header and its final bits, which ordinary streams may pass by chance, so every metablock
+/// is parsed: after other streams, references to the static dictionary, to the distances every
+/// stream starts with or to the bytes before the stream would all decode differently.
+pub fn splices_cleanly(compressed: &[u8], window_size: u8) -> bool {
+  let mut parser = match StreamParser::new(compressed) {
+    Ok(parser) => parser,
+    Err(_) => return false,
+  };
+  let first = window_size == 0;
+  if !first && (parser.window_size > window_size || parser.large_window != (window_size > 24)) {
+    return false;
+  }
+  let dictionary_window = match magic_header(compressed) {
+    Some((flags, _)) => flags == MAGIC_CATABLE_DICTIONARY && parser.window_size == window_size,
+    None => false,
+  };
+  let mut bit_offset = parser.header_bits;
+  let mut pushed = 0usize;
+  let mut any_metablock = false;
+  while !parser.ended() {
+    let metablock = match parser.parse(compressed, bit_offset) {
+      Ok(metablock) => metablock,
+      Err(_) => return false,
+    };
+    if metablock.is_last && metablock.kind != MetablockKind::LastEmpty {
+      return false;
+    }
+    if !first && metablock.kind == MetablockKind::Compressed {
+      if !any_metablock
+        || metablock.dictionary_before_full_window
+        || (metablock.dictionary_after_full_window && !dictionary_window)
+        || metablock.max_distance > (1i64 << window_size) - 16
+        || reads_initial_distances(&metablock, pushed)
+        || metablock.uses_context_before_stream {
+        return false;
+      }
+    }
+    pushed += metablock.distances_pushed;
+    bit_offset = metablock.end_bit;
+    any_metablock = true;
+  }
+  (bit_offset + 7) / 8 == compressed.len()
+}
+
+/// Rewrites a whole standalone brotli stream with a ConcatRewriter.
+pub fn reencode_for_concat(compressed: &[u8],
+                           window_size: u8,
+                           params: &BrotliEncoderParams) -> io::Result<Vec<u8>> {
+  let mut rewriter = ConcatRewriter::new(window_size, params);
+  let mut output = Vec::new();
+  match rewriter.write(compressed, &mut output) {
+    Ok(_) => {},
+    Err(e) => return Err(e),
+  }
+  match rewriter.finish(&mut output) {
+    Ok(_) => Ok(output),
+    Err(e) => Err(e),
+  }
+}
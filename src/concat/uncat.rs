@@ -0,0 +1,283 @@
+#![cfg(feature="std")]
+use std::vec::Vec;
+use super::{BroCatliResult, MAGIC_MEMBER, MAGIC_NUMBER, MARKER_CONTENT_BYTES,
+            metablock_header_end, stream_window_size, window_size_bits, write_bits};
+use super::metablock::StreamParser;
+
+/// One of the streams that went into a concatenation made with BroCatli::mark_members,
+/// found from the markers without decompressing anything but the first stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UncatMember {
+  /// position of the stream in the concatenation, 0 for the first
+  pub index: u32,
+  window_size: u8,
+  // byte offset of the first metablock of the member, or 0 for the first one, which keeps the
+  // window bits it starts with
+  start: usize,
+  // bit offset where the marker of the next member starts, None for the last member
+  end_bit: Option<usize>,
+}
+
+// where the pieces of a standalone member come from
+struct Layout {
+  header_len: usize,
+  body_start: usize,
+  body_end: usize,
+  tail_len: usize,
+}
+
+impl UncatMember {
+  fn layout(&self, concatenated: &[u8]) -> Result<Layout, BroCatliResult> {
+    let (header_len, body_start) = if self.start == 0 {
+      (0, 0)
+    } else {
+      match standalone_header(self.window_size, &concatenated[self.start..], None) {
+        Ok((header_len, replaced_len)) => (header_len, self.start + replaced_len),
+        Err(e) => return Err(e),
+      }
+    };
+    let (body_end, tail_len) = match self.end_bit {
+      // the final metablock bits (1,1) that were taken off when the next stream was appended go back
+      Some(end_bit) => (end_bit / 8, if end_bit % 8 < 7 { 1 } else { 2 }),
+      None => (concatenated.len(), 0),
+    };
+    if body_end < body_start || body_end > concatenated.len() {
+      return Err(BroCatliResult::BrotliFileNotCraftedForConcatenation);
+    }
+    Ok(Layout {
+      header_len: header_len,
+      body_start: body_start,
+      body_end: body_end,
+      tail_len: tail_len,
+    })
+  }
+  /// the number of bytes write_standalone needs
+  pub fn standalone_len(&self, concatenated: &[u8]) -> Result<usize, BroCatliResult> {
+    match self.layout(concatenated) {
+      Ok(layout) => Ok(layout.header_len + layout.body_end - layout.body_start + layout.tail_len),
+      Err(e) => Err(e),
+    }
+  }
+  /// Writes the member as a brotli file of its own, with a header for the window of the
+  /// concatenation, and returns how many bytes that took.
+  pub fn write_standalone(&self, concatenated: &[u8], out: &mut [u8]) -> Result<usize, BroCatliResult> {
+    let layout = match self.layout(concatenated) {
+      Ok(layout) => layout,
+      Err(e) => return Err(e),
+    };
+    let total = layout.header_len + layout.body_end - layout.body_start + layout.tail_len;
+    if out.len() < total {
+      return Err(BroCatliResult::NeedsMoreOutput);
+    }
+    if layout.header_len != 0 {
+      if let Err(e) = standalone_header(self.window_size, &concatenated[self.start..], Some(out)) {
+        return Err(e);
+      }
+    }
+    let body_len = layout.body_end - layout.body_start;
+    out[layout.header_len..layout.header_len + body_len].clone_from_slice(
+      &concatenated[layout.body_start..layout.body_end]);
+    if let Some(end_bit) = self.end_bit {
+      let used_bits = end_bit % 8;
+      let last_bits = u16::from(concatenated[layout.body_end]) & ((1 << used_bits) - 1);
+      let last_bits = last_bits | (3 << used_bits);
+      let tail = &mut out[layout.header_len + body_len..total];
+      tail[0] = last_bits as u8;
+      if tail.len() > 1 {
+        tail[1] = (last_bits >> 8) as u8;
+      }
+    }
+    Ok(total)
+  }
+}
+
+/// The start of a standalone stream for a member after the first, whose first metablock starts
+/// at the beginning of bytes: the window bits and then the header of that metablock, realigned.
+/// Writes it to out if given and returns its length and that of the bytes it replaces.
+pub fn standalone_header(window_size: u8, bytes: &[u8], out: Option<&mut [u8]>) -> Result<(usize, usize), BroCatliResult> {
+  let header_end = match metablock_header_end(bytes, 0) {
+    Ok(header_end) => header_end,
+    Err(_) => return Err(BroCatliResult::BrotliFileNotCraftedForConcatenation),
+  };
+  let (window_bits, window_bits_len) = window_size_bits(window_size);
+  let replaced_len = (header_end + 7) / 8;
+  if let Some(out) = out {
+    let mut header_bits = 0u64;
+    for (index, item) in bytes[..replaced_len].iter().enumerate() {
+      header_bits |= u64::from(*item) << (index * 8);
+    }
+    let mut bit_offset = 0usize;
+    write_bits(window_bits_len, window_bits, &mut bit_offset, out);
+    write_bits(header_end, header_bits, &mut bit_offset, out);
+  }
+  Ok(((window_bits_len + header_end + 7) / 8, replaced_len))
+}
+
+/// The marker metablock whose contents start at content_start.
+pub struct Marker {
+  pub start_bit: usize,
+  pub index: u32,
+  pub tag: u64,
+}
+
+pub fn marker_at(concatenated: &[u8], content_start: usize) -> Option<Marker> {
+  let content = &concatenated[content_start..content_start + MARKER_CONTENT_BYTES];
+  if content[..2] != MAGIC_NUMBER[..] || content[2] != MAGIC_MEMBER {
+    return None;
+  }
+  let gap = usize::from(content[3]);
+  if gap < 14 || gap > 21 || content_start * 8 < gap {
+    return None;
+  }
+  let start_bit = content_start * 8 - gap;
+  let mut bits = 0u64;
+  for (index, item) in concatenated[start_bit / 8..content_start].iter().enumerate() {
+    bits |= u64::from(*item) << (index * 8);
+  }
+  bits >>= start_bit % 8;
+  // not last, metadata, 1 byte of length, the length and then zeros up to the contents
+  if bits != 0x16 | ((MARKER_CONTENT_BYTES as u64 - 1) << 6) {
+    return None;
+  }
+  let mut tag = 0u64;
+  for (index, item) in content[8..].iter().enumerate() {
+    tag |= u64::from(*item) << (index * 8);
+  }
+  Some(Marker {
+    start_bit: start_bit,
+    index: u32::from(content[4]) | (u32::from(content[5]) << 8) | (u32::from(content[6]) << 16) | (u32::from(content[7]) << 24),
+    tag: tag,
+  })
+}
+
+/// Iterates over the members of a concatenation made with BroCatli::mark_members. A stream
+/// without markers comes out as a single member.
+///
+/// The first stream gets parsed metablock by metablock up to its marker, which confirms that the
+/// marker is not part of data stored in it, say a marked concatenation compressed again. That
+/// marker gives the tag of the concatenation, which the later ones then have to carry.
+pub struct UncatMembers<'a> {
+  concatenated: &'a [u8],
+  window_size: u8,
+  start: usize,
+  index: u32,
+  done: bool,
+  tag: Option<u64>,
+  // the first stream, parsed up to first_member_bit; None once it fails to parse
+  first_member: Option<StreamParser>,
+  first_member_bit: usize,
+}
+
+pub fn uncat_members<'a>(concatenated: &'a [u8]) -> Result<UncatMembers<'a>, BroCatliResult> {
+  let window_size = match stream_window_size(concatenated) {
+    Ok(window_size) => window_size,
+    Err(e) => return Err(e),
+  };
+  let first_member = StreamParser::new(concatenated).ok();
+  let first_member_bit = match first_member {
+    Some(ref parser) => parser.header_bits,
+    None => 0,
+  };
+  Ok(UncatMembers {
+    concatenated: concatenated,
+    window_size: window_size,
+    start: 0,
+    index: 0,
+    done: false,
+    tag: None,
+    first_member: first_member,
+    first_member_bit: first_member_bit,
+  })
+}
+
+impl<'a> UncatMembers<'a> {
+  /// the tag the markers of the concatenation carry, once the first of them has been found
+  pub fn member_tag(&self) -> Option<u64> {
+    self.tag
+  }
+  // whether the metablocks of the first stream end at end_bit
+  fn ends_first_member(&mut self, end_bit: usize) -> bool {
+    if let Some(ref mut parser) = self.first_member {
+      while self.first_member_bit < end_bit && !parser.ended() {
+        match parser.parse(self.concatenated, self.first_member_bit) {
+          Ok(metablock) => self.first_member_bit = metablock.end_bit,
+          Err(_) => break,
+        }
+      }
+    }
+    if self.first_member_bit < end_bit {
+      self.first_member = None;
+    }
+    self.first_member.is_some() && self.first_member_bit == end_bit
+  }
+  // the bit offset at which the marker whose contents start at content_start begins, if that is
+  // the marker of the member after the current one
+  fn marker_start(&mut self, content_start: usize) -> Option<usize> {
+    let marker = match marker_at(self.concatenated, content_start) {
+      Some(marker) => marker,
+      None => return None,
+    };
+    if marker.index != self.index.wrapping_add(1) || marker.start_bit < self.start * 8 {
+      return None;
+    }
+    match self.tag {
+      Some(tag) if tag != marker.tag => return None,
+      Some(_) => {},
+      None => {
+        if !self.ends_first_member(marker.start_bit) {
+          return None;
+        }
+        self.tag = Some(marker.tag);
+        self.first_member = None;
+      },
+    }
+    Some(marker.start_bit)
+  }
+}
+
+impl<'a> Iterator for UncatMembers<'a> {
+  type Item = UncatMember;
+  fn next(&mut self) -> Option<UncatMember> {
+    if self.done {
+      return None;
+    }
+    let mut member = UncatMember {
+      index: self.index,
+      window_size: self.window_size,
+      start: self.start,
+      end_bit: None,
+    };
+    let mut content_start = self.start + 2;
+    while content_start + MARKER_CONTENT_BYTES <= self.concatenated.len() {
+      if let Some(marker_start) = self.marker_start(content_start) {
+        member.end_bit = Some(marker_start);
+        self.start = content_start + MARKER_CONTENT_BYTES;
+        self.index = self.index.wrapping_add(1);
+        return Some(member);
+      }
+      content_start += 1;
+    }
+    self.done = true;
+    Some(member)
+  }
+}
+
+/// Splits a concatenation made with BroCatli::mark_members back into standalone brotli files.
+pub fn uncat(concatenated: &[u8]) -> Result<Vec<Vec<u8>>, BroCatliResult> {
+  let members = match uncat_members(concatenated) {
+    Ok(members) => members,
+    Err(e) => return Err(e),
+  };
+  let mut ret = Vec::new();
+  for member in members {
+    let mut standalone = vec![0u8; match member.standalone_len(concatenated) {
+      Ok(len) => len,
+      Err(e) => return Err(e),
+    }];
+    match member.write_standalone(concatenated, &mut standalone[..]) {
+      Ok(_) => ret.push(standalone),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(ret)
+}
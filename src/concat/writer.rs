@@ -0,0 +1,405 @@
+#![cfg_attr(not(feature="std"), allow(unused_imports))]
+use core;
+use super::{BroCatli, BroCatliResult, LEADING_HEADER_BYTES};
+use brotli_decompressor::CustomWrite;
+pub use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use enc::writer::write_all;
+#[cfg(feature="std")]
+pub use alloc_stdlib::StandardAlloc;
+#[cfg(feature="std")]
+use std::io;
+#[cfg(feature="std")]
+use std::io::{Write, Error};
+#[cfg(feature="std")]
+use std::vec::Vec;
+#[cfg(feature="std")]
+use enc::BrotliEncoderParams;
+#[cfg(feature="std")]
+use super::reencode::ConcatRewriter;
+#[cfg(feature="std")]
+use super::stream_window_size;
+
+/// Concatenates catable brotli files into a single brotli stream as they are written.
+/// Call start_new_file() before the bytes of each file; the trailing metablock is
+/// emitted by finish(), into_inner() or on drop.
+#[cfg(feature="std")]
+pub struct BroCatliWriter<W: Write> {
+  inner: BroCatliWriterCustomIo<io::Error,
+                                HeldOutput<W>,
+                                <StandardAlloc as Allocator<u8>>::AllocatedMemory>,
+  reencode: Option<Reencode>,
+}
+
+// with window growth the output is held until finish, when its header can be rewritten
+#[cfg(feature="std")]
+struct HeldOutput<W: Write> {
+  output: W,
+  held: Option<Vec<u8>>,
+}
+
+#[cfg(feature="std")]
+impl<W: Write> CustomWrite<io::Error> for HeldOutput<W> {
+  fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+    match self.held {
+      Some(ref mut held) => {
+        held.extend_from_slice(data);
+        Ok(data.len())
+      },
+      None => self.output.write(data),
+    }
+  }
+  fn flush(&mut self) -> Result<(), io::Error> {
+    match self.held {
+      Some(_) => Ok(()),
+      None => self.output.flush(),
+    }
+  }
+}
+
+#[cfg(feature="std")]
+struct Reencode {
+  params: BrotliEncoderParams,
+  // the start of the current file, until it says what window the file has
+  header: Vec<u8>,
+  rewriter: Option<ConcatRewriter>,
+  in_file: bool,
+  output: Vec<u8>,
+  // from finishing a file in start_new_file, to be returned by the next write or finish
+  error: Option<io::Error>,
+}
+
+#[cfg(feature="std")]
+impl<W: Write> BroCatliWriter<W> {
+  pub fn new(w: W, buffer_size: usize) -> Self {
+    Self::build(w, buffer_size, BroCatli::new(), None)
+  }
+  /// the window size of the output is fixed up front, so every file must use a window no larger than log_window_size
+  pub fn new_with_window_size(w: W, buffer_size: usize, log_window_size: u8) -> Self {
+    Self::build(w, buffer_size, BroCatli::new_with_window_size(log_window_size), None)
+  }
+  /// Lets later files have larger windows than the first one, which must be catable.
+  /// The output is held in memory until finish, which rewrites its header for the largest window.
+  pub fn with_window_growth(w: W, buffer_size: usize) -> Self {
+    Self::build(w, buffer_size, BroCatli::new_with_window_growth(), None)
+  }
+  /// starts from a BroCatli set up elsewhere, eg by scan_window_sizes
+  pub fn with_state(w: W, buffer_size: usize, state: BroCatli) -> Self {
+    Self::build(w, buffer_size, state, None)
+  }
+  /// Also accepts files that were not compressed to be concatenated: each file is passed through a
+  /// ConcatRewriter, which compresses again with params only the metablocks that cannot be copied
+  /// in bitwise. An error finishing a file in start_new_file is returned by the next write or finish.
+  pub fn with_reencode(w: W, buffer_size: usize, params: &BrotliEncoderParams) -> Self {
+    Self::build(w, buffer_size, BroCatli::new(), Some(params))
+  }
+  pub fn with_reencode_and_window_size(w: W, buffer_size: usize, log_window_size: u8, params: &BrotliEncoderParams) -> Self {
+    Self::build(w, buffer_size, BroCatli::new_with_window_size(log_window_size), Some(params))
+  }
+  pub fn with_reencode_and_state(w: W, buffer_size: usize, state: BroCatli, params: &BrotliEncoderParams) -> Self {
+    Self::build(w, buffer_size, state, Some(params))
+  }
+  fn build(w: W, buffer_size: usize, state: BroCatli, params: Option<&BrotliEncoderParams>) -> Self {
+    let mut alloc = StandardAlloc::default();
+    let buffer = <StandardAlloc as Allocator<u8>>::alloc_cell(&mut alloc, if buffer_size == 0 { 4096 } else { buffer_size });
+    let held = if state.rewrites_leading_header() { Some(Vec::new()) } else { None };
+    BroCatliWriter::<W> {
+      inner: BroCatliWriterCustomIo::with_state(HeldOutput::<W>{output: w, held: held},
+                                                buffer,
+                                                state,
+                                                io::Error::from),
+      reencode: params.map(|params| Reencode {
+        params: params.clone(),
+        header: Vec::new(),
+        rewriter: None,
+        in_file: false,
+        output: Vec::new(),
+        error: None,
+      }),
+    }
+  }
+  pub fn start_new_file(&mut self) {
+    if self.reencode.is_some() {
+      if let Err(e) = self.finish_file() {
+        self.reencode.as_mut().unwrap().error = Some(e);
+      }
+      self.reencode.as_mut().unwrap().in_file = true;
+    }
+    self.inner.start_new_file();
+  }
+  // sets up the rewriter for the current file once its header is there, or at the end of the file
+  fn start_rewriter(&mut self, at_end: bool) -> Result<(), Error> {
+    let file_window_size = match stream_window_size(&self.reencode.as_ref().unwrap().header[..]) {
+      Ok(file_window_size) => file_window_size,
+      Err(BroCatliResult::NeedsMoreInput) if !at_end => return Ok(()),
+      Err(_) => 0,
+    };
+    let mut window_size = self.inner.window_size();
+    if window_size != 0 && file_window_size > window_size && self.inner.state.window_can_grow_to(file_window_size) {
+      window_size = file_window_size;
+    }
+    let reencode = self.reencode.as_mut().unwrap();
+    let mut rewriter = ConcatRewriter::new(window_size, &reencode.params);
+    let header = core::mem::replace(&mut reencode.header, Vec::new());
+    match rewriter.write(&header[..], &mut reencode.output) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    reencode.rewriter = Some(rewriter);
+    Ok(())
+  }
+  fn write_rewritten(&mut self) -> Result<(), Error> {
+    let reencode = self.reencode.as_mut().unwrap();
+    let result = self.inner.write(&reencode.output[..]).map(|_| ());
+    reencode.output.clear();
+    result
+  }
+  fn finish_file(&mut self) -> Result<(), Error> {
+    if !self.reencode.as_ref().unwrap().in_file {
+      return Ok(());
+    }
+    self.reencode.as_mut().unwrap().in_file = false;
+    if self.reencode.as_ref().unwrap().rewriter.is_none() {
+      match self.start_rewriter(true) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+    }
+    let reencode = self.reencode.as_mut().unwrap();
+    match reencode.rewriter.take().unwrap().finish(&mut reencode.output) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    self.write_rewritten()
+  }
+  pub fn finish(&mut self) -> Result<(), Error> {
+    if self.reencode.is_some() {
+      if let Some(e) = self.reencode.as_mut().unwrap().error.take() {
+        return Err(e);
+      }
+      match self.finish_file() {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+    }
+    match self.inner.finish() {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    self.write_held()
+  }
+  // writes out the output held for window growth or merged magic number headers, starting with the
+  // rewritten header; that is out of reach afterwards so the window may not grow any further
+  fn write_held(&mut self) -> Result<(), Error> {
+    let held = match self.inner.output.as_mut().unwrap().held.take() {
+      Some(held) => held,
+      None => return Ok(()),
+    };
+    self.inner.state.grow_window = false;
+    let mut header = [0u8; LEADING_HEADER_BYTES];
+    let (replaced_len, header_len) = match self.inner.state.leading_header(&mut header[..]) {
+      Ok(lengths) => lengths,
+      Err(failure) => return Err(io::Error::from(failure)),
+    };
+    let output = &mut self.inner.output.as_mut().unwrap().output;
+    match output.write_all(&header[..header_len]) {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    output.write_all(&held[replaced_len..])
+  }
+  pub fn get_ref(&self) -> &W {
+    &self.inner.get_ref().output
+  }
+  pub fn get_mut(&mut self) -> &mut W {
+    &mut self.inner.get_mut().output
+  }
+  pub fn into_inner(mut self) -> W {
+    match self.finish() {
+      Ok(_) => {},
+      Err(_) => {},
+    }
+    core::mem::replace(&mut self.inner.output, None).unwrap().output
+  }
+}
+
+#[cfg(feature="std")]
+impl<W: Write> Drop for BroCatliWriter<W> {
+  fn drop(&mut self) {
+    let holding = match self.inner.output {
+      Some(ref output) => output.held.is_some(),
+      None => false,
+    };
+    if holding || (self.reencode.is_some() && self.inner.output.is_some()) {
+      match self.finish() {
+        Ok(_) => {},
+        Err(_) => {},
+      }
+    }
+  }
+}
+
+#[cfg(feature="std")]
+impl<W: Write> Write for BroCatliWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+    if self.reencode.is_none() {
+      return self.inner.write(buf);
+    }
+    if let Some(e) = self.reencode.as_mut().unwrap().error.take() {
+      return Err(e);
+    }
+    self.reencode.as_mut().unwrap().in_file = true;
+    if self.reencode.as_ref().unwrap().rewriter.is_none() {
+      self.reencode.as_mut().unwrap().header.extend_from_slice(buf);
+      match self.start_rewriter(false) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+    } else {
+      let reencode = self.reencode.as_mut().unwrap();
+      match reencode.rewriter.as_mut().unwrap().write(buf, &mut reencode.output) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+    }
+    match self.write_rewritten() {
+      Ok(_) => Ok(buf.len()),
+      Err(e) => Err(e),
+    }
+  }
+  fn flush(&mut self) -> Result<(), Error> {
+    // with re-encoding on, the metablock being read and the end of the file are held back
+    self.inner.flush()
+  }
+}
+
+pub struct BroCatliWriterCustomIo<ErrType,
+                                  W: CustomWrite<ErrType>,
+                                  BufferType: SliceWrapperMut<u8>> {
+  output_buffer: BufferType,
+  output_offset: usize,
+  output: Option<W>,
+  state: BroCatli,
+  error_from_result: fn(BroCatliResult) -> ErrType,
+}
+
+impl<ErrType,
+     W: CustomWrite<ErrType>,
+     BufferType: SliceWrapperMut<u8>>
+BroCatliWriterCustomIo<ErrType, W, BufferType> {
+  pub fn new(w: W,
+             buffer: BufferType,
+             error_from_result: fn(BroCatliResult) -> ErrType) -> Self {
+    Self::with_state(w, buffer, BroCatli::new(), error_from_result)
+  }
+  pub fn new_with_window_size(w: W,
+                              buffer: BufferType,
+                              log_window_size: u8,
+                              error_from_result: fn(BroCatliResult) -> ErrType) -> Self {
+    Self::with_state(w, buffer, BroCatli::new_with_window_size(log_window_size), error_from_result)
+  }
+  pub fn with_state(w: W,
+                    buffer: BufferType,
+                    state: BroCatli,
+                    error_from_result: fn(BroCatliResult) -> ErrType) -> Self {
+    BroCatliWriterCustomIo {
+      output_buffer: buffer,
+      output_offset: 0,
+      output: Some(w),
+      state: state,
+      error_from_result: error_from_result,
+    }
+  }
+  pub fn start_new_file(&mut self) {
+    self.state.new_brotli_file();
+  }
+  pub fn window_size(&self) -> u8 {
+    self.state.window_size()
+  }
+  /// see BroCatli::leading_header: once finished, the output may need a new start
+  pub fn leading_header(&self, header: &mut [u8]) -> Result<(usize, usize), BroCatliResult> {
+    self.state.leading_header(header)
+  }
+  fn write_buffered(&mut self) -> Result<(), ErrType> {
+    if self.output_offset != 0 {
+      match write_all(self.output.as_mut().unwrap(), &self.output_buffer.slice()[..self.output_offset]) {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+      self.output_offset = 0;
+    }
+    Ok(())
+  }
+  /// terminates the concatenated stream; nothing may be written afterwards unless a new file is started
+  pub fn finish(&mut self) -> Result<(), ErrType> {
+    loop {
+      match self.state.finish(self.output_buffer.slice_mut(), &mut self.output_offset) {
+        BroCatliResult::NeedsMoreOutput => {},
+        BroCatliResult::Success => return self.write_buffered(),
+        failure => return Err((self.error_from_result)(failure)),
+      }
+      match self.write_buffered() {
+        Ok(_) => {},
+        Err(e) => return Err(e),
+      }
+    }
+  }
+  pub fn get_ref(&self) -> &W {
+    self.output.as_ref().unwrap()
+  }
+  pub fn get_mut(&mut self) -> &mut W {
+    self.output.as_mut().unwrap()
+  }
+  pub fn into_inner(mut self) -> W {
+    match self.finish() {
+      Ok(_) => {},
+      Err(_) => {},
+    }
+    core::mem::replace(&mut self.output, None).unwrap()
+  }
+}
+
+impl<ErrType,
+     W: CustomWrite<ErrType>,
+     BufferType: SliceWrapperMut<u8>> Drop for
+BroCatliWriterCustomIo<ErrType, W, BufferType> {
+  fn drop(&mut self) {
+    if self.output.is_some() {
+      match self.finish() {
+        Ok(_) => {},
+        Err(_) => {},
+      }
+    }
+  }
+}
+
+impl<ErrType,
+     W: CustomWrite<ErrType>,
+     BufferType: SliceWrapperMut<u8>> CustomWrite<ErrType> for
+BroCatliWriterCustomIo<ErrType, W, BufferType> {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, ErrType> {
+    let mut input_offset = 0usize;
+    // keep going until the BroCatli asks for input: a file header may still be pending after the
+    // input is used up, and it would be lost if the next file were started before it was written
+    loop {
+      match self.state.stream(buf, &mut input_offset, self.output_buffer.slice_mut(), &mut self.output_offset) {
+        BroCatliResult::NeedsMoreOutput => match self.write_buffered() {
+          Ok(_) => {},
+          Err(e) => return Err(e),
+        },
+        BroCatliResult::NeedsMoreInput | BroCatliResult::Success => if input_offset == buf.len() {
+          return Ok(buf.len());
+        },
+        failure => return Err((self.error_from_result)(failure)),
+      }
+    }
+  }
+  fn flush(&mut self) -> Result<(), ErrType> {
+    // the last couple of bytes stay behind in the BroCatli until the next file or finish
+    // decides whether they carry the final metablock bits
+    match self.write_buffered() {
+      Ok(_) => {},
+      Err(e) => return Err(e),
+    }
+    self.output.as_mut().unwrap().flush()
+  }
+}
@@ -0,0 +1,777 @@
+#![cfg(feature="std")]
+// An experimental CommandSink that codes the metablock IR with an adaptive range coder in place of
+// brotli's static huffman codes, to measure how much ratio those leave behind. Literals are coded
+// a nibble at a time from the CMPrior and Stride1Prior CDFs of prior_eval, mixed by Weights; all
+// other fields of the commands use adaptive binary models. The format is not stable.
+use core;
+use core::fmt;
+use std::error;
+use std::vec::Vec;
+use alloc::SliceWrapper;
+use super::super::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength,
+                               kBrotliDictionarySizeBitsByLength,
+                               kBrotliMinDictionaryWordLength, kBrotliMaxDictionaryWordLength};
+use super::super::transform::{TransformDictionaryWord, kNumTransforms};
+use super::backward_references::BrotliEncoderParams;
+use super::combined_alloc::BrotliAlloc;
+use super::command_sink::CommandSink;
+use super::histogram::ContextType;
+use super::input_pair::{InputPair, InputReferenceMut};
+use super::interface;
+use super::interface::{LiteralPredictionModeNibble, PredictionModeContextMap};
+use super::ir_interpret::Context;
+use super::prior_eval::{Prior, CMPrior, Stride1Prior, CDF, init_cdfs,
+                        CONTEXT_MAP_PRIOR_SIZE, STRIDE_PRIOR_SIZE, DEFAULT_SPEED};
+use super::s16;
+use super::weights::{Weights, Prob, BLEND_FIXED_POINT_PRECISION};
+
+pub const ARITHMETIC_MAGIC: [u8; 4] = [0x1b, b'I', b'R', 1];
+
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const PROB_ADAPT_SHIFT: u32 = 5;
+const RANGE_TOP: u32 = 1 << 24;
+// no literal insert or copy in a valid stream comes near this: metablocks hold at most 16MiB
+const MAX_RUN: usize = 1 << 25;
+// 256 literal block types with 64 contexts each
+const MAX_LITERAL_CONTEXT_MAP_LEN: usize = 256 * 64;
+const NUM_DISTANCE_SLOTS: u32 = 5;
+const MAX_STRIDE: u8 = 7;
+
+const CMD_LITERAL: u32 = 0;
+const CMD_COPY: u32 = 1;
+const CMD_DICT: u32 = 2;
+const CMD_BLOCK_SWITCH_LITERAL: u32 = 3;
+const CMD_PREDICTION_MODE: u32 = 4;
+const CMD_END: u32 = 5;
+
+#[derive(Debug,Clone,Copy, PartialEq)]
+pub enum ArithmeticDecodeError {
+  InvalidMagic,
+  Truncated,
+  TrailingData,
+  InvalidCommand,
+  InvalidDistance,
+  InvalidDictionaryWord,
+  RunTooLong,
+}
+
+impl fmt::Display for ArithmeticDecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let message = match *self {
+      ArithmeticDecodeError::InvalidMagic => "not an arithmetic coded stream",
+      ArithmeticDecodeError::Truncated => "stream ended unexpectedly",
+      ArithmeticDecodeError::TrailingData => "data after the end of the stream",
+      ArithmeticDecodeError::InvalidCommand => "invalid command",
+      ArithmeticDecodeError::InvalidDistance => "distance beyond the start of the output",
+      ArithmeticDecodeError::InvalidDictionaryWord => "invalid dictionary word",
+      ArithmeticDecodeError::RunTooLong => "insert or copy too long",
+    };
+    f.write_str(message)
+  }
+}
+
+impl error::Error for ArithmeticDecodeError {}
+
+// Both directions drive the same models through this trait, so the decoder cannot drift from the
+// encoder: the encoder codes the value it is given and returns it, the decoder ignores it and
+// returns what it read.
+trait BitCoder {
+  fn bit(&mut self, prob: &mut u16, bit: u32) -> u32;
+  fn direct_bits(&mut self, value: u32, num_bits: u32) -> u32;
+  fn nibble(&mut self, freqs: &[u32; 16], total: u32, nibble: u8) -> u8;
+}
+
+#[inline(always)]
+fn adapt(prob: &mut u16, bit: u32) {
+  if bit == 0 {
+    *prob += ((1 << PROB_BITS) - *prob) >> PROB_ADAPT_SHIFT;
+  } else {
+    *prob -= *prob >> PROB_ADAPT_SHIFT;
+  }
+}
+
+struct RangeEncoder {
+  low: u64,
+  range: u32,
+  cache: u8,
+  cache_size: u64,
+  output: Vec<u8>,
+}
+
+impl RangeEncoder {
+  fn new(output: Vec<u8>) -> Self {
+    RangeEncoder {
+      low: 0,
+      range: 0xffff_ffff,
+      cache: 0,
+      cache_size: 1,
+      output: output,
+    }
+  }
+  fn shift_low(&mut self) {
+    if self.low < 0xff00_0000 || self.low >= 1 << 32 {
+      let carry = (self.low >> 32) as u8;
+      let mut byte = self.cache;
+      loop {
+        self.output.push(byte.wrapping_add(carry));
+        byte = 0xff;
+        self.cache_size -= 1;
+        if self.cache_size == 0 {
+          break;
+        }
+      }
+      self.cache = (self.low >> 24) as u8;
+    }
+    self.cache_size += 1;
+    self.low = (self.low & 0x00ff_ffff) << 8;
+  }
+  fn normalize(&mut self) {
+    while self.range < RANGE_TOP {
+      self.range <<= 8;
+      self.shift_low();
+    }
+  }
+  fn finish(mut self) -> Vec<u8> {
+    for _ in 0..5 {
+      self.shift_low();
+    }
+    self.output
+  }
+}
+
+impl BitCoder for RangeEncoder {
+  fn bit(&mut self, prob: &mut u16, bit: u32) -> u32 {
+    let bound = (self.range >> PROB_BITS) * u32::from(*prob);
+    if bit == 0 {
+      self.range = bound;
+    } else {
+      self.low += u64::from(bound);
+      self.range -= bound;
+    }
+    adapt(prob, bit);
+    self.normalize();
+    bit
+  }
+  fn direct_bits(&mut self, value: u32, num_bits: u32) -> u32 {
+    for i in (0..num_bits).rev() {
+      self.range >>= 1;
+      if (value >> i) & 1 != 0 {
+        self.low += u64::from(self.range);
+      }
+      self.normalize();
+    }
+    value
+  }
+  fn nibble(&mut self, freqs: &[u32; 16], total: u32, nibble: u8) -> u8 {
+    let start: u32 = freqs[..usize::from(nibble)].iter().sum();
+    let r = self.range / total;
+    self.low += u64::from(r * start);
+    self.range = r * freqs[usize::from(nibble)];
+    self.normalize();
+    nibble
+  }
+}
+
+struct RangeDecoder<'a> {
+  input: &'a [u8],
+  offset: usize,
+  code: u32,
+  range: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+  fn new(input: &'a [u8]) -> Self {
+    let mut ret = RangeDecoder {
+      input: input,
+      offset: 0,
+      code: 0,
+      range: 0xffff_ffff,
+    };
+    for _ in 0..5 {
+      ret.code = (ret.code << 8) | u32::from(ret.next_byte());
+    }
+    ret
+  }
+  // reading past the end yields zeros; a complete stream is consumed exactly, so that shows up
+  // as offset > input.len()
+  fn next_byte(&mut self) -> u8 {
+    let byte = match self.input.get(self.offset) {
+      Some(byte) => *byte,
+      None => 0,
+    };
+    self.offset += 1;
+    byte
+  }
+  fn normalize(&mut self) {
+    while self.range < RANGE_TOP {
+      self.range <<= 8;
+      self.code = (self.code << 8) | u32::from(self.next_byte());
+    }
+  }
+  fn overrun(&self) -> bool {
+    self.offset > self.input.len()
+  }
+}
+
+impl<'a> BitCoder for RangeDecoder<'a> {
+  fn bit(&mut self, prob: &mut u16, _bit: u32) -> u32 {
+    let bound = (self.range >> PROB_BITS) * u32::from(*prob);
+    let bit = if self.code < bound {
+      self.range = bound;
+      0
+    } else {
+      self.code -= bound;
+      self.range -= bound;
+      1
+    };
+    adapt(prob, bit);
+    self.normalize();
+    bit
+  }
+  fn direct_bits(&mut self, _value: u32, num_bits: u32) -> u32 {
+    let mut value = 0u32;
+    for _ in 0..num_bits {
+      self.range >>= 1;
+      let bit = if self.code >= self.range {
+        self.code -= self.range;
+        1
+      } else {
+        0
+      };
+      value = (value << 1) | bit;
+      self.normalize();
+    }
+    value
+  }
+  fn nibble(&mut self, freqs: &[u32; 16], total: u32, _nibble: u8) -> u8 {
+    let r = self.range / total;
+    let target = core::cmp::min(self.code / r, total - 1);
+    let mut start = 0u32;
+    let mut nibble = 0usize;
+    while start + freqs[nibble] <= target {
+      start += freqs[nibble];
+      nibble += 1;
+    }
+    self.code -= r * start;
+    self.range = r * freqs[nibble];
+    self.normalize();
+    nibble as u8
+  }
+}
+
+// codes the low num_bits of value most significant bit first; probs holds 1 << num_bits models
+fn code_tree<Coder: BitCoder>(coder: &mut Coder, probs: &mut [u16], num_bits: u32, value: u32) -> u32 {
+  let mut node = 1usize;
+  for i in (0..num_bits).rev() {
+    let bit = coder.bit(&mut probs[node], (value >> i) & 1);
+    node = (node << 1) | bit as usize;
+  }
+  node as u32 - (1 << num_bits)
+}
+
+// the bit length of the value, then up to 3 bits below the leading one adaptively, with the
+// rest at even odds
+struct UintModel {
+  length: [u16; 64],
+  mantissa: [[u16; 8]; 33],
+}
+
+impl UintModel {
+  fn new() -> Self {
+    UintModel {
+      length: [PROB_INIT; 64],
+      mantissa: [[PROB_INIT; 8]; 33],
+    }
+  }
+  fn code<Coder: BitCoder>(&mut self, coder: &mut Coder, value: u32) -> u32 {
+    let num_bits = 32 - value.leading_zeros();
+    let num_bits = core::cmp::min(code_tree(coder, &mut self.length, 6, num_bits), 32);
+    if num_bits <= 1 {
+      return num_bits;
+    }
+    let rest = num_bits - 1;
+    let adaptive_bits = core::cmp::min(rest, 3);
+    let direct_bits = rest - adaptive_bits;
+    let high = code_tree(coder,
+                         &mut self.mantissa[num_bits as usize][..1 << adaptive_bits],
+                         adaptive_bits,
+                         (value >> direct_bits) & ((1 << adaptive_bits) - 1));
+    let low = coder.direct_bits(value & ((1u32 << direct_bits) - 1), direct_bits);
+    (1u32 << rest) | (high << direct_bits) | low
+  }
+}
+
+// keeps every CDF entry within an i16 (and u8_to_speed within a u16)
+fn sanitize_speed(speed: (u8, u8)) -> (u16, u16) {
+  let limit = interface::speed_to_u8(16384);
+  let inc = interface::u8_to_speed(core::cmp::min(speed.0, limit));
+  let max = interface::u8_to_speed(core::cmp::min(speed.1, limit));
+  if inc == 0 && max == 0 {
+    return DEFAULT_SPEED;
+  }
+  (core::cmp::max(core::cmp::min(inc, 4096), 1),
+   core::cmp::max(max, 256))
+}
+
+fn dictionary_word(word_size: u32, word_id: u32, transform: u32, word: &mut [u8; 38]) -> Option<usize> {
+  let copy_len = word_size as usize;
+  if copy_len < kBrotliMinDictionaryWordLength as usize
+     || copy_len > kBrotliMaxDictionaryWordLength as usize
+     || transform >= kNumTransforms as u32
+     || word_id >> kBrotliDictionarySizeBitsByLength[copy_len] != 0 {
+    return None;
+  }
+  let word_index = word_id as usize * copy_len + kBrotliDictionaryOffsetsByLength[copy_len] as usize;
+  Some(TransformDictionaryWord(&mut word[..],
+                               &kBrotliDictionary[word_index..word_index + copy_len],
+                               copy_len as i32,
+                               transform as i32) as usize)
+}
+
+#[inline(always)]
+fn clamp_prob(prob: u32) -> Prob {
+  core::cmp::max(core::cmp::min(prob, (1 << BLEND_FIXED_POINT_PRECISION) - 1), 1) as Prob
+}
+
+struct Models {
+  command_type: [[u16; 8]; 8],
+  literal_len: UintModel,
+  copy_len: UintModel,
+  distance_slot: [u16; 8],
+  distance: UintModel,
+  word_size: [u16; 32],
+  word_id: UintModel,
+  transform: [u16; 128],
+  block_type: UintModel,
+  literal_stride: [u16; 16],
+  prediction_mode: [u16; 16],
+  speed: [u16; 256],
+  context_map_len: UintModel,
+  context_map_repeat: u16,
+  context_map_entry: [u16; 256],
+  cm_priors: Vec<s16>,
+  stride_priors: Vec<s16>,
+  weights: Vec<Weights>,
+  // state of the current metablock
+  literal_context_map: Vec<u8>,
+  context_type: ContextType,
+  cm_speed: [(u16, u16); 2],
+  stride_speed: [(u16, u16); 2],
+  literal_block_type: u8,
+  stride: u8,
+  distance_cache: [u32; 4],
+  last_command: u32,
+}
+
+impl Models {
+  fn new() -> Self {
+    let mut cm_priors = vec![s16::splat(0); CONTEXT_MAP_PRIOR_SIZE];
+    let mut stride_priors = vec![s16::splat(0); STRIDE_PRIOR_SIZE];
+    init_cdfs(&mut cm_priors);
+    init_cdfs(&mut stride_priors);
+    Models {
+      command_type: [[PROB_INIT; 8]; 8],
+      literal_len: UintModel::new(),
+      copy_len: UintModel::new(),
+      distance_slot: [PROB_INIT; 8],
+      distance: UintModel::new(),
+      word_size: [PROB_INIT; 32],
+      word_id: UintModel::new(),
+      transform: [PROB_INIT; 128],
+      block_type: UintModel::new(),
+      literal_stride: [PROB_INIT; 16],
+      prediction_mode: [PROB_INIT; 16],
+      speed: [PROB_INIT; 256],
+      context_map_len: UintModel::new(),
+      context_map_repeat: PROB_INIT,
+      context_map_entry: [PROB_INIT; 256],
+      cm_priors: cm_priors,
+      stride_priors: stride_priors,
+      weights: vec![Weights::new(); 256 * 2],
+      literal_context_map: Vec::new(),
+      context_type: ContextType::CONTEXT_LSB6,
+      cm_speed: [DEFAULT_SPEED; 2],
+      stride_speed: [DEFAULT_SPEED; 2],
+      literal_block_type: 0,
+      stride: 0,
+      distance_cache: [4, 11, 15, 16],
+      last_command: CMD_END,
+    }
+  }
+  fn code_command_type<Coder: BitCoder>(&mut self, coder: &mut Coder, command: u32) -> u32 {
+    let ret = code_tree(coder, &mut self.command_type[self.last_command as usize], 3, command);
+    self.last_command = ret;
+    ret
+  }
+  // speeds are the cm low, cm high, stride low and stride high (increment, max) pairs as f8
+  fn code_prediction_mode<Coder: BitCoder>(&mut self,
+                                          coder: &mut Coder,
+                                          mode: u8,
+                                          speeds: [u8; 8],
+                                          literal_context_map: &[u8]) -> Result<(), ArithmeticDecodeError> {
+    let mode = code_tree(coder, &mut self.prediction_mode, 4, u32::from(mode)) as u8;
+    let mut coded_speeds = [0u8; 8];
+    for (coded, speed) in coded_speeds.iter_mut().zip(speeds.iter()) {
+      *coded = code_tree(coder, &mut self.speed, 8, u32::from(*speed)) as u8;
+    }
+    let len = self.context_map_len.code(coder, literal_context_map.len() as u32) as usize;
+    if len > MAX_LITERAL_CONTEXT_MAP_LEN {
+      return Err(ArithmeticDecodeError::InvalidCommand);
+    }
+    self.literal_context_map.clear();
+    let mut prev = 0u8;
+    for i in 0..len {
+      let entry = literal_context_map.get(i).cloned().unwrap_or(0);
+      let entry = if coder.bit(&mut self.context_map_repeat, (entry != prev) as u32) == 0 {
+        prev
+      } else {
+        code_tree(coder, &mut self.context_map_entry, 8, u32::from(entry)) as u8
+      };
+      self.literal_context_map.push(entry);
+      prev = entry;
+    }
+    self.context_type = match LiteralPredictionModeNibble(mode).to_context_enum() {
+      Ok(context_type) => context_type,
+      Err(_) => ContextType::CONTEXT_LSB6,
+    };
+    self.cm_speed = [sanitize_speed((coded_speeds[0], coded_speeds[1])),
+                     sanitize_speed((coded_speeds[2], coded_speeds[3]))];
+    self.stride_speed = [sanitize_speed((coded_speeds[4], coded_speeds[5])),
+                         sanitize_speed((coded_speeds[6], coded_speeds[7]))];
+    self.literal_block_type = 0;
+    self.stride = 0;
+    Ok(())
+  }
+  fn code_block_switch<Coder: BitCoder>(&mut self, coder: &mut Coder, block_type: u8, stride: u8) {
+    self.literal_block_type = self.block_type.code(coder, u32::from(block_type)) as u8;
+    self.stride = core::cmp::min(code_tree(coder, &mut self.literal_stride, 4, u32::from(stride)) as u8, MAX_STRIDE);
+  }
+  fn code_distance<Coder: BitCoder>(&mut self, coder: &mut Coder, distance: u32) -> u32 {
+    let slot = match self.distance_cache.iter().position(|cached| *cached == distance) {
+      Some(index) => index as u32,
+      None => 4,
+    };
+    let slot = code_tree(coder, &mut self.distance_slot, 3, slot);
+    let (distance, last) = if slot < 4 {
+      (self.distance_cache[slot as usize], slot as usize)
+    } else {
+      (self.distance.code(coder, distance.wrapping_sub(1)).wrapping_add(1), 3)
+    };
+    if slot != 0 && slot < NUM_DISTANCE_SLOTS {
+      for i in (0..last).rev() {
+        self.distance_cache[i + 1] = self.distance_cache[i];
+      }
+      self.distance_cache[0] = distance;
+    }
+    if slot >= NUM_DISTANCE_SLOTS {
+      0
+    } else {
+      distance
+    }
+  }
+  fn code_nibble<Coder: BitCoder>(&mut self,
+                                  coder: &mut Coder,
+                                  stride_byte: u8,
+                                  selected_context: usize,
+                                  high_nibble: Option<u8>,
+                                  nibble: u8) -> u8 {
+    let cm_index = CMPrior::lookup_lin(stride_byte, selected_context as u8, selected_context, high_nibble);
+    let stride_index = Stride1Prior::lookup_lin(stride_byte, selected_context as u8, selected_context, high_nibble);
+    let speed_index = if high_nibble.is_some() { 0 } else { 1 };
+    let cm_cdf = self.cm_priors[cm_index];
+    let stride_cdf = self.stride_priors[stride_index];
+    let weights = &mut self.weights[selected_context * 2 + speed_index];
+    let cm_weight = u32::from(weights.norm_weight());
+    let one = 1u32 << BLEND_FIXED_POINT_PRECISION;
+    let cm_total = cm_cdf.extract(15) as u32;
+    let stride_total = stride_cdf.extract(15) as u32;
+    let mut cm_probs = [0u32; 16];
+    let mut stride_probs = [0u32; 16];
+    let mut mixed = [0u32; 16];
+    let mut freqs = [0u32; 16];
+    let mut total = 0u32;
+    for i in 0..16 {
+      let (cm_pdf, stride_pdf) = if i == 0 {
+        (cm_cdf.extract(0), stride_cdf.extract(0))
+      } else {
+        (cm_cdf.extract(i) - cm_cdf.extract(i - 1), stride_cdf.extract(i) - stride_cdf.extract(i - 1))
+      };
+      cm_probs[i] = (core::cmp::max(cm_pdf, 0) as u32) * one / cm_total;
+      stride_probs[i] = (core::cmp::max(stride_pdf, 0) as u32) * one / stride_total;
+      mixed[i] = (cm_weight * cm_probs[i] + (one - cm_weight) * stride_probs[i]) >> BLEND_FIXED_POINT_PRECISION;
+      // every nibble stays codable however sure the models are
+      freqs[i] = ((mixed[i] * (one - 16)) >> BLEND_FIXED_POINT_PRECISION) + 1;
+      total += freqs[i];
+    }
+    let nibble = coder.nibble(&freqs, total, nibble);
+    let index = usize::from(nibble);
+    weights.update([clamp_prob(cm_probs[index]), clamp_prob(stride_probs[index])], clamp_prob(mixed[index]));
+    CDF::from(&mut self.cm_priors[cm_index]).update(nibble, self.cm_speed[speed_index]);
+    CDF::from(&mut self.stride_priors[stride_index]).update(nibble, self.stride_speed[speed_index]);
+    nibble
+  }
+  // history holds every byte before the literal
+  fn code_literal<Coder: BitCoder>(&mut self, coder: &mut Coder, history: &[u8], literal: u8) -> u8 {
+    let pos = history.len();
+    let byte_back = |distance: usize| if pos >= distance { history[pos - distance] } else { 0 };
+    let stride_byte = byte_back(usize::from(self.stride) + 1);
+    let context = Context(byte_back(1), byte_back(2), self.context_type);
+    let context_map_index = (usize::from(self.literal_block_type) << 6) | usize::from(context);
+    let selected_context = match self.literal_context_map.get(context_map_index) {
+      Some(entry) => usize::from(*entry),
+      None => usize::from(context),
+    };
+    let high = self.code_nibble(coder, stride_byte, selected_context, None, literal >> 4);
+    let low = self.code_nibble(coder, stride_byte, selected_context, Some(high), literal & 0xf);
+    (high << 4) | low
+  }
+}
+
+pub struct ArithmeticSink {
+  encoder: RangeEncoder,
+  models: Models,
+  // the last window_size bytes of input at most
+  history: Vec<u8>,
+  window_size: usize,
+}
+
+impl Default for ArithmeticSink {
+  fn default() -> Self {
+    Self::new(BrotliEncoderParams::default().lgwin)
+  }
+}
+
+impl ArithmeticSink {
+  // lgwin should match the params handed to BrotliCompressToSink
+  pub fn new(lgwin: i32) -> Self {
+    ArithmeticSink {
+      encoder: RangeEncoder::new(ARITHMETIC_MAGIC.to_vec()),
+      models: Models::new(),
+      history: Vec::new(),
+      window_size: 1usize << lgwin,
+    }
+  }
+  // ends the stream, returning the complete file for arithmetic_decompress
+  pub fn finish(mut self) -> Vec<u8> {
+    self.models.code_command_type(&mut self.encoder, CMD_END);
+    self.encoder.finish()
+  }
+}
+
+impl<Alloc: BrotliAlloc> CommandSink<Alloc> for ArithmeticSink {
+  fn push_metablock(&mut self,
+                    prediction_mode: &PredictionModeContextMap<InputReferenceMut>,
+                    commands: &[interface::StaticCommand],
+                    input: InputPair,
+                    _alloc: &mut Alloc) {
+    let mut speeds = [0u8; 8];
+    let (cm_speed, stride_speed) = if prediction_mode.has_context_speeds() {
+      (prediction_mode.context_map_speed_f8(), prediction_mode.stride_context_speed_f8())
+    } else {
+      let default = (interface::speed_to_u8(DEFAULT_SPEED.0), interface::speed_to_u8(DEFAULT_SPEED.1));
+      ([default; 2], [default; 2])
+    };
+    for (i, speed) in cm_speed.iter().chain(stride_speed.iter()).enumerate() {
+      speeds[2 * i] = speed.0;
+      speeds[2 * i + 1] = speed.1;
+    }
+    let models = &mut self.models;
+    let encoder = &mut self.encoder;
+    models.code_command_type(encoder, CMD_PREDICTION_MODE);
+    let _ = models.code_prediction_mode(encoder,
+                                        prediction_mode.literal_prediction_mode().prediction_mode(),
+                                        speeds,
+                                        prediction_mode.literal_context_map.slice());
+    // the commands only reference the metablock, so it may be taken in whole up front
+    if self.history.len() > self.window_size {
+      let excess = self.history.len() - self.window_size;
+      self.history.drain(..excess);
+    }
+    let mut pos = self.history.len();
+    self.history.extend_from_slice(input.0.slice());
+    self.history.extend_from_slice(input.1.slice());
+    for command in commands.iter() {
+      match interface::thaw_pair(command, &input) {
+        interface::Command::Literal(ref literal) => {
+          let data = literal.slice();
+          if data.is_empty() {
+            continue;
+          }
+          models.code_command_type(encoder, CMD_LITERAL);
+          models.literal_len.code(encoder, data.len() as u32 - 1);
+          for byte in data.iter() {
+            models.code_literal(encoder, &self.history[..pos], *byte);
+            pos += 1;
+          }
+        },
+        interface::Command::Copy(ref copy) => {
+          if copy.num_bytes == 0 {
+            continue;
+          }
+          models.code_command_type(encoder, CMD_COPY);
+          models.code_distance(encoder, copy.distance);
+          models.copy_len.code(encoder, copy.num_bytes - 1);
+          pos += copy.num_bytes as usize;
+        },
+        interface::Command::Dict(ref dict) => {
+          models.code_command_type(encoder, CMD_DICT);
+          code_tree(encoder, &mut models.word_size, 5, u32::from(dict.word_size));
+          models.word_id.code(encoder, dict.word_id);
+          code_tree(encoder, &mut models.transform, 7, u32::from(dict.transform));
+          pos += usize::from(dict.final_size);
+        },
+        interface::Command::BlockSwitchLiteral(ref block_switch) => {
+          models.code_command_type(encoder, CMD_BLOCK_SWITCH_LITERAL);
+          models.code_block_switch(encoder, block_switch.block_type(), block_switch.stride());
+        },
+        interface::Command::BlockSwitchCommand(_) |
+        interface::Command::BlockSwitchDistance(_) |
+        interface::Command::PredictionMode(_) => {},
+      }
+    }
+    debug_assert_eq!(pos, self.history.len());
+  }
+}
+
+// Decodes a stream written by ArithmeticSink back to the original input.
+pub fn arithmetic_decompress(input: &[u8]) -> Result<Vec<u8>, ArithmeticDecodeError> {
+  if input.len() < ARITHMETIC_MAGIC.len() {
+    return Err(ArithmeticDecodeError::Truncated);
+  }
+  if input[..ARITHMETIC_MAGIC.len()] != ARITHMETIC_MAGIC {
+    return Err(ArithmeticDecodeError::InvalidMagic);
+  }
+  let mut decoder = RangeDecoder::new(&input[ARITHMETIC_MAGIC.len()..]);
+  let mut models = Models::new();
+  let mut output = Vec::<u8>::new();
+  loop {
+    match models.code_command_type(&mut decoder, 0) {
+      CMD_LITERAL => {
+        let len = models.literal_len.code(&mut decoder, 0) as usize + 1;
+        if len > MAX_RUN {
+          return Err(ArithmeticDecodeError::RunTooLong);
+        }
+        for _ in 0..len {
+          let byte = models.code_literal(&mut decoder, &output, 0);
+          output.push(byte);
+          if decoder.overrun() {
+            return Err(ArithmeticDecodeError::Truncated);
+          }
+        }
+      },
+      CMD_COPY => {
+        let distance = models.code_distance(&mut decoder, 0) as usize;
+        let len = models.copy_len.code(&mut decoder, 0) as usize + 1;
+        if distance == 0 || distance > output.len() {
+          return Err(ArithmeticDecodeError::InvalidDistance);
+        }
+        if len > MAX_RUN {
+          return Err(ArithmeticDecodeError::RunTooLong);
+        }
+        for _ in 0..len {
+          let byte = output[output.len() - distance];
+          output.push(byte);
+        }
+      },
+      CMD_DICT => {
+        let word_size = code_tree(&mut decoder, &mut models.word_size, 5, 0);
+        let word_id = models.word_id.code(&mut decoder, 0);
+        let transform = code_tree(&mut decoder, &mut models.transform, 7, 0);
+        let mut word = [0u8; 38];
+        match dictionary_word(word_size, word_id, transform, &mut word) {
+          Some(len) => output.extend_from_slice(&word[..len]),
+          None => return Err(ArithmeticDecodeError::InvalidDictionaryWord),
+        }
+      },
+      CMD_BLOCK_SWITCH_LITERAL => {
+        models.code_block_switch(&mut decoder, 0, 0);
+      },
+      CMD_PREDICTION_MODE => {
+        if let Err(err) = models.code_prediction_mode(&mut decoder, 0, [0; 8], &[]) {
+          return Err(err);
+        }
+      },
+      CMD_END => break,
+      _ => return Err(ArithmeticDecodeError::InvalidCommand),
+    }
+    if decoder.overrun() {
+      return Err(ArithmeticDecodeError::Truncated);
+    }
+  }
+  if decoder.overrun() {
+    return Err(ArithmeticDecodeError::Truncated);
+  }
+  if decoder.offset < decoder.input.len() {
+    return Err(ArithmeticDecodeError::TrailingData);
+  }
+  Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+  use std::vec::Vec;
+  use std::io;
+  use super::{ArithmeticSink, ArithmeticDecodeError, arithmetic_decompress};
+  use super::super::{BrotliCompress, BrotliEncoderParams, StandardAlloc, IoReaderWrapper};
+  use super::super::command_sink::BrotliCompressToSink;
+
+  fn sink_data(data: &[u8], params: &BrotliEncoderParams) -> ArithmeticSink {
+    let mut sink = ArithmeticSink::new(params.lgwin);
+    let mut input_buffer = [0u8; 4096];
+    let mut output_buffer = [0u8; 4096];
+    BrotliCompressToSink(&mut IoReaderWrapper(&mut &data[..]),
+                         &mut input_buffer[..],
+                         &mut output_buffer[..],
+                         params,
+                         StandardAlloc::default(),
+                         &mut sink,
+                         io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected eof")).unwrap();
+    sink
+  }
+
+  fn compress(data: &[u8], quality: i32) -> (Vec<u8>, usize) {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = quality;
+    let mut brotli = Vec::new();
+    BrotliCompress(&mut &data[..], &mut brotli, &params).unwrap();
+    (sink_data(data, &params).finish(), brotli.len())
+  }
+
+  #[test]
+  fn test_arithmetic_roundtrip() {
+    let data = include_bytes!("../../testdata/alice29.txt");
+    for quality in [0, 5, 9].iter() {
+      let (compressed, brotli_size) = compress(&data[..], *quality);
+      assert_eq!(arithmetic_decompress(&compressed).unwrap(), &data[..]);
+      assert!(compressed.len() < brotli_size);
+    }
+    let (compressed, _) = compress(&[], 5);
+    assert_eq!(arithmetic_decompress(&compressed).unwrap(), Vec::<u8>::new());
+  }
+
+  #[test]
+  fn test_arithmetic_history_stays_within_the_window() {
+    let data = include_bytes!("../../testdata/alice29.txt");
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.lgwin = 16;
+    let sink = sink_data(&data[..], &params);
+    assert!(sink.history.len() < data.len());
+    assert_eq!(arithmetic_decompress(&sink.finish()).unwrap(), &data[..]);
+  }
+
+  #[test]
+  fn test_arithmetic_corrupt() {
+    let data = include_bytes!("../../testdata/asyoulik.txt");
+    let (mut compressed, _) = compress(&data[..], 5);
+    assert_eq!(arithmetic_decompress(&compressed[..compressed.len() - 1]),
+               Err(ArithmeticDecodeError::Truncated));
+    assert_eq!(arithmetic_decompress(&compressed[..compressed.len() / 2]),
+               Err(ArithmeticDecodeError::Truncated));
+    compressed.push(0);
+    assert_eq!(arithmetic_decompress(&compressed), Err(ArithmeticDecodeError::TrailingData));
+    compressed[0] = 0;
+    assert_eq!(arithmetic_decompress(&compressed), Err(ArithmeticDecodeError::InvalidMagic));
+  }
+}
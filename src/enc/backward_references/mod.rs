@@ -8,6 +8,7 @@ use super::hash_to_binary_tree::{H10, H10Buckets, H10DefaultParams, ZopfliNode};
 use super::dictionary_hash::kStaticDictionaryHash;
 use super::static_dict::{BROTLI_UNALIGNED_LOAD32, BROTLI_UNALIGNED_LOAD64, FindMatchLengthWithLimit, FindMatchLengthWithLimitMin4};
 use super::static_dict::BrotliDictionary;
+use super::chunking::UnionChunkingPolicy;
 use super::super::alloc;
 use super::super::alloc::{SliceWrapper, SliceWrapperMut, Allocator};
 use super::util::{Log2FloorNonZero, brotli_max_size_t, floatX};
@@ -77,6 +78,9 @@ pub struct BrotliEncoderParams {
   pub hasher: BrotliHasherParams,
   /// produce an IR of the compression file
   pub log_meta_block: bool,
+  /// only hand the IR of each metablock to the callback: no bit stream is stored, so the output is
+  /// not a brotli file (implies log_meta_block)
+  pub log_meta_block_only: bool,
   /// attempt to detect how many bytes before the current byte generates the best prediction of it
   /// * 0 = off (stride 1 always)
   /// * 1 = on per 16th of a file
@@ -98,6 +102,10 @@ pub struct BrotliEncoderParams {
   pub catable: bool,
   /// can use the dictionary (default yes unless catable is set)
   pub use_dictionary: bool,
+  /// with catable: use the dictionary anyway, but only from a full window (2^lgwin - 16 bytes) into the
+  /// stream on, where a dictionary reference decodes the same however much data precedes the stream,
+  /// provided the concatenation keeps the same window. Implies magic_number, which marks such streams for BroCatli.
+  pub catable_dictionary: bool,
   /// construct brotli in such a way that another concatable brotli file may be appended
   pub appendable: bool,
   /// include a magic number and version number and size_hint at the beginning
@@ -105,6 +113,8 @@ pub struct BrotliEncoderParams {
   /// prefer to compute the map of previously seen strings
   /// just once for all the threads at the beginning, since they overlap significantly
   pub favor_cpu_efficiency: bool,
+  /// for multithreaded compression: where to cut the input into separately compressed chunks
+  pub multi_chunking: UnionChunkingPolicy,
 }
 
 impl Default for BrotliEncoderParams {
@@ -2159,6 +2169,8 @@ fn CreateBackwardReferences<AH: AnyHasher>(dictionary: Option<&BrotliDictionary>
                                            num_literals: &mut usize) {
   let gap = 0usize;
   let max_backward_limit: usize = (1usize << (*params).lgwin).wrapping_sub(16usize);
+  // before that the distance a dictionary word gets coded with depends on what precedes the stream
+  let catable_dictionary_start = if params.catable && params.catable_dictionary { max_backward_limit } else { 0 };
   let mut new_commands_count: usize = 0;
   let mut insert_length: usize = *last_insert_len;
   let pos_end: usize = position.wrapping_add(num_bytes);
@@ -2186,7 +2198,7 @@ fn CreateBackwardReferences<AH: AnyHasher>(dictionary: Option<&BrotliDictionary>
     sr.len_x_code = 0usize;
     sr.distance = 0usize;
     sr.score = kMinScore;
-    if hasher.FindLongestMatch(dictionary,
+    if hasher.FindLongestMatch(if position >= catable_dictionary_start { dictionary } else { None },
                                dictionary_hash,
                                ringbuffer,
                                ringbuffer_mask,
@@ -2218,7 +2230,7 @@ fn CreateBackwardReferences<AH: AnyHasher>(dictionary: Option<&BrotliDictionary>
           sr2.distance = 0usize;
           sr2.score = kMinScore;
           max_distance = brotli_min_size_t(position.wrapping_add(1usize), max_backward_limit);
-          is_match_found = hasher.FindLongestMatch(dictionary,
+          is_match_found = hasher.FindLongestMatch(if position + 1 >= catable_dictionary_start { dictionary } else { None },
                                                    dictionary_hash,
                                                    ringbuffer,
                                                    ringbuffer_mask,
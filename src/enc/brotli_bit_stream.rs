@@ -286,7 +286,7 @@ fn process_command_queue<'a, CmdProcessor: interface::CommandProcessor<'a> > (
         }
         let copy_len = copylen_code as usize;
         let actual_copy_len : usize;
-        let max_distance = core::cmp::min(recoder_state.num_bytes_encoded, window_size_from_lgwin(params.lgwin));
+        let max_distance = core::cmp::min(recoder_state.num_bytes_encoded + recoder_state.dictionary_size, window_size_from_lgwin(params.lgwin));
         assert!(inserts.len() <= mb_len);
         if inserts.len() != 0 {
             let mut tmp_inserts = inserts;
@@ -2536,16 +2536,49 @@ fn block_split_reference<'a,
             distance_context_map: mb.distance_context_map.slice().split_at(mb.distance_context_map_size).0,
         }
 }
+
+// Hands the IR of a metablock to callback exactly as the BrotliStore* functions log it, without
+// storing any bits: for params.log_meta_block_only. mb is None for the metablocks stored without
+// a block split, and context_type None for an uncompressed one.
+pub fn BrotliLogMetaBlock<Alloc: BrotliAlloc, Cb>
+  (alloc: &mut Alloc,
+   input: &[u8],
+   start_pos: usize,
+   length: usize,
+   mask: usize,
+   params: &BrotliEncoderParams,
+   context_type: Option<ContextType>,
+   distance_cache: &[i32; kNumDistanceCacheEntries],
+   commands: &[Command],
+   mb: Option<&MetaBlockSplit<Alloc>>,
+   recoder_state: &mut RecoderState,
+   callback: &mut Cb) where Cb: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                      &mut [interface::StaticCommand],
+                                      InputPair, &mut Alloc) {
+  let (input0, input1) = InputPairFromMaskedInput(input, start_pos, length, mask);
+  let block_split = match mb {
+    Some(mb) => block_split_reference(mb),
+    None => block_split_nop(),
+  };
+  LogMetaBlock(alloc, commands, input0, input1, distance_cache, recoder_state,
+               block_split,
+               params,
+               context_type,
+               callback);
+}
      
 #[derive(Clone, Copy)]
 pub struct RecoderState {
     pub num_bytes_encoded : usize,
+    // bytes of custom dictionary preceding the input: copies may reach back this far as well
+    pub dictionary_size : usize,
 }
 
 impl RecoderState {
     pub fn new() -> Self {
         RecoderState{
             num_bytes_encoded:0,
+            dictionary_size:0,
         }
     }
 }
@@ -2802,7 +2835,9 @@ pub fn BrotliWriteMetadataMetaBlock(params: &BrotliEncoderParams, storage_ix: &m
     
     BrotliWriteBits(8u8, 3 + size_hint_count as u64, storage_ix, storage); // 1 byte of data: writing 12 for the magic number header
     JumpToByteBoundary(storage_ix, storage);
-    let magic_number: [u8;3] = if params.catable && !params.use_dictionary {
+    let magic_number: [u8;3] = if params.catable && params.catable_dictionary {
+        [0xe1, 0x97, 0x83]
+    } else if params.catable && !params.use_dictionary {
         [0xe1, 0x97, 0x81]
     } else if params.appendable {
         [0xe1, 0x97, 0x82]
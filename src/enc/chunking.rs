@@ -0,0 +1,152 @@
+use core::cmp;
+// Policies for cutting the input of CompressMulti into independently compressed chunks.
+// Each chunk is compressed with all the data before it as a custom dictionary, and the results are
+// concatenated, so the output only depends on where the chunks are cut: with FixedSizeChunks or
+// ContentDefinedChunks that is independent of how many threads share the work.
+
+/// one chunk per thread, of equal size (the original behavior): boundaries move with the thread count
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EqualChunks {}
+
+impl EqualChunks {
+  pub fn chunk_end(&self, data: &[u8], chunk_start: usize, num_threads: usize) -> usize {
+    for thread_index in 0..num_threads {
+      let end = ((thread_index + 1) * data.len()) / num_threads;
+      if end > chunk_start {
+        return end;
+      }
+    }
+    data.len()
+  }
+  pub fn max_chunks(&self, _data_len: usize, num_threads: usize) -> usize {
+    num_threads
+  }
+}
+
+/// chunks of chunk_size bytes (the last may be shorter) no matter how many threads there are
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixedSizeChunks {
+  pub chunk_size: usize,
+}
+
+impl FixedSizeChunks {
+  pub fn new(chunk_size: usize) -> Self {
+    FixedSizeChunks {
+      chunk_size: cmp::max(chunk_size, 1),
+    }
+  }
+  pub fn chunk_end(&self, data: &[u8], chunk_start: usize, _num_threads: usize) -> usize {
+    cmp::min(chunk_start.saturating_add(cmp::max(self.chunk_size, 1)), data.len())
+  }
+  pub fn max_chunks(&self, data_len: usize, _num_threads: usize) -> usize {
+    cmp::max(1, (data_len + cmp::max(self.chunk_size, 1) - 1) / cmp::max(self.chunk_size, 1))
+  }
+}
+
+impl Default for FixedSizeChunks {
+  fn default() -> Self {
+    FixedSizeChunks::new(1 << 22)
+  }
+}
+
+/// cuts wherever a gear rolling hash of the last 64 bytes has its top avg_size_log2 bits clear,
+/// so boundaries follow the content: an insertion only moves the cuts near it.
+/// Chunks are kept between min_size and max_size bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContentDefinedChunks {
+  pub min_size: usize,
+  pub avg_size_log2: u8,
+  pub max_size: usize,
+}
+
+impl ContentDefinedChunks {
+  /// chunks average around 1 << avg_size_log2 bytes, and are between a quarter and four times that
+  pub fn new(avg_size_log2: u8) -> Self {
+    let avg_size_log2 = cmp::min(cmp::max(avg_size_log2, 8), 40);
+    ContentDefinedChunks {
+      min_size: 1usize << (avg_size_log2 - 2),
+      avg_size_log2: avg_size_log2,
+      max_size: 1usize << (avg_size_log2 + 2),
+    }
+  }
+  pub fn chunk_end(&self, data: &[u8], chunk_start: usize, _num_threads: usize) -> usize {
+    let max_size = cmp::max(self.max_size, 1);
+    let min_size = cmp::min(self.min_size, max_size);
+    let limit = cmp::min(chunk_start.saturating_add(max_size), data.len());
+    let first_candidate = chunk_start.saturating_add(cmp::max(min_size, 1));
+    if first_candidate >= limit {
+      return limit;
+    }
+    let mask = if self.avg_size_log2 == 0 {
+      0
+    } else {
+      !0u64 << (64 - cmp::min(self.avg_size_log2, 63) as u32)
+    };
+    // the hash only depends on the last 64 bytes, so start it that far ahead of the first candidate
+    let mut hash = 0u64;
+    let warmup = if first_candidate - chunk_start > 64 { first_candidate - 64 } else { chunk_start };
+    for &byte in data[warmup..first_candidate].iter() {
+      hash = (hash << 1).wrapping_add(gear(byte));
+    }
+    for pos in first_candidate..limit {
+      if hash & mask == 0 {
+        return pos;
+      }
+      hash = (hash << 1).wrapping_add(gear(data[pos]));
+    }
+    limit
+  }
+  pub fn max_chunks(&self, data_len: usize, _num_threads: usize) -> usize {
+    let min_size = cmp::max(cmp::min(self.min_size, self.max_size), 1);
+    cmp::max(1, (data_len + min_size - 1) / min_size)
+  }
+}
+
+impl Default for ContentDefinedChunks {
+  fn default() -> Self {
+    ContentDefinedChunks::new(22)
+  }
+}
+
+// a fixed pseudorandom value per byte (splitmix64), so boundaries are the same on every machine
+fn gear(byte: u8) -> u64 {
+  let mut z = (byte as u64).wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+  z ^ (z >> 31)
+}
+
+/// the chunking policy a BrotliEncoderParams carries to the compression threads
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnionChunkingPolicy {
+  Equal(EqualChunks),
+  FixedSize(FixedSizeChunks),
+  ContentDefined(ContentDefinedChunks),
+}
+
+impl Default for UnionChunkingPolicy {
+  fn default() -> Self {
+    UnionChunkingPolicy::Equal(EqualChunks::default())
+  }
+}
+
+impl UnionChunkingPolicy {
+  /// returns the end of the chunk that begins at chunk_start, which is past chunk_start whenever
+  /// chunk_start < data.len()
+  pub fn chunk_end(&self, data: &[u8], chunk_start: usize, num_threads: usize) -> usize {
+    match *self {
+      UnionChunkingPolicy::Equal(ref policy) => policy.chunk_end(data, chunk_start, num_threads),
+      UnionChunkingPolicy::FixedSize(ref policy) => policy.chunk_end(data, chunk_start, num_threads),
+      UnionChunkingPolicy::ContentDefined(ref policy) => policy.chunk_end(data, chunk_start, num_threads),
+    }
+  }
+  /// an upper bound on the number of chunks data_len bytes get cut into, which
+  /// BrotliEncoderMaxCompressedSizeMulti sizes the output buffer by
+  pub fn max_chunks(&self, data_len: usize, num_threads: usize) -> usize {
+    match *self {
+      UnionChunkingPolicy::Equal(ref policy) => policy.max_chunks(data_len, num_threads),
+      UnionChunkingPolicy::FixedSize(ref policy) => policy.max_chunks(data_len, num_threads),
+      UnionChunkingPolicy::ContentDefined(ref policy) => policy.max_chunks(data_len, num_threads),
+    }
+  }
+}
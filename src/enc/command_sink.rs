@@ -0,0 +1,75 @@
+use super::backward_references::BrotliEncoderParams;
+use super::combined_alloc::BrotliAlloc;
+use super::input_pair::{InputPair, InputReferenceMut};
+use super::interface;
+use super::BrotliCompressCustomIo;
+use brotli_decompressor::{CustomRead, CustomWrite};
+
+// A CommandSink receives the intermediate representation of each metablock in place of the
+// brotli bit stream: the prediction mode and context map the encoder chose, the commands and the
+// input they refer to (literal data in the commands is a SliceOffset into input, see thaw_pair).
+// Every metablock of the file is pushed in order, so a sink may keep state across them and
+// produce a format of its own from the whole stream.
+pub trait CommandSink<Alloc: BrotliAlloc> {
+  fn push_metablock(&mut self,
+                    prediction_mode: &interface::PredictionModeContextMap<InputReferenceMut>,
+                    commands: &[interface::StaticCommand],
+                    input: InputPair,
+                    alloc: &mut Alloc);
+}
+
+// The lowest quality that produces metablock IR: 0 and 1 use the fragment compressors, which write
+// the bit stream directly.
+pub const MIN_COMMAND_SINK_QUALITY: i32 = 2;
+
+struct DiscardOutput;
+
+impl<ErrType> CustomWrite<ErrType> for DiscardOutput {
+  fn write(&mut self, data: &[u8]) -> Result<usize, ErrType> {
+    Ok(data.len())
+  }
+  fn flush(&mut self) -> Result<(), ErrType> {
+    Ok(())
+  }
+}
+
+// Compresses all of r, handing every metablock to sink instead of writing it out: the encoder
+// stops once a metablock's IR is built (params.log_meta_block_only), so no huffman codes are
+// computed and no bit stream is stored. The quality is raised to MIN_COMMAND_SINK_QUALITY if needed.
+pub fn BrotliCompressToSink<ErrType,
+                            InputType,
+                            Alloc: BrotliAlloc,
+                            Sink: CommandSink<Alloc>>
+  (r: &mut InputType,
+   input_buffer: &mut [u8],
+   output_buffer: &mut [u8],
+   params: &BrotliEncoderParams,
+   alloc: Alloc,
+   sink: &mut Sink,
+   unexpected_eof_error_constant: ErrType)
+   -> Result<(), ErrType>
+  where InputType: CustomRead<ErrType>
+{
+  let mut sink_params = params.clone();
+  sink_params.log_meta_block = true;
+  sink_params.log_meta_block_only = true;
+  if sink_params.quality < MIN_COMMAND_SINK_QUALITY {
+    sink_params.quality = MIN_COMMAND_SINK_QUALITY;
+  }
+  let mut callback = |pm: &mut interface::PredictionModeContextMap<InputReferenceMut>,
+                      cmds: &mut [interface::StaticCommand],
+                      mb: InputPair,
+                      m: &mut Alloc| sink.push_metablock(pm, cmds, mb, m);
+  // all that is left of the output is the stream header
+  match BrotliCompressCustomIo(r,
+                               &mut DiscardOutput,
+                               input_buffer,
+                               output_buffer,
+                               &sink_params,
+                               alloc,
+                               &mut callback,
+                               unexpected_eof_error_constant) {
+    Ok(_) => Ok(()),
+    Err(e) => Err(e),
+  }
+}
@@ -18,11 +18,13 @@ use super::block_split::BlockSplit;
 use super::brotli_bit_stream::{BrotliBuildAndStoreHuffmanTreeFast, BrotliStoreHuffmanTree,
                                BrotliStoreMetaBlock, BrotliStoreMetaBlockFast,
                                BrotliStoreMetaBlockTrivial, BrotliStoreUncompressedMetaBlock,
+                               BrotliLogMetaBlock,
                                BrotliWriteEmptyLastMetaBlock, BrotliWriteMetadataMetaBlock,
                                MetaBlockSplit, RecoderState, JumpToByteBoundary};
                                
 use enc::input_pair::InputReferenceMut;
 use super::command::{Command, GetLengthCode, BrotliDistanceParams};
+use super::chunking::UnionChunkingPolicy;
 use super::compress_fragment::BrotliCompressFragmentFast;
 use super::compress_fragment_two_pass::{BrotliCompressFragmentTwoPass, BrotliWriteBits};
 #[allow(unused_imports)]
@@ -340,6 +342,10 @@ value: u32) -> i32 {
     params.favor_cpu_efficiency = value != 0;
     return 1i32;
   }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_CATABLE_DICTIONARY as (i32) {
+    params.catable_dictionary = value != 0;
+    return 1i32;
+  }
   0i32  
 }
 
@@ -384,6 +390,7 @@ pub fn BrotliEncoderInitParams() -> BrotliEncoderParams {
            },
            mode: BrotliEncoderMode::BROTLI_MODE_GENERIC,
            log_meta_block: false,
+           log_meta_block_only: false,
            large_window:false,
            avoid_distance_prefix_search:false,
            quality: 11,
@@ -399,9 +406,11 @@ pub fn BrotliEncoderInitParams() -> BrotliEncoderParams {
            literal_adaptation: [(0,0);4],
            catable: false,
            use_dictionary: true,
+           catable_dictionary: false,
            appendable: false,
            magic_number: false,
            favor_cpu_efficiency:false,
+           multi_chunking: UnionChunkingPolicy::default(),
            hasher: BrotliHasherParams {
              type_: 6,
              block_bits: 9 - 1,
@@ -622,6 +631,10 @@ pub fn SanitizeParams(params: &mut BrotliEncoderParams) {
   }
   if params.catable {
       params.appendable = true;
+      if params.catable_dictionary {
+          params.use_dictionary = true;
+          params.magic_number = true;
+      }
   }
 }
 
@@ -1226,6 +1239,8 @@ pub fn BrotliEncoderSetCustomDictionaryWithOptionalPrecomputedHasher<Alloc: Brot
   CopyInputToRingBuffer(s, dict_size, dict);
   (*s).last_flush_pos_ = dict_size as u64;
   (*s).last_processed_pos_ = dict_size as u64;
+  // copies may reach back into the dictionary: the IR log must not mistake those for static dictionary words
+  (*s).recoder_state.dictionary_size = dict_size;
   if dict_size > 0 {
     (*s).prev_byte_ = dict[(dict_size.wrapping_sub(1usize) as (usize))];
   }
@@ -1249,8 +1264,12 @@ pub fn BrotliEncoderSetCustomDictionaryWithOptionalPrecomputedHasher<Alloc: Brot
     }
   }
 }
-pub fn BrotliEncoderMaxCompressedSizeMulti(input_size: usize, num_threads: usize) -> usize {
-  BrotliEncoderMaxCompressedSize(input_size) + num_threads * 8
+// Every chunk is compressed to a brotli stream of its own before they are concatenated. The bounds of
+// the streams sum to no more than the bound for the whole input plus, per chunk, the fixed overhead
+// of a stream (that of a one byte stream) and the 8 byte size each chunk is stored with until joined.
+pub fn BrotliEncoderMaxCompressedSizeMulti(input_size: usize, policy: &UnionChunkingPolicy, num_threads: usize) -> usize {
+  let num_chunks = policy.max_chunks(input_size, num_threads);
+  BrotliEncoderMaxCompressedSize(input_size) + num_chunks * (BrotliEncoderMaxCompressedSize(1) + 8)
 }
 
 pub fn BrotliEncoderMaxCompressedSize(input_size: usize) -> usize {
@@ -1985,6 +2004,9 @@ fn WriteMetaBlockInternal<Alloc: BrotliAlloc,
   let last_bytes_bits: u8;
   let literal_context_lut = BROTLI_CONTEXT_LUT(literal_context_mode);
   let mut block_params = params.clone();
+  if params.log_meta_block_only && bytes == 0usize {
+    return;
+  }
   if bytes == 0usize {
     BrotliWriteBits(2usize, 3, storage_ix, storage);
     *storage_ix = (*storage_ix).wrapping_add(7u32 as (usize)) & !7u32 as (usize);
@@ -1997,6 +2019,16 @@ fn WriteMetaBlockInternal<Alloc: BrotliAlloc,
                     num_literals,
                     num_commands) == 0 {
     dist_cache[..4].clone_from_slice(&saved_dist_cache[..4]);
+    if params.log_meta_block_only {
+      let insert = [Command{insert_len_: bytes as u32,
+                            copy_len_: 0,
+                            dist_extra_: 0,
+                            cmd_prefix_: 0,
+                            dist_prefix_: 0}];
+      BrotliLogMetaBlock(alloc, data, wrapped_last_flush_pos as (usize), bytes, mask, params, None,
+                         &[0i32; kNumDistanceCacheEntries], &insert, None, recoder_state, cb);
+      return;
+    }
     BrotliStoreUncompressedMetaBlock(alloc,
                                      is_last,
                                      data,
@@ -2024,6 +2056,12 @@ fn WriteMetaBlockInternal<Alloc: BrotliAlloc,
                               params.dist.num_direct_distance_codes,
                               params.dist.distance_postfix_bits);
   }*/ // why was this removed??
+  if params.log_meta_block_only && (*params).quality < 4i32 {
+    BrotliLogMetaBlock(alloc, data, wrapped_last_flush_pos as (usize), bytes, mask, params,
+                       Some(ContextType::CONTEXT_LSB6), saved_dist_cache,
+                       commands.split_at(num_commands).0, None, recoder_state, cb);
+    return;
+  }
   if (*params).quality <= 2i32 {
     BrotliStoreMetaBlockFast(alloc,
                              data,
@@ -2100,6 +2138,13 @@ fn WriteMetaBlockInternal<Alloc: BrotliAlloc,
                            dst_scratch_space,
                            &mut mb);
     }
+    if params.log_meta_block_only {
+      BrotliLogMetaBlock(alloc, data, wrapped_last_flush_pos as (usize), bytes, mask, &block_params,
+                         Some(literal_context_mode), saved_dist_cache,
+                         commands.split_at(num_commands).0, Some(&mb), recoder_state, cb);
+      mb.destroy(alloc);
+      return;
+    }
     if (*params).quality >= 4i32 {
         let mut num_effective_dist_codes = block_params.dist.alphabet_size;
         if num_effective_dist_codes > BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS as u32 {
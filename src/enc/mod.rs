@@ -34,12 +34,17 @@ pub mod ir_interpret;
 pub mod prior_eval;
 pub mod stride_eval;
 pub mod context_map_entropy;
+pub mod command_sink;
+pub mod arithmetic_sink;
 pub mod pdf;
 pub use self::combined_alloc::{CombiningAllocator, BrotliAlloc};
 mod compat;
+pub mod chunking;
 pub mod threading;
 pub mod singlethreading;
 pub mod multithreading;
+pub mod scoped_threading;
+pub mod rayon_threading;
 pub mod fixed_queue;
 pub mod worker_pool;
 #[cfg(feature="simd")]
@@ -64,6 +69,8 @@ pub use self::util::floatX;
 pub use self::pdf::PDF;
 pub use self::hash_to_binary_tree::ZopfliNode;
 pub use self::backward_references::{BrotliEncoderParams, UnionHasher};
+pub use self::chunking::{UnionChunkingPolicy, EqualChunks, FixedSizeChunks, ContentDefinedChunks};
+pub use self::command_sink::{CommandSink, BrotliCompressToSink};
 pub use self::encode::{
     BrotliEncoderInitParams,
     BrotliEncoderSetParameter,
@@ -96,11 +103,16 @@ pub use self::threading::{SendAlloc,
                           BrotliEncoderThreadError,
                           BatchSpawnableLite,
                           CompressionThreadResult,
+                          CompressionThreadInput,
 };
 #[cfg(feature="std")]
 pub use self::worker_pool::{
   compress_worker_pool,
   new_work_pool,
+  submit_compress_worker_pool,
+  CompressionTenant,
+  FinishedCompression,
+  PendingCompression,
   WorkerPool,
 };
 #[cfg(not(feature="std"))]
@@ -118,13 +130,13 @@ pub fn compress_multi<Alloc:BrotliAlloc+Send+'static,
   alloc_per_thread:&mut [SendAlloc<CompressionThreadResult<Alloc>,
                                    backward_references::UnionHasher<Alloc>,
                                    Alloc,
-                                   <WorkerPool<CompressionThreadResult<Alloc>, backward_references::UnionHasher<Alloc>, Alloc, (SliceW, BrotliEncoderParams)> as BatchSpawnableLite<CompressionThreadResult<Alloc>, backward_references::UnionHasher<Alloc>, Alloc, (SliceW, BrotliEncoderParams)>>::JoinHandle>],
+                                   <WorkerPool<CompressionThreadResult<Alloc>, backward_references::UnionHasher<Alloc>, Alloc, CompressionThreadInput<SliceW>> as BatchSpawnableLite<CompressionThreadResult<Alloc>, backward_references::UnionHasher<Alloc>, Alloc, CompressionThreadInput<SliceW>>>::JoinHandle>],
 ) -> Result<usize, BrotliEncoderThreadError>
   where <Alloc as Allocator<u8>>::AllocatedMemory: Send,
         <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
         <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
   let mut work_pool = self::worker_pool::new_work_pool(alloc_per_thread.len() - 1);
-  compress_worker_pool(params, owned_input,output, alloc_per_thread,&mut work_pool)
+  compress_worker_pool(params, owned_input,output, alloc_per_thread,&mut work_pool, 0, &mut |_, _, _, _| ())
 }
 
 #[cfg(not(feature="std"))]
@@ -133,6 +145,10 @@ pub use self::singlethreading::compress_multi;
 pub use self::singlethreading::compress_multi as compress_multi_no_threadpool;
 #[cfg(feature="std")]
 pub use self::multithreading::compress_multi as compress_multi_no_threadpool;
+#[cfg(feature="scoped-threads")]
+pub use self::scoped_threading::{ScopedSpawner, compress_multi as compress_multi_scoped};
+#[cfg(all(feature="rayon", feature="std"))]
+pub use self::rayon_threading::{RayonSpawner, compress_multi as compress_multi_rayon};
 
 
 #[cfg(feature="std")]
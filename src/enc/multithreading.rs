@@ -143,6 +143,6 @@ pub fn compress_multi<Alloc:BrotliAlloc+Send+'static,
   where <Alloc as Allocator<u8>>::AllocatedMemory: Send,
         <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
         <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
-  CompressMulti(params, owned_input, output, alloc_per_thread, &mut MultiThreadedSpawner::default())
+  CompressMulti(params, owned_input, output, alloc_per_thread, &mut MultiThreadedSpawner::default(), 0, &mut |_, _, _, _| ())
 }
 
@@ -29,6 +29,7 @@ pub enum BrotliEncoderParameter {
   BROTLI_PARAM_MAGIC_NUMBER = 169,
   BROTLI_PARAM_NO_DICTIONARY = 170,
   BROTLI_PARAM_FAVOR_EFFICIENCY = 171,
+  BROTLI_PARAM_CATABLE_DICTIONARY = 172,
   UNUSED7=7,
   UNUSED8=8,
   UNUSED9=9,
@@ -172,7 +173,6 @@ pub enum BrotliEncoderParameter {
   UNUSED147=147,
   UNUSED148=148,
   UNUSED149=149,
-  UNUSED172=172,
   UNUSED173=173,
   UNUSED174=174,
   UNUSED175=175,
@@ -0,0 +1,131 @@
+#![cfg(all(feature="rayon", feature="std"))]
+use core::marker::PhantomData;
+use std;
+use std::sync::{Arc, RwLock, mpsc};
+use std::panic;
+use std::time::Duration;
+use rayon;
+use alloc::{SliceWrapper, Allocator};
+use enc::BrotliAlloc;
+use enc::BrotliEncoderParams;
+use enc::backward_references::UnionHasher;
+use enc::threading::{
+  CompressMulti,
+  SendAlloc,
+  InternalSendAlloc,
+  BatchSpawnableLite,
+  Joinable,
+  Owned,
+  OwnedRetriever,
+  CompressionThreadResult,
+  InternalOwned,
+  BrotliEncoderThreadError,
+  AnyBoxConstructor,
+};
+
+// spawns each work item onto a rayon pool: either the global pool or one the application already owns
+
+
+pub struct RayonJoinable<T:Send+'static, U:Send+'static>(mpsc::Receiver<std::thread::Result<T>>, PhantomData<U>);
+
+impl<T:Send+'static, U:Send+'static+AnyBoxConstructor> Joinable<T, U> for RayonJoinable<T, U> {
+  fn join(self) -> Result<T, U> {
+    loop {
+      match self.0.try_recv() {
+        Ok(Ok(t)) => return Ok(t),
+        Ok(Err(e)) => return Err(<U as AnyBoxConstructor>::new(e)),
+        Err(mpsc::TryRecvError::Disconnected) => return Err(<U as AnyBoxConstructor>::new(
+          std::boxed::Box::new(mpsc::TryRecvError::Disconnected))),
+        Err(mpsc::TryRecvError::Empty) => {},
+      }
+      // if we are ourselves running on a rayon worker, blocking here could starve the pool
+      // of the very thread that would run our job, so help out with pending work instead
+      match rayon::yield_now() {
+        Some(rayon::Yield::Executed) => {},
+        Some(rayon::Yield::Idle) => {
+          if let Ok(ret) = self.0.recv_timeout(Duration::from_millis(1)) {
+            return match ret {
+              Ok(t) => Ok(t),
+              Err(e) => Err(<U as AnyBoxConstructor>::new(e)),
+            };
+          }
+        },
+        None => return match self.0.recv() {
+          Ok(Ok(t)) => Ok(t),
+          Ok(Err(e)) => Err(<U as AnyBoxConstructor>::new(e)),
+          Err(e) => Err(<U as AnyBoxConstructor>::new(std::boxed::Box::new(e))),
+        },
+      }
+    }
+  }
+}
+
+#[derive(Default, Clone)]
+pub struct RayonSpawner {
+  pool: Option<Arc<rayon::ThreadPool>>, // None means the rayon global pool
+}
+
+impl RayonSpawner {
+  pub fn new(pool: Arc<rayon::ThreadPool>) -> Self {
+    RayonSpawner {
+      pool: Some(pool),
+    }
+  }
+}
+
+impl<ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+'static+Sync>
+  BatchSpawnableLite<ReturnValue, ExtraInput, Alloc, U> for RayonSpawner
+  where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
+  type JoinHandle = RayonJoinable<ReturnValue, BrotliEncoderThreadError>;
+  type FinalJoinHandle = Arc<RwLock<U>>;
+  fn make_spawner(
+    &mut self,
+    input: &mut Owned<U>,
+  ) -> Self::FinalJoinHandle {
+    Arc::<RwLock<U>>::new(RwLock::new(core::mem::replace(input, Owned(InternalOwned::Borrowed)).unwrap()))
+  }
+  fn spawn(
+    &mut self,
+    locked_input: &mut Self::FinalJoinHandle,
+    work:&mut SendAlloc<ReturnValue, ExtraInput, Alloc, Self::JoinHandle>,
+    index: usize,
+    num_threads: usize,
+    f: fn(ExtraInput, usize, usize, &U, Alloc) -> ReturnValue,
+  ) {
+    let (alloc, extra_input) = work.replace_with_default();
+    let (tx, rx) = mpsc::sync_channel(1);
+    let locked_input = locked_input.clone();
+    let job = move || {
+      // rayon aborts the process on an unhandled panic in spawn, so report it through join instead
+      let ret = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+        locked_input.view(move |guard:&U| f(extra_input, index, num_threads, guard, alloc)).unwrap()
+      }));
+      let _ = tx.send(ret);
+    };
+    match self.pool {
+      Some(ref pool) => pool.spawn(job),
+      None => rayon::spawn(job),
+    }
+    *work = SendAlloc(InternalSendAlloc::Join(RayonJoinable(rx, PhantomData::default())));
+  }
+}
+
+pub fn compress_multi<Alloc:BrotliAlloc+Send+'static,
+                      SliceW: SliceWrapper<u8>+Send+'static+Sync> (
+  params:&BrotliEncoderParams,
+  owned_input: &mut Owned<SliceW>,
+  output: &mut [u8],
+  alloc_per_thread:&mut [SendAlloc<CompressionThreadResult<Alloc>,
+                                   UnionHasher<Alloc>,
+                                   Alloc,
+                                   RayonJoinable<CompressionThreadResult<Alloc>, BrotliEncoderThreadError>>],
+  spawner: &mut RayonSpawner,
+) -> Result<usize, BrotliEncoderThreadError>
+  where <Alloc as Allocator<u8>>::AllocatedMemory: Send,
+        <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
+        <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
+  CompressMulti(params, owned_input, output, alloc_per_thread, spawner, 0, &mut |_, _, _, _| ())
+}
@@ -170,6 +170,15 @@ CompressorReaderCustomIo<ErrType, R, BufferType, Alloc>
 
         ret
     }
+
+    pub fn with_params(r: R, buffer : BufferType,
+                       alloc : Alloc,
+                       invalid_data_error_type : ErrType,
+                       params: &BrotliEncoderParams) -> Self {
+        let mut ret = Self::new(r, buffer, alloc, invalid_data_error_type, params.quality as u32, params.lgwin as u32);
+        ret.state.0.params = params.clone();
+        ret
+    }
     pub fn copy_to_front(&mut self) {
         let avail_in = self.input_len - self.input_offset;
         if self.input_offset == self.input_buffer.slice_mut().len() {
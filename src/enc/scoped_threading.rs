@@ -0,0 +1,108 @@
+#![cfg(feature="scoped-threads")]
+use core::marker::PhantomData;
+use std;
+use std::sync::{Arc, RwLock, mpsc};
+use std::panic;
+use alloc::Allocator;
+use enc::BrotliAlloc;
+use enc::BrotliEncoderParams;
+use enc::backward_references::UnionHasher;
+use enc::input_pair::InputReference;
+use enc::threading::{
+  CompressMulti,
+  SendAlloc,
+  InternalSendAlloc,
+  BatchSpawnableLite,
+  Joinable,
+  Owned,
+  OwnedRetriever,
+  CompressionThreadResult,
+  InternalOwned,
+  BrotliEncoderThreadError,
+  AnyBoxConstructor,
+};
+
+// in-place scoped thread create: the input is borrowed for the life of the scope, never copied
+
+
+pub struct ScopedJoinable<T:Send+'static, U:Send+'static>(mpsc::Receiver<std::thread::Result<T>>, PhantomData<U>);
+
+impl<T:Send+'static, U:Send+'static+AnyBoxConstructor> Joinable<T, U> for ScopedJoinable<T, U> {
+  fn join(self) -> Result<T, U> {
+      match self.0.recv() {
+          Ok(Ok(t)) => Ok(t),
+          Ok(Err(e)) => Err(<U as AnyBoxConstructor>::new(e)),
+          Err(e) => Err(<U as AnyBoxConstructor>::new(std::boxed::Box::new(e))),
+      }
+  }
+}
+
+pub struct ScopedSpawner<'scope, 'env:'scope> {
+  scope: &'scope std::thread::Scope<'scope, 'env>,
+}
+
+impl<'scope, 'env> ScopedSpawner<'scope, 'env> {
+  pub fn new(scope: &'scope std::thread::Scope<'scope, 'env>) -> Self {
+    ScopedSpawner {
+      scope: scope,
+    }
+  }
+}
+
+impl<'scope, 'env,
+     ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+Sync+'env>
+  BatchSpawnableLite<ReturnValue, ExtraInput, Alloc, U> for ScopedSpawner<'scope, 'env>
+  where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
+  type JoinHandle = ScopedJoinable<ReturnValue, BrotliEncoderThreadError>;
+  type FinalJoinHandle = Arc<RwLock<U>>;
+  fn make_spawner(
+    &mut self,
+    input: &mut Owned<U>,
+  ) -> Self::FinalJoinHandle {
+    Arc::<RwLock<U>>::new(RwLock::new(core::mem::replace(input, Owned(InternalOwned::Borrowed)).unwrap()))
+  }
+  fn spawn(
+    &mut self,
+    locked_input: &mut Self::FinalJoinHandle,
+    work:&mut SendAlloc<ReturnValue, ExtraInput, Alloc, Self::JoinHandle>,
+    index: usize,
+    num_threads: usize,
+    f: fn(ExtraInput, usize, usize, &U, Alloc) -> ReturnValue,
+  ) {
+    let (alloc, extra_input) = work.replace_with_default();
+    let (tx, rx) = mpsc::sync_channel(1);
+    let locked_input = locked_input.clone();
+    self.scope.spawn(move || {
+      // catch the panic here so the scope does not re-raise it when it closes:
+      // the caller sees it as a ThreadExecError from join instead
+      let ret = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+        locked_input.view(move |guard:&U| f(extra_input, index, num_threads, guard, alloc)).unwrap()
+      }));
+      let _ = tx.send(ret);
+    });
+    *work = SendAlloc(InternalSendAlloc::Join(ScopedJoinable(rx, PhantomData::default())));
+  }
+}
+
+// compresses a borrowed input slice on scoped threads, so unlike CompressMultiSlice
+// the input need not be 'static nor copied into an Owned buffer first
+pub fn compress_multi<Alloc:BrotliAlloc+Send+'static> (
+  params:&BrotliEncoderParams,
+  input: &[u8],
+  output: &mut [u8],
+  alloc_per_thread:&mut [SendAlloc<CompressionThreadResult<Alloc>,
+                                   UnionHasher<Alloc>,
+                                   Alloc,
+                                   ScopedJoinable<CompressionThreadResult<Alloc>, BrotliEncoderThreadError>>],
+) -> Result<usize, BrotliEncoderThreadError>
+  where <Alloc as Allocator<u8>>::AllocatedMemory: Send,
+        <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
+        <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
+  std::thread::scope(|scope| {
+    let mut owned_input = Owned::new(InputReference{data: input, orig_offset: 0});
+    CompressMulti(params, &mut owned_input, output, alloc_per_thread, &mut ScopedSpawner::new(scope), 0, &mut |_, _, _, _| ())
+  })
+}
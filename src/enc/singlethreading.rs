@@ -4,6 +4,8 @@ use std;
 use alloc::{SliceWrapper, Allocator};
 use enc::BrotliAlloc;
 use enc::BrotliEncoderParams;
+use enc::interface;
+use enc::input_pair::{InputPair, InputReferenceMut};
 use core::marker::PhantomData;
 use super::backward_references::{UnionHasher};
 use enc::threading::{
@@ -16,6 +18,7 @@ use enc::threading::{
   Owned,
   OwnedRetriever,
   CompressionThreadResult,
+  CompressionThreadInput,
   InternalOwned,
   PoisonedThreadError,
   BrotliEncoderThreadError,
@@ -131,7 +134,7 @@ pub fn compress_multi<Alloc:BrotliAlloc+Send+'static,
                                    Alloc,
                                    <SingleThreadedSpawner as BatchSpawnable<CompressionThreadResult<Alloc>,UnionHasher<Alloc>, Alloc, SliceW>>::JoinHandle>],
 ) -> Result<usize, BrotliEncoderThreadError> where <Alloc as Allocator<u8>>::AllocatedMemory: Send, <Alloc as Allocator<u16>>::AllocatedMemory: Send, <Alloc as Allocator<u32>>::AllocatedMemory: Send {
-  CompressMulti(params, owned_input, output, alloc_per_thread, &mut SingleThreadedSpawner::default())
+  CompressMulti(params, owned_input, output, alloc_per_thread, &mut SingleThreadedSpawner::default(), 0, &mut |_, _, _, _| ())
 }
 
 pub struct WorkerPool<A,B,C, D> {
@@ -140,6 +143,45 @@ pub struct WorkerPool<A,B,C, D> {
   c: PhantomData<C>,
   d: PhantomData<D>,
 }
+// without std everything runs on the calling thread: these keep the std WorkerPool interface
+impl<A,B,C,D> WorkerPool<A,B,C,D> {
+  pub fn new(num_threads: usize) -> Self {
+    new_work_pool(num_threads)
+  }
+  pub fn resize(&mut self, _num_threads: usize) {
+  }
+  pub fn num_threads(&self) -> usize {
+    1
+  }
+  pub fn shutdown(self) {
+  }
+}
+impl<ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+'static+Sync>
+  BatchSpawnableLite<ReturnValue, ExtraInput, Alloc, U> for WorkerPool<ReturnValue, ExtraInput, Alloc, U>
+  where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
+  type JoinHandle = <SingleThreadedSpawner as BatchSpawnable<ReturnValue, ExtraInput, Alloc, U>>::JoinHandle;
+  type FinalJoinHandle = <SingleThreadedSpawner as BatchSpawnable<ReturnValue, ExtraInput, Alloc, U>>::FinalJoinHandle;
+
+  fn make_spawner(
+    &mut self,
+    input: &mut Owned<U>,
+  ) -> Self::FinalJoinHandle {
+     <SingleThreadedSpawner as BatchSpawnable<ReturnValue, ExtraInput, Alloc, U>>::make_spawner(&mut SingleThreadedSpawner::default(), input)
+  }
+  fn spawn(
+    &mut self,
+    handle:&mut Self::FinalJoinHandle,
+    alloc_per_thread:&mut SendAlloc<ReturnValue, ExtraInput, Alloc, Self::JoinHandle>,
+    index: usize,
+    num_threads: usize,
+    f: fn(ExtraInput, usize, usize, &U, Alloc) -> ReturnValue,
+  ) {
+   <SingleThreadedSpawner as BatchSpawnable<ReturnValue, ExtraInput, Alloc, U>>::spawn(&mut SingleThreadedSpawner::default(), handle, alloc_per_thread, index, num_threads, f)
+  }
+}
 pub fn new_work_pool<A,B, C, D>(_num_threads: usize) -> WorkerPool<A, B, C, D>{
   WorkerPool::<A,B,C, D>{
     a:PhantomData::default(),
@@ -150,7 +192,10 @@ pub fn new_work_pool<A,B, C, D>(_num_threads: usize) -> WorkerPool<A, B, C, D>{
 }
 
 pub fn compress_worker_pool<Alloc:BrotliAlloc+Send+'static,
-                      SliceW: SliceWrapper<u8>+Send+'static+Sync> (
+                            SliceW: SliceWrapper<u8>+Send+'static+Sync,
+                            MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                     &mut [interface::StaticCommand],
+                                                     InputPair, &mut Alloc)> (
   params:&BrotliEncoderParams,
   owned_input: &mut Owned<SliceW>,
   output: &mut [u8],
@@ -161,7 +206,9 @@ pub fn compress_worker_pool<Alloc:BrotliAlloc+Send+'static,
                                                                             UnionHasher<Alloc>,
                                                                             Alloc,
                                                                             SliceW>>::JoinHandle>],
-  _worker_pool:&mut WorkerPool<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, (SliceW, BrotliEncoderParams)>,
+  _worker_pool:&mut WorkerPool<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, CompressionThreadInput<SliceW>>,
+  custom_dictionary_len: usize,
+  metablock_callback: &mut MetablockCallback,
 ) -> Result<usize, BrotliEncoderThreadError> where <Alloc as Allocator<u8>>::AllocatedMemory: Send, <Alloc as Allocator<u16>>::AllocatedMemory: Send, <Alloc as Allocator<u32>>::AllocatedMemory: Send {
-  compress_multi(params, owned_input, output, alloc_per_thread)
+  CompressMulti(params, owned_input, output, alloc_per_thread, &mut SingleThreadedSpawner::default(), custom_dictionary_len, metablock_callback)
 }
@@ -20,6 +20,10 @@ use concat::{
   BroCatliResult,
 };
 use core::ops::Range;
+use super::chunking::UnionChunkingPolicy;
+use super::interface;
+use super::interface::SliceOffset;
+use super::input_pair::{InputPair, InputReference, InputReferenceMut};
 use super::backward_references::{BrotliEncoderParams, UnionHasher, CloneWithAlloc, AnyHasher};
 pub type PoisonedThreadError = ();
 
@@ -44,6 +48,11 @@ pub enum BrotliEncoderThreadError {
     ConcatenationFinalizationError(BroCatliResult),
     OtherThreadPanic,
     ThreadExecError(LowLevelThreadError),
+    /// the work item with this index failed: it was compressing the chunks that start within this range
+    /// of the input (not counting any custom dictionary prefix)
+    WorkItemPanic(usize, Range<usize>, LowLevelThreadError),
+    /// the metablock IR logged by the work item with this index could not be read back
+    MetablockLogError(usize),
 }
 
 impl AnyBoxConstructor for BrotliEncoderThreadError {
@@ -57,8 +66,53 @@ pub struct CompressedFileChunk<Alloc:BrotliAlloc+Send+'static> where <Alloc as A
     data_backing:<Alloc as Allocator<u8>>::AllocatedMemory,
     data_size: usize,
 }
+// what the work items of a CompressMulti call share: the input, whose first dictionary_prefix bytes are
+// a custom dictionary, the params, the number of threads and (with std) where every chunk starts, found
+// in a single pass over the input so work items need not walk every chunk before their own
+pub struct CompressionThreadInput<SliceW> {
+  input: SliceW,
+  params: BrotliEncoderParams,
+  dictionary_prefix: usize,
+  num_threads: usize,
+  #[cfg(feature="std")]
+  chunk_starts: std::vec::Vec<usize>,
+}
+
+impl<SliceW:SliceWrapper<u8>> CompressionThreadInput<SliceW> {
+  #[cfg(feature="std")]
+  fn new(input: SliceW, params: &BrotliEncoderParams, dictionary_prefix: usize, num_threads: usize) -> Self {
+    let starts = chunk_starts(&params.multi_chunking, &input.slice()[dictionary_prefix..], num_threads);
+    CompressionThreadInput{
+      input: input,
+      params: params.clone(),
+      dictionary_prefix: dictionary_prefix,
+      num_threads: num_threads,
+      chunk_starts: starts,
+    }
+  }
+  #[cfg(not(feature="std"))]
+  fn new(input: SliceW, params: &BrotliEncoderParams, dictionary_prefix: usize, num_threads: usize) -> Self {
+    CompressionThreadInput{
+      input: input,
+      params: params.clone(),
+      dictionary_prefix: dictionary_prefix,
+      num_threads: num_threads,
+    }
+  }
+  // with more chunks than threads, each chunk is a work item of its own
+  #[cfg(feature="std")]
+  fn num_items(&self) -> usize {
+    core::cmp::max(self.num_threads, self.chunk_starts.len())
+  }
+  #[cfg(not(feature="std"))]
+  fn num_items(&self) -> usize {
+    self.num_threads
+  }
+}
+
 pub struct CompressionThreadResult<Alloc:BrotliAlloc+Send+'static> where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
   compressed: Result<CompressedFileChunk<Alloc>, BrotliEncoderThreadError>,
+  metablock_log: CompressedFileChunk<Alloc>,
   alloc: Alloc,
 }
 pub enum InternalSendAlloc<ReturnVal:Send+'static, ExtraInput:Send+'static,Alloc:BrotliAlloc+Send+'static, Join: Joinable<ReturnVal, BrotliEncoderThreadError>>
@@ -158,13 +212,13 @@ impl<T> Owned<T> {
 
 
 
-pub trait OwnedRetriever<U:Send+'static> {
+pub trait OwnedRetriever<U:Send> {
   fn view<T, F:FnOnce(&U)-> T>(&self, f:F) -> Result<T, PoisonedThreadError>;
   fn unwrap(self) -> Result<U, PoisonedThreadError>;
 }
 
 #[cfg(feature="std")]
-impl<U:Send+'static> OwnedRetriever<U> for std::sync::Arc<std::sync::RwLock<U>> {
+impl<U:Send> OwnedRetriever<U> for std::sync::Arc<std::sync::RwLock<U>> {
   fn view<T, F:FnOnce(&U)-> T>(&self, f:F) -> Result<T, PoisonedThreadError> {
       match self.read() {
           Ok(ref u) => Ok(f(u)),
@@ -217,7 +271,7 @@ pub trait BatchSpawnable<ReturnValue:Send+'static,
 pub trait BatchSpawnableLite<ReturnValue:Send+'static,
                              ExtraInput:Send+'static,
                          Alloc:BrotliAlloc+Send+'static,
-                         U:Send+'static+Sync>
+                         U:Send+Sync>
   where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static
 {
   type JoinHandle: Joinable<ReturnValue, BrotliEncoderThreadError>;
@@ -253,26 +307,37 @@ impl<ReturnValue:Send+'static,
   }
 }*/
 
+// copies custom_dictionary (empty for none) and the input into a single buffer, so every worker sees
+// the dictionary as the start of its prefix: see CompressMulti
 pub fn CompressMultiSlice<Alloc:BrotliAlloc+Send+'static,
                           Spawner:BatchSpawnableLite<CompressionThreadResult<Alloc>,
                                                      UnionHasher<Alloc>,
                                                      Alloc,
-                                                     (<Alloc as Allocator<u8>>::AllocatedMemory, BrotliEncoderParams)>> (
+                                                     CompressionThreadInput<<Alloc as Allocator<u8>>::AllocatedMemory>>,
+                          MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                   &mut [interface::StaticCommand],
+                                                   InputPair, &mut Alloc)> (
   params:&BrotliEncoderParams,
   input_slice: &[u8],
   output: &mut [u8],
   alloc_per_thread:&mut [SendAlloc<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, Spawner::JoinHandle>],
   thread_spawner: &mut Spawner,
+  dict: &[u8],
+  metablock_callback: &mut MetablockCallback,
 ) -> Result<usize, BrotliEncoderThreadError> where <Alloc as Allocator<u8>>::AllocatedMemory: Send+Sync, <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync, <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
   let input = if let InternalSendAlloc::A(ref mut alloc, ref _extra) = alloc_per_thread[0].0 {
-    let mut input = <Alloc as Allocator<u8>>::alloc_cell(alloc, input_slice.len());
-    input.slice_mut().clone_from_slice(input_slice);
+    let mut input = <Alloc as Allocator<u8>>::alloc_cell(alloc, dict.len() + input_slice.len());
+    {
+      let (dict_dst, input_dst) = input.slice_mut().split_at_mut(dict.len());
+      dict_dst.clone_from_slice(dict);
+      input_dst.clone_from_slice(input_slice);
+    }
     input
   } else {
     <Alloc as Allocator<u8>>::AllocatedMemory::default()
   };
   let mut owned_input = Owned::new(input);
-  let ret = CompressMulti(params, &mut owned_input, output, alloc_per_thread, thread_spawner);
+  let ret = CompressMulti(params, &mut owned_input, output, alloc_per_thread, thread_spawner, dict.len(), metablock_callback);
   if let InternalSendAlloc::A(ref mut alloc, ref _extra) = alloc_per_thread[0].0 {
     <Alloc as Allocator<u8>>::free_cell(alloc, owned_input.unwrap());
   }
@@ -283,45 +348,400 @@ fn get_range(thread_index: usize, num_threads: usize, file_size: usize) -> Range
     ((thread_index * file_size) / num_threads)..(((thread_index + 1) * file_size) / num_threads)
 }
 
-fn compress_part<Alloc: BrotliAlloc+Send+'static,
-                 SliceW:SliceWrapper<u8>>(
+// the range of the shared input that a work item compresses, skipping past any custom dictionary prefix
+fn get_data_range<SliceW:SliceWrapper<u8>>(thread_index: usize, num_threads: usize, shared: &CompressionThreadInput<SliceW>) -> Range<usize> {
+    let dict_len = shared.dictionary_prefix;
+    let range = get_range(thread_index, num_threads, shared.input.len() - dict_len);
+    (range.start + dict_len)..(range.end + dict_len)
+}
+
+// The metablock IR a worker produces is serialized into a byte log, since only the u8 memory of the
+// allocator is known to be Send, and then handed to the caller's callback in input order once joined.
+// Layout per metablock: literal context map, distance context map, the sizes of the two halves of the
+// InputPair and then the commands. Lengths and offsets into the input are little endian u64, since the
+// input may exceed 4 GiB; the other integers are little endian u32, as they are in the commands.
+const IR_COPY: u8 = 0;
+const IR_DICT: u8 = 1;
+const IR_LITERAL: u8 = 2;
+const IR_BLOCK_SWITCH_COMMAND: u8 = 3;
+const IR_BLOCK_SWITCH_LITERAL: u8 = 4;
+const IR_BLOCK_SWITCH_DISTANCE: u8 = 5;
+const IR_PREDICTION_MODE: u8 = 6;
+
+fn log_bytes<Alloc:BrotliAlloc+Send+'static>(log: &mut CompressedFileChunk<Alloc>, alloc: &mut Alloc, data: &[u8]) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  if log.data_size + data.len() > log.data_backing.len() {
+    let new_len = core::cmp::max(log.data_backing.len() * 2, log.data_size + data.len() + 4096);
+    let mut new_backing = <Alloc as Allocator<u8>>::alloc_cell(alloc, new_len);
+    new_backing.slice_mut()[..log.data_size].clone_from_slice(&log.data_backing.slice()[..log.data_size]);
+    <Alloc as Allocator<u8>>::free_cell(alloc, mem::replace(&mut log.data_backing, new_backing));
+  }
+  log.data_backing.slice_mut()[log.data_size..log.data_size + data.len()].clone_from_slice(data);
+  log.data_size += data.len();
+}
+
+fn log_u32<Alloc:BrotliAlloc+Send+'static>(log: &mut CompressedFileChunk<Alloc>, alloc: &mut Alloc, val: u32) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  log_bytes(log, alloc, &[val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]);
+}
+
+fn log_u64<Alloc:BrotliAlloc+Send+'static>(log: &mut CompressedFileChunk<Alloc>, alloc: &mut Alloc, val: u64) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  log_u32(log, alloc, val as u32);
+  log_u32(log, alloc, (val >> 32) as u32);
+}
+
+fn log_slice_offset<Alloc:BrotliAlloc+Send+'static>(log: &mut CompressedFileChunk<Alloc>, alloc: &mut Alloc, val: &SliceOffset) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  log_u64(log, alloc, val.offset() as u64);
+  log_u32(log, alloc, val.len32());
+}
+
+#[cfg(not(feature="external-literal-probability"))]
+fn log_literal_prob<Alloc:BrotliAlloc+Send+'static>(_log: &mut CompressedFileChunk<Alloc>, _alloc: &mut Alloc, _prob: &interface::FeatureFlagSliceType<SliceOffset>) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+}
+
+#[cfg(feature="external-literal-probability")]
+fn log_literal_prob<Alloc:BrotliAlloc+Send+'static>(log: &mut CompressedFileChunk<Alloc>, alloc: &mut Alloc, prob: &interface::FeatureFlagSliceType<SliceOffset>) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  log_slice_offset(log, alloc, &prob.0);
+}
+
+fn log_metablock<Alloc:BrotliAlloc+Send+'static>(
+  log: &mut CompressedFileChunk<Alloc>,
+  pm: &interface::PredictionModeContextMap<InputReferenceMut>,
+  cmds: &[interface::StaticCommand],
+  mb: &InputPair,
+  alloc: &mut Alloc,
+) where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  log_u64(log, alloc, pm.literal_context_map.slice().len() as u64);
+  log_bytes(log, alloc, pm.literal_context_map.slice());
+  log_u64(log, alloc, pm.predmode_speed_and_distance_context_map.slice().len() as u64);
+  log_bytes(log, alloc, pm.predmode_speed_and_distance_context_map.slice());
+  log_u64(log, alloc, mb.0.len() as u64);
+  log_u64(log, alloc, mb.1.len() as u64);
+  log_u64(log, alloc, cmds.len() as u64);
+  for cmd in cmds.iter() {
+    match *cmd {
+      interface::Command::Copy(ref copy) => {
+        log_bytes(log, alloc, &[IR_COPY]);
+        log_u32(log, alloc, copy.distance);
+        log_u32(log, alloc, copy.num_bytes);
+      },
+      interface::Command::Dict(ref dict) => {
+        log_bytes(log, alloc, &[IR_DICT, dict.word_size, dict.transform, dict.final_size, dict.empty]);
+        log_u32(log, alloc, dict.word_id);
+      },
+      interface::Command::Literal(ref lit) => {
+        log_bytes(log, alloc, &[IR_LITERAL, lit.high_entropy as u8]);
+        log_slice_offset(log, alloc, &lit.data);
+        log_literal_prob(log, alloc, &lit.prob);
+      },
+      interface::Command::BlockSwitchCommand(ref switch) => {
+        log_bytes(log, alloc, &[IR_BLOCK_SWITCH_COMMAND, switch.block_type()]);
+      },
+      interface::Command::BlockSwitchLiteral(ref switch) => {
+        log_bytes(log, alloc, &[IR_BLOCK_SWITCH_LITERAL, switch.block_type(), switch.stride()]);
+      },
+      interface::Command::BlockSwitchDistance(ref switch) => {
+        log_bytes(log, alloc, &[IR_BLOCK_SWITCH_DISTANCE, switch.block_type()]);
+      },
+      interface::Command::PredictionMode(ref pm) => {
+        log_bytes(log, alloc, &[IR_PREDICTION_MODE]);
+        log_slice_offset(log, alloc, &pm.literal_context_map);
+        log_slice_offset(log, alloc, &pm.predmode_speed_and_distance_context_map);
+      },
+    }
+  }
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> u32 {
+  let ret = u32::from(data[*pos]) | (u32::from(data[*pos + 1]) << 8) | (u32::from(data[*pos + 2]) << 16) | (u32::from(data[*pos + 3]) << 24);
+  *pos += 4;
+  ret
+}
+
+fn read_u64(data: &[u8], pos: &mut usize) -> u64 {
+  let ret = u64::from(read_u32(data, pos));
+  ret | (u64::from(read_u32(data, pos)) << 32)
+}
+
+// reads a metablock log back: past the end of it every read yields zero and marks it truncated, so
+// that a damaged log is reported rather than indexed out of bounds
+struct LogReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+  truncated: bool,
+}
+
+impl<'a> LogReader<'a> {
+  fn has(&mut self, len: usize) -> bool {
+    if self.data.len() - self.pos < len {
+      self.truncated = true;
+      self.pos = self.data.len();
+      false
+    } else {
+      true
+    }
+  }
+  // where the next len bytes start, stepping past them
+  fn skip(&mut self, len: usize) -> usize {
+    let start = self.pos;
+    if self.has(len) {
+      self.pos += len;
+    }
+    start
+  }
+  fn u8(&mut self) -> u8 {
+    if !self.has(1) {
+      return 0;
+    }
+    self.pos += 1;
+    self.data[self.pos - 1]
+  }
+  fn u32(&mut self) -> u32 {
+    if !self.has(4) {
+      return 0;
+    }
+    read_u32(self.data, &mut self.pos)
+  }
+  // a length or offset: one that does not fit in usize cannot be valid either
+  fn usize(&mut self) -> usize {
+    if !self.has(8) {
+      return 0;
+    }
+    let val = read_u64(self.data, &mut self.pos);
+    if val as usize as u64 != val {
+      self.truncated = true;
+      return 0;
+    }
+    val as usize
+  }
+  fn slice_offset(&mut self) -> SliceOffset {
+    let offset = self.usize();
+    SliceOffset(offset, self.u32())
+  }
+}
+
+#[cfg(not(feature="external-literal-probability"))]
+fn read_literal_prob(_reader: &mut LogReader) -> interface::FeatureFlagSliceType<SliceOffset> {
+  interface::FeatureFlagSliceType::<SliceOffset>::default()
+}
+
+#[cfg(feature="external-literal-probability")]
+fn read_literal_prob(reader: &mut LogReader) -> interface::FeatureFlagSliceType<SliceOffset> {
+  interface::FeatureFlagSliceType::<SliceOffset>(reader.slice_offset())
+}
+
+fn read_command(reader: &mut LogReader) -> Result<interface::StaticCommand, ()> {
+  let command = match reader.u8() {
+    IR_COPY => {
+      let distance = reader.u32();
+      interface::Command::Copy(interface::CopyCommand{distance: distance, num_bytes: reader.u32()})
+    },
+    IR_DICT => {
+      let word_size = reader.u8();
+      let transform = reader.u8();
+      let final_size = reader.u8();
+      let empty = reader.u8();
+      interface::Command::Dict(interface::DictCommand{
+        word_size: word_size,
+        transform: transform,
+        final_size: final_size,
+        empty: empty,
+        word_id: reader.u32(),
+      })
+    },
+    IR_LITERAL => {
+      let high_entropy = reader.u8() != 0;
+      let lit_data = reader.slice_offset();
+      interface::Command::Literal(interface::LiteralCommand{
+        data: lit_data,
+        prob: read_literal_prob(reader),
+        high_entropy: high_entropy,
+      })
+    },
+    IR_BLOCK_SWITCH_COMMAND => interface::Command::BlockSwitchCommand(interface::BlockSwitch::new(reader.u8())),
+    IR_BLOCK_SWITCH_LITERAL => {
+      let block_type = reader.u8();
+      interface::Command::BlockSwitchLiteral(interface::LiteralBlockSwitch::new(block_type, reader.u8()))
+    },
+    IR_BLOCK_SWITCH_DISTANCE => interface::Command::BlockSwitchDistance(interface::BlockSwitch::new(reader.u8())),
+    IR_PREDICTION_MODE => {
+      let literal_context_map = reader.slice_offset();
+      interface::Command::PredictionMode(interface::PredictionModeContextMap::<SliceOffset>{
+        literal_context_map: literal_context_map,
+        predmode_speed_and_distance_context_map: reader.slice_offset(),
+      })
+    },
+    _ => return Err(()),
+  };
+  if reader.truncated {
+    return Err(());
+  }
+  Ok(command)
+}
+
+// hands each logged metablock of one work item to the callback; input is the data range it compressed.
+// Fails without calling the callback again if the log is damaged
+fn replay_metablock_log<Alloc:BrotliAlloc+Send+'static,
+                        MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                 &mut [interface::StaticCommand],
+                                                 InputPair, &mut Alloc)>(
+  log: &mut CompressedFileChunk<Alloc>,
+  input: &[u8],
+  alloc: &mut Alloc,
+  metablock_callback: &mut MetablockCallback,
+) -> Result<(), ()> where <Alloc as Allocator<u8>>::AllocatedMemory: Send {
+  let mut pos = 0usize;
+  let mut mb_start = 0usize;
+  while pos < log.data_size {
+    let mut reader = LogReader{data: &log.data_backing.slice()[..log.data_size], pos: pos, truncated: false};
+    let literal_context_map_len = reader.usize();
+    let literal_context_map_start = reader.skip(literal_context_map_len);
+    let distance_context_map_len = reader.usize();
+    let distance_context_map_start = reader.skip(distance_context_map_len);
+    let input0_len = reader.usize();
+    let input1_len = reader.usize();
+    let num_commands = reader.usize();
+    // every command takes at least one byte, which bounds the allocation a damaged count can ask for
+    if reader.truncated || num_commands > reader.data.len() - reader.pos
+      || input0_len > input.len() - mb_start || input1_len > input.len() - mb_start - input0_len {
+      return Err(());
+    }
+    let mut cmds = <Alloc as Allocator<interface::StaticCommand>>::alloc_cell(alloc, num_commands);
+    let mut damaged = false;
+    for cmd in cmds.slice_mut().iter_mut() {
+      match read_command(&mut reader) {
+        Ok(command) => *cmd = command,
+        Err(()) => {
+          damaged = true;
+          break;
+        },
+      }
+    }
+    pos = reader.pos;
+    if damaged {
+      <Alloc as Allocator<interface::StaticCommand>>::free_cell(alloc, cmds);
+      return Err(());
+    }
+    {
+      let (literal_region, distance_region) = log.data_backing.slice_mut().split_at_mut(distance_context_map_start);
+      let mut pm = interface::PredictionModeContextMap::<InputReferenceMut>{
+        literal_context_map: InputReferenceMut{
+          data: &mut literal_region[literal_context_map_start..literal_context_map_start + literal_context_map_len],
+          orig_offset: 0,
+        },
+        predmode_speed_and_distance_context_map: InputReferenceMut{
+          data: &mut distance_region[..distance_context_map_len],
+          orig_offset: 0,
+        },
+      };
+      let mb = InputPair(InputReference{data: &input[mb_start..mb_start + input0_len], orig_offset: 0},
+                         InputReference{data: &input[mb_start + input0_len..mb_start + input0_len + input1_len], orig_offset: input0_len});
+      metablock_callback(&mut pm, cmds.slice_mut(), mb, alloc);
+    }
+    <Alloc as Allocator<interface::StaticCommand>>::free_cell(alloc, cmds);
+    mb_start += input0_len + input1_len;
+  }
+  Ok(())
+}
+
+// the bytes before each compressed chunk in a work item's result, holding its size
+const CHUNK_SIZE_BYTES: usize = 8;
+
+// where every chunk starts, relative to the data after any custom dictionary prefix: no data at all is
+// a single empty chunk
+#[cfg(feature="std")]
+fn chunk_starts(policy: &UnionChunkingPolicy, data: &[u8], num_threads: usize) -> std::vec::Vec<usize> {
+  let mut starts = std::vec::Vec::new();
+  let mut chunk_start = 0usize;
+  loop {
+    starts.push(chunk_start);
+    chunk_start = policy.chunk_end(data, chunk_start, num_threads);
+    if chunk_start >= data.len() {
+      return starts;
+    }
+  }
+}
+
+// the data whose chunks a work item compresses, relative to the data after any custom dictionary prefix.
+// With more chunks than threads each chunk is a work item of its own; otherwise each work item takes an
+// equal share of the data and compresses the chunks that start within it
+#[cfg(feature="std")]
+fn item_range<SliceW:SliceWrapper<u8>>(shared: &CompressionThreadInput<SliceW>, item_index: usize, num_items: usize) -> Range<usize> {
+  let data_len = shared.input.slice().len() - shared.dictionary_prefix;
+  let starts = &shared.chunk_starts[..];
+  if starts.len() > shared.num_threads {
+    return starts[item_index]..(if item_index + 1 < starts.len() { starts[item_index + 1] } else { data_len });
+  }
+  let work_range = get_range(item_index, num_items, data_len);
+  let first_start = match starts.iter().find(|start| **start >= work_range.start) {
+    Some(start) => *start,
+    None => data_len,
+  };
+  first_start..work_range.end
+}
+
+// without std the chunks before the item's share of the data are walked to find where its first one starts
+#[cfg(not(feature="std"))]
+fn item_range<SliceW:SliceWrapper<u8>>(shared: &CompressionThreadInput<SliceW>, item_index: usize, num_items: usize) -> Range<usize> {
+  let data = &shared.input.slice()[shared.dictionary_prefix..];
+  let work_range = get_range(item_index, num_items, data.len());
+  let mut chunk_start = 0usize;
+  while chunk_start < work_range.start {
+    chunk_start = shared.params.multi_chunking.chunk_end(data, chunk_start, shared.num_threads);
+  }
+  chunk_start..work_range.end
+}
+
+// the chunk of data a work item compresses next, if it starts within the work range. When there is no
+// data at all, work item 0 compresses a single empty chunk so the output is still a valid brotli file
+fn next_chunk(policy: &UnionChunkingPolicy, data: &[u8], item_index: usize, num_threads: usize,
+              work_range: &Range<usize>, chunk_start: usize, first: bool) -> Option<Range<usize>> {
+  if chunk_start < work_range.end {
+    Some(chunk_start..policy.chunk_end(data, chunk_start, num_threads))
+  } else if first && data.len() == 0 && item_index == 0 {
+    Some(0..0)
+  } else {
+    None
+  }
+}
+
+// compresses input[range] into output, with everything before range as the dictionary
+fn compress_chunk<Alloc: BrotliAlloc+Send+'static,
+                  SliceW:SliceWrapper<u8>>(
   hasher: UnionHasher<Alloc>,
-  thread_index: usize,
-  num_threads: usize,
-  input_and_params:&(SliceW, BrotliEncoderParams),
-  mut alloc: Alloc,
-) -> CompressionThreadResult<Alloc> where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
-  let mut range = get_range(thread_index, num_threads, input_and_params.0.len());
-  let mut mem = <Alloc as Allocator<u8>>::alloc_cell(&mut alloc,
-                                                     BrotliEncoderMaxCompressedSize(range.end - range.start));
+  shared:&CompressionThreadInput<SliceW>,
+  mut range: Range<usize>,
+  output: &mut [u8],
+  metablock_log: &mut CompressedFileChunk<Alloc>,
+  alloc: Alloc,
+) -> (Alloc, Result<usize, BrotliEncoderThreadError>) where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
   let mut state = BrotliEncoderCreateInstance(alloc);
-  state.params = input_and_params.1.clone();
-  if thread_index != 0 {
+  state.params = shared.params.clone();
+  if range.start != shared.dictionary_prefix {
     state.params.catable = true; // make sure we can concatenate this to the other work results
     state.params.magic_number = false; // no reason to pepper this around
   }
   state.params.appendable = true; // make sure we are at least appendable, so that future items can be catted in
-  if thread_index != 0 {
+  if range.start != 0 {
+    // everything before our range, including any custom dictionary prefix, is our dictionary
     BrotliEncoderSetCustomDictionaryWithOptionalPrecomputedHasher(
-      &mut state, range.start, &input_and_params.0.slice()[..range.start], hasher,
+      &mut state, range.start, &shared.input.slice()[..range.start], hasher,
     );
   }
   let mut out_offset = 0usize;
   let compression_result;
-  let mut available_out = mem.len();
+  let mut available_out = output.len();
   loop {
     let mut next_in_offset = 0usize;
     let mut available_in = range.end - range.start;
     let result = BrotliEncoderCompressStream(&mut state,
                                              BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
                                              &mut available_in,
-                                             &input_and_params.0.slice()[range.clone()],
+                                             &shared.input.slice()[range.clone()],
                                              &mut next_in_offset,  
                                              &mut available_out,
-                                             mem.slice_mut(),
+                                             output,
                                              &mut out_offset,
                                              &mut None,
-                                             &mut |_a,_b,_c,_d|());
+                                             &mut |pm, cmds, mb, m| log_metablock(metablock_log, pm, cmds, &mb, m));
     let new_range = range.start + next_in_offset..range.end;
     range = new_range;
     if result != 0 {
@@ -333,47 +753,140 @@ fn compress_part<Alloc: BrotliAlloc+Send+'static,
     }
   }
   BrotliEncoderDestroyInstance(&mut state);
-    match compression_result {
-        Ok(size) => {
-            CompressionThreadResult::<Alloc>{
-                compressed:Ok(CompressedFileChunk{data_backing:mem, data_size:size}),
-                alloc:state.m8,
-            }
-        },
-        Err(e) => {
-            <Alloc as Allocator<u8>>::free_cell(&mut state.m8, mem);
-            CompressionThreadResult::<Alloc>{
-                compressed:Err(e),
-                alloc:state.m8,
-            }
-        },
+  (state.m8, compression_result)
+}
+
+// compresses each chunk that starts within this work item's share of the data: the chunks are stored
+// one after another in the result, each preceded by its size as a little endian u64
+fn compress_part<Alloc: BrotliAlloc+Send+'static,
+                 SliceW:SliceWrapper<u8>>(
+  mut hasher: UnionHasher<Alloc>,
+  item_index: usize,
+  num_items: usize,
+  shared:&CompressionThreadInput<SliceW>,
+  mut alloc: Alloc,
+) -> CompressionThreadResult<Alloc> where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
+  let dict_len = shared.dictionary_prefix;
+  let policy = shared.params.multi_chunking;
+  let num_threads = shared.num_threads;
+  let data = &shared.input.slice()[dict_len..];
+  let work_range = item_range(shared, item_index, num_items);
+  let first_start = work_range.start;
+  let mut out_size = 0usize;
+  let mut chunk_start = first_start;
+  while let Some(chunk) = next_chunk(&policy, data, item_index, num_threads, &work_range, chunk_start, chunk_start == first_start) {
+    out_size += CHUNK_SIZE_BYTES + BrotliEncoderMaxCompressedSize(chunk.end - chunk.start);
+    if chunk.end == chunk_start {
+      break;
     }
+    chunk_start = chunk.end;
+  }
+  let mut mem = <Alloc as Allocator<u8>>::alloc_cell(&mut alloc, out_size);
+  let mut out_offset = 0usize;
+  let mut compression_result = Ok(());
+  let mut metablock_log = CompressedFileChunk::<Alloc>{
+    data_backing: <Alloc as Allocator<u8>>::AllocatedMemory::default(),
+    data_size: 0,
+  };
+  chunk_start = first_start;
+  while let Some(chunk) = next_chunk(&policy, data, item_index, num_threads, &work_range, chunk_start, chunk_start == first_start) {
+    let (size_field, chunk_out) = mem.slice_mut()[out_offset..].split_at_mut(CHUNK_SIZE_BYTES);
+    let (ret_alloc, result) = compress_chunk(mem::replace(&mut hasher, UnionHasher::Uninit),
+                                             shared,
+                                             (chunk.start + dict_len)..(chunk.end + dict_len),
+                                             chunk_out,
+                                             &mut metablock_log,
+                                             alloc);
+    alloc = ret_alloc;
+    match result {
+      Ok(size) => {
+        // a chunk of 4 GiB or more can compress to more than a u32 holds
+        for (index, byte) in size_field.iter_mut().enumerate() {
+          *byte = ((size as u64) >> (8 * index)) as u8;
+        }
+        out_offset += CHUNK_SIZE_BYTES + size;
+      },
+      Err(e) => {
+        compression_result = Err(e);
+        break;
+      },
+    }
+    if chunk.end == chunk_start {
+      break;
+    }
+    chunk_start = chunk.end;
+  }
+  hasher.free(&mut alloc); // unless no chunk started in our range, the first chunk consumed it
+  match compression_result {
+    Ok(()) => {
+      CompressionThreadResult::<Alloc>{
+        compressed:Ok(CompressedFileChunk{data_backing:mem, data_size:out_offset}),
+        metablock_log:metablock_log,
+        alloc:alloc,
+      }
+    },
+    Err(e) => {
+      <Alloc as Allocator<u8>>::free_cell(&mut alloc, mem);
+      <Alloc as Allocator<u8>>::free_cell(&mut alloc, mem::replace(&mut metablock_log.data_backing,
+                                                                   <Alloc as Allocator<u8>>::AllocatedMemory::default()));
+      metablock_log.data_size = 0;
+      CompressionThreadResult::<Alloc>{
+        compressed:Err(e),
+        metablock_log:metablock_log,
+        alloc:alloc,
+      }
+    },
+  }
 }
 
+// The first custom_dictionary_len bytes of owned_input are a custom dictionary (0 for none): each work
+// item is primed with it followed by all the input that precedes its own range, and the decoder must be
+// handed the same dictionary. If params.log_meta_block is set, each work item records its metablock IR,
+// which is passed to metablock_callback in input order as the results are joined.
 pub fn CompressMulti<Alloc:BrotliAlloc+Send+'static,
-                     SliceW: SliceWrapper<u8>+Send+'static+Sync,
+                     SliceW: SliceWrapper<u8>+Send+Sync,
                      Spawner:BatchSpawnableLite<CompressionThreadResult<Alloc>,
                                                 UnionHasher<Alloc>,
                                                 Alloc,
-                                                (SliceW, BrotliEncoderParams)>> (
+                                                CompressionThreadInput<SliceW>>,
+                     MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                              &mut [interface::StaticCommand],
+                                              InputPair, &mut Alloc)> (
   params:&BrotliEncoderParams,
   owned_input: &mut Owned<SliceW>,
   output: &mut [u8],
   alloc_per_thread:&mut [SendAlloc<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, Spawner::JoinHandle>],
   thread_spawner: &mut Spawner,
+  custom_dictionary_len: usize,
+  metablock_callback: &mut MetablockCallback,
 ) -> Result<usize, BrotliEncoderThreadError> where <Alloc as Allocator<u8>>::AllocatedMemory: Send, <Alloc as Allocator<u16>>::AllocatedMemory: Send, <Alloc as Allocator<u32>>::AllocatedMemory: Send{
   let num_threads = alloc_per_thread.len();
   let actually_owned_mem = mem::replace(owned_input, Owned(InternalOwned::Borrowed));
-  let mut owned_input_pair = Owned::new((actually_owned_mem.unwrap(), params.clone()));
+  let input = actually_owned_mem.unwrap();
+  assert!(custom_dictionary_len <= input.len());
+  let mut owned_input_pair = Owned::new(CompressionThreadInput::new(input, params, custom_dictionary_len, num_threads));
+  let data_len = owned_input_pair.view().input.len() - custom_dictionary_len;
+  // with more chunks than threads every chunk is a work item, and a thread takes the next one as soon as
+  // it is done with its last, so the work of other compressions sharing a pool interleaves with ours
+  let num_items = owned_input_pair.view().num_items();
+  let windowed = num_items > num_threads;
+  let mut num_spawned = num_threads;
   // start thread spawner
   let mut spawner_and_input = thread_spawner.make_spawner(&mut owned_input_pair);
-  if num_threads > 1 {
+  if num_threads > 1 || windowed {
     // spawn first thread without "custom dictionary" while we compute the custom dictionary for other work items
-      thread_spawner.spawn(&mut spawner_and_input, &mut alloc_per_thread[0], 0, num_threads, compress_part);
+      thread_spawner.spawn(&mut spawner_and_input, &mut alloc_per_thread[0], 0, num_items, compress_part);
     }
     // populate all hashers at once, cloning them one by one
     let mut compression_last_thread_result;
-    if num_threads > 1 && params.favor_cpu_efficiency {
+    // the precomputed hashers are primed up to where each work item begins, which is only where its first chunk begins for equal chunks
+    let equal_chunks = if let UnionChunkingPolicy::Equal(_) = params.multi_chunking { true } else { false };
+    if windowed {
+      for thread_index in 1..num_threads {
+        thread_spawner.spawn(&mut spawner_and_input, &mut alloc_per_thread[thread_index], thread_index, num_items, compress_part);
+      }
+      compression_last_thread_result = Err(());
+    } else if num_threads > 1 && params.favor_cpu_efficiency && equal_chunks {
       let mut local_params = params.clone();
       SanitizeParams(&mut local_params);
       let mut hasher = UnionHasher::Uninit;
@@ -385,11 +898,14 @@ pub fn CompressMulti<Alloc:BrotliAlloc+Send+'static,
                   0,
                   0);
       for thread_index in 1..num_threads {
-        let res = spawner_and_input.view(|input_and_params:&(SliceW, BrotliEncoderParams)| -> () {
-          let range = get_range(thread_index - 1, num_threads, input_and_params.0.len());
+        let res = spawner_and_input.view(|shared:&CompressionThreadInput<SliceW>| -> () {
+          let mut range = get_data_range(thread_index - 1, num_threads, shared);
+          if thread_index == 1 {
+            range.start = 0; // the custom dictionary prefix is part of every dictionary
+          }
           let overlap = hasher.StoreLookahead().wrapping_sub(1usize);
           if range.end - range.start > overlap {
-            hasher.BulkStoreRange(input_and_params.0.slice(),
+            hasher.BulkStoreRange(shared.input.slice(),
                                   !(0usize),
                                   if range.start > overlap {range.start - overlap} else {0}, range.end - overlap);
           }
@@ -406,11 +922,11 @@ pub fn CompressMulti<Alloc:BrotliAlloc+Send+'static,
         }
       }
       let (alloc, _extra) = alloc_per_thread[num_threads -1].replace_with_default();
-      compression_last_thread_result = spawner_and_input.view(move |input_and_params:&(SliceW, BrotliEncoderParams)| -> CompressionThreadResult<Alloc> {
+      compression_last_thread_result = spawner_and_input.view(move |shared:&CompressionThreadInput<SliceW>| -> CompressionThreadResult<Alloc> {
         compress_part(hasher,
                       num_threads - 1,
                       num_threads,
-                      input_and_params,
+                      shared,
                       alloc,
         )
       });
@@ -421,61 +937,123 @@ pub fn CompressMulti<Alloc:BrotliAlloc+Send+'static,
         }
       }
       let (alloc, _extra) = alloc_per_thread[num_threads - 1].replace_with_default();
-      compression_last_thread_result = spawner_and_input.view(move |input_and_params:&(SliceW, BrotliEncoderParams)| -> CompressionThreadResult<Alloc> {
+      compression_last_thread_result = spawner_and_input.view(move |shared:&CompressionThreadInput<SliceW>| -> CompressionThreadResult<Alloc> {
         compress_part(UnionHasher::Uninit,
                       num_threads - 1,
                       num_threads,
-                      input_and_params,
+                      shared,
                       alloc,
         )
       });
     }
     let mut compression_result = Err(BrotliEncoderThreadError::InsufficientOutputSpace);
+    let mut failure = None;
     let mut out_file_size = 0usize;
     let mut bro_cat_li = BroCatli::new();
-    for (index, thread) in alloc_per_thread.iter_mut().enumerate() {
-      let mut cur_result = if index + 1 == num_threads {
+    for index in 0..num_items {
+      if index == num_spawned {
+        break; // a failure stopped us handing out the rest
+      }
+      let slot = index % num_threads;
+      let mut cur_result = if !windowed && index + 1 == num_items {
         match mem::replace(&mut compression_last_thread_result, Err(())){
           Ok(result) => result,
           Err(_err) => return Err(BrotliEncoderThreadError::OtherThreadPanic),
         }
       } else {
-        match mem::replace(&mut thread.0, InternalSendAlloc::SpawningOrJoining(PhantomData::default())) {
+        match mem::replace(&mut alloc_per_thread[slot].0, InternalSendAlloc::SpawningOrJoining(PhantomData::default())) {
           InternalSendAlloc::A(_, _) | InternalSendAlloc::SpawningOrJoining(_) => panic!("Thread not properly spawned"),
           InternalSendAlloc::Join(join) => match join.join() {
             Ok(result) => result,
             Err(err) => {
-              return Err(err);
+              // keep joining the rest so none is left running on our input, then report this one
+              if let None = failure {
+                let range = match spawner_and_input.view(|shared:&CompressionThreadInput<SliceW>| item_range(shared, index, num_items)) {
+                  Ok(range) => range,
+                  Err(_) => get_range(index, num_items, data_len),
+                };
+                failure = Some(match err {
+                  BrotliEncoderThreadError::ThreadExecError(e) => BrotliEncoderThreadError::WorkItemPanic(index, range, e),
+                  other => other,
+                });
+              }
+              continue;
             }
           }
         }
       };
+      if let Some(_) = failure {
+        if let Ok(compressed_out) = mem::replace(&mut cur_result.compressed, Err(BrotliEncoderThreadError::OtherThreadPanic)) {
+          <Alloc as Allocator<u8>>::free_cell(&mut cur_result.alloc, compressed_out.data_backing);
+        }
+        <Alloc as Allocator<u8>>::free_cell(&mut cur_result.alloc, mem::replace(&mut cur_result.metablock_log.data_backing,
+                                                                               <Alloc as Allocator<u8>>::AllocatedMemory::default()));
+        alloc_per_thread[slot].0 = InternalSendAlloc::A(cur_result.alloc, UnionHasher::Uninit);
+        continue;
+      }
+      if cur_result.metablock_log.data_size != 0 {
+        let log = &mut cur_result.metablock_log;
+        let alloc = &mut cur_result.alloc;
+        let res = spawner_and_input.view(|shared:&CompressionThreadInput<SliceW>| {
+          let data = &shared.input.slice()[shared.dictionary_prefix..];
+          let first_start = item_range(shared, index, num_items).start;
+          replay_metablock_log(log, &data[first_start..], alloc, metablock_callback)
+        });
+        match res {
+          Ok(Ok(())) => {},
+          Ok(Err(())) => {
+            // reported once the rest are joined, so that none is left running on our input
+            failure = Some(BrotliEncoderThreadError::MetablockLogError(index));
+          },
+          Err(_e) => return Err(BrotliEncoderThreadError::OtherThreadPanic),
+        }
+      }
+      <Alloc as Allocator<u8>>::free_cell(&mut cur_result.alloc, mem::replace(&mut cur_result.metablock_log.data_backing,
+                                                                             <Alloc as Allocator<u8>>::AllocatedMemory::default()));
       match cur_result.compressed {
         Ok(compressed_out) => {
-          bro_cat_li.new_brotli_file();
-          let mut in_offset = 0usize;
-          let cat_result = bro_cat_li.stream(&compressed_out.data_backing.slice()[..compressed_out.data_size],
-                                             &mut in_offset,
-                                             output,
-                                             &mut out_file_size);
-          match cat_result {
-            BroCatliResult::Success | BroCatliResult::NeedsMoreInput  => {
-              compression_result = Ok(out_file_size);
-            },
-            BroCatliResult::NeedsMoreOutput => {
-              compression_result = Err(BrotliEncoderThreadError::InsufficientOutputSpace); // not enough space
-            },
-            err => {
-              compression_result = Err(BrotliEncoderThreadError::ConcatenationError(err)); // misc error
-            },
+          let chunks = &compressed_out.data_backing.slice()[..compressed_out.data_size];
+          let mut chunk_offset = 0usize;
+          while chunk_offset < chunks.len() {
+            let chunk_size = read_u64(chunks, &mut chunk_offset) as usize;
+            bro_cat_li.new_brotli_file();
+            let mut in_offset = 0usize;
+            let cat_result = bro_cat_li.stream(&chunks[chunk_offset..chunk_offset + chunk_size],
+                                               &mut in_offset,
+                                               output,
+                                               &mut out_file_size);
+            chunk_offset += chunk_size;
+            match cat_result {
+              BroCatliResult::Success | BroCatliResult::NeedsMoreInput  => {
+                compression_result = Ok(out_file_size);
+              },
+              BroCatliResult::NeedsMoreOutput => {
+                failure = Some(BrotliEncoderThreadError::InsufficientOutputSpace); // not enough space
+                break;
+              },
+              err => {
+                failure = Some(BrotliEncoderThreadError::ConcatenationError(err)); // misc error
+                break;
+              },
+            }
           }
           <Alloc as Allocator<u8>>::free_cell(&mut cur_result.alloc, compressed_out.data_backing);
         }
         Err(e) => {
-          compression_result = Err(e);
+          failure = Some(e);
         }
       }
-      thread.0 = InternalSendAlloc::A(cur_result.alloc, UnionHasher::Uninit);
+      alloc_per_thread[slot].0 = InternalSendAlloc::A(cur_result.alloc, UnionHasher::Uninit);
+      if windowed && failure.is_none() && num_spawned < num_items {
+        thread_spawner.spawn(&mut spawner_and_input, &mut alloc_per_thread[slot], num_spawned, num_items, compress_part);
+        num_spawned += 1;
+      }
+    }
+    if let Some(e) = failure {
+      if let Ok(retrieved_owned_input) = spawner_and_input.unwrap() {
+        *owned_input = Owned::new(retrieved_owned_input.input);
+      }
+      return Err(e);
     }
     if let Err(e) = compression_result {
       return Err(e);
@@ -485,7 +1063,7 @@ pub fn CompressMulti<Alloc:BrotliAlloc+Send+'static,
       err => compression_result = Err(BrotliEncoderThreadError::ConcatenationFinalizationError(err)),
     }
     if let Ok(retrieved_owned_input) = spawner_and_input.unwrap() {
-      *owned_input = Owned::new(retrieved_owned_input.0); // return the input to its rightful owner before returning
+      *owned_input = Owned::new(retrieved_owned_input.input); // return the input to its rightful owner before returning
     } else {
       if let Ok(_) = compression_result {
         compression_result = Err(BrotliEncoderThreadError::OtherThreadPanic);
@@ -494,3 +1072,25 @@ pub fn CompressMulti<Alloc:BrotliAlloc+Send+'static,
     compression_result
 }
 
+#[cfg(test)]
+mod test {
+  use super::{LogReader, read_command, IR_COPY, IR_PREDICTION_MODE};
+  use enc::interface;
+
+  #[test]
+  fn test_read_command_reports_damaged_logs() {
+    let copy = [IR_COPY, 7, 0, 0, 0, 3, 0, 0, 0];
+    let mut reader = LogReader{data: &copy[..], pos: 0, truncated: false};
+    match read_command(&mut reader) {
+      Ok(interface::Command::Copy(copy)) => assert_eq!((copy.distance, copy.num_bytes), (7, 3)),
+      _ => panic!("a whole copy command reads back"),
+    }
+    let mut reader = LogReader{data: &copy[..6], pos: 0, truncated: false};
+    assert!(read_command(&mut reader).is_err());
+    let mut reader = LogReader{data: &[IR_PREDICTION_MODE + 1], pos: 0, truncated: false};
+    assert!(read_command(&mut reader).is_err());
+    let mut reader = LogReader{data: &[IR_PREDICTION_MODE, 0, 0], pos: 0, truncated: false};
+    assert!(read_command(&mut reader).is_err());
+    assert_eq!(reader.pos, 3);
+  }
+}
@@ -1,6 +1,9 @@
 #![cfg(feature="std")]
 use core::mem;
 use std;
+use std::collections::VecDeque;
+use std::vec::Vec;
+use std::panic;
 
 use std::sync:: {
   Arc,
@@ -10,6 +13,9 @@ use std::sync:: {
 use alloc::{SliceWrapper, Allocator};
 use enc::BrotliAlloc;
 use enc::BrotliEncoderParams;
+use enc::BrotliEncoderMaxCompressedSizeMulti;
+use enc::interface;
+use enc::input_pair::{InputPair, InputReferenceMut};
 use enc::backward_references::UnionHasher;
 use enc::threading::{
   CompressMulti,
@@ -18,11 +24,14 @@ use enc::threading::{
   BatchSpawnableLite,
   Joinable,
   Owned,
+  OwnedRetriever,
   CompressionThreadResult,
+  CompressionThreadInput,
   InternalOwned,
   BrotliEncoderThreadError,
+  LowLevelThreadError,
+  PoisonedThreadError,
 };
-use enc::fixed_queue::{MAX_THREADS, FixedQueue};
 // in-place thread create
 
 use std::sync::RwLock;
@@ -31,7 +40,7 @@ use std::sync::RwLock;
 
 
 struct JobReply<T:Send+'static> {
-  result:T,
+  result:Result<T, LowLevelThreadError>,
   work_id: u64,
 }
 
@@ -48,31 +57,145 @@ struct JobRequest<ReturnValue:Send+'static,
   work_id: u64,
 }
 
+// how far a tenant's pass advances per job at weight 1: a tenant of weight w advances STRIDE / w,
+// so it is dispatched w times as often as a weight 1 tenant while both have jobs queued
+const STRIDE: u64 = 1 << 20;
+
+// the jobs of one caller: every compression submitted to the pool gets one of these, so a single huge
+// input only ever takes its fair share of the workers
+struct TenantQueue<ReturnValue:Send+'static,
+                   ExtraInput:Send+'static,
+                   Alloc:BrotliAlloc+Send+'static,
+                   U:Send+'static+Sync> {
+  id: u64,
+  jobs: VecDeque<JobRequest<ReturnValue, ExtraInput, Alloc, U>>,
+  weight: u32,
+  priority: i32,
+  pass: u64, // stride scheduling: the tenant with queued jobs and the lowest pass goes next
+  closed: bool, // its owner is gone: remove it once the last job is dispatched
+}
 
 struct WorkQueue<ReturnValue:Send+'static,
                  ExtraInput:Send+'static,
                  Alloc:BrotliAlloc+Send+'static,
                  U:Send+'static+Sync> {
-  jobs: FixedQueue<JobRequest<ReturnValue,ExtraInput, Alloc,U>>,
-  results: FixedQueue<JobReply<ReturnValue>>,
+  tenants: Vec<TenantQueue<ReturnValue, ExtraInput, Alloc, U>>,
+  results: Vec<JobReply<ReturnValue>>,
   shutdown: bool,
   immediate_shutdown: bool,
   num_in_progress: usize,
   cur_work_id: u64,
+  cur_tenant_id: u64,
+  global_pass: u64, // pass of the last dispatched job: a tenant that was idle restarts from here
+  num_workers: usize, // worker threads currently running
+  target_workers: usize, // what the pool was last sized to: surplus workers exit, missing ones are respawned
+  workers: Vec<std::thread::JoinHandle<()>>,
+  // compressions submitted to run in the background: each needs a thread of its own to run its last work
+  // item and collect the rest, so they queue for at most target_workers collector threads
+  submissions: VecDeque<Submission>,
+  num_collectors: usize,
+  num_idle_collectors: usize,
+  collectors: Vec<std::thread::JoinHandle<()>>,
 }
+
+type Submission = std::boxed::Box<dyn FnOnce() + Send + 'static>;
 impl <ReturnValue:Send+'static,
       ExtraInput:Send+'static,
       Alloc:BrotliAlloc+Send+'static,
       U:Send+'static+Sync> Default for WorkQueue<ReturnValue, ExtraInput, Alloc, U> {
   fn default() -> Self {
     WorkQueue {
-      jobs: FixedQueue::default(),
-      results: FixedQueue::default(),
+      tenants: Vec::new(),
+      results: Vec::new(),
       num_in_progress: 0,
       immediate_shutdown: false,
       shutdown:false,
       cur_work_id: 0,
+      cur_tenant_id: 0,
+      global_pass: 0,
+      num_workers: 0,
+      target_workers: 0,
+      workers: Vec::new(),
+      submissions: VecDeque::new(),
+      num_collectors: 0,
+      num_idle_collectors: 0,
+      collectors: Vec::new(),
+    }
+  }
+}
+
+impl <ReturnValue:Send+'static,
+      ExtraInput:Send+'static,
+      Alloc:BrotliAlloc+Send+'static,
+      U:Send+'static+Sync> WorkQueue<ReturnValue, ExtraInput, Alloc, U> {
+  fn add_tenant(&mut self, weight: u32, priority: i32) -> u64 {
+    let id = self.cur_tenant_id;
+    self.cur_tenant_id += 1;
+    self.tenants.push(TenantQueue{
+      id: id,
+      jobs: VecDeque::new(),
+      weight: core::cmp::max(weight, 1),
+      priority: priority,
+      pass: self.global_pass,
+      closed: false,
+    });
+    id
+  }
+  fn tenant_mut(&mut self, id: u64) -> Option<&mut TenantQueue<ReturnValue, ExtraInput, Alloc, U>> {
+    self.tenants.iter_mut().find(|tenant| tenant.id == id)
+  }
+  fn close_tenant(&mut self, id: u64) {
+    if let Some(tenant) = self.tenant_mut(id) {
+      tenant.closed = true;
     }
+    self.tenants.retain(|tenant| !(tenant.closed && tenant.jobs.is_empty()));
+  }
+  fn push_job(&mut self, tenant_id: u64, job: JobRequest<ReturnValue, ExtraInput, Alloc, U>) {
+    // workers outlive a graceful shutdown for as long as collectors may queue jobs
+    if (self.shutdown && self.num_collectors == 0) || self.immediate_shutdown || self.tenant_mut(tenant_id).is_none() {
+      // nothing will ever run it: fail its join rather than leave it waiting forever
+      self.results.push(JobReply{
+        result: Err(std::boxed::Box::new("worker pool is shut down")),
+        work_id: job.work_id,
+      });
+      return;
+    }
+    let global_pass = self.global_pass;
+    let tenant = self.tenant_mut(tenant_id).unwrap();
+    if tenant.jobs.is_empty() {
+      // no banking up credit while idle
+      tenant.pass = core::cmp::max(tenant.pass, global_pass);
+    }
+    tenant.jobs.push_back(job);
+  }
+  // the next job by priority, then by weighted round robin among the tenants of that priority
+  fn pop_job(&mut self) -> Option<JobRequest<ReturnValue, ExtraInput, Alloc, U>> {
+    let mut best: Option<usize> = None;
+    for (index, tenant) in self.tenants.iter().enumerate() {
+      if tenant.jobs.is_empty() {
+        continue;
+      }
+      best = match best {
+        Some(best_index) if (self.tenants[best_index].priority, core::cmp::Reverse(self.tenants[best_index].pass))
+                            >= (tenant.priority, core::cmp::Reverse(tenant.pass)) => Some(best_index),
+        _ => Some(index),
+      };
+    }
+    let index = match best {
+      Some(index) => index,
+      None => return None,
+    };
+    let ret;
+    {
+      let tenant = &mut self.tenants[index];
+      ret = tenant.jobs.pop_front();
+      self.global_pass = tenant.pass;
+      tenant.pass += STRIDE / u64::from(tenant.weight);
+    }
+    if self.tenants[index].closed && self.tenants[index].jobs.is_empty() {
+      self.tenants.remove(index);
+    }
+    ret
   }
 }
 
@@ -80,12 +203,48 @@ pub struct GuardedQueue<ReturnValue:Send+'static,
                         ExtraInput:Send+'static,
                       Alloc:BrotliAlloc+Send+'static,
                        U:Send+'static+Sync>(Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>);
+
+impl<ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+'static+Sync> Clone for GuardedQueue<ReturnValue, ExtraInput, Alloc, U> {
+  fn clone(&self) -> Self {
+    GuardedQueue(self.0.clone())
+  }
+}
+
 pub struct WorkerPool<ReturnValue:Send+'static,
                       ExtraInput:Send+'static,
                       Alloc:BrotliAlloc+Send+'static,
                       U:Send+'static+Sync> {
   queue: GuardedQueue<ReturnValue, ExtraInput, Alloc, U>,
-  join: [Option<std::thread::JoinHandle<()>>;MAX_THREADS],
+}
+
+// removes a worker from the count however it leaves do_work, even by panicking,
+// so the next resize notices the pool is short a thread and starts a replacement
+struct WorkerExit<ReturnValue:Send+'static,
+                  ExtraInput:Send+'static,
+                  Alloc:BrotliAlloc+Send+'static,
+                  U:Send+'static+Sync> {
+  queue: Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>,
+  counted: bool,
+}
+
+impl <ReturnValue:Send+'static,
+      ExtraInput:Send+'static,
+      Alloc:BrotliAlloc+Send+'static,
+      U:Send+'static+Sync> Drop for WorkerExit<ReturnValue, ExtraInput, Alloc, U> {
+  fn drop(&mut self) {
+    if !self.counted {
+      let &(ref lock, ref cvar) = &*self.queue;
+      let mut local_queue = match lock.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+      };
+      local_queue.num_workers -= 1;
+      cvar.notify_all();
+    }
+  }
 }
 
 impl <ReturnValue:Send+'static,
@@ -93,132 +252,299 @@ impl <ReturnValue:Send+'static,
       Alloc:BrotliAlloc+Send+'static,
       U:Send+'static+Sync> Drop for WorkerPool<ReturnValue, ExtraInput, Alloc, U> {
   fn drop(&mut self) {
+    let workers;
+    let collectors;
     {
       let &(ref lock, ref cvar) = &*self.queue.0;
       let mut local_queue = lock.lock().unwrap();
       local_queue.immediate_shutdown = true;
+      // jobs that will now never run still owe their joiners an answer
+      let mut abandoned = Vec::new();
+      for tenant in local_queue.tenants.iter_mut() {
+        for job in tenant.jobs.drain(..) {
+          abandoned.push(job.work_id);
+        }
+      }
+      for work_id in abandoned {
+        local_queue.results.push(JobReply{
+          result: Err(std::boxed::Box::new("worker pool was dropped")),
+          work_id: work_id,
+        });
+      }
+      workers = mem::replace(&mut local_queue.workers, Vec::new());
+      collectors = mem::replace(&mut local_queue.collectors, Vec::new());
       cvar.notify_all();
     }
-    for thread_handle in self.join.iter_mut() {
-      if let Some(th) = mem::replace(thread_handle, None) {
-        th.join().unwrap();
-      }
+    for th in workers.into_iter().chain(collectors) {
+      let _ = th.join();
     }
   }
 }
-impl <ReturnValue:Send+'static,
-      ExtraInput:Send+'static,
-      Alloc:BrotliAlloc+Send+'static,
-      U:Send+'static+Sync> WorkerPool<ReturnValue, ExtraInput, Alloc, U> {
-  fn do_work(queue:Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>) {
-    loop {
-      let ret;
-      { // need to drop possible job before the final lock is taken,
-        // so refcount of possible_job Arc is 0 by the time the job is delivered
-        // to the caller. We basically need a barrier (the lock) to happen
-        // after the destructor that decrefs possible_job
-        let possible_job;
-        {
-          let &(ref lock, ref cvar) = &*queue;
-          let mut local_queue = lock.lock().unwrap();
-          if local_queue.immediate_shutdown {
+
+fn do_work<ReturnValue:Send+'static,
+           ExtraInput:Send+'static,
+           Alloc:BrotliAlloc+Send+'static,
+           U:Send+'static+Sync>(queue:Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>) {
+  let mut exit = WorkerExit{queue: queue.clone(), counted: false};
+  loop {
+    let ret;
+    { // need to drop possible job before the final lock is taken,
+      // so refcount of possible_job Arc is 0 by the time the job is delivered
+      // to the caller. We basically need a barrier (the lock) to happen
+      // after the destructor that decrefs possible_job
+      let possible_job;
+      {
+        let &(ref lock, ref cvar) = &*queue;
+        let mut local_queue = lock.lock().unwrap();
+        if local_queue.immediate_shutdown {
+          break;
+        }
+        if local_queue.num_workers > local_queue.target_workers {
+          // the pool was shrunk: retire this worker, and count it out now so no other worker also retires for it
+          local_queue.num_workers -= 1;
+          exit.counted = true;
+          cvar.notify_all();
+          break;
+        }
+        possible_job = if let Some(res) = local_queue.pop_job() {
+          cvar.notify_all();
+          local_queue.num_in_progress += 1;
+          res
+        } else {
+          // a collector may still have jobs to queue
+          if local_queue.shutdown && local_queue.num_collectors == 0 {
             break;
-          }
-          possible_job = if let Some(res) = local_queue.jobs.pop() {
-            cvar.notify_all();
-            local_queue.num_in_progress += 1;
-            res
           } else {
-            if local_queue.shutdown{
-              break;
-            } else {
-              let _ = cvar.wait(local_queue); // unlock immediately, unfortunately
-              continue;
-            }
-          };
-        }
-        ret = if let Ok(job_data) = possible_job.data.read() {
-          JobReply{
-            result: (possible_job.func)(possible_job.extra_input,
-                                        possible_job.index,
-                                        possible_job.thread_size,
-                                        &*job_data,
-                                        possible_job.alloc),
-            work_id:possible_job.work_id,
+            mem::drop(cvar.wait(local_queue).unwrap()); // unlock immediately, unfortunately
+            continue;
           }
-        } else{
-          break; // poisoned lock
         };
       }
-      {
-        let &(ref lock, ref cvar) = &*queue;
-        let mut local_queue = lock.lock().unwrap();
-        local_queue.num_in_progress -= 1;
-        local_queue.results.push(ret).unwrap();
-        cvar.notify_all();
-      }
+      let JobRequest{func, extra_input, index, thread_size, data, alloc, work_id} = possible_job;
+      let result = match data.read() {
+        // a panicking job reports back through its join rather than taking the worker down with it
+        Ok(job_data) => panic::catch_unwind(panic::AssertUnwindSafe(|| {
+          func(extra_input, index, thread_size, &*job_data, alloc)
+        })),
+        Err(_) => Err(std::boxed::Box::new("poisoned input lock") as LowLevelThreadError),
+      };
+      ret = JobReply{
+        result: result,
+        work_id:work_id,
+      };
+    }
+    {
+      let &(ref lock, ref cvar) = &*queue;
+      let mut local_queue = lock.lock().unwrap();
+      local_queue.num_in_progress -= 1;
+      local_queue.results.push(ret);
+      cvar.notify_all();
     }
   }
-  fn _push_job(&mut self, job:JobRequest<ReturnValue, ExtraInput, Alloc, U>) {
-    let &(ref lock, ref cvar) = &*self.queue.0;
-    let mut local_queue = lock.lock().unwrap();
-      loop {
-        if local_queue.jobs.size() + local_queue.num_in_progress + local_queue.results.size() < MAX_THREADS {
-          local_queue.jobs.push(job).unwrap();
-          cvar.notify_all();
-          break;
-        }
-        local_queue = cvar.wait(local_queue).unwrap();
-      }    
-    
+}
+
+// runs submitted compressions until there are none left and the pool is shutting down; jobs queued
+// after an immediate shutdown fail straight away, so the remaining ones still finish quickly
+fn do_collect<ReturnValue:Send+'static,
+              ExtraInput:Send+'static,
+              Alloc:BrotliAlloc+Send+'static,
+              U:Send+'static+Sync>(queue:Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>) {
+  let &(ref lock, ref cvar) = &*queue;
+  let mut local_queue = lock.lock().unwrap();
+  loop {
+    if let Some(submission) = local_queue.submissions.pop_front() {
+      mem::drop(local_queue);
+      submission(); // reports its own panics
+      local_queue = lock.lock().unwrap();
+    } else if local_queue.shutdown || local_queue.immediate_shutdown {
+      break;
+    } else {
+      local_queue.num_idle_collectors += 1;
+      local_queue = cvar.wait(local_queue).unwrap();
+      local_queue.num_idle_collectors -= 1;
+    }
   }
-  fn _try_push_job(&mut self, job:JobRequest<ReturnValue, ExtraInput, Alloc, U>)->Result<(),JobRequest<ReturnValue, ExtraInput, Alloc, U>> {
-    let &(ref lock, ref cvar) = &*self.queue.0;
-    let mut local_queue = lock.lock().unwrap();
-    if local_queue.jobs.size() + local_queue.num_in_progress + local_queue.results.size() < MAX_THREADS {
-      local_queue.jobs.push(job).unwrap();
-      cvar.notify_all();
-      Ok(())
+  local_queue.num_collectors -= 1;
+  cvar.notify_all();
+}
+
+// queues a submission for a collector, starting one if none is idle and there are fewer than the pool
+// has workers; gives it back if the pool is shutting down and it would never run
+fn submit<ReturnValue:Send+'static,
+          ExtraInput:Send+'static,
+          Alloc:BrotliAlloc+Send+'static,
+          U:Send+'static+Sync>(queue:&Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>,
+                               submission: Submission) -> Result<(), Submission> {
+  let &(ref lock, ref cvar) = &**queue;
+  let mut local_queue = lock.lock().unwrap();
+  if local_queue.shutdown || local_queue.immediate_shutdown {
+    return Err(submission);
+  }
+  local_queue.submissions.push_back(submission);
+  let mut i = 0;
+  while i < local_queue.collectors.len() {
+    if local_queue.collectors[i].is_finished() {
+      let _ = local_queue.collectors.swap_remove(i).join();
+    } else {
+      i += 1;
+    }
+  }
+  if local_queue.submissions.len() > local_queue.num_idle_collectors
+    && local_queue.num_collectors < core::cmp::max(local_queue.target_workers, 1) {
+    local_queue.num_collectors += 1;
+    let collector_queue = queue.clone();
+    local_queue.collectors.push(std::thread::spawn(move || do_collect(collector_queue)));
+  }
+  cvar.notify_all();
+  Ok(())
+}
+
+// starts workers until target_workers are running, replacing any that retired or died,
+// and reaps the handles of the ones that are gone
+fn ensure_workers<ReturnValue:Send+'static,
+                  ExtraInput:Send+'static,
+                  Alloc:BrotliAlloc+Send+'static,
+                  U:Send+'static+Sync>(queue:&Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>) {
+  let &(ref lock, ref cvar) = &**queue;
+  let mut local_queue = lock.lock().unwrap();
+  if local_queue.immediate_shutdown {
+    return;
+  }
+  let mut i = 0;
+  while i < local_queue.workers.len() {
+    if local_queue.workers[i].is_finished() {
+      let _ = local_queue.workers.swap_remove(i).join();
     } else {
-      Err(job)
+      i += 1;
     }
   }
-  fn start(queue:Arc<(Mutex<WorkQueue<ReturnValue, ExtraInput, Alloc, U>>, Condvar)>) -> std::thread::JoinHandle<()> {
-    std::thread::spawn(move || Self::do_work(queue))
+  while local_queue.num_workers < local_queue.target_workers {
+    local_queue.num_workers += 1;
+    let worker_queue = queue.clone();
+    local_queue.workers.push(std::thread::spawn(move || do_work(worker_queue)));
   }
+  cvar.notify_all();
+}
+
+impl <ReturnValue:Send+'static,
+      ExtraInput:Send+'static,
+      Alloc:BrotliAlloc+Send+'static,
+      U:Send+'static+Sync> WorkerPool<ReturnValue, ExtraInput, Alloc, U> {
   pub fn new(num_threads: usize) -> Self {
     let queue = Arc::new((Mutex::new(WorkQueue::default()), Condvar::new()));
-    WorkerPool{
-      queue: GuardedQueue(queue.clone()),
-      join:[
-        Some(Self::start(queue.clone())),
-        if 1 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 2 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 3 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 4 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 5 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 6 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 7 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 8 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 9 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 10 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 11 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 12 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 13 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 14 < num_threads { Some(Self::start(queue.clone())) } else {None},
-        if 15 < num_threads { Some(Self::start(queue.clone())) } else {None},
-      ],
+    let mut ret = WorkerPool{
+      queue: GuardedQueue(queue),
+    };
+    ret.resize(num_threads);
+    ret
+  }
+  /// grows or shrinks the pool to num_threads (at least 1). Surplus workers exit once they finish their
+  /// current job, and queued jobs are unaffected
+  pub fn resize(&mut self, num_threads: usize) {
+    {
+      let &(ref lock, _) = &*self.queue.0;
+      lock.lock().unwrap().target_workers = core::cmp::max(num_threads, 1);
+    }
+    ensure_workers(&self.queue.0);
+  }
+  /// the number of worker threads the pool is sized to
+  pub fn num_threads(&self) -> usize {
+    let &(ref lock, _) = &*self.queue.0;
+    let local_queue = lock.lock().unwrap();
+    local_queue.target_workers
+  }
+  /// a separate job queue on this pool for one caller, which may move to another thread. While several
+  /// tenants have jobs queued, the highest priority ones go first, and those of equal priority share the
+  /// workers in proportion to their weight. Spawning through the pool itself uses a fresh tenant of
+  /// weight 1 and priority 0 for each compression. Compressions take turns one chunk at a time, so on a
+  /// shared pool long inputs are best cut by a chunking policy with bounded chunks
+  pub fn new_tenant(&self, weight: u32, priority: i32) -> WorkerPoolTenant<ReturnValue, ExtraInput, Alloc, U> {
+    let &(ref lock, _) = &*self.queue.0;
+    let id = lock.lock().unwrap().add_tenant(weight, priority);
+    WorkerPoolTenant{
+      lease: TenantLease{
+        queue: self.queue.clone(),
+        id: id,
+      },
+    }
+  }
+  /// finishes every job and compression already queued, then stops the workers. Outstanding join handles
+  /// remain valid
+  pub fn shutdown(self) {
+    let workers;
+    let collectors;
+    {
+      let &(ref lock, ref cvar) = &*self.queue.0;
+      let mut local_queue = lock.lock().unwrap();
+      local_queue.shutdown = true;
+      workers = mem::replace(&mut local_queue.workers, Vec::new());
+      collectors = mem::replace(&mut local_queue.collectors, Vec::new());
+      cvar.notify_all();
+    }
+    for th in collectors.into_iter().chain(workers) {
+      let _ = th.join();
+    }
+  }
+}
+
+// keeps a tenant's queue registered with the pool, and closes it when dropped
+struct TenantLease<ReturnValue:Send+'static,
+                   ExtraInput:Send+'static,
+                   Alloc:BrotliAlloc+Send+'static,
+                   U:Send+'static+Sync> {
+  queue: GuardedQueue<ReturnValue, ExtraInput, Alloc, U>,
+  id: u64,
+}
+
+impl<ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+'static+Sync> Drop for TenantLease<ReturnValue, ExtraInput, Alloc, U> {
+  fn drop(&mut self) {
+    let &(ref lock, _) = &*self.queue.0;
+    if let Ok(mut local_queue) = lock.lock() {
+      local_queue.close_tenant(self.id);
     }
   }
 }
 
+pub struct WorkerPoolTenant<ReturnValue:Send+'static,
+                            ExtraInput:Send+'static,
+                            Alloc:BrotliAlloc+Send+'static,
+                            U:Send+'static+Sync> {
+  lease: TenantLease<ReturnValue, ExtraInput, Alloc, U>,
+}
+
+// the input shared by the jobs of one compression, along with the tenant queue they go to
+pub struct WorkerPoolInput<ReturnValue:Send+'static,
+                           ExtraInput:Send+'static,
+                           Alloc:BrotliAlloc+Send+'static,
+                           U:Send+'static+Sync> {
+  input: Arc<RwLock<U>>,
+  tenant: u64,
+  lease: Option<TenantLease<ReturnValue, ExtraInput, Alloc, U>>, // set when the tenant lasts just this compression
+}
+
+impl<ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+'static+Sync> OwnedRetriever<U> for WorkerPoolInput<ReturnValue, ExtraInput, Alloc, U> {
+  fn view<T, F:FnOnce(&U)-> T>(&self, f:F) -> Result<T, PoisonedThreadError> {
+    self.input.view(f)
+  }
+  fn unwrap(self) -> Result<U, PoisonedThreadError> {
+    let WorkerPoolInput{input, tenant:_tenant, lease} = self;
+    mem::drop(lease);
+    input.unwrap()
+  }
+}
 
 pub fn new_work_pool<Alloc:BrotliAlloc+Send+'static, SliceW: SliceWrapper<u8>+Send+'static+Sync>(
   num_threads:usize,
 ) -> WorkerPool<CompressionThreadResult<Alloc>,
                 UnionHasher<Alloc>,
                 Alloc,
-                (SliceW, BrotliEncoderParams)>
+                CompressionThreadInput<SliceW>>
   where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static,
         <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
         <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
@@ -232,6 +558,21 @@ pub struct WorkerJoinable<ReturnValue:Send+'static,
   queue: GuardedQueue<ReturnValue, ExtraInput, Alloc, U>,
   work_id: u64,
 }
+
+fn take_reply<ReturnValue:Send+'static,
+              ExtraInput:Send+'static,
+              Alloc:BrotliAlloc+Send+'static,
+              U:Send+'static+Sync>(local_queue: &mut WorkQueue<ReturnValue, ExtraInput, Alloc, U>,
+                                   work_id: u64) -> Option<Result<ReturnValue, BrotliEncoderThreadError>> {
+  match local_queue.results.iter().position(|item| item.work_id == work_id) {
+    Some(index) => Some(match local_queue.results.swap_remove(index).result {
+      Ok(result) => Ok(result),
+      Err(err) => Err(BrotliEncoderThreadError::ThreadExecError(err)),
+    }),
+    None => None,
+  }
+}
+
 impl<ReturnValue:Send+'static,
      ExtraInput:Send+'static,
      Alloc:BrotliAlloc+Send+'static,
@@ -240,14 +581,42 @@ impl<ReturnValue:Send+'static,
     let &(ref lock, ref cvar) = &*self.queue.0;
     let mut local_queue = lock.lock().unwrap();
     loop {
-      match local_queue.results.remove(|data:&Option<JobReply<ReturnValue>>|if let Some(ref item) = *data { item.work_id == self.work_id} else {false}) {
-        Some(matched) => return Ok(matched.result),
+      match take_reply(&mut local_queue, self.work_id) {
+        Some(result) => return result,
         None => local_queue = cvar.wait(local_queue).unwrap(),
       };
     }
   }
 }
 
+fn spawn_on_tenant<ReturnValue:Send+'static,
+                   ExtraInput:Send+'static,
+                   Alloc:BrotliAlloc+Send+'static,
+                   U:Send+'static+Sync>(
+  queue: &GuardedQueue<ReturnValue, ExtraInput, Alloc, U>,
+  locked_input: &mut WorkerPoolInput<ReturnValue, ExtraInput, Alloc, U>,
+  work:&mut SendAlloc<ReturnValue, ExtraInput, Alloc, WorkerJoinable<ReturnValue, ExtraInput, Alloc, U>>,
+  index: usize,
+  num_threads: usize,
+  f: fn(ExtraInput, usize, usize, &U, Alloc) -> ReturnValue,
+) where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static {
+  let &(ref lock, ref cvar) = &*queue.0;
+  let mut local_queue = lock.lock().unwrap();
+  let work_id = local_queue.cur_work_id;
+  local_queue.cur_work_id += 1;
+  let (local_alloc, local_extra) = work.replace_with_default();
+  local_queue.push_job(locked_input.tenant, JobRequest{
+    func:f,
+    extra_input:local_extra,
+    index: index,
+    thread_size: num_threads,
+    data: locked_input.input.clone(),
+    alloc: local_alloc,
+    work_id:work_id,
+  });
+  *work = SendAlloc(InternalSendAlloc::Join(WorkerJoinable{queue:queue.clone(), work_id:work_id}));
+  cvar.notify_all();
+}
 
 impl<ReturnValue:Send+'static,
      ExtraInput:Send+'static,
@@ -256,14 +625,19 @@ impl<ReturnValue:Send+'static,
   where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static,
         <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
         <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
-  type FinalJoinHandle = Arc<RwLock<U>>;
+  type FinalJoinHandle = WorkerPoolInput<ReturnValue, ExtraInput, Alloc, U>;
   type JoinHandle = WorkerJoinable<ReturnValue, ExtraInput, Alloc, U>;
 
   fn make_spawner(
     &mut self,
     input: &mut Owned<U>,
   ) -> Self::FinalJoinHandle {
-    std::sync::Arc::<RwLock<U>>::new(RwLock::new(mem::replace(input, Owned(InternalOwned::Borrowed)).unwrap()))
+    let lease = self.new_tenant(1, 0).lease;
+    WorkerPoolInput{
+      input: std::sync::Arc::<RwLock<U>>::new(RwLock::new(mem::replace(input, Owned(InternalOwned::Borrowed)).unwrap())),
+      tenant: lease.id,
+      lease: Some(lease),
+    }
   }
   fn spawn(
     &mut self,
@@ -273,49 +647,180 @@ impl<ReturnValue:Send+'static,
     num_threads: usize,
     f: fn(ExtraInput, usize, usize, &U, Alloc) -> ReturnValue,
   ) {
-    assert!(num_threads <= MAX_THREADS);
-    let &(ref lock, ref cvar) = &*self.queue.0;
-    let mut local_queue = lock.lock().unwrap();
+    spawn_on_tenant(&self.queue, locked_input, work, index, num_threads, f)
+  }
+}
+
+impl<ReturnValue:Send+'static,
+     ExtraInput:Send+'static,
+     Alloc:BrotliAlloc+Send+'static,
+     U:Send+'static+Sync> BatchSpawnableLite<ReturnValue, ExtraInput, Alloc, U> for WorkerPoolTenant<ReturnValue, ExtraInput, Alloc, U>
+  where <Alloc as Allocator<u8>>::AllocatedMemory:Send+'static,
+        <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
+        <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
+  type FinalJoinHandle = WorkerPoolInput<ReturnValue, ExtraInput, Alloc, U>;
+  type JoinHandle = WorkerJoinable<ReturnValue, ExtraInput, Alloc, U>;
+
+  fn make_spawner(
+    &mut self,
+    input: &mut Owned<U>,
+  ) -> Self::FinalJoinHandle {
+    WorkerPoolInput{
+      input: std::sync::Arc::<RwLock<U>>::new(RwLock::new(mem::replace(input, Owned(InternalOwned::Borrowed)).unwrap())),
+      tenant: self.lease.id,
+      lease: None,
+    }
+  }
+  fn spawn(
+    &mut self,
+    locked_input: &mut Self::FinalJoinHandle,
+    work:&mut SendAlloc<ReturnValue, ExtraInput, Alloc, Self::JoinHandle>,
+    index: usize,
+    num_threads: usize,
+    f: fn(ExtraInput, usize, usize, &U, Alloc) -> ReturnValue,
+  ) {
+    spawn_on_tenant(&self.lease.queue, locked_input, work, index, num_threads, f)
+  }
+}
+
+pub type CompressionTenant<Alloc, SliceW> = WorkerPoolTenant<CompressionThreadResult<Alloc>,
+                                                             UnionHasher<Alloc>,
+                                                             Alloc,
+                                                             CompressionThreadInput<SliceW>>;
+
+pub struct FinishedCompression<SliceW> {
+  /// the input handed back, unless the compression itself panicked
+  pub input: Option<SliceW>,
+  pub compressed: Result<Vec<u8>, BrotliEncoderThreadError>,
+}
+
+/// a compression running in the background on a worker pool: see submit_compress_worker_pool
+pub struct PendingCompression<SliceW:Send+'static> {
+  finished: Arc<(Mutex<Option<FinishedCompression<SliceW>>>, Condvar)>,
+}
+
+impl<SliceW:Send+'static> PendingCompression<SliceW> {
+  fn done(finished: FinishedCompression<SliceW>) -> Self {
+    PendingCompression{
+      finished: Arc::new((Mutex::new(Some(finished)), Condvar::new())),
+    }
+  }
+  pub fn is_done(&self) -> bool {
+    let &(ref lock, _) = &*self.finished;
+    lock.lock().unwrap().is_some()
+  }
+  /// the finished compression, or the pending one back if it is still running
+  pub fn try_wait(self) -> Result<FinishedCompression<SliceW>, Self> {
+    let finished = {
+      let &(ref lock, _) = &*self.finished;
+      let mut slot = lock.lock().unwrap();
+      slot.take()
+    };
+    match finished {
+      Some(finished) => Ok(finished),
+      None => Err(self),
+    }
+  }
+  pub fn wait(self) -> FinishedCompression<SliceW> {
+    let &(ref lock, ref cvar) = &*self.finished;
+    let mut slot = lock.lock().unwrap();
     loop {
-      if local_queue.jobs.size() + local_queue.num_in_progress + local_queue.results.size() <= MAX_THREADS {
-        let work_id = local_queue.cur_work_id;
-        local_queue.cur_work_id += 1;
-        let (local_alloc, local_extra) = work.replace_with_default();
-        local_queue.jobs.push(JobRequest{
-          func:f,
-          extra_input:local_extra,
-          index: index,
-          thread_size: num_threads,
-          data: locked_input.clone(),
-          alloc: local_alloc,
-          work_id:work_id,
-        }).unwrap();
-        *work = SendAlloc(InternalSendAlloc::Join(WorkerJoinable{queue:GuardedQueue(self.queue.0.clone()), work_id:work_id}));
-        cvar.notify_all();
-        break;
-      } else{
-        local_queue = cvar.wait(local_queue).unwrap(); // hope room frees up
+      match slot.take() {
+        Some(finished) => return finished,
+        None => slot = cvar.wait(slot).unwrap(),
       }
     }
   }
 }
 
+/// starts compressing input on the tenant's queue without waiting for it: there is one work item per
+/// allocator in alloc_per_thread. The last of them runs on a collector thread of the pool, which also
+/// collects the results; the pool has at most as many collectors as workers, and further submissions
+/// wait their turn for one
+pub fn submit_compress_worker_pool<Alloc:BrotliAlloc+Send+'static,
+                                   SliceW: SliceWrapper<u8>+Send+'static+Sync> (
+  params:&BrotliEncoderParams,
+  input: SliceW,
+  alloc_per_thread: Vec<Alloc>,
+  mut tenant: CompressionTenant<Alloc, SliceW>,
+) -> PendingCompression<SliceW>
+  where <Alloc as Allocator<u8>>::AllocatedMemory: Send,
+        <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
+        <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
+  let params = params.clone();
+  let queue = tenant.lease.queue.0.clone();
+  let finished = Arc::new((Mutex::new(None), Condvar::new()));
+  let slot = finished.clone();
+  // the input comes back through here should the pool turn the submission down
+  let returned_input = Arc::new(Mutex::new(None));
+  let submitted_input = returned_input.clone();
+  *returned_input.lock().unwrap() = Some(input);
+  let submission: Submission = std::boxed::Box::new(move || {
+    let input = submitted_input.lock().unwrap().take().unwrap();
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(move || {
+      let num_threads = core::cmp::max(alloc_per_thread.len(), 1);
+      let mut output = vec![0u8; BrotliEncoderMaxCompressedSizeMulti(input.len(), &params.multi_chunking, num_threads)];
+      let mut send_allocs: Vec<_> = alloc_per_thread.into_iter().map(|alloc| SendAlloc::new(alloc, UnionHasher::Uninit)).collect();
+      let mut owned_input = Owned::new(input);
+      let compressed = CompressMulti(&params, &mut owned_input, &mut output[..], &mut send_allocs[..], &mut tenant, 0, &mut |_, _, _, _| ()).map(|size| {
+        output.truncate(size);
+        output
+      });
+      FinishedCompression{
+        input: match owned_input.0 {
+          InternalOwned::Item(input) => Some(input),
+          InternalOwned::Borrowed => None,
+        },
+        compressed: compressed,
+      }
+    }));
+    let &(ref lock, ref cvar) = &*slot;
+    let mut slot = match lock.lock() {
+      Ok(guard) => guard,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+    *slot = Some(match result {
+      Ok(finished) => finished,
+      Err(err) => FinishedCompression{
+        input: None,
+        compressed: Err(BrotliEncoderThreadError::ThreadExecError(err)),
+      },
+    });
+    cvar.notify_all();
+  });
+  match submit(&queue, submission) {
+    Ok(()) => PendingCompression{
+      finished: finished,
+    },
+    Err(_submission) => PendingCompression::done(FinishedCompression{
+      input: returned_input.lock().unwrap().take(),
+      compressed: Err(BrotliEncoderThreadError::ThreadExecError(std::boxed::Box::new("worker pool is shut down"))),
+    }),
+  }
+}
+
 
+// see CompressMulti: the first custom_dictionary_len bytes of owned_input are the dictionary (0 for none)
 pub fn compress_worker_pool<Alloc:BrotliAlloc+Send+'static,
-                      SliceW: SliceWrapper<u8>+Send+'static+Sync> (
+                            SliceW: SliceWrapper<u8>+Send+'static+Sync,
+                            MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                     &mut [interface::StaticCommand],
+                                                     InputPair, &mut Alloc)> (
   params:&BrotliEncoderParams,
   owned_input: &mut Owned<SliceW>,
   output: &mut [u8],
   alloc_per_thread:&mut [SendAlloc<CompressionThreadResult<Alloc>,
                                    UnionHasher<Alloc>,
                                    Alloc,
-                                   <WorkerPool<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, (SliceW, BrotliEncoderParams)> as BatchSpawnableLite<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, (SliceW, BrotliEncoderParams)>>::JoinHandle>],
-  work_pool: &mut WorkerPool<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, (SliceW, BrotliEncoderParams)>,
+                                   <WorkerPool<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, CompressionThreadInput<SliceW>> as BatchSpawnableLite<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, CompressionThreadInput<SliceW>>>::JoinHandle>],
+  work_pool: &mut WorkerPool<CompressionThreadResult<Alloc>, UnionHasher<Alloc>, Alloc, CompressionThreadInput<SliceW>>,
+  custom_dictionary_len: usize,
+  metablock_callback: &mut MetablockCallback,
 ) -> Result<usize, BrotliEncoderThreadError>
   where <Alloc as Allocator<u8>>::AllocatedMemory: Send,
         <Alloc as Allocator<u16>>::AllocatedMemory: Send+Sync,
         <Alloc as Allocator<u32>>::AllocatedMemory: Send+Sync {
-  CompressMulti(params, owned_input, output, alloc_per_thread, work_pool)
+  CompressMulti(params, owned_input, output, alloc_per_thread, work_pool, custom_dictionary_len, metablock_callback)
 }
 
 // out of place thread create
@@ -177,6 +177,19 @@ CompressorWriterCustomIo<ErrType, W, BufferType, Alloc>
 
         ret
     }
+
+    pub fn with_params(w: W, buffer : BufferType,
+                       alloc : Alloc,
+                       invalid_data_error_type : ErrType,
+                       params: &BrotliEncoderParams) -> Self {
+        let mut ret = Self::new(w, buffer, alloc, invalid_data_error_type, params.quality as u32, params.lgwin as u32);
+        ret.state.params = params.clone();
+        ret
+    }
+    // writes the last metablock, after which further writes fail
+    pub fn finish(&mut self) -> Result<(), ErrType> {
+        self.flush_or_close(BrotliEncoderOperation::BROTLI_OPERATION_FINISH)
+    }
     fn flush_or_close(&mut self, op:BrotliEncoderOperation) -> Result<(), ErrType>{
        let mut nop_callback = |_data:&mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
                                _cmds: &mut [interface::StaticCommand],
@@ -240,7 +253,7 @@ impl<ErrType,
      Alloc: BrotliAlloc> Drop for
 CompressorWriterCustomIo<ErrType, W, BufferType, Alloc> {
     fn drop(&mut self) {
-        if self.output.is_some() {
+        if self.output.is_some() && self.error_if_invalid_data.is_some() {
             match self.flush_or_close(BrotliEncoderOperation::BROTLI_OPERATION_FINISH) {
                 Ok(_) => {},
               Err(_) => {},
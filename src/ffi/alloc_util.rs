@@ -2,6 +2,7 @@ use alloc::{Allocator,SliceWrapper,SliceWrapperMut};
 
 use ::enc::BrotliAlloc;
 use brotli_decompressor::ffi::alloc_util::SubclassableAllocator;
+use brotli_decompressor::ffi::interface::{CAllocator, c_void};
 
 
 
@@ -51,16 +52,61 @@ unsafe impl<T:Clone+Default> Send for SendableMemoryBlock<T>{}
 
 
 
+/// Places to_box with the custom allocator if there is one, else on the heap; see allocators_usable
+/// for the allocators this can be called with.
+pub unsafe fn box_with_allocator<T>(allocators: &CAllocator, to_box: T) -> *mut T {
+  if let Some(alloc) = allocators.alloc_func {
+    if allocators.free_func.is_none() {
+      panic!("either both alloc and free must exist or neither");
+    }
+    let ptr = alloc(allocators.opaque, core::mem::size_of::<T>());
+    let ret = core::mem::transmute::<*mut c_void, *mut T>(ptr);
+    core::ptr::write(ret, to_box);
+    ret
+  } else {
+    box_without_custom_alloc(to_box)
+  }
+}
+
+/// Drops and frees what box_with_allocator returned for the same allocators.
+pub unsafe fn free_with_allocator<T>(ptr: *mut T, allocators: CAllocator) {
+  if let Some(_) = allocators.alloc_func {
+    if let Some(free_fn) = allocators.free_func {
+      let _to_free = core::ptr::read(ptr);
+      free_fn(allocators.opaque, core::mem::transmute::<*mut T, *mut c_void>(ptr));
+    }
+  } else {
+    free_without_custom_alloc(ptr);
+  }
+}
+
+#[cfg(feature="std")]
+fn box_without_custom_alloc<T>(to_box: T) -> *mut T {
+  brotli_decompressor::ffi::alloc_util::Box::<T>::into_raw(brotli_decompressor::ffi::alloc_util::Box::<T>::new(to_box))
+}
+
 #[cfg(not(feature="std"))]
-#[cfg(feature="no-stdlib-ffi-binding")]
-#[panic_handler]
-extern fn panic_impl(_: &::core::panic::PanicInfo) -> ! {
-    loop {}
+fn box_without_custom_alloc<T>(_to_box: T) -> *mut T {
+  // allocators_usable already turned the caller away
+  core::ptr::null_mut()
+}
+
+#[cfg(feature="std")]
+unsafe fn free_without_custom_alloc<T>(ptr: *mut T) {
+  let _state = brotli_decompressor::ffi::alloc_util::Box::from_raw(ptr);
 }
 
+#[cfg(not(feature="std"))]
+unsafe fn free_without_custom_alloc<T>(_ptr: *mut T) {
+  unreachable!();
+}
+
+// a staticlib for bare metal must supply its own panic handler; build it with panic=abort so it
+// needs no unwinding support either
 #[cfg(not(feature="std"))]
 #[cfg(feature="no-stdlib-ffi-binding")]
-#[lang = "eh_personality"]
-extern "C" fn eh_personality() {
+#[panic_handler]
+fn panic_impl(_: &::core::panic::PanicInfo) -> ! {
+    loop {}
 }
 
@@ -1,6 +1,10 @@
+#![allow(deprecated)]
 use core;
 pub use brotli_decompressor::ffi::interface::{
-  c_void
+  brotli_alloc_func,
+  brotli_free_func,
+  c_void,
+  CAllocator,
 };
 use brotli_decompressor::ffi::{
   slice_from_raw_parts_or_nil,
@@ -8,9 +12,13 @@ use brotli_decompressor::ffi::{
 };
 use concat::BroCatli;
 pub use concat::BroCatliResult;
+use super::alloc_util::{box_with_allocator, free_with_allocator};
+use super::compressor::allocators_usable;
 pub type BroccoliResult = BroCatliResult;
 // a tool to concatenate brotli files together
 
+/// The concatenation state passed by value; it only has room for the BroCatli of old and is
+/// serialized on every call, so new code should use BroccoliInstance instead.
 #[repr(C)]
 pub struct BroccoliState {
     more_data: *mut c_void,
@@ -51,18 +59,84 @@ impl Into<BroCatli> for BroccoliState {
     }
 }
 
+/// The concatenation state behind an opaque pointer, placed with the allocator it was created with.
+pub struct BroccoliInstance {
+  custom_allocator: CAllocator,
+  state: BroCatli,
+}
+
+// the version byte in front of the serialized BroCatli in a checkpoint
+const CHECKPOINT_VERSION: u8 = 2;
+/// bytes written by BroccoliInstanceCheckpoint
+pub const CHECKPOINT_BYTES: usize = 1 + ::concat::SERIALIZED_BYTES;
+
+unsafe fn concat_stream(
+  bro_catli: &mut BroCatli,
+  available_in: *mut usize,
+  input_buf_ptr: *mut*const u8,
+  available_out: *mut usize,
+  output_buf_ptr: *mut*mut u8) -> BroccoliResult {
+  let input_buf = slice_from_raw_parts_or_nil(*input_buf_ptr, *available_in);
+  let output_buf = slice_from_raw_parts_or_nil_mut(*output_buf_ptr, *available_out);
+  let mut input_offset = 0usize;
+  let mut output_offset = 0usize;
+  let ret = bro_catli.stream(input_buf, &mut input_offset, output_buf, &mut output_offset);
+  *input_buf_ptr = (*input_buf_ptr).offset(input_offset as isize);
+  *output_buf_ptr = (*output_buf_ptr).offset(output_offset as isize);
+  *available_in -= input_offset;
+  *available_out -= output_offset;
+  ret
+}
+
+unsafe fn concat_finish(
+  bro_catli: &mut BroCatli,
+  available_out: *mut usize,
+  output_buf_ptr: *mut*mut u8) -> BroccoliResult {
+  let output_buf = slice_from_raw_parts_or_nil_mut(*output_buf_ptr, *available_out);
+  let mut output_offset = 0usize;
+  let ret = bro_catli.finish(output_buf, &mut output_offset);
+  *output_buf_ptr = (*output_buf_ptr).offset(output_offset as isize);
+  *available_out -= output_offset;
+  ret
+}
+
+unsafe fn leading_header(
+  bro_catli: &BroCatli,
+  header: *mut u8,
+  header_len: *mut usize,
+  replaced_len: *mut usize) -> BroccoliResult {
+  let header_buf = slice_from_raw_parts_or_nil_mut(header, ::concat::LEADING_HEADER_BYTES);
+  match bro_catli.leading_header(header_buf) {
+    Ok((replaced, written)) => {
+      *replaced_len = replaced;
+      *header_len = written;
+      BroCatliResult::Success
+    },
+    Err(failure) => failure,
+  }
+}
+
+#[deprecated(note="use BroccoliInstanceCreate")]
 #[no_mangle]
 pub extern fn BroccoliCreateInstance() -> BroccoliState {
     BroCatli::new().into()
 }
+#[deprecated(note="use BroccoliInstanceCreateWithWindowSize")]
 #[no_mangle]
 pub extern fn BroccoliCreateInstanceWithWindowSize(window_size: u8) -> BroccoliState {
     BroCatli::new_with_window_size(window_size).into()
 }
+#[deprecated(note="use BroccoliInstanceCreateWithWindowGrowth")]
+#[no_mangle]
+pub extern fn BroccoliCreateInstanceWithWindowGrowth() -> BroccoliState {
+    BroCatli::new_with_window_growth().into()
+}
+#[deprecated(note="use BroccoliInstanceDestroy")]
 #[no_mangle]
 pub extern fn BroccoliDestroyInstance(_state: BroccoliState) {
 }
 
+#[deprecated(note="use BroccoliInstanceNewBrotliFile")]
 #[no_mangle]
 pub unsafe extern fn BroccoliNewBrotliFile(state: *mut BroccoliState) {
     let mut bro_catli: BroCatli = (*state).into();
@@ -70,6 +144,7 @@ pub unsafe extern fn BroccoliNewBrotliFile(state: *mut BroccoliState) {
     *state = BroccoliState::from(bro_catli);
 }
 
+#[deprecated(note="use BroccoliInstanceConcatStream")]
 #[no_mangle]
 pub unsafe extern fn BroccoliConcatStream(
   state: *mut BroccoliState,
@@ -77,20 +152,13 @@ pub unsafe extern fn BroccoliConcatStream(
   input_buf_ptr: *mut*const u8,
   available_out: *mut usize,
   output_buf_ptr: *mut*mut u8) -> BroccoliResult {
-  let input_buf = slice_from_raw_parts_or_nil(*input_buf_ptr, *available_in);
-  let output_buf = slice_from_raw_parts_or_nil_mut(*output_buf_ptr, *available_out);
-  let mut input_offset = 0usize;
-  let mut output_offset = 0usize;
   let mut bro_catli: BroCatli = (*state).into();
-  let ret = bro_catli.stream(input_buf, &mut input_offset, output_buf, &mut output_offset);
-  *input_buf_ptr = (*input_buf_ptr).offset(input_offset as isize);
-  *output_buf_ptr = (*output_buf_ptr).offset(output_offset as isize);
-  *available_in -= input_offset;
-  *available_out -= output_offset;
+  let ret = concat_stream(&mut bro_catli, available_in, input_buf_ptr, available_out, output_buf_ptr);
   *state = BroccoliState::from(bro_catli);
   ret
 }
 
+#[deprecated(note="use BroccoliInstanceConcatStreaming")]
 #[no_mangle]
 pub unsafe extern fn BroccoliConcatStreaming(
   state: *mut BroccoliState,
@@ -104,25 +172,23 @@ pub unsafe extern fn BroccoliConcatStreaming(
     &mut input_buf,
     available_out,
     &mut output_buf)
-      
+
 }
 
+#[deprecated(note="use BroccoliInstanceConcatFinish")]
 #[no_mangle]
 pub unsafe extern fn BroccoliConcatFinish(
   state: *mut BroccoliState,
   available_out: *mut usize,
   output_buf_ptr: *mut*mut u8) -> BroCatliResult {
-  let output_buf = slice_from_raw_parts_or_nil_mut(*output_buf_ptr, *available_out);
-  let mut output_offset = 0usize;
   let mut bro_catli: BroCatli = (*state).into();
-  let ret = bro_catli.finish(output_buf, &mut output_offset);
-  *output_buf_ptr = (*output_buf_ptr).offset(output_offset as isize);
-  *available_out -= output_offset;
+  let ret = concat_finish(&mut bro_catli, available_out, output_buf_ptr);
   *state = BroccoliState::from(bro_catli);
   ret
 }
 
 // exactly the same as BrotliConcatFinish but without the indirect
+#[deprecated(note="use BroccoliInstanceConcatFinished")]
 #[no_mangle]
 pub unsafe extern fn BroccoliConcatFinished(
   state: *mut BroccoliState,
@@ -133,3 +199,296 @@ pub unsafe extern fn BroccoliConcatFinished(
     available_out,
     &mut output_buf)
 }
+
+#[deprecated(note="use BroccoliInstanceMergeMagicHeaders")]
+#[no_mangle]
+pub unsafe extern fn BroccoliMergeMagicHeaders(state: *mut BroccoliState) {
+    let mut bro_catli: BroCatli = (*state).into();
+    bro_catli.merge_magic_headers();
+    *state = BroccoliState::from(bro_catli);
+}
+
+// once finished, the first *replaced_len bytes of the output must be replaced by the
+// *header_len bytes written to header (which needs room for 32) for the window to have grown
+// or the magic number headers to be merged
+#[deprecated(note="use BroccoliInstanceLeadingHeader")]
+#[no_mangle]
+pub unsafe extern fn BroccoliLeadingHeader(
+  state: *const BroccoliState,
+  header: *mut u8,
+  header_len: *mut usize,
+  replaced_len: *mut usize) -> BroCatliResult {
+  let bro_catli: BroCatli = (*state).into();
+  leading_header(&bro_catli, header, header_len, replaced_len)
+}
+
+unsafe fn create_instance(
+  state: BroCatli,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void) -> *mut BroccoliInstance {
+  if !allocators_usable(alloc_func, free_func) {
+    return core::ptr::null_mut();
+  }
+  let allocators = CAllocator {
+    alloc_func: alloc_func,
+    free_func: free_func,
+    opaque: opaque,
+  };
+  let to_box = BroccoliInstance {
+    custom_allocator: allocators.clone(),
+    state: state,
+  };
+  box_with_allocator(&allocators, to_box)
+}
+
+// NULL when only one of alloc_func and free_func is given, or neither without std
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceCreate(
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void) -> *mut BroccoliInstance {
+  create_instance(BroCatli::new(), alloc_func, free_func, opaque)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceCreateWithWindowSize(
+  window_size: u8,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void) -> *mut BroccoliInstance {
+  create_instance(BroCatli::new_with_window_size(window_size), alloc_func, free_func, opaque)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceCreateWithWindowGrowth(
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void) -> *mut BroccoliInstance {
+  create_instance(BroCatli::new_with_window_growth(), alloc_func, free_func, opaque)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceDestroy(instance: *mut BroccoliInstance) {
+  if instance.is_null() {
+    return;
+  }
+  let allocators = (*instance).custom_allocator.clone();
+  free_with_allocator(instance, allocators);
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceMergeMagicHeaders(instance: *mut BroccoliInstance) {
+  (*instance).state.merge_magic_headers();
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceMarkMembers(instance: *mut BroccoliInstance) {
+  (*instance).state.mark_members();
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceMarkMembersWithTag(instance: *mut BroccoliInstance, tag: u64) {
+  (*instance).state.mark_members_with_tag(tag);
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceNewBrotliFile(instance: *mut BroccoliInstance) {
+  (*instance).state.new_brotli_file();
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceConcatStream(
+  instance: *mut BroccoliInstance,
+  available_in: *mut usize,
+  input_buf_ptr: *mut*const u8,
+  available_out: *mut usize,
+  output_buf_ptr: *mut*mut u8) -> BroccoliResult {
+  concat_stream(&mut (*instance).state, available_in, input_buf_ptr, available_out, output_buf_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceConcatStreaming(
+  instance: *mut BroccoliInstance,
+  available_in: *mut usize,
+  mut input_buf: *const u8,
+  available_out: *mut usize,
+  mut output_buf: *mut u8) -> BroccoliResult {
+  concat_stream(&mut (*instance).state, available_in, &mut input_buf, available_out, &mut output_buf)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceConcatFinish(
+  instance: *mut BroccoliInstance,
+  available_out: *mut usize,
+  output_buf_ptr: *mut*mut u8) -> BroccoliResult {
+  concat_finish(&mut (*instance).state, available_out, output_buf_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceConcatFinished(
+  instance: *mut BroccoliInstance,
+  available_out: *mut usize,
+  mut output_buf: *mut u8) -> BroccoliResult {
+  concat_finish(&mut (*instance).state, available_out, &mut output_buf)
+}
+
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceLeadingHeader(
+  instance: *const BroccoliInstance,
+  header: *mut u8,
+  header_len: *mut usize,
+  replaced_len: *mut usize) -> BroccoliResult {
+  leading_header(&(*instance).state, header, header_len, replaced_len)
+}
+
+// writes the state to checkpoint, which has room for *checkpoint_len bytes, and sets
+// *checkpoint_len to the bytes written; NeedsMoreOutput with *checkpoint_len set to the room
+// needed if there is not enough
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceCheckpoint(
+  instance: *const BroccoliInstance,
+  checkpoint: *mut u8,
+  checkpoint_len: *mut usize) -> BroccoliResult {
+  if *checkpoint_len < CHECKPOINT_BYTES {
+    *checkpoint_len = CHECKPOINT_BYTES;
+    return BroCatliResult::NeedsMoreOutput;
+  }
+  let buffer = slice_from_raw_parts_or_nil_mut(checkpoint, CHECKPOINT_BYTES);
+  for item in buffer.iter_mut() {
+    *item = 0;
+  }
+  buffer[0] = CHECKPOINT_VERSION;
+  (*instance).state.serialize_to_buffer(&mut buffer[1..]).unwrap();
+  *checkpoint_len = CHECKPOINT_BYTES;
+  BroCatliResult::Success
+}
+
+// replaces the state with one saved by BroccoliInstanceCheckpoint, keeping the allocator;
+// InvalidCheckpoint leaves the state as it was
+#[no_mangle]
+pub unsafe extern fn BroccoliInstanceRestore(
+  instance: *mut BroccoliInstance,
+  checkpoint: *const u8,
+  checkpoint_len: usize) -> BroccoliResult {
+  let buffer = slice_from_raw_parts_or_nil(checkpoint, checkpoint_len);
+  if buffer.len() < CHECKPOINT_BYTES || buffer[0] != CHECKPOINT_VERSION {
+    return BroCatliResult::InvalidCheckpoint;
+  }
+  match BroCatli::deserialize_from_buffer(&buffer[1..CHECKPOINT_BYTES]) {
+    Ok(state) => {
+      (*instance).state = state;
+      BroCatliResult::Success
+    },
+    Err(()) => BroCatliResult::InvalidCheckpoint,
+  }
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+  use super::*;
+  use ::enc::BrotliEncoderParams;
+  use std::vec::Vec;
+
+  fn repeated(text: &[u8], count: usize) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for _ in 0..count {
+      ret.extend_from_slice(text);
+    }
+    ret
+  }
+
+  fn compressed(input: &[u8], first: bool) -> Vec<u8> {
+    let mut params = BrotliEncoderParams::default();
+    params.quality = 5;
+    params.appendable = first;
+    params.catable = !first;
+    params.use_dictionary = first;
+    let mut output = Vec::new();
+    ::BrotliCompress(&mut &input[..], &mut output, &params).unwrap();
+    output
+  }
+
+  unsafe fn stream_all(instance: *mut BroccoliInstance, mut input: &[u8], output: &mut Vec<u8>) {
+    let mut buffer = [0u8; 64];
+    loop {
+      let mut available_in = input.len();
+      let mut input_ptr = input.as_ptr();
+      let mut available_out = buffer.len();
+      let mut output_ptr = buffer.as_mut_ptr();
+      let ret = BroccoliInstanceConcatStream(instance, &mut available_in, &mut input_ptr,
+                                             &mut available_out, &mut output_ptr);
+      output.extend_from_slice(&buffer[..buffer.len() - available_out]);
+      input = &input[input.len() - available_in..];
+      match ret {
+        BroCatliResult::NeedsMoreOutput => {},
+        BroCatliResult::NeedsMoreInput => break,
+        failure => panic!("{:?}", failure),
+      }
+    }
+  }
+
+  unsafe fn finish(instance: *mut BroccoliInstance, output: &mut Vec<u8>) {
+    let mut buffer = [0u8; 64];
+    loop {
+      let mut available_out = buffer.len();
+      let ret = BroccoliInstanceConcatFinished(instance, &mut available_out, buffer.as_mut_ptr());
+      output.extend_from_slice(&buffer[..buffer.len() - available_out]);
+      match ret {
+        BroCatliResult::NeedsMoreOutput => {},
+        BroCatliResult::Success => break,
+        failure => panic!("{:?}", failure),
+      }
+    }
+  }
+
+  #[test]
+  fn test_instance_concatenates_across_checkpoint() {
+    let first = repeated(b"the first of the files to concatenate, ", 40);
+    let second = repeated(b"and then the second one after it", 50);
+    let first_brotli = compressed(&first, true);
+    let second_brotli = compressed(&second, false);
+    let mut output = Vec::new();
+    let mut checkpoint = [0u8; CHECKPOINT_BYTES];
+    unsafe {
+      let instance = BroccoliInstanceCreate(None, None, core::ptr::null_mut());
+      assert!(!instance.is_null());
+      BroccoliInstanceNewBrotliFile(instance);
+      stream_all(instance, &first_brotli[..], &mut output);
+      let mut checkpoint_len = 0;
+      assert_eq!(BroccoliInstanceCheckpoint(instance, checkpoint.as_mut_ptr(), &mut checkpoint_len),
+                 BroCatliResult::NeedsMoreOutput);
+      assert_eq!(checkpoint_len, CHECKPOINT_BYTES);
+      assert_eq!(BroccoliInstanceCheckpoint(instance, checkpoint.as_mut_ptr(), &mut checkpoint_len),
+                 BroCatliResult::Success);
+      BroccoliInstanceDestroy(instance);
+
+      let instance = BroccoliInstanceCreate(None, None, core::ptr::null_mut());
+      assert_eq!(BroccoliInstanceRestore(instance, checkpoint.as_ptr(), checkpoint_len - 1),
+                 BroCatliResult::InvalidCheckpoint);
+      assert_eq!(BroccoliInstanceRestore(instance, checkpoint.as_ptr(), checkpoint_len),
+                 BroCatliResult::Success);
+      BroccoliInstanceNewBrotliFile(instance);
+      stream_all(instance, &second_brotli[..], &mut output);
+      finish(instance, &mut output);
+      BroccoliInstanceDestroy(instance);
+    }
+    let mut decompressed = Vec::new();
+    ::BrotliDecompress(&mut &output[..], &mut decompressed).unwrap();
+    let mut expected = first.clone();
+    expected.extend_from_slice(&second[..]);
+    assert_eq!(decompressed, expected);
+  }
+
+  #[test]
+  fn test_instance_rejects_mismatched_allocators() {
+    extern "C" fn unused_alloc(_opaque: *mut c_void, _size: usize) -> *mut c_void {
+      core::ptr::null_mut()
+    }
+    unsafe {
+      assert!(BroccoliInstanceCreate(Some(unused_alloc), None, core::ptr::null_mut()).is_null());
+      BroccoliInstanceDestroy(core::ptr::null_mut());
+    }
+  }
+}
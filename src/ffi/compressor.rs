@@ -4,6 +4,10 @@
 use std::{panic,thread, io};
 #[cfg(feature="std")]
 use std::io::Write;
+#[cfg(feature="std")]
+use core::cell::{Cell, RefCell};
+#[cfg(not(feature="std"))]
+use core::sync::atomic::{AtomicI32, Ordering};
 
 use core;
 use brotli_decompressor::ffi::alloc_util;
@@ -19,6 +23,10 @@ use brotli_decompressor::ffi::{
   slice_from_raw_parts_or_nil_mut,
 };
 use ::enc::encode::BrotliEncoderStateStruct;
+use ::enc::interface;
+use ::enc::interface::Unfreezable;
+use ::enc::input_pair::{InputPair, InputReferenceMut};
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 use super::alloc_util::BrotliSubclassableAllocator;
 
 #[repr(C)]
@@ -40,15 +48,448 @@ pub enum BrotliEncoderMode {
   BROTLI_MODE_FORCE_SIGNED_PRIOR = 6,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrotliEncoderIRCommandType {
+  BROTLI_IR_COPY = 0,
+  BROTLI_IR_DICT = 1,
+  BROTLI_IR_LITERAL = 2,
+  BROTLI_IR_BLOCK_SWITCH_COMMAND = 3,
+  BROTLI_IR_BLOCK_SWITCH_LITERAL = 4,
+  BROTLI_IR_BLOCK_SWITCH_DISTANCE = 5,
+  BROTLI_IR_PREDICTION_MODE = 6,
+}
+
+/// One command of a metablock as handed to a BrotliEncoderMetablockCallback; only the fields
+/// that belong to its command_type are set, the rest are zero.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BrotliEncoderIRCommand {
+  pub command_type: BrotliEncoderIRCommandType,
+  pub block_type: u8, // block switches
+  pub stride: u8, // literal block switches
+  pub word_size: u8, // dictionary words, as stored in the dictionary
+  pub transform: u8,
+  pub high_entropy: u8, // literals
+  pub word_id: u32,
+  pub distance: u32, // copies
+  pub num_bytes: u32, // bytes the command produces: copy length, literal count or transformed word size
+  pub data: *const u8, // literals, pointing into the input
+}
+
+impl Default for BrotliEncoderIRCommand {
+  fn default() -> Self {
+    BrotliEncoderIRCommand {
+      command_type: BrotliEncoderIRCommandType::BROTLI_IR_COPY,
+      block_type: 0,
+      stride: 0,
+      word_size: 0,
+      transform: 0,
+      high_entropy: 0,
+      word_id: 0,
+      distance: 0,
+      num_bytes: 0,
+      data: core::ptr::null(),
+    }
+  }
+}
+
+/// the context maps a metablock was encoded with, see interface::PredictionModeContextMap
+#[repr(C)]
+pub struct BrotliEncoderPredictionModeContextMap {
+  pub literal_context_map: *const u8,
+  pub literal_context_map_size: usize,
+  pub predmode_speed_and_distance_context_map: *const u8,
+  pub predmode_speed_and_distance_context_map_size: usize,
+}
+
+/// Called with each metablock's context maps and commands, which stay valid only for the duration of the call.
+pub type BrotliEncoderMetablockCallback = Option<unsafe extern "C" fn(opaque: *mut c_void,
+                                                                      context_map: *const BrotliEncoderPredictionModeContextMap,
+                                                                      commands: *const BrotliEncoderIRCommand,
+                                                                      num_commands: usize)>;
+
+/// Why the last encoder call failed, see BrotliEncoderGetErrorCode and BrotliEncoderGetLastErrorCode.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrotliEncoderErrorCode {
+  BROTLI_ENCODER_NO_ERROR = 0,
+  /// unknown parameter or value, or a parameter set after compression started
+  BROTLI_ENCODER_ERROR_INVALID_PARAMETER = -1,
+  /// a NULL state or a thread count of zero
+  BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS = -2,
+  BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL = -3,
+  /// an operation the stream does not allow in its current state, eg. input during a flush
+  BROTLI_ENCODER_ERROR_INVALID_OPERATION = -4,
+  /// the output of the threads could not be joined into one stream
+  BROTLI_ENCODER_ERROR_CONCATENATION = -5,
+  /// the encoder panicked, see BrotliEncoderGetLastPanicMessage
+  BROTLI_ENCODER_ERROR_PANIC = -6,
+  /// the read or write callback of a BrotliEncoderReader or BrotliEncoderWriter failed
+  BROTLI_ENCODER_ERROR_CALLBACK = -7,
+}
+
+pub fn BrotliEncoderErrorStr(c: BrotliEncoderErrorCode) -> &'static str {
+  match c {
+    BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR => "NO_ERROR\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER => "ERROR_INVALID_PARAMETER\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS => "ERROR_INVALID_ARGUMENTS\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL => "ERROR_OUTPUT_TOO_SMALL\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION => "ERROR_INVALID_OPERATION\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CONCATENATION => "ERROR_CONCATENATION\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC => "ERROR_PANIC\0",
+    BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CALLBACK => "ERROR_CALLBACK\0",
+  }
+}
+
+/// The inverse of `code as i32`; values no code has come out as BROTLI_ENCODER_ERROR_PANIC.
+pub fn error_code_from_i32(code: i32) -> BrotliEncoderErrorCode {
+  match code {
+    0 => BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR,
+    -1 => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER,
+    -2 => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS,
+    -3 => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL,
+    -4 => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION,
+    -5 => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CONCATENATION,
+    -7 => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CALLBACK,
+    _ => BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC,
+  }
+}
+
+fn thread_error_code(err: &::enc::BrotliEncoderThreadError) -> BrotliEncoderErrorCode {
+  match *err {
+    ::enc::BrotliEncoderThreadError::InsufficientOutputSpace =>
+      BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL,
+    ::enc::BrotliEncoderThreadError::ConcatenationDidNotProcessFullFile |
+    ::enc::BrotliEncoderThreadError::ConcatenationError(_) |
+    ::enc::BrotliEncoderThreadError::ConcatenationFinalizationError(_) =>
+      BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CONCATENATION,
+    ::enc::BrotliEncoderThreadError::OtherThreadPanic |
+    ::enc::BrotliEncoderThreadError::MetablockLogError(_) |
+    ::enc::BrotliEncoderThreadError::ThreadExecError(_) |
+    ::enc::BrotliEncoderThreadError::WorkItemPanic(_, _, _) =>
+      BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC,
+  }
+}
+
+#[cfg(feature="std")]
+thread_local! {
+  static LAST_ERROR_CODE: Cell<BrotliEncoderErrorCode> = Cell::new(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+  static LAST_PANIC_MESSAGE: RefCell<[u8; 256]> = RefCell::new([0u8; 256]);
+}
+
+/// Records code as the outcome of the last encoder call on this thread.
+#[cfg(feature="std")]
+pub fn set_last_error_code(code: BrotliEncoderErrorCode) {
+  LAST_ERROR_CODE.with(|last| last.set(code));
+}
+
+// without std there are no threads to keep apart, so bare metal callers share one slot
+#[cfg(not(feature="std"))]
+static LAST_ERROR_CODE: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(not(feature="std"))]
+pub fn set_last_error_code(code: BrotliEncoderErrorCode) {
+  LAST_ERROR_CODE.store(code as i32, Ordering::Relaxed);
+}
+
+/// Whether an encoder can be created with these allocators: both or neither, and without std
+/// both, since there is no heap to fall back on.
+pub fn allocators_usable(alloc_func: brotli_alloc_func, free_func: brotli_free_func) -> bool {
+  alloc_func.is_some() == free_func.is_some() && (cfg!(feature="std") || alloc_func.is_some())
+}
+
+/// Records a panic payload for BrotliEncoderGetLastPanicMessage, cut short to fit its buffer,
+/// and returns what it says.
+#[cfg(feature="std")]
+pub fn set_last_panic_message(err: &::enc::threading::LowLevelThreadError) -> &str {
+  let message = if let Some(st) = err.downcast_ref::<&str>() {
+    *st
+  } else if let Some(st) = err.downcast_ref::<std::string::String>() {
+    &st[..]
+  } else {
+    "panic without a message"
+  };
+  LAST_PANIC_MESSAGE.with(|last| {
+    let mut str_cpy = last.borrow_mut();
+    let xlen = core::cmp::min(message.len(), str_cpy.len() - 1);
+    str_cpy[..xlen].clone_from_slice(&message.as_bytes()[..xlen]);
+    str_cpy[xlen] = 0; // null terminate
+  });
+  set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC);
+  message
+}
+
+/// Records the error a multithreaded compression ended with as the last one on this thread.
+pub fn set_last_thread_error(err: &::enc::BrotliEncoderThreadError) {
+  #[cfg(feature="std")]
+  match *err {
+    ::enc::BrotliEncoderThreadError::ThreadExecError(ref payload) |
+    ::enc::BrotliEncoderThreadError::WorkItemPanic(_, _, ref payload) => {
+      set_last_panic_message(payload);
+    },
+    _ => {},
+  }
+  set_last_error_code(thread_error_code(err));
+}
+
+/// Every encoder parameter at once, for BrotliEncoderSetParams and BrotliEncoderGetParams.
+/// size holds the size of the struct the caller was built with, so that fields added to the end
+/// later on leave callers built against an older header working. Flags are 0 or 1.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BrotliEncoderParamsC {
+  pub size: usize,
+  pub mode: u32, // a BrotliEncoderMode
+  pub quality: i32,
+  pub lgwin: i32,
+  pub lgblock: i32,
+  pub size_hint: u64,
+  pub disable_literal_context_modeling: i32,
+  pub q9_5: i32,
+  pub large_window: i32,
+  pub avoid_distance_prefix_search: i32,
+  pub catable: i32,
+  pub use_dictionary: i32, // with catable, only honoured together with catable_dictionary
+  pub catable_dictionary: i32,
+  pub appendable: i32,
+  pub magic_number: i32,
+  pub favor_cpu_efficiency: i32,
+  // see BrotliHasherParams: the encoder still picks the hasher type from the quality
+  pub hasher_type: i32,
+  pub hasher_bucket_bits: i32,
+  pub hasher_block_bits: i32,
+  pub hasher_hash_len: i32,
+  pub hasher_num_last_distances_to_check: i32,
+  pub hasher_literal_byte_score: i32,
+  pub stride_detection_quality: u8,
+  pub high_entropy_detection_quality: u8,
+  pub cdf_adaptation_detection: u8,
+  pub prior_bitmask_detection: u8,
+  /// (speed, max) pairs for stride_low, stride_speed, cm_low and cm_speed, as set one at a time
+  /// by BROTLI_PARAM_SPEED_LOW, BROTLI_PARAM_SPEED_LOW_MAX and so on
+  pub literal_adaptation: [u16; 8],
+}
+
+// where size_hint starts: after size and four 32 bit fields, aligned like a u64
+const PARAMS_C_SIZE_HINT_OFFSET: usize = (core::mem::size_of::<usize>() + 16 + core::mem::align_of::<u64>() - 1)
+  / core::mem::align_of::<u64>() * core::mem::align_of::<u64>();
+
+/// The smallest size BrotliEncoderParamsC may be given as: the struct up to and including
+/// favor_cpu_efficiency. Callers whose struct ends there, or anywhere after it, are accepted, and
+/// the fields past the end of their struct keep their defaults.
+pub const BROTLI_ENCODER_PARAMS_C_V1_SIZE: usize = PARAMS_C_SIZE_HINT_OFFSET + 8 + 10 * 4;
+
+fn params_c(params: &::enc::BrotliEncoderParams) -> BrotliEncoderParamsC {
+  let mut literal_adaptation = [0u16; 8];
+  for (index, &(speed, max)) in params.literal_adaptation.iter().enumerate() {
+    literal_adaptation[index * 2] = speed;
+    literal_adaptation[index * 2 + 1] = max;
+  }
+  BrotliEncoderParamsC {
+    size: core::mem::size_of::<BrotliEncoderParamsC>(),
+    mode: params.mode as u32,
+    quality: params.quality,
+    lgwin: params.lgwin,
+    lgblock: params.lgblock,
+    size_hint: params.size_hint as u64,
+    disable_literal_context_modeling: params.disable_literal_context_modeling,
+    q9_5: params.q9_5 as i32,
+    large_window: params.large_window as i32,
+    avoid_distance_prefix_search: params.avoid_distance_prefix_search as i32,
+    catable: params.catable as i32,
+    use_dictionary: params.use_dictionary as i32,
+    catable_dictionary: params.catable_dictionary as i32,
+    appendable: params.appendable as i32,
+    magic_number: params.magic_number as i32,
+    favor_cpu_efficiency: params.favor_cpu_efficiency as i32,
+    hasher_type: params.hasher.type_,
+    hasher_bucket_bits: params.hasher.bucket_bits,
+    hasher_block_bits: params.hasher.block_bits,
+    hasher_hash_len: params.hasher.hash_len,
+    hasher_num_last_distances_to_check: params.hasher.num_last_distances_to_check,
+    hasher_literal_byte_score: params.hasher.literal_byte_score,
+    stride_detection_quality: params.stride_detection_quality,
+    high_entropy_detection_quality: params.high_entropy_detection_quality,
+    cdf_adaptation_detection: params.cdf_adaptation_detection,
+    prior_bitmask_detection: params.prior_bitmask_detection,
+    literal_adaptation: literal_adaptation,
+  }
+}
+
+// copies c over params, or returns false without touching them if a field is out of range
+pub fn apply_params_c(c: &BrotliEncoderParamsC, params: &mut ::enc::BrotliEncoderParams) -> bool {
+  use ::enc::backward_references::BrotliEncoderMode as Mode;
+  let mode = match c.mode {
+    0 => Mode::BROTLI_MODE_GENERIC,
+    1 => Mode::BROTLI_MODE_TEXT,
+    2 => Mode::BROTLI_MODE_FONT,
+    3 => Mode::BROTLI_FORCE_LSB_PRIOR,
+    4 => Mode::BROTLI_FORCE_MSB_PRIOR,
+    5 => Mode::BROTLI_FORCE_UTF8_PRIOR,
+    6 => Mode::BROTLI_FORCE_SIGNED_PRIOR,
+    _ => return false,
+  };
+  if c.disable_literal_context_modeling != 0 && c.disable_literal_context_modeling != 1 {
+    return false;
+  }
+  params.mode = mode;
+  params.quality = c.quality;
+  params.lgwin = c.lgwin;
+  params.lgblock = c.lgblock;
+  params.size_hint = core::cmp::min(c.size_hint, usize::MAX as u64) as usize;
+  params.disable_literal_context_modeling = c.disable_literal_context_modeling;
+  params.q9_5 = c.q9_5 != 0;
+  params.large_window = c.large_window != 0;
+  params.avoid_distance_prefix_search = c.avoid_distance_prefix_search != 0;
+  params.catable = c.catable != 0;
+  params.catable_dictionary = c.catable_dictionary != 0;
+  // the same as setting BROTLI_PARAM_CATABLE would leave it
+  params.use_dictionary = c.use_dictionary != 0 && (!params.catable || params.catable_dictionary);
+  params.appendable = c.appendable != 0;
+  params.magic_number = c.magic_number != 0;
+  params.favor_cpu_efficiency = c.favor_cpu_efficiency != 0;
+  params.hasher.type_ = c.hasher_type;
+  params.hasher.bucket_bits = c.hasher_bucket_bits;
+  params.hasher.block_bits = c.hasher_block_bits;
+  params.hasher.hash_len = c.hasher_hash_len;
+  params.hasher.num_last_distances_to_check = c.hasher_num_last_distances_to_check;
+  params.hasher.literal_byte_score = c.hasher_literal_byte_score;
+  params.stride_detection_quality = c.stride_detection_quality;
+  params.high_entropy_detection_quality = c.high_entropy_detection_quality;
+  params.cdf_adaptation_detection = c.cdf_adaptation_detection;
+  params.prior_bitmask_detection = c.prior_bitmask_detection;
+  for (index, item) in params.literal_adaptation.iter_mut().enumerate() {
+    *item = (c.literal_adaptation[index * 2], c.literal_adaptation[index * 2 + 1]);
+  }
+  true
+}
+
+// writes params to the first size bytes of the caller's struct, which holds at least the v1 fields;
+// any bytes past ours belong to fields this library does not know about and read as zero
+unsafe fn write_params_c(params: &::enc::BrotliEncoderParams, out: *mut BrotliEncoderParamsC, size: usize) -> bool {
+  if out.is_null() || size < BROTLI_ENCODER_PARAMS_C_V1_SIZE {
+    return false;
+  }
+  let mut ours = [0u8; core::mem::size_of::<BrotliEncoderParamsC>()];
+  let mut params = params_c(params);
+  params.size = size;
+  core::ptr::write(ours.as_mut_ptr() as *mut BrotliEncoderParamsC, params);
+  let theirs = slice_from_raw_parts_or_nil_mut(out as *mut u8, size);
+  let known = core::cmp::min(size, ours.len());
+  theirs[..known].clone_from_slice(&ours[..known]);
+  for item in theirs[known..].iter_mut() {
+    *item = 0;
+  }
+  true
+}
+
+// reads the first (*params).size bytes of the caller's struct, which must hold at least the v1
+// fields: the fields past its end take their defaults, and bytes past the end of ours belong to
+// fields this library does not know about, which must be zero
+pub unsafe fn read_params_c(params: *const BrotliEncoderParamsC) -> Result<BrotliEncoderParamsC, BrotliEncoderErrorCode> {
+  let size = (*params).size;
+  if size < BROTLI_ENCODER_PARAMS_C_V1_SIZE {
+    return Err(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+  }
+  let theirs = slice_from_raw_parts_or_nil(params as *const u8, size);
+  let mut ret = params_c(&::enc::BrotliEncoderParams::default());
+  let known = core::cmp::min(size, core::mem::size_of::<BrotliEncoderParamsC>());
+  if theirs[known..].iter().any(|item| *item != 0) {
+    return Err(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+  }
+  slice_from_raw_parts_or_nil_mut(&mut ret as *mut BrotliEncoderParamsC as *mut u8, known).clone_from_slice(&theirs[..known]);
+  Ok(ret)
+}
+
 #[repr(C)]
 pub struct BrotliEncoderState {
   pub custom_allocator: CAllocator,
   pub compressor: BrotliEncoderStateStruct<BrotliSubclassableAllocator>,
+  pub metablock_callback: BrotliEncoderMetablockCallback,
+  pub metablock_callback_opaque: *mut c_void,
+  pub error_code: BrotliEncoderErrorCode,
+}
+
+// records code both as the error of state_ptr, if there is one, and as the last one on this thread
+unsafe fn set_error_code(state_ptr: *mut BrotliEncoderState, code: BrotliEncoderErrorCode) -> i32 {
+  if !state_ptr.is_null() {
+    (*state_ptr).error_code = code;
+  }
+  set_last_error_code(code);
+  if code == BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR { 1 } else { 0 }
+}
+
+fn ir_command(command: &interface::StaticCommand, mb: &InputPair) -> BrotliEncoderIRCommand {
+  let mut ret = BrotliEncoderIRCommand::default();
+  match *command {
+    interface::Command::Copy(ref copy) => {
+      ret.distance = copy.distance;
+      ret.num_bytes = copy.num_bytes;
+    },
+    interface::Command::Dict(ref dict) => {
+      ret.command_type = BrotliEncoderIRCommandType::BROTLI_IR_DICT;
+      ret.word_size = dict.word_size;
+      ret.transform = dict.transform;
+      ret.word_id = dict.word_id;
+      ret.num_bytes = u32::from(dict.final_size);
+    },
+    interface::Command::Literal(ref literal) => {
+      ret.command_type = BrotliEncoderIRCommandType::BROTLI_IR_LITERAL;
+      ret.high_entropy = literal.high_entropy as u8;
+      if let Ok(data) = literal.data.thaw_pair(mb) {
+        ret.data = data.data.as_ptr();
+        ret.num_bytes = data.data.len() as u32;
+      }
+    },
+    interface::Command::BlockSwitchCommand(ref switch) => {
+      ret.command_type = BrotliEncoderIRCommandType::BROTLI_IR_BLOCK_SWITCH_COMMAND;
+      ret.block_type = switch.block_type();
+    },
+    interface::Command::BlockSwitchLiteral(ref switch) => {
+      ret.command_type = BrotliEncoderIRCommandType::BROTLI_IR_BLOCK_SWITCH_LITERAL;
+      ret.block_type = switch.block_type();
+      ret.stride = switch.stride();
+    },
+    interface::Command::BlockSwitchDistance(ref switch) => {
+      ret.command_type = BrotliEncoderIRCommandType::BROTLI_IR_BLOCK_SWITCH_DISTANCE;
+      ret.block_type = switch.block_type();
+    },
+    interface::Command::PredictionMode(_) => {
+      ret.command_type = BrotliEncoderIRCommandType::BROTLI_IR_PREDICTION_MODE;
+    },
+  }
+  ret
+}
+
+// flattens a metablock for the C callback, using the encoder's allocator for the command array
+unsafe fn call_metablock_callback(callback: unsafe extern "C" fn(*mut c_void,
+                                                                 *const BrotliEncoderPredictionModeContextMap,
+                                                                 *const BrotliEncoderIRCommand,
+                                                                 usize),
+                                  opaque: *mut c_void,
+                                  pm: &mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                  commands: &mut [interface::StaticCommand],
+                                  mb: InputPair,
+                                  alloc: &mut BrotliSubclassableAllocator) {
+  let mut flat = <BrotliSubclassableAllocator as Allocator<BrotliEncoderIRCommand>>::alloc_cell(alloc, commands.len());
+  for (out, command) in flat.slice_mut().iter_mut().zip(commands.iter()) {
+    *out = ir_command(command, &mb);
+  }
+  let context_map = BrotliEncoderPredictionModeContextMap {
+    literal_context_map: pm.literal_context_map.slice().as_ptr(),
+    literal_context_map_size: pm.literal_context_map.slice().len(),
+    predmode_speed_and_distance_context_map: pm.predmode_speed_and_distance_context_map.slice().as_ptr(),
+    predmode_speed_and_distance_context_map_size: pm.predmode_speed_and_distance_context_map.slice().len(),
+  };
+  callback(opaque, &context_map, flat.slice().as_ptr(), commands.len());
+  <BrotliSubclassableAllocator as Allocator<BrotliEncoderIRCommand>>::free_cell(alloc, flat);
 }
 
 #[cfg(not(feature="std"))]
 fn brotli_new_compressor_without_custom_alloc(_to_box: BrotliEncoderState) -> *mut BrotliEncoderState{
-    panic!("Must supply allocators if calling divans when compiled without features=std");
+    // allocators_usable already turned the caller away
+    core::ptr::null_mut()
 }
 
 #[cfg(feature="std")]
@@ -69,12 +510,16 @@ unsafe fn free_compressor_no_custom_alloc(_state_ptr: *mut BrotliEncoderState) {
 
 
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCreateInstance(
     alloc_func: brotli_alloc_func,
     free_func: brotli_free_func,
     opaque: *mut c_void,
 ) -> *mut BrotliEncoderState {
+  if !allocators_usable(alloc_func, free_func) {
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return core::ptr::null_mut();
+  }
   match catch_panic_cstate(|| {
     let allocators = CAllocator {
       alloc_func:alloc_func,
@@ -87,6 +532,9 @@ pub unsafe extern fn BrotliEncoderCreateInstance(
         BrotliSubclassableAllocator::new(
           SubclassableAllocator::new(allocators.clone())),
       ),
+      metablock_callback: None,
+      metablock_callback_opaque: core::ptr::null_mut(),
+      error_code: BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR,
     };
     if let Some(alloc) = alloc_func {
       if free_func.is_none() {
@@ -108,16 +556,37 @@ pub unsafe extern fn BrotliEncoderCreateInstance(
   }
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderSetParameter(
   state_ptr: *mut BrotliEncoderState,
   param: ::enc::encode::BrotliEncoderParameter,
   value: u32,
 ) -> i32 {
-  ::enc::encode::BrotliEncoderSetParameter(&mut (*state_ptr).compressor, param, value)
+  if state_ptr.is_null() {
+    return set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+  }
+  if ::enc::encode::BrotliEncoderSetParameter(&mut (*state_ptr).compressor, param, value) == 0 {
+    return set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+  }
+  set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR)
 }
 
-#[no_mangle]
+/// Registers callback to be called with the intermediate representation of every metablock
+/// the encoder writes from now on, or stops the calls if callback is NULL.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderSetMetablockCallback(
+  state_ptr: *mut BrotliEncoderState,
+  callback: BrotliEncoderMetablockCallback,
+  opaque: *mut c_void,
+) {
+  (*state_ptr).metablock_callback = callback;
+  (*state_ptr).metablock_callback_opaque = opaque;
+  ::enc::encode::BrotliEncoderSetParameter(&mut (*state_ptr).compressor,
+                                           ::enc::encode::BrotliEncoderParameter::BROTLI_METABLOCK_CALLBACK,
+                                           callback.is_some() as u32);
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderDestroyInstance(state_ptr: *mut BrotliEncoderState) {
   if state_ptr.is_null() {
     return;
@@ -133,21 +602,21 @@ pub unsafe extern fn BrotliEncoderDestroyInstance(state_ptr: *mut BrotliEncoderS
     free_compressor_no_custom_alloc(state_ptr);
   }
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderIsFinished(
   state_ptr: *mut BrotliEncoderState,
 ) -> i32 {
   ::enc::encode::BrotliEncoderIsFinished(&mut (*state_ptr).compressor)
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderHasMoreOutput(
   state_ptr: *mut BrotliEncoderState,
 ) -> i32 {
   ::enc::encode::BrotliEncoderHasMoreOutput(&mut (*state_ptr).compressor)
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderSetCustomDictionary(
   state_ptr: *mut BrotliEncoderState,
   size: usize,
@@ -159,25 +628,119 @@ pub unsafe extern fn BrotliEncoderSetCustomDictionary(
       0
   }) {
       error_print(panic_err);
+      set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC);
   }
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderTakeOutput(
   state_ptr: *mut BrotliEncoderState,
   size: *mut usize,
 ) -> *const u8 {
   ::enc::encode::BrotliEncoderTakeOutput(&mut (*state_ptr).compressor, &mut *size).as_ptr()
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderGetErrorCode(state_ptr: *const BrotliEncoderState) -> BrotliEncoderErrorCode {
+  if state_ptr.is_null() {
+    return BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS;
+  }
+  (*state_ptr).error_code
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderGetErrorString(state_ptr: *const BrotliEncoderState) -> *const u8 {
+  BrotliEncoderErrorString(BrotliEncoderGetErrorCode(state_ptr))
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub extern fn BrotliEncoderErrorString(c: BrotliEncoderErrorCode) -> *const u8 {
+  BrotliEncoderErrorStr(c).as_ptr()
+}
+
+/// The outcome of the last encoder call on this thread, for the calls without a state to ask.
+#[cfg(feature="std")]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub extern fn BrotliEncoderGetLastErrorCode() -> BrotliEncoderErrorCode {
+  LAST_ERROR_CODE.with(|last| last.get())
+}
+
+#[cfg(not(feature="std"))]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub extern fn BrotliEncoderGetLastErrorCode() -> BrotliEncoderErrorCode {
+  error_code_from_i32(LAST_ERROR_CODE.load(Ordering::Relaxed))
+}
+
+/// What the last panic caught on this thread said, as a NUL terminated string that stays valid
+/// until the next one; empty if there was none.
+#[cfg(feature="std")]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub extern fn BrotliEncoderGetLastPanicMessage() -> *const u8 {
+  LAST_PANIC_MESSAGE.with(|last| last.as_ptr() as *const u8)
+}
+
+#[cfg(not(feature="std"))]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub extern fn BrotliEncoderGetLastPanicMessage() -> *const u8 {
+  "\0".as_ptr()
+}
+
+/// Fills params with the default parameters and sets its size field to size, which must be at
+/// least BROTLI_ENCODER_PARAMS_C_V1_SIZE.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderParamsInit(params: *mut BrotliEncoderParamsC, size: usize) -> i32 {
+  if !write_params_c(&::enc::BrotliEncoderParams::default(), params, size) {
+    return set_error_code(core::ptr::null_mut(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+  }
+  set_error_code(core::ptr::null_mut(), BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR)
+}
+
+/// Sets every parameter of state from params at once; like BrotliEncoderSetParameter, only
+/// before compression starts. Fields past the end of the caller's struct keep their defaults, and
+/// fields past the ones this library knows about must be zero.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderSetParams(
+  state_ptr: *mut BrotliEncoderState,
+  params: *const BrotliEncoderParamsC,
+) -> i32 {
+  if state_ptr.is_null() || params.is_null() {
+    return set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+  }
+  let params = match read_params_c(params) {
+    Ok(params) => params,
+    Err(code) => return set_error_code(state_ptr, code),
+  };
+  if (*state_ptr).compressor.is_initialized_ {
+    return set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+  }
+  if !apply_params_c(&params, &mut (*state_ptr).compressor.params) {
+    return set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+  }
+  set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR)
+}
+
+/// Reads every parameter of state into params, whose size field must already be set.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderGetParams(
+  state_ptr: *const BrotliEncoderState,
+  params: *mut BrotliEncoderParamsC,
+) -> i32 {
+  if state_ptr.is_null() || params.is_null() || !write_params_c(&(*state_ptr).compressor.params, params, (*params).size) {
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return 0;
+  }
+  set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+  1
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub extern fn BrotliEncoderVersion() -> u32 {
   ::enc::encode::BrotliEncoderVersion()
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub extern fn BrotliEncoderMaxCompressedSize(input_size: usize) -> usize {
   ::enc::encode::BrotliEncoderMaxCompressedSize(input_size)
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCompress(
   quality: i32,
   lgwin: i32,
@@ -186,6 +749,10 @@ pub unsafe extern fn BrotliEncoderCompress(
   input_buffer: *const u8,
   encoded_size: *mut usize,
   encoded_buffer: *mut u8) -> i32 {
+  if !allocators_usable(None, None) {
+    // there is nothing to allocate with but std
+    return set_error_code(core::ptr::null_mut(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION);
+  }
   match catch_panic(|| {
     let input_buf = slice_from_raw_parts_or_nil(input_buffer, input_size);
     let encoded_buf = slice_from_raw_parts_or_nil_mut(encoded_buffer, *encoded_size);
@@ -229,7 +796,11 @@ pub unsafe extern fn BrotliEncoderCompress(
       )
 
   }) {
-    Ok(ret) => ret,
+    Ok(0) => set_error_code(core::ptr::null_mut(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL),
+    Ok(ret) => {
+      set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+      ret
+    },
     Err(panic_err) => {
       error_print(panic_err);
       0
@@ -237,7 +808,7 @@ pub unsafe extern fn BrotliEncoderCompress(
   }
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCompressStreaming(
   state_ptr: *mut BrotliEncoderState,
   op: BrotliEncoderOperation,
@@ -256,7 +827,7 @@ pub unsafe extern fn BrotliEncoderCompressStreaming(
                               
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCompressStream(
   state_ptr: *mut BrotliEncoderState,
   op: BrotliEncoderOperation,
@@ -265,6 +836,9 @@ pub unsafe extern fn BrotliEncoderCompressStream(
   available_out: *mut usize,
   output_buf_ptr: *mut*mut u8,
   total_out: *mut usize) -> i32 {
+  if state_ptr.is_null() {
+    return set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+  }
   match catch_panic(|| {
     let mut input_offset = 0usize;
     let mut output_offset = 0usize;
@@ -291,6 +865,8 @@ pub unsafe extern fn BrotliEncoderCompressStream(
         (&mut [], false)
       };
       let mut to = Some(0usize);
+      let callback = (*state_ptr).metablock_callback;
+      let opaque = (*state_ptr).metablock_callback_opaque;
       result = ::enc::encode::BrotliEncoderCompressStream(
         &mut (*state_ptr).compressor,
         translated_op,
@@ -301,7 +877,9 @@ pub unsafe extern fn BrotliEncoderCompressStream(
         output_buf,
         &mut output_offset,
         &mut to,
-        &mut |_a,_b,_c,_d|(),
+        &mut |pm, commands, mb, alloc| if let Some(callback) = callback {
+          call_metablock_callback(callback, opaque, pm, commands, mb, alloc);
+        },
       );
       if !total_out.is_null() {
         *total_out = to.unwrap_or(0);
@@ -315,16 +893,20 @@ pub unsafe extern fn BrotliEncoderCompressStream(
     }
     result
   }) {
-    Ok(ret) => ret,
+    Ok(0) => set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION),
+    Ok(ret) => {
+      set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+      ret
+    },
     Err(panic_err) => {
       error_print(panic_err);
-      0
+      set_error_code(state_ptr, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC)
     },
   }
 }
 
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderMallocU8(state_ptr: *mut BrotliEncoderState, size: usize) -> *mut u8 {
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
         return core::mem::transmute::<*mut c_void, *mut u8>(alloc_fn((*state_ptr).custom_allocator.opaque, size));
@@ -333,7 +915,7 @@ pub unsafe extern fn BrotliEncoderMallocU8(state_ptr: *mut BrotliEncoderState, s
     }
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderFreeU8(state_ptr: *mut BrotliEncoderState, data: *mut u8, size: usize) {
     if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
         free_fn((*state_ptr).custom_allocator.opaque, core::mem::transmute::<*mut u8, *mut c_void>(data));
@@ -342,7 +924,7 @@ pub unsafe extern fn BrotliEncoderFreeU8(state_ptr: *mut BrotliEncoderState, dat
     }
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderMallocUsize(state_ptr: *mut BrotliEncoderState, size: usize) -> *mut usize {
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
         return core::mem::transmute::<*mut c_void, *mut usize>(alloc_fn((*state_ptr).custom_allocator.opaque,
@@ -351,7 +933,7 @@ pub unsafe extern fn BrotliEncoderMallocUsize(state_ptr: *mut BrotliEncoderState
         return alloc_util::alloc_stdlib(size);
     }
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderFreeUsize(state_ptr: *mut BrotliEncoderState, data: *mut usize, size: usize) {
     if let Some(free_fn) = (*state_ptr).custom_allocator.free_func {
         free_fn((*state_ptr).custom_allocator.opaque, core::mem::transmute::<*mut usize, *mut c_void>(data));
@@ -360,6 +942,159 @@ pub unsafe extern fn BrotliEncoderFreeUsize(state_ptr: *mut BrotliEncoderState,
     }
 }
 
+// The same functions under CBrotliEncoder names that do not clash with libbrotlienc, so both can
+// be linked into one program; see c/brotli/cencode.h.
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCreateInstance(
+    alloc_func: brotli_alloc_func,
+    free_func: brotli_free_func,
+    opaque: *mut c_void,
+) -> *mut BrotliEncoderState {
+  BrotliEncoderCreateInstance(alloc_func, free_func, opaque)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderSetParameter(
+  state_ptr: *mut BrotliEncoderState,
+  param: ::enc::encode::BrotliEncoderParameter,
+  value: u32,
+) -> i32 {
+  BrotliEncoderSetParameter(state_ptr, param, value)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderSetMetablockCallback(
+  state_ptr: *mut BrotliEncoderState,
+  callback: BrotliEncoderMetablockCallback,
+  opaque: *mut c_void,
+) {
+  BrotliEncoderSetMetablockCallback(state_ptr, callback, opaque)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderDestroyInstance(state_ptr: *mut BrotliEncoderState) {
+  BrotliEncoderDestroyInstance(state_ptr)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderIsFinished(
+  state_ptr: *mut BrotliEncoderState,
+) -> i32 {
+  BrotliEncoderIsFinished(state_ptr)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderHasMoreOutput(
+  state_ptr: *mut BrotliEncoderState,
+) -> i32 {
+  BrotliEncoderHasMoreOutput(state_ptr)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderSetCustomDictionary(
+  state_ptr: *mut BrotliEncoderState,
+  size: usize,
+  dict: *const u8,
+) {
+  BrotliEncoderSetCustomDictionary(state_ptr, size, dict)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderTakeOutput(
+  state_ptr: *mut BrotliEncoderState,
+  size: *mut usize,
+) -> *const u8 {
+  BrotliEncoderTakeOutput(state_ptr, size)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderGetErrorCode(state_ptr: *const BrotliEncoderState) -> BrotliEncoderErrorCode {
+  BrotliEncoderGetErrorCode(state_ptr)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderGetErrorString(state_ptr: *const BrotliEncoderState) -> *const u8 {
+  BrotliEncoderGetErrorString(state_ptr)
+}
+#[no_mangle]
+pub extern fn CBrotliEncoderErrorString(c: BrotliEncoderErrorCode) -> *const u8 {
+  BrotliEncoderErrorString(c)
+}
+#[no_mangle]
+pub extern fn CBrotliEncoderGetLastErrorCode() -> BrotliEncoderErrorCode {
+  BrotliEncoderGetLastErrorCode()
+}
+#[no_mangle]
+pub extern fn CBrotliEncoderGetLastPanicMessage() -> *const u8 {
+  BrotliEncoderGetLastPanicMessage()
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderParamsInit(params: *mut BrotliEncoderParamsC, size: usize) -> i32 {
+  BrotliEncoderParamsInit(params, size)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderSetParams(
+  state_ptr: *mut BrotliEncoderState,
+  params: *const BrotliEncoderParamsC,
+) -> i32 {
+  BrotliEncoderSetParams(state_ptr, params)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderGetParams(
+  state_ptr: *const BrotliEncoderState,
+  params: *mut BrotliEncoderParamsC,
+) -> i32 {
+  BrotliEncoderGetParams(state_ptr, params)
+}
+#[no_mangle]
+pub extern fn CBrotliEncoderVersion() -> u32 {
+  BrotliEncoderVersion()
+}
+#[no_mangle]
+pub extern fn CBrotliEncoderMaxCompressedSize(input_size: usize) -> usize {
+  BrotliEncoderMaxCompressedSize(input_size)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompress(
+  quality: i32,
+  lgwin: i32,
+  mode: BrotliEncoderMode,
+  input_size: usize,
+  input_buffer: *const u8,
+  encoded_size: *mut usize,
+  encoded_buffer: *mut u8) -> i32 {
+  BrotliEncoderCompress(quality, lgwin, mode, input_size, input_buffer, encoded_size, encoded_buffer)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompressStreaming(
+  state_ptr: *mut BrotliEncoderState,
+  op: BrotliEncoderOperation,
+  available_in: *mut usize,
+  input_buf: *const u8,
+  available_out: *mut usize,
+  output_buf: *mut u8,
+) -> i32 {
+  BrotliEncoderCompressStreaming(state_ptr, op, available_in, input_buf, available_out, output_buf)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompressStream(
+  state_ptr: *mut BrotliEncoderState,
+  op: BrotliEncoderOperation,
+  available_in: *mut usize,
+  input_buf_ptr: *mut*const u8,
+  available_out: *mut usize,
+  output_buf_ptr: *mut*mut u8,
+  total_out: *mut usize) -> i32 {
+  BrotliEncoderCompressStream(state_ptr, op, available_in, input_buf_ptr, available_out, output_buf_ptr, total_out)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderMallocU8(state_ptr: *mut BrotliEncoderState, size: usize) -> *mut u8 {
+  BrotliEncoderMallocU8(state_ptr, size)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderFreeU8(state_ptr: *mut BrotliEncoderState, data: *mut u8, size: usize) {
+  BrotliEncoderFreeU8(state_ptr, data, size)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderMallocUsize(state_ptr: *mut BrotliEncoderState, size: usize) -> *mut usize {
+  BrotliEncoderMallocUsize(state_ptr, size)
+}
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderFreeUsize(state_ptr: *mut BrotliEncoderState, data: *mut usize, size: usize) {
+  BrotliEncoderFreeUsize(state_ptr, data, size)
+}
+
 
 
 #[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
@@ -373,8 +1108,8 @@ fn catch_panic_cstate<F:FnOnce()->*mut BrotliEncoderState+panic::UnwindSafe>(f:
 }
 
 #[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
-fn error_print<Err:core::fmt::Debug>(err: Err) {
-    let _ign = writeln!(&mut io::stderr(), "Internal Error {:?}", err);
+pub fn error_print(err: ::enc::threading::LowLevelThreadError) {
+    let _ign = writeln!(&mut io::stderr(), "Internal Error {:?}", set_last_panic_message(&err));
 }
 
 // can't catch panics in a reliable way without std:: configure with panic=abort. These shouldn't happen
@@ -389,5 +1124,222 @@ fn catch_panic_cstate<F:FnOnce()->*mut BrotliEncoderState>(f: F) -> Result<*mut
 }
 
 #[cfg(any(not(feature="std"), feature="pass-through-ffi-panics"))]
-fn error_print<Err>(_err: Err) {
+pub fn error_print<Err>(_err: Err) {
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+  use super::*;
+  use ::enc::encode::BrotliEncoderParameter;
+  use std::ffi::CStr;
+  use std::os::raw::c_char;
+  use std::vec::Vec;
+
+  struct Replay {
+    input: Vec<u8>,
+    pos: usize,
+    metablocks: usize,
+    context_maps: usize,
+    literals: usize,
+  }
+
+  unsafe extern "C" fn replay_metablock(opaque: *mut c_void,
+                                        context_map: *const BrotliEncoderPredictionModeContextMap,
+                                        commands: *const BrotliEncoderIRCommand,
+                                        num_commands: usize) {
+    let replay = &mut *(opaque as *mut Replay);
+    replay.metablocks += 1;
+    if (*context_map).predmode_speed_and_distance_context_map_size != 0 {
+      replay.context_maps += 1;
+    }
+    for command in slice_from_raw_parts_or_nil(commands, num_commands).iter() {
+      match command.command_type {
+        BrotliEncoderIRCommandType::BROTLI_IR_LITERAL => {
+          let data = slice_from_raw_parts_or_nil(command.data, command.num_bytes as usize);
+          assert_eq!(data, &replay.input[replay.pos..replay.pos + data.len()]);
+          replay.literals += data.len();
+        },
+        BrotliEncoderIRCommandType::BROTLI_IR_COPY => {
+          let distance = command.distance as usize;
+          for index in replay.pos..replay.pos + command.num_bytes as usize {
+            assert_eq!(replay.input[index], replay.input[index - distance]);
+          }
+        },
+        _ => {},
+      }
+      match command.command_type {
+        BrotliEncoderIRCommandType::BROTLI_IR_LITERAL |
+        BrotliEncoderIRCommandType::BROTLI_IR_COPY |
+        BrotliEncoderIRCommandType::BROTLI_IR_DICT => replay.pos += command.num_bytes as usize,
+        _ => {},
+      }
+    }
+  }
+
+  #[test]
+  fn test_metablock_callback_replays_input() {
+    let mut input = Vec::new();
+    for index in 0..20000u32 {
+      input.extend_from_slice(&[(index % 251) as u8, (index * 7 % 13) as u8, b'x']);
+    }
+    let mut replay = Replay {
+      input: input.clone(),
+      pos: 0,
+      metablocks: 0,
+      context_maps: 0,
+      literals: 0,
+    };
+    let mut output = vec![0u8; BrotliEncoderMaxCompressedSize(input.len())];
+    unsafe {
+      let state = BrotliEncoderCreateInstance(None, None, core::ptr::null_mut());
+      BrotliEncoderSetParameter(state, BrotliEncoderParameter::BROTLI_PARAM_QUALITY, 5);
+      BrotliEncoderSetMetablockCallback(state, Some(replay_metablock), &mut replay as *mut Replay as *mut c_void);
+      let mut available_in = input.len();
+      let mut next_in = input.as_ptr();
+      let mut available_out = output.len();
+      let mut next_out = output.as_mut_ptr();
+      assert_eq!(BrotliEncoderCompressStream(state, BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+                                             &mut available_in, &mut next_in,
+                                             &mut available_out, &mut next_out,
+                                             core::ptr::null_mut()), 1);
+      assert_eq!(BrotliEncoderIsFinished(state), 1);
+      BrotliEncoderDestroyInstance(state);
+    }
+    assert!(replay.metablocks != 0 && replay.context_maps != 0);
+    assert!(replay.literals != 0 && replay.literals < input.len());
+    assert_eq!(replay.pos, input.len());
+  }
+
+  #[test]
+  fn test_prefixed_encoder_round_trips() {
+    let input = b"The same encoder under CBrotliEncoder names, the same encoder under CBrotliEncoder names";
+    let mut output = vec![0u8; CBrotliEncoderMaxCompressedSize(input.len())];
+    let mut output_size = output.len();
+    unsafe {
+      assert_eq!(CBrotliEncoderCompress(9, 16, BrotliEncoderMode::BROTLI_MODE_TEXT, input.len(), input.as_ptr(),
+                                        &mut output_size, output.as_mut_ptr()), 1);
+    }
+    let mut decompressed = Vec::new();
+    ::BrotliDecompress(&mut &output[..output_size], &mut decompressed).unwrap();
+    assert_eq!(&decompressed[..], &input[..]);
+  }
+
+  #[test]
+  fn test_error_codes_tell_failures_apart() {
+    let input = [b'a'; 4096];
+    let mut output = [0u8; 4];
+    unsafe {
+      let state = BrotliEncoderCreateInstance(None, None, core::ptr::null_mut());
+      assert_eq!(BrotliEncoderGetErrorCode(state), BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+      assert_eq!(BrotliEncoderSetParameter(state, BrotliEncoderParameter::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING, 2), 0);
+      assert_eq!(BrotliEncoderGetErrorCode(state), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+      assert_eq!(CStr::from_ptr(BrotliEncoderGetErrorString(state) as *const c_char).to_str().unwrap(),
+                 "ERROR_INVALID_PARAMETER");
+      assert_eq!(BrotliEncoderSetParameter(state, BrotliEncoderParameter::BROTLI_PARAM_QUALITY, 5), 1);
+      assert_eq!(BrotliEncoderGetErrorCode(state), BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+      BrotliEncoderDestroyInstance(state);
+
+      let mut output_size = output.len();
+      assert_eq!(BrotliEncoderCompress(5, 16, BrotliEncoderMode::BROTLI_MODE_GENERIC, input.len(), input.as_ptr(),
+                                       &mut output_size, output.as_mut_ptr()), 0);
+      assert_eq!(BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL);
+
+      // quality 10 is unimplemented and panics
+      let mut output_size = output.len();
+      assert_eq!(BrotliEncoderCompress(10, 16, BrotliEncoderMode::BROTLI_MODE_GENERIC, input.len(), input.as_ptr(),
+                                       &mut output_size, output.as_mut_ptr()), 0);
+      assert_eq!(BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC);
+      assert_eq!(CStr::from_ptr(BrotliEncoderGetLastPanicMessage() as *const c_char).to_str().unwrap(),
+                 "Unimplemented: need to set 9.5 here");
+    }
+  }
+
+  extern "C" fn unused_alloc(_opaque: *mut c_void, _size: usize) -> *mut c_void {
+    unreachable!();
+  }
+
+  #[test]
+  fn test_mismatched_allocators_fail_without_panicking() {
+    unsafe {
+      assert!(BrotliEncoderCreateInstance(Some(unused_alloc), None, core::ptr::null_mut()).is_null());
+      assert_eq!(BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+      assert_eq!(CStr::from_ptr(BrotliEncoderGetLastPanicMessage() as *const c_char).to_bytes().len(), 0);
+      assert!(super::super::custom_io::BrotliEncoderCreateReader(core::ptr::null(), 0, None, core::ptr::null_mut(),
+                                                                 Some(unused_alloc), None, core::ptr::null_mut()).is_null());
+      assert!(::ffi::decompressor::CBrotliDecoderCreateInstance(Some(unused_alloc), None, core::ptr::null_mut()).is_null());
+      assert!(allocators_usable(None, None));
+    }
+  }
+
+  #[test]
+  fn test_params_struct_round_trips_and_rejects_unknown_fields() {
+    let input = [b'a'; 4096];
+    let mut output = vec![0u8; BrotliEncoderMaxCompressedSize(input.len())];
+    // a caller built against a newer header with one more field
+    let mut newer = [0u64; 1 + core::mem::size_of::<BrotliEncoderParamsC>() / 8];
+    let params = newer.as_mut_ptr() as *mut BrotliEncoderParamsC;
+    unsafe {
+      assert_eq!(BrotliEncoderParamsInit(params, BROTLI_ENCODER_PARAMS_C_V1_SIZE - 1), 0);
+      assert_eq!(BrotliEncoderParamsInit(params, core::mem::size_of_val(&newer)), 1);
+      assert_eq!((*params).quality, 11);
+      (*params).quality = 5;
+      (*params).lgwin = 18;
+      (*params).size_hint = 1 << 40;
+      (*params).literal_adaptation[5] = 7;
+      let state = BrotliEncoderCreateInstance(None, None, core::ptr::null_mut());
+      *newer.last_mut().unwrap() = 1;
+      assert_eq!(BrotliEncoderSetParams(state, params), 0);
+      assert_eq!(BrotliEncoderGetErrorCode(state), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+      *newer.last_mut().unwrap() = 0;
+      assert_eq!(BrotliEncoderSetParams(state, params), 1);
+      let mut read_back = params_c(&::enc::BrotliEncoderParams::default());
+      assert_eq!(BrotliEncoderGetParams(state, &mut read_back), 1);
+      assert_eq!((read_back.quality, read_back.lgwin), (5, 18));
+      assert_eq!(read_back.size_hint, core::cmp::min(1 << 40, usize::MAX as u64));
+      assert_eq!((*state).compressor.params.literal_adaptation[2], (0, 7));
+      let mut available_in = input.len();
+      let mut next_in = input.as_ptr();
+      let mut available_out = output.len();
+      let mut next_out = output.as_mut_ptr();
+      assert_eq!(BrotliEncoderCompressStream(state, BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+                                             &mut available_in, &mut next_in,
+                                             &mut available_out, &mut next_out,
+                                             core::ptr::null_mut()), 1);
+      assert_eq!(BrotliEncoderSetParams(state, params), 0);
+      BrotliEncoderDestroyInstance(state);
+      let encoded_len = output.len() - available_out;
+      let mut decompressed = Vec::new();
+      ::BrotliDecompress(&mut &output[..encoded_len], &mut decompressed).unwrap();
+      assert_eq!(&decompressed[..], &input[..]);
+    }
+  }
+
+  #[test]
+  fn test_params_struct_accepts_the_v1_layout() {
+    let full = params_c(&::enc::BrotliEncoderParams::default());
+    let v1_end = &full.favor_cpu_efficiency as *const i32 as usize + 4 - &full as *const BrotliEncoderParamsC as usize;
+    assert_eq!(BROTLI_ENCODER_PARAMS_C_V1_SIZE, v1_end);
+    // a caller built against a header whose struct ended with favor_cpu_efficiency, followed by bytes it owns
+    let mut older = [!0u64; core::mem::size_of::<BrotliEncoderParamsC>() / 8];
+    let params = older.as_mut_ptr() as *mut BrotliEncoderParamsC;
+    let caller_owned = |older: &[u64]| unsafe {
+      slice_from_raw_parts_or_nil(older.as_ptr() as *const u8, core::mem::size_of_val(older))
+    }[BROTLI_ENCODER_PARAMS_C_V1_SIZE..].iter().all(|item| *item == 0xff);
+    unsafe {
+      assert_eq!(BrotliEncoderParamsInit(params, BROTLI_ENCODER_PARAMS_C_V1_SIZE), 1);
+      assert!(caller_owned(&older));
+      (*params).quality = 5;
+      let state = BrotliEncoderCreateInstance(None, None, core::ptr::null_mut());
+      (*state).compressor.params.hasher.bucket_bits = 3;
+      assert_eq!(BrotliEncoderSetParams(state, params), 1);
+      assert_eq!((*state).compressor.params.quality, 5);
+      // the fields past the end of the caller's struct are set to their defaults rather than read
+      assert_eq!((*state).compressor.params.hasher.bucket_bits, ::enc::BrotliEncoderParams::default().hasher.bucket_bits);
+      assert_eq!(BrotliEncoderGetParams(state, params), 1);
+      assert_eq!((*params).quality, 5);
+      assert!(caller_owned(&older));
+      BrotliEncoderDestroyInstance(state);
+    }
+  }
 }
@@ -0,0 +1,623 @@
+#![cfg(not(feature="safe"))]
+
+#[cfg(feature="std")]
+use std::{panic, thread};
+
+use core;
+use brotli_decompressor::ffi::alloc_util::SubclassableAllocator;
+use brotli_decompressor::ffi::interface::{
+  brotli_alloc_func,
+  brotli_free_func,
+  CAllocator,
+  c_void,
+};
+use brotli_decompressor::ffi::{
+  slice_from_raw_parts_or_nil,
+  slice_from_raw_parts_or_nil_mut,
+};
+use brotli_decompressor::{CustomRead, CustomWrite};
+use ::enc::reader::CompressorReaderCustomIo;
+use ::enc::writer::CompressorWriterCustomIo;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use super::alloc_util::{BrotliSubclassableAllocator, box_with_allocator, free_with_allocator};
+use super::compressor::{
+  BrotliEncoderErrorCode,
+  BrotliEncoderParamsC,
+  allocators_usable,
+  catch_panic,
+  error_code_from_i32,
+  error_print,
+  set_last_error_code,
+};
+
+/// Consumes up to size bytes of compressed data and returns how many it took, or 0 or less on failure.
+pub type brotli_encoder_write_func = Option<unsafe extern "C" fn(opaque: *mut c_void,
+                                                                 data: *const u8,
+                                                                 size: usize) -> isize>;
+
+/// Fills up to size bytes of data with input and returns how many it wrote, 0 at the end of
+/// the input or less than 0 on failure.
+pub type brotli_encoder_read_func = Option<unsafe extern "C" fn(opaque: *mut c_void,
+                                                                data: *mut u8,
+                                                                size: usize) -> isize>;
+
+// the staging buffer of a writer or reader, handed back to the allocator it came from when dropped
+pub struct CallbackBuffer {
+  alloc: BrotliSubclassableAllocator,
+  data: <BrotliSubclassableAllocator as Allocator<u8>>::AllocatedMemory,
+}
+
+impl CallbackBuffer {
+  unsafe fn new(allocators: &CAllocator, size: usize) -> CallbackBuffer {
+    let mut alloc = BrotliSubclassableAllocator::new(SubclassableAllocator::new(allocators.clone()));
+    let data = <BrotliSubclassableAllocator as Allocator<u8>>::alloc_cell(&mut alloc, if size == 0 { 4096 } else { size });
+    CallbackBuffer {
+      alloc: alloc,
+      data: data,
+    }
+  }
+}
+
+impl SliceWrapper<u8> for CallbackBuffer {
+  fn slice(&self) -> &[u8] {
+    self.data.slice()
+  }
+}
+
+impl SliceWrapperMut<u8> for CallbackBuffer {
+  fn slice_mut(&mut self) -> &mut [u8] {
+    self.data.slice_mut()
+  }
+}
+
+impl Drop for CallbackBuffer {
+  fn drop(&mut self) {
+    let data = core::mem::replace(&mut self.data, Default::default());
+    <BrotliSubclassableAllocator as Allocator<u8>>::free_cell(&mut self.alloc, data);
+  }
+}
+
+pub struct CallbackWriter {
+  func: brotli_encoder_write_func,
+  opaque: *mut c_void,
+}
+
+impl CustomWrite<BrotliEncoderErrorCode> for CallbackWriter {
+  fn write(&mut self, data: &[u8]) -> Result<usize, BrotliEncoderErrorCode> {
+    if let Some(func) = self.func {
+      let written = unsafe { func(self.opaque, data.as_ptr(), data.len()) };
+      if written > 0 && written as usize <= data.len() {
+        return Ok(written as usize);
+      }
+    }
+    Err(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CALLBACK)
+  }
+  fn flush(&mut self) -> Result<(), BrotliEncoderErrorCode> {
+    Ok(())
+  }
+}
+
+pub struct CallbackReader {
+  func: brotli_encoder_read_func,
+  opaque: *mut c_void,
+}
+
+impl CustomRead<BrotliEncoderErrorCode> for CallbackReader {
+  fn read(&mut self, data: &mut [u8]) -> Result<usize, BrotliEncoderErrorCode> {
+    if let Some(func) = self.func {
+      let read = unsafe { func(self.opaque, data.as_mut_ptr(), data.len()) };
+      if read >= 0 && read as usize <= data.len() {
+        return Ok(read as usize);
+      }
+    }
+    Err(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CALLBACK)
+  }
+}
+
+#[repr(C)]
+pub struct BrotliEncoderWriter {
+  pub custom_allocator: CAllocator,
+  pub writer: CompressorWriterCustomIo<BrotliEncoderErrorCode,
+                                       CallbackWriter,
+                                       CallbackBuffer,
+                                       BrotliSubclassableAllocator>,
+  pub error_code: BrotliEncoderErrorCode,
+}
+
+#[repr(C)]
+pub struct BrotliEncoderReader {
+  pub custom_allocator: CAllocator,
+  pub reader: CompressorReaderCustomIo<BrotliEncoderErrorCode,
+                                       CallbackReader,
+                                       CallbackBuffer,
+                                       BrotliSubclassableAllocator>,
+  pub error_code: BrotliEncoderErrorCode,
+}
+
+// the parameters a writer or reader starts with: the defaults if params is NULL
+unsafe fn params_from_c(params: *const BrotliEncoderParamsC) -> Result<::enc::BrotliEncoderParams, BrotliEncoderErrorCode> {
+  let mut ret = ::enc::BrotliEncoderParams::default();
+  if params.is_null() {
+    return Ok(ret);
+  }
+  let params = match super::compressor::read_params_c(params) {
+    Ok(params) => params,
+    Err(code) => return Err(code),
+  };
+  if !super::compressor::apply_params_c(&params, &mut ret) {
+    return Err(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+  }
+  Ok(ret)
+}
+
+// records code both on the handle and as the last error of this thread
+fn finish_with(handle_code: &mut BrotliEncoderErrorCode, code: BrotliEncoderErrorCode) -> i32 {
+  *handle_code = code;
+  set_last_error_code(code);
+  if code == BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR { 1 } else { 0 }
+}
+
+/// Creates a writer that compresses whatever it is given and hands the output to write_func.
+/// params may be NULL for the defaults; buffer_size of 0 picks 4096 bytes.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderCreateWriter(
+  params: *const BrotliEncoderParamsC,
+  buffer_size: usize,
+  write_func: brotli_encoder_write_func,
+  write_opaque: *mut c_void,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void,
+) -> *mut BrotliEncoderWriter {
+  if write_func.is_none() || !allocators_usable(alloc_func, free_func) {
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return core::ptr::null_mut();
+  }
+  let params = match params_from_c(params) {
+    Ok(params) => params,
+    Err(code) => {
+      set_last_error_code(code);
+      return core::ptr::null_mut();
+    }
+  };
+  match catch_panic_ptr(|| {
+    let allocators = CAllocator {
+      alloc_func: alloc_func,
+      free_func: free_func,
+      opaque: opaque,
+    };
+    let to_box = BrotliEncoderWriter {
+      custom_allocator: allocators.clone(),
+      writer: CompressorWriterCustomIo::with_params(
+        CallbackWriter {
+          func: write_func,
+          opaque: write_opaque,
+        },
+        CallbackBuffer::new(&allocators, buffer_size),
+        BrotliSubclassableAllocator::new(SubclassableAllocator::new(allocators.clone())),
+        BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION,
+        &params),
+      error_code: BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR,
+    };
+    box_with_allocator(&allocators, to_box)
+  }) {
+    Ok(ret) => {
+      set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+      ret
+    },
+    Err(err) => {
+      error_print(err);
+      core::ptr::null_mut()
+    }
+  }
+}
+
+/// Compresses all of input, calling write_func whenever the buffer fills. After a failure
+/// every further call on writer fails with the same error.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderWriterWrite(
+  writer: *mut BrotliEncoderWriter,
+  input_size: usize,
+  input: *const u8,
+) -> i32 {
+  if writer.is_null() {
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return 0;
+  }
+  if (*writer).error_code != BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR {
+    let code = (*writer).error_code;
+    return finish_with(&mut (*writer).error_code, code);
+  }
+  match catch_panic(|| {
+    let input_buf = slice_from_raw_parts_or_nil(input, input_size);
+    match (*writer).writer.write(input_buf) {
+      Ok(_) => BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR as i32,
+      Err(code) => code as i32,
+    }
+  }) {
+    Ok(code) => finish_with(&mut (*writer).error_code, error_code_from_i32(code)),
+    Err(err) => {
+      error_print(err);
+      finish_with(&mut (*writer).error_code, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC)
+    }
+  }
+}
+
+/// Emits everything written so far as complete metablocks through write_func.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderWriterFlush(writer: *mut BrotliEncoderWriter) -> i32 {
+  writer_op(writer, false)
+}
+
+/// Ends the stream and hands its last bytes to write_func; further writes fail.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderWriterFinish(writer: *mut BrotliEncoderWriter) -> i32 {
+  writer_op(writer, true)
+}
+
+unsafe fn writer_op(writer: *mut BrotliEncoderWriter, finish: bool) -> i32 {
+  if writer.is_null() {
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return 0;
+  }
+  if (*writer).error_code != BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR {
+    let code = (*writer).error_code;
+    return finish_with(&mut (*writer).error_code, code);
+  }
+  match catch_panic(|| {
+    let result = if finish {
+      (*writer).writer.finish()
+    } else {
+      (*writer).writer.flush()
+    };
+    match result {
+      Ok(_) => BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR as i32,
+      Err(code) => code as i32,
+    }
+  }) {
+    Ok(code) => finish_with(&mut (*writer).error_code, error_code_from_i32(code)),
+    Err(err) => {
+      error_print(err);
+      finish_with(&mut (*writer).error_code, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC)
+    }
+  }
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderWriterGetErrorCode(writer: *const BrotliEncoderWriter) -> BrotliEncoderErrorCode {
+  if writer.is_null() {
+    return BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS;
+  }
+  (*writer).error_code
+}
+
+/// Frees writer, first finishing the stream unless that was done or writer failed.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderDestroyWriter(writer: *mut BrotliEncoderWriter) {
+  if writer.is_null() {
+    return;
+  }
+  if (*writer).error_code != BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR {
+    // don't bother a callback that already failed with the end of the stream
+    (*writer).writer.get_mut().func = None;
+  }
+  let allocators = (*writer).custom_allocator.clone();
+  free_with_allocator(writer, allocators);
+}
+
+/// Creates a reader that pulls its input through read_func and hands out the compressed stream.
+/// params may be NULL for the defaults; buffer_size of 0 picks 4096 bytes.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderCreateReader(
+  params: *const BrotliEncoderParamsC,
+  buffer_size: usize,
+  read_func: brotli_encoder_read_func,
+  read_opaque: *mut c_void,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void,
+) -> *mut BrotliEncoderReader {
+  if read_func.is_none() || !allocators_usable(alloc_func, free_func) {
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return core::ptr::null_mut();
+  }
+  let params = match params_from_c(params) {
+    Ok(params) => params,
+    Err(code) => {
+      set_last_error_code(code);
+      return core::ptr::null_mut();
+    }
+  };
+  match catch_panic_ptr(|| {
+    let allocators = CAllocator {
+      alloc_func: alloc_func,
+      free_func: free_func,
+      opaque: opaque,
+    };
+    let to_box = BrotliEncoderReader {
+      custom_allocator: allocators.clone(),
+      reader: CompressorReaderCustomIo::with_params(
+        CallbackReader {
+          func: read_func,
+          opaque: read_opaque,
+        },
+        CallbackBuffer::new(&allocators, buffer_size),
+        BrotliSubclassableAllocator::new(SubclassableAllocator::new(allocators.clone())),
+        BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION,
+        &params),
+      error_code: BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR,
+    };
+    box_with_allocator(&allocators, to_box)
+  }) {
+    Ok(ret) => {
+      set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+      ret
+    },
+    Err(err) => {
+      error_print(err);
+      core::ptr::null_mut()
+    }
+  }
+}
+
+/// Fills encoded with up to *encoded_size bytes of the compressed stream, calling read_func
+/// as needed, and sets *encoded_size to how many it wrote: 0 once the stream has ended.
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderReaderRead(
+  reader: *mut BrotliEncoderReader,
+  encoded_size: *mut usize,
+  encoded: *mut u8,
+) -> i32 {
+  if reader.is_null() || encoded_size.is_null() || *encoded_size == 0 {
+    if !encoded_size.is_null() {
+      *encoded_size = 0;
+    }
+    set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return 0;
+  }
+  let capacity = *encoded_size;
+  *encoded_size = 0;
+  if (*reader).error_code != BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR {
+    let code = (*reader).error_code;
+    return finish_with(&mut (*reader).error_code, code);
+  }
+  match catch_panic(|| {
+    let output_buf = slice_from_raw_parts_or_nil_mut(encoded, capacity);
+    match (*reader).reader.read(output_buf) {
+      Ok(size) => {
+        *encoded_size = size;
+        BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR as i32
+      },
+      Err(code) => code as i32,
+    }
+  }) {
+    Ok(code) => finish_with(&mut (*reader).error_code, error_code_from_i32(code)),
+    Err(err) => {
+      error_print(err);
+      finish_with(&mut (*reader).error_code, BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_PANIC)
+    }
+  }
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderReaderGetErrorCode(reader: *const BrotliEncoderReader) -> BrotliEncoderErrorCode {
+  if reader.is_null() {
+    return BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS;
+  }
+  (*reader).error_code
+}
+
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderDestroyReader(reader: *mut BrotliEncoderReader) {
+  if reader.is_null() {
+    return;
+  }
+  let allocators = (*reader).custom_allocator.clone();
+  free_with_allocator(reader, allocators);
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCreateWriter(
+  params: *const BrotliEncoderParamsC,
+  buffer_size: usize,
+  write_func: brotli_encoder_write_func,
+  write_opaque: *mut c_void,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void,
+) -> *mut BrotliEncoderWriter {
+  BrotliEncoderCreateWriter(params, buffer_size, write_func, write_opaque, alloc_func, free_func, opaque)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderWriterWrite(
+  writer: *mut BrotliEncoderWriter,
+  input_size: usize,
+  input: *const u8,
+) -> i32 {
+  BrotliEncoderWriterWrite(writer, input_size, input)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderWriterFlush(writer: *mut BrotliEncoderWriter) -> i32 {
+  BrotliEncoderWriterFlush(writer)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderWriterFinish(writer: *mut BrotliEncoderWriter) -> i32 {
+  BrotliEncoderWriterFinish(writer)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderWriterGetErrorCode(writer: *const BrotliEncoderWriter) -> BrotliEncoderErrorCode {
+  BrotliEncoderWriterGetErrorCode(writer)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderDestroyWriter(writer: *mut BrotliEncoderWriter) {
+  BrotliEncoderDestroyWriter(writer)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCreateReader(
+  params: *const BrotliEncoderParamsC,
+  buffer_size: usize,
+  read_func: brotli_encoder_read_func,
+  read_opaque: *mut c_void,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void,
+) -> *mut BrotliEncoderReader {
+  BrotliEncoderCreateReader(params, buffer_size, read_func, read_opaque, alloc_func, free_func, opaque)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderReaderRead(
+  reader: *mut BrotliEncoderReader,
+  encoded_size: *mut usize,
+  encoded: *mut u8,
+) -> i32 {
+  BrotliEncoderReaderRead(reader, encoded_size, encoded)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderReaderGetErrorCode(reader: *const BrotliEncoderReader) -> BrotliEncoderErrorCode {
+  BrotliEncoderReaderGetErrorCode(reader)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderDestroyReader(reader: *mut BrotliEncoderReader) {
+  BrotliEncoderDestroyReader(reader)
+}
+
+#[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
+fn catch_panic_ptr<T, F:FnOnce()->*mut T+panic::UnwindSafe>(f: F) -> thread::Result<*mut T> {
+    panic::catch_unwind(f)
+}
+
+#[cfg(any(not(feature="std"), feature="pass-through-ffi-panics"))]
+fn catch_panic_ptr<T, F:FnOnce()->*mut T>(f: F) -> Result<*mut T, ()> {
+    Ok(f())
+}
+
+#[cfg(test)]
+#[cfg(feature="std")]
+mod test {
+  use super::*;
+  use super::super::compressor::{BrotliEncoderParamsInit, BrotliEncoderGetLastErrorCode};
+  use std::vec::Vec;
+
+  unsafe extern "C" fn collect(opaque: *mut c_void, data: *const u8, size: usize) -> isize {
+    let output = &mut *(opaque as *mut Vec<u8>);
+    // take at most 100 bytes at a time to exercise short writes
+    let size = core::cmp::min(size, 100);
+    output.extend_from_slice(slice_from_raw_parts_or_nil(data, size));
+    size as isize
+  }
+
+  unsafe extern "C" fn refuse(_opaque: *mut c_void, _data: *const u8, _size: usize) -> isize {
+    -1
+  }
+
+  unsafe extern "C" fn feed(opaque: *mut c_void, data: *mut u8, size: usize) -> isize {
+    let input = &mut *(opaque as *mut &[u8]);
+    let size = core::cmp::min(core::cmp::min(size, input.len()), 777);
+    slice_from_raw_parts_or_nil_mut(data, size).clone_from_slice(&input[..size]);
+    *input = &input[size..];
+    size as isize
+  }
+
+  fn test_input() -> Vec<u8> {
+    let mut input = Vec::new();
+    for index in 0..20000u32 {
+      input.extend_from_slice(&[(index % 251) as u8, (index * 7 % 13) as u8, b'y']);
+    }
+    input
+  }
+
+  fn test_params() -> BrotliEncoderParamsC {
+    unsafe {
+      let mut params: BrotliEncoderParamsC = core::mem::zeroed();
+      assert_eq!(BrotliEncoderParamsInit(&mut params, core::mem::size_of::<BrotliEncoderParamsC>()), 1);
+      params.quality = 5;
+      params.lgwin = 18;
+      params
+    }
+  }
+
+  #[test]
+  fn test_writer_round_trips_through_callback() {
+    let input = test_input();
+    let params = test_params();
+    let mut output = Vec::<u8>::new();
+    unsafe {
+      let writer = BrotliEncoderCreateWriter(&params, 512, Some(collect), &mut output as *mut Vec<u8> as *mut c_void,
+                                             None, None, core::ptr::null_mut());
+      assert!(!writer.is_null());
+      for chunk in input.chunks(3000) {
+        assert_eq!(BrotliEncoderWriterWrite(writer, chunk.len(), chunk.as_ptr()), 1);
+      }
+      assert_eq!(BrotliEncoderWriterFlush(writer), 1);
+      let mut flushed = Vec::new();
+      ::BrotliDecompress(&mut &output[..], &mut flushed).unwrap_err();
+      assert_eq!(BrotliEncoderWriterFinish(writer), 1);
+      let finished_len = output.len();
+      assert_eq!(BrotliEncoderWriterWrite(writer, 1, input.as_ptr()), 0);
+      assert_eq!(BrotliEncoderWriterGetErrorCode(writer), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION);
+      BrotliEncoderDestroyWriter(writer);
+      assert_eq!(output.len(), finished_len);
+    }
+    let mut decompressed = Vec::new();
+    ::BrotliDecompress(&mut &output[..], &mut decompressed).unwrap();
+    assert_eq!(decompressed, input);
+  }
+
+  #[test]
+  fn test_writer_reports_callback_failure() {
+    let input = test_input();
+    let params = test_params();
+    unsafe {
+      let writer = BrotliEncoderCreateWriter(&params, 0, Some(refuse), core::ptr::null_mut(),
+                                             None, None, core::ptr::null_mut());
+      assert_eq!(BrotliEncoderWriterWrite(writer, input.len(), input.as_ptr()), 1);
+      assert_eq!(BrotliEncoderWriterFinish(writer), 0);
+      assert_eq!(BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CALLBACK);
+      assert_eq!(BrotliEncoderWriterFlush(writer), 0);
+      assert_eq!(BrotliEncoderWriterGetErrorCode(writer), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_CALLBACK);
+      BrotliEncoderDestroyWriter(writer);
+      assert!(BrotliEncoderCreateWriter(core::ptr::null(), 0, None, core::ptr::null_mut(),
+                                        None, None, core::ptr::null_mut()).is_null());
+      assert_eq!(BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    }
+  }
+
+  #[test]
+  fn test_reader_round_trips_through_callback() {
+    let input = test_input();
+    let mut remaining = &input[..];
+    let params = test_params();
+    let mut output = Vec::<u8>::new();
+    unsafe {
+      let reader = BrotliEncoderCreateReader(&params, 1024, Some(feed), &mut remaining as *mut &[u8] as *mut c_void,
+                                             None, None, core::ptr::null_mut());
+      assert!(!reader.is_null());
+      let mut buffer = [0u8; 333];
+      loop {
+        let mut size = buffer.len();
+        assert_eq!(BrotliEncoderReaderRead(reader, &mut size, buffer.as_mut_ptr()), 1);
+        if size == 0 {
+          break;
+        }
+        output.extend_from_slice(&buffer[..size]);
+      }
+      let mut size = 0;
+      assert_eq!(BrotliEncoderReaderRead(reader, &mut size, buffer.as_mut_ptr()), 0);
+      assert_eq!(BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+      BrotliEncoderDestroyReader(reader);
+    }
+    assert!(output.len() < input.len());
+    let mut decompressed = Vec::new();
+    ::BrotliDecompress(&mut &output[..], &mut decompressed).unwrap();
+    assert_eq!(decompressed, input);
+  }
+}
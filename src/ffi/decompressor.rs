@@ -11,6 +11,10 @@ pub unsafe extern fn CBrotliDecoderCreateInstance(
     free_func: brotli_free_func,
     opaque: *mut c_void,
 ) -> *mut ffi::BrotliDecoderState {
+   if !super::compressor::allocators_usable(alloc_func, free_func) {
+       // the decoder would panic over these, which without std means an abort
+       return core::ptr::null_mut();
+   }
    ffi::BrotliDecoderCreateInstance(alloc_func, free_func, opaque)
 }
 
@@ -1,13 +1,12 @@
 #![cfg(not(feature="safe"))]
 #[cfg(feature="std")]
-use std::{panic,thread, io};
-#[cfg(feature="std")]
-use std::io::Write;
+use std::{panic,thread};
 mod test;
 use core;
 #[allow(unused_imports)]
 use brotli_decompressor;
 use super::compressor;
+use super::compressor::{BrotliEncoderErrorCode, error_print};
 use ::enc::encode::{BrotliEncoderCreateInstance,
                     BrotliEncoderOperation,
                     BrotliEncoderSetParameter,
@@ -35,6 +34,12 @@ use ::enc::threading::{SendAlloc,Owned};
 use alloc::SliceWrapper;
 pub const MAX_THREADS: usize = 16;
 
+// records code as the outcome of the call for BrotliEncoderGetLastErrorCode and returns it as a BROTLI_BOOL
+fn finish_with(code: BrotliEncoderErrorCode) -> i32 {
+  compressor::set_last_error_code(code);
+  if code == BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR { 1 } else { 0 }
+}
+
 struct SliceRef<'a> (&'a [u8]);
 impl<'a> SliceWrapper<u8> for SliceRef<'a> {
     fn slice(&self) -> &[u8] { self.0 }
@@ -51,9 +56,10 @@ macro_rules! make_send_alloc {
         })), UnionHasher::Uninit)
   )
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub extern fn BrotliEncoderMaxCompressedSizeMulti(input_size: usize, num_threads: usize) -> usize {
-  ::enc::encode::BrotliEncoderMaxCompressedSizeMulti(input_size, num_threads)
+  // the C interface always cuts the input into one chunk per thread
+  ::enc::encode::BrotliEncoderMaxCompressedSizeMulti(input_size, &::enc::UnionChunkingPolicy::default(), num_threads)
 }
 
 fn help_brotli_encoder_compress_single(
@@ -92,7 +98,7 @@ fn help_brotli_encoder_compress_single(
   result
 }
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCompressMulti(
   num_params: usize,
   param_keys: *const BrotliEncoderParameter,
@@ -106,8 +112,8 @@ pub unsafe extern fn BrotliEncoderCompressMulti(
   free_func: brotli_free_func,
   alloc_opaque_per_thread: *mut*mut c_void,
 ) -> i32 {
-  if desired_num_threads == 0 {
-    return 0;
+  if desired_num_threads == 0 || !compressor::allocators_usable(alloc_func, free_func) {
+    return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
   }
   let num_threads = core::cmp::min(desired_num_threads, MAX_THREADS);
   match compressor::catch_panic(|| {
@@ -123,14 +129,17 @@ pub unsafe extern fn BrotliEncoderCompressMulti(
       };
       let m8 = BrotliSubclassableAllocator::new(
         SubclassableAllocator::new(allocators.clone()));
-      return help_brotli_encoder_compress_single(
+      if help_brotli_encoder_compress_single(
         param_keys_slice,
         param_values_slice,
         input_slice,
         output_slice,
         &mut *encoded_size,
         m8,
-      )
+      ) == 0 {
+        return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL);
+      }
+      return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
     }
     let null_opaques = [core::ptr::null_mut::<c_void>();MAX_THREADS];
     let alloc_opaque = if alloc_opaque_per_thread.is_null() {
@@ -141,7 +150,7 @@ pub unsafe extern fn BrotliEncoderCompressMulti(
     let mut params = BrotliEncoderParams::default();
     for (k,v) in param_keys_slice.iter().zip(param_values_slice.iter()) {
       if set_parameter(&mut params, *k, *v) == 0 {
-        return 0;
+        return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
       }
     }
     let mut alloc_array:[_;MAX_THREADS] = [
@@ -173,9 +182,10 @@ pub unsafe extern fn BrotliEncoderCompressMulti(
     match res {
       Ok(size) => {
         *encoded_size = size;
-        return 1;
+        return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
       },
-      Err(_err) => {
+      Err(err) => {
+        compressor::set_last_thread_error(&err);
         return 0;
       }
     }
@@ -194,12 +204,13 @@ pub struct BrotliEncoderWorkPool {
   work_pool: enc::WorkerPool<enc::CompressionThreadResult<BrotliSubclassableAllocator>,
                              UnionHasher<BrotliSubclassableAllocator>,
                              BrotliSubclassableAllocator,
-                             (SliceRef<'static>, BrotliEncoderParams)>,
+                             enc::CompressionThreadInput<SliceRef<'static>>>,
 }
 
 #[cfg(not(feature="std"))]
 fn brotli_new_work_pool_without_custom_alloc(_to_box: BrotliEncoderWorkPool) -> *mut BrotliEncoderWorkPool{
-    panic!("Must supply allocators if calling divans when compiled without features=std");
+    // allocators_usable already turned the caller away
+    core::ptr::null_mut()
 }
 
 #[cfg(feature="std")]
@@ -207,13 +218,22 @@ fn brotli_new_work_pool_without_custom_alloc(to_box: BrotliEncoderWorkPool) -> *
     brotli_decompressor::ffi::alloc_util::Box::<BrotliEncoderWorkPool>::into_raw(
         brotli_decompressor::ffi::alloc_util::Box::<BrotliEncoderWorkPool>::new(to_box))
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCreateWorkPool(
   num_threads: usize,
   alloc_func: brotli_alloc_func,
   free_func: brotli_free_func,
   opaque: *mut c_void,
 ) -> *mut BrotliEncoderWorkPool {
+  if !cfg!(feature="std") {
+    // no threads to pool: BrotliEncoderCompressWorkPool compresses on the caller's thread given NULL
+    compressor::set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_OPERATION);
+    return core::ptr::null_mut();
+  }
+  if !compressor::allocators_usable(alloc_func, free_func) {
+    compressor::set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return core::ptr::null_mut();
+  }
   match catch_panic_wstate(|| {
     let allocators = CAllocator {
     
@@ -257,8 +277,11 @@ struct UnsafeUnwindBox(*mut BrotliEncoderWorkPool);
 #[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
 impl panic::RefUnwindSafe for UnsafeUnwindBox{}
 
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderDestroyWorkPool(work_pool_ptr: *mut BrotliEncoderWorkPool) {
+  if work_pool_ptr.is_null() {
+    return;
+  }
   let wpp = UnsafeUnwindBox(work_pool_ptr);
   if let Err(panic_err) = compressor::catch_panic(|| {
   if let Some(_) = (*wpp.0).custom_allocator.alloc_func {
@@ -275,7 +298,7 @@ pub unsafe extern fn BrotliEncoderDestroyWorkPool(work_pool_ptr: *mut BrotliEnco
       error_print(panic_err);
   }
 }
-#[no_mangle]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
 pub unsafe extern fn BrotliEncoderCompressWorkPool(
   work_pool: *mut BrotliEncoderWorkPool,
   num_params: usize,
@@ -290,8 +313,8 @@ pub unsafe extern fn BrotliEncoderCompressWorkPool(
   free_func: brotli_free_func,
   alloc_opaque_per_thread: *mut*mut c_void,
 ) -> i32 {
-  if desired_num_threads == 0 {
-    return 0;
+  if desired_num_threads == 0 || !compressor::allocators_usable(alloc_func, free_func) {
+    return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
   }
   if work_pool.is_null() {
     match compressor::catch_panic(|| BrotliEncoderCompressMulti(
@@ -326,7 +349,7 @@ pub unsafe extern fn BrotliEncoderCompressWorkPool(
     let mut params = BrotliEncoderParams::default();
     for (k,v) in param_keys_slice.iter().zip(param_values_slice.iter()) {
       if set_parameter(&mut params, *k, *v) == 0 {
-        return 0;
+        return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
       }
     }
     let num_threads = core::cmp::min(desired_num_threads, MAX_THREADS);
@@ -354,13 +377,16 @@ pub unsafe extern fn BrotliEncoderCompressWorkPool(
       slice_from_raw_parts_or_nil_mut(encoded, *encoded_size),
       &mut alloc_array[..num_threads],
       &mut (*work_pool_wrapper.0).work_pool,
+      0,
+      &mut |_, _, _, _| (),
     );
     match res {
       Ok(size) => {
         *encoded_size = size;
-        return 1;
+        return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
       },
-      Err(_err) => {
+      Err(err) => {
+        compressor::set_last_thread_error(&err);
         return 0;
       },
     }
@@ -373,21 +399,252 @@ pub unsafe extern fn BrotliEncoderCompressWorkPool(
   }
 }
 
+/// a compression running in the background on a work pool, from BrotliEncoderCompressWorkPoolSubmit
+#[cfg(feature="std")]
+pub struct BrotliEncoderWorkPoolJob {
+  pending: Option<enc::PendingCompression<SliceRef<'static>>>,
+  // output kept from a BrotliEncoderCompressWorkPoolWait call whose buffer was too small
+  compressed: Option<std::vec::Vec<u8>>,
+}
+
+#[cfg(feature="std")]
+struct UnsafeUnwindJob(*mut BrotliEncoderWorkPoolJob);
+#[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
+impl panic::RefUnwindSafe for UnsafeUnwindJob{}
+
+#[cfg(feature="std")]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderCompressWorkPoolSubmit(
+  work_pool: *mut BrotliEncoderWorkPool,
+  num_params: usize,
+  param_keys: *const BrotliEncoderParameter,
+  param_values: *const u32,
+  input_size: usize,
+  input: *const u8,
+  desired_num_threads: usize,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  alloc_opaque_per_thread: *mut*mut c_void,
+) -> *mut BrotliEncoderWorkPoolJob {
+  if desired_num_threads == 0 || work_pool.is_null() {
+    compressor::set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    return core::ptr::null_mut();
+  }
+  let work_pool_wrapper = UnsafeUnwindBox(work_pool);
+  match catch_panic_job(|| {
+    let alloc_opaque = if alloc_opaque_per_thread.is_null() {
+      None
+    } else {
+      Some(slice_from_raw_parts_or_nil(alloc_opaque_per_thread, desired_num_threads))
+    };
+    let param_keys_slice = slice_from_raw_parts_or_nil(param_keys, num_params);
+    let param_values_slice = slice_from_raw_parts_or_nil(param_values, num_params);
+    let mut params = BrotliEncoderParams::default();
+    for (k,v) in param_keys_slice.iter().zip(param_values_slice.iter()) {
+      if set_parameter(&mut params, *k, *v) == 0 {
+        compressor::set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+        return core::ptr::null_mut();
+      }
+    }
+    let alloc_per_thread = (0..desired_num_threads).map(|index| BrotliSubclassableAllocator::new(
+      SubclassableAllocator::new(
+        CAllocator{
+          alloc_func:alloc_func,
+          free_func:free_func,
+          opaque:match alloc_opaque {
+            Some(opaques) => opaques[index],
+            None => core::ptr::null_mut(),
+          },
+        }))).collect();
+    // the caller promises the input outlives the job, which BrotliEncoderCompressWorkPoolWait ends
+    let input_slice = core::mem::transmute::<&[u8], &'static [u8]>(slice_from_raw_parts_or_nil(input, input_size));
+    let pending = enc::submit_compress_worker_pool(
+      &params,
+      SliceRef(input_slice),
+      alloc_per_thread,
+      (*work_pool_wrapper.0).work_pool.new_tenant(1, 0),
+    );
+    compressor::set_last_error_code(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR);
+    std::boxed::Box::into_raw(std::boxed::Box::new(BrotliEncoderWorkPoolJob{pending: Some(pending), compressed: None}))
+  }) {
+    Ok(ret) => ret, // no panic
+    Err(panic_err) => {
+      error_print(panic_err); // print panic
+      core::ptr::null_mut() // fail
+    },
+  }
+}
+
+#[cfg(feature="std")]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderWorkPoolJobIsDone(job: *const BrotliEncoderWorkPoolJob) -> i32 {
+  if job.is_null() {
+    return 1;
+  }
+  match (*job).pending {
+    Some(ref pending) => if pending.is_done() { 1 } else { 0 },
+    None => 1,
+  }
+}
+
+#[cfg(feature="std")]
+#[cfg_attr(not(feature="ffi-prefixed-encoder-only"), no_mangle)]
+pub unsafe extern fn BrotliEncoderCompressWorkPoolWait(
+  job: *mut BrotliEncoderWorkPoolJob,
+  encoded_size: *mut usize,
+  encoded: *mut u8,
+) -> i32 {
+  if job.is_null() {
+    return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+  }
+  let job_wrapper = UnsafeUnwindJob(job);
+  match compressor::catch_panic(|| {
+    let job = &mut *job_wrapper.0;
+    if let Some(pending) = job.pending.take() {
+      match pending.wait().compressed {
+        Ok(compressed) => job.compressed = Some(compressed),
+        Err(err) => {
+          core::mem::drop(std::boxed::Box::from_raw(job_wrapper.0));
+          compressor::set_last_thread_error(&err);
+          return 0;
+        },
+      }
+    }
+    let compressed_len = match job.compressed {
+      Some(ref compressed) => compressed.len(),
+      None => 0,
+    };
+    if compressed_len > *encoded_size {
+      // the job stays alive so the caller can retry with a buffer of the reported size
+      *encoded_size = compressed_len;
+      return finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL);
+    }
+    let job = std::boxed::Box::from_raw(job_wrapper.0);
+    if let Some(compressed) = job.compressed {
+      slice_from_raw_parts_or_nil_mut(encoded, *encoded_size)[..compressed.len()].clone_from_slice(&compressed[..]);
+    }
+    *encoded_size = compressed_len;
+    finish_with(BrotliEncoderErrorCode::BROTLI_ENCODER_NO_ERROR)
+  }) {
+    Ok(ret) => ret, // no panic
+    Err(panic_err) => {
+      error_print(panic_err); // print panic
+      0 // fail
+    },
+  }
+}
+
+// CBrotliEncoder names for the functions above; see c/brotli/cencode.h.
+#[no_mangle]
+pub extern fn CBrotliEncoderMaxCompressedSizeMulti(input_size: usize, num_threads: usize) -> usize {
+  BrotliEncoderMaxCompressedSizeMulti(input_size, num_threads)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompressMulti(
+  num_params: usize,
+  param_keys: *const BrotliEncoderParameter,
+  param_values: *const u32,
+  input_size: usize,
+  input: *const u8,
+  encoded_size: *mut usize,
+  encoded: *mut u8,
+  desired_num_threads: usize,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  alloc_opaque_per_thread: *mut*mut c_void,
+) -> i32 {
+  BrotliEncoderCompressMulti(num_params, param_keys, param_values, input_size, input,
+                             encoded_size, encoded, desired_num_threads,
+                             alloc_func, free_func, alloc_opaque_per_thread)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCreateWorkPool(
+  num_threads: usize,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  opaque: *mut c_void,
+) -> *mut BrotliEncoderWorkPool {
+  BrotliEncoderCreateWorkPool(num_threads, alloc_func, free_func, opaque)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderDestroyWorkPool(work_pool_ptr: *mut BrotliEncoderWorkPool) {
+  BrotliEncoderDestroyWorkPool(work_pool_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompressWorkPool(
+  work_pool: *mut BrotliEncoderWorkPool,
+  num_params: usize,
+  param_keys: *const BrotliEncoderParameter,
+  param_values: *const u32,
+  input_size: usize,
+  input: *const u8,
+  encoded_size: *mut usize,
+  encoded: *mut u8,
+  desired_num_threads: usize,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  alloc_opaque_per_thread: *mut*mut c_void,
+) -> i32 {
+  BrotliEncoderCompressWorkPool(work_pool, num_params, param_keys, param_values, input_size, input,
+                                encoded_size, encoded, desired_num_threads,
+                                alloc_func, free_func, alloc_opaque_per_thread)
+}
+
+#[cfg(feature="std")]
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompressWorkPoolSubmit(
+  work_pool: *mut BrotliEncoderWorkPool,
+  num_params: usize,
+  param_keys: *const BrotliEncoderParameter,
+  param_values: *const u32,
+  input_size: usize,
+  input: *const u8,
+  desired_num_threads: usize,
+  alloc_func: brotli_alloc_func,
+  free_func: brotli_free_func,
+  alloc_opaque_per_thread: *mut*mut c_void,
+) -> *mut BrotliEncoderWorkPoolJob {
+  BrotliEncoderCompressWorkPoolSubmit(work_pool, num_params, param_keys, param_values, input_size, input,
+                                      desired_num_threads, alloc_func, free_func, alloc_opaque_per_thread)
+}
+
+#[cfg(feature="std")]
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderWorkPoolJobIsDone(job: *const BrotliEncoderWorkPoolJob) -> i32 {
+  BrotliEncoderWorkPoolJobIsDone(job)
+}
+
+#[cfg(feature="std")]
+#[no_mangle]
+pub unsafe extern fn CBrotliEncoderCompressWorkPoolWait(
+  job: *mut BrotliEncoderWorkPoolJob,
+  encoded_size: *mut usize,
+  encoded: *mut u8,
+) -> i32 {
+  BrotliEncoderCompressWorkPoolWait(job, encoded_size, encoded)
+}
+
 #[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
 fn catch_panic_wstate<F:FnOnce()->*mut BrotliEncoderWorkPool+panic::UnwindSafe>(f: F) -> thread::Result<*mut BrotliEncoderWorkPool> {
     panic::catch_unwind(f)
 }
 
 #[cfg(all(feature="std", not(feature="pass-through-ffi-panics")))]
-fn error_print<Err:core::fmt::Debug>(err: Err) {
-    let _ign = writeln!(&mut io::stderr(), "Internal Error {:?}", err);
+fn catch_panic_job<F:FnOnce()->*mut BrotliEncoderWorkPoolJob+panic::UnwindSafe>(f: F) -> thread::Result<*mut BrotliEncoderWorkPoolJob> {
+    panic::catch_unwind(f)
 }
 
-#[cfg(any(not(feature="std"), feature="pass-through-ffi-panics"))]
-fn catch_panic_wstate<F:FnOnce()->*mut BrotliEncoderWorkPool>(f: F) -> Result<*mut BrotliEncoderWorkPool, ()> {
+#[cfg(all(feature="std", feature="pass-through-ffi-panics"))]
+fn catch_panic_job<F:FnOnce()->*mut BrotliEncoderWorkPoolJob>(f: F) -> Result<*mut BrotliEncoderWorkPoolJob, ()> {
     Ok(f())
 }
 
 #[cfg(any(not(feature="std"), feature="pass-through-ffi-panics"))]
-fn error_print<Err>(_err: Err) {
+fn catch_panic_wstate<F:FnOnce()->*mut BrotliEncoderWorkPool>(f: F) -> Result<*mut BrotliEncoderWorkPool, ()> {
+    Ok(f())
 }
+
@@ -89,6 +89,53 @@ fn test_compress_empty_workpool() {
 }
 
 
+#[test]
+fn test_compress_workpool_submit_wait() {
+  let input = [102, 114, 111, 109, 32, 99, 116, 121, 112, 101, 115, 32, 105, 109, 112, 111, 114, 116, 32, 42, 10, 10, 99, 108, 97, 115, 115, 32, 69, 110, 117, 109, 84, 121, 112, 101, 40, 116, 121, 112, 101, 40, 99, 95, 117, 105, 110, 116, 41, 41, 58, 10, 32, 32, 32, 32, 100, 101, 102, 32, 95, 95, 110, 101, 119, 95, 95, 40, 109, 101, 116, 97, 99, 108, 115, 41, 58, 10, 32, 32, 32, 32, 32, 32, 32, 32, 112, 97, 115, 115, 10];
+  let params = [BrotliEncoderParameter::BROTLI_PARAM_QUALITY, BrotliEncoderParameter::BROTLI_PARAM_LGWIN, BrotliEncoderParameter::BROTLI_PARAM_SIZE_HINT, BrotliEncoderParameter::BROTLI_PARAM_CATABLE, BrotliEncoderParameter::BROTLI_PARAM_MAGIC_NUMBER, BrotliEncoderParameter::BROTLI_PARAM_Q9_5];
+  let values = [5u32,16u32,91u32,0u32,0u32,0u32];
+  let mut encoded_size = [BrotliEncoderMaxCompressedSizeMulti(input.len(), 4); 2];
+  let mut encoded_backing = [[0u8;145]; 2];
+  let ret = unsafe {
+    let wp = BrotliEncoderCreateWorkPool(2, None, None, core::ptr::null_mut());
+    // both jobs are in flight on the pool at once
+    let jobs = [2usize, 4usize].iter().map(|num_threads| BrotliEncoderCompressWorkPoolSubmit(
+      wp,
+      params.len(),
+      params[..].as_ptr(),
+      values[..].as_ptr(),
+      input.len(),
+      input[..].as_ptr(),
+      *num_threads,
+      None,
+      None,
+      core::ptr::null_mut())).collect::<std::vec::Vec<_>>();
+    let mut inner_ret = [0i32; 2];
+    for (index, job) in jobs.into_iter().enumerate() {
+      assert!(!job.is_null());
+      inner_ret[index] = BrotliEncoderCompressWorkPoolWait(job, &mut encoded_size[index], encoded_backing[index].as_mut_ptr());
+    }
+    BrotliEncoderDestroyWorkPool(wp);
+    inner_ret
+  };
+  assert_eq!(ret, [1, 1]);
+  for (encoded, encoded_size) in encoded_backing.iter().zip(encoded_size.iter()) {
+    let mut rt_size = 256;
+    let mut rt_buffer = [0u8;256];
+    let ret2 = unsafe {
+      super::super::decompressor::CBrotliDecoderDecompress(*encoded_size, encoded.as_ptr(),
+                                                           &mut rt_size, rt_buffer.as_mut_ptr())
+    };
+    match ret2 {
+      super::super::decompressor::ffi::interface::BrotliDecoderResult::BROTLI_DECODER_RESULT_SUCCESS => {
+      },
+      _ => panic!("{}", ret2 as i32),
+    }
+    assert_eq!(rt_size, input.len());
+    assert_eq!(&rt_buffer[..rt_size], &input[..]);
+  }
+}
+
 #[test]
 fn test_compress_empty_multi_raw() {
   let input = [];
@@ -173,10 +220,10 @@ fn test_compress_empty_multi_raw_one_thread() {
   let params = [BrotliEncoderParameter::BROTLI_PARAM_QUALITY, BrotliEncoderParameter::BROTLI_PARAM_Q9_5, BrotliEncoderParameter::BROTLI_PARAM_CATABLE,  BrotliEncoderParameter::BROTLI_PARAM_APPENDABLE, BrotliEncoderParameter::BROTLI_PARAM_MAGIC_NUMBER, ];
   let values = [10u32,1u32,1u32,1u32,1u32];
   let mut encoded_size = BrotliEncoderMaxCompressedSizeMulti(input.len(), 1);
-  let mut encoded_backing = [0u8;25];
+  let mut encoded_backing = [0u8;48];
   let encoded = &mut encoded_backing[..encoded_size];
   assert_eq!(params.len(), 5);
-  assert_eq!(encoded_size, 25);  
+  assert_eq!(encoded_size, 48);  
   let ret = unsafe {
     BrotliEncoderCompressMulti(
       params.len(),
@@ -248,3 +295,42 @@ fn test_compress_empty_multi_catable() {
   assert_eq!(&rt_buffer[..rt_size], &input[..]);
 }
 
+
+#[test]
+fn test_compress_workpool_reports_error_codes() {
+  let input = [b'a'; 4096];
+  let params = [BrotliEncoderParameter::BROTLI_PARAM_QUALITY, BrotliEncoderParameter::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING];
+  let bad_values = [5u32, 2u32];
+  let values = [5u32, 0u32];
+  let mut encoded = [0u8; 16];
+  unsafe {
+    let wp = BrotliEncoderCreateWorkPool(2, None, None, core::ptr::null_mut());
+    let mut encoded_size = encoded.len();
+    assert_eq!(BrotliEncoderCompressWorkPool(wp, params.len(), params[..].as_ptr(), values[..].as_ptr(),
+                                             input.len(), input[..].as_ptr(), &mut encoded_size, encoded.as_mut_ptr(),
+                                             0, None, None, core::ptr::null_mut()), 0);
+    assert_eq!(compressor::BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_ARGUMENTS);
+    let job = BrotliEncoderCompressWorkPoolSubmit(wp, params.len(), params[..].as_ptr(), bad_values[..].as_ptr(),
+                                                  input.len(), input[..].as_ptr(), 2, None, None, core::ptr::null_mut());
+    assert!(job.is_null());
+    assert_eq!(compressor::BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_INVALID_PARAMETER);
+    let job = BrotliEncoderCompressWorkPoolSubmit(wp, params.len(), params[..].as_ptr(), values[..].as_ptr(),
+                                                  input.len(), input[..].as_ptr(), 2, None, None, core::ptr::null_mut());
+    assert_eq!(BrotliEncoderCompressWorkPoolWait(job, &mut encoded_size, encoded.as_mut_ptr()), 0);
+    assert_eq!(compressor::BrotliEncoderGetLastErrorCode(), BrotliEncoderErrorCode::BROTLI_ENCODER_ERROR_OUTPUT_TOO_SMALL);
+    assert!(encoded_size > encoded.len());
+    // the job survives so the caller can retry with the size it was told
+    let mut larger = vec![0u8; encoded_size];
+    assert_eq!(BrotliEncoderCompressWorkPoolWait(job, &mut encoded_size, larger.as_mut_ptr()), 1);
+    assert_eq!(encoded_size, larger.len());
+    let mut rt_size = input.len();
+    let mut rt_buffer = [0u8; 4096];
+    match super::super::decompressor::CBrotliDecoderDecompress(encoded_size, larger.as_ptr(),
+                                                               &mut rt_size, rt_buffer.as_mut_ptr()) {
+      super::super::decompressor::ffi::interface::BrotliDecoderResult::BROTLI_DECODER_RESULT_SUCCESS => {},
+      ret2 => panic!("{:?}", ret2 as i32),
+    }
+    assert_eq!(&rt_buffer[..rt_size], &input[..]);
+    BrotliEncoderDestroyWorkPool(wp);
+  }
+}
@@ -5,13 +5,14 @@
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
 #![cfg_attr(feature="benchmark", feature(test))]
-#![cfg_attr(feature="no-stdlib-ffi-binding",cfg_attr(not(feature="std"), feature(lang_items)))]
 #[macro_use]
 // <-- for debugging, remove xprintln from bit_reader and replace with println
 #[cfg(feature="std")]
 extern crate std;
 #[cfg(feature="simd")]
 extern crate packed_simd_2;
+#[cfg(all(feature="rayon", feature="std"))]
+extern crate rayon;
 #[cfg(feature="std")]
 extern crate alloc_stdlib;
 #[allow(unused_imports)]
@@ -68,6 +69,7 @@ pub use brotli_decompressor::{BrotliDecompressStream, BrotliResult};
 #[cfg(feature="std")]
 pub use enc::{BrotliCompress, BrotliCompressCustomAlloc};
 pub use enc::{BrotliCompressCustomIo, BrotliCompressCustomIoCustomDict};
+pub use enc::{CommandSink, BrotliCompressToSink};
 
 #[cfg(feature="std")]
 pub use enc::reader::{CompressorReader};